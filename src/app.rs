@@ -15,6 +15,12 @@
 // You should have received a copy of the GNU General Public License
 // along with RTRK. If not, see <https://www.gnu.org/licenses/>.
 
+mod export;
+mod history;
+mod order;
+mod pattern;
+mod preset;
+mod settings;
 mod voice;
 
 const SKIN: &str = r#"
@@ -28,23 +34,23 @@ const SKIN: &str = r#"
 ┃      '                             \  ___^_____/    /\____|     /__:       \ ┃
 ┃                                     \/   ;      \  /  4ZM  \___/   |_______/ ┃
 ┠──────────────────────────────────────────────────'/──────────────────────────┨
-┃ ▚▚▚▚▚▚▞▚▞▚▞▚▞▚▞▚▞▚▞▚▞▚█████                            |    |   |   |        ┃
+┃                                                       |    |   |   |        ┃
 ┠──────────────────────────────────────────────────────────────────────────────┨
-┃ ## : ▁▂▃▄▅▆▇██▆▅▃  :  ▆▅▃▁▂▃▄▅▆▇█   :  ▅▆▇█▅▆▇█▆▅▃▁  : ▃▁▁▁▂▃▄▅▆▇█▆▃  :  gFx ┃
+┃ ## : ▁▂▃▄▅▆▇██▆▅▃▂ : ▆▅▃▁▂▃▄▅▆▇█▇▆ : ▅▆▇█▅▆▇█▆▅▃▁▁ : ▃▁▁▁▂▃▄▅▆▇█▆▃ :  gFx    ┃
 ┠──────────────────────────────────────────────────────────────────────────────┨
-┃ 09 . C#4 1 A0 101  .  --- - -- ---  .  --- - -- ---  .  --- - -- ---  .  2FF ┃
-┃ 0A : --- - -- ---  :  C#4 1 A0 101  :  --- - -- ---  :  --- - -- ---  :  --- ┃
-┃ 09 : C#4 1 A0 101  :  --- - -- ---  :  --- - -- ---  :  --- - -- ---  :  --- ┃
-┃ 0A : --- - -- ---  :  F#4 1 A0 101  :  --- - -- ---  :  --- - -- ---  :  --- ┃
-┃ 0B > --- - FF --- <:> --- - -- --- <:> --- - -- --- <:> --- - -- --- <:> --- ┃
-┃ 0C : --- - -- 105  :  --- - -- ---  :  --- - -- ---  :  --- - -- ---  :  000 ┃
-┃ 0D : A-5 4 20 ---  :  --- - -- ---  :  A-5 4 20 ---  :  --- - -- ---  :  --- ┃
-┃ 0C : --- - -- 105  :  --- - -- ---  :  --- - -- ---  :  --- - -- ---  :  020 ┃
-┃ 0D ' A-5 4 20 ---  '  --- - -- ---  '  C#4 1 A0 101  '  --- - -- ---  '  --- ┃
+┃                                                                              ┃
+┃                                                                              ┃
+┃                                                                              ┃
+┃                                                                              ┃
+┃                                                                              ┃
+┃                                                                              ┃
+┃                                                                              ┃
+┃                                                                              ┃
+┃                                                                              ┃
 ┗━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━┛
 "#;
 
-use crate::synth::{AsyncSynth, Frequency};
+use crate::synth::{AsyncSynth, Velocity};
 use crate::uifw::interaction::{CharModifiers, Event};
 use crate::uifw::pos::Pos;
 use crate::uifw::widget::button::{button_rc, ButtonRc, ButtonView};
@@ -52,14 +58,27 @@ use crate::uifw::widget::focus::{FocusChain, FocusableRc};
 use crate::uifw::widget::label::{label, Label};
 use crate::uifw::widget::{Focusable, Task, View, Widget};
 use crate::uifw::TaskProcessor;
-use crate::{impl_focusable_with_focuschain, synth};
-use synth::rodio::RodioAudioSink;
+use crate::{impl_focusable_with_focuschain, song, synth};
+use export::{export_options_rc, ExportOptionsRc, ExportOptionsView};
+use history::History;
+use order::{order_editor_rc, OrderEditorRc, OrderEditorView};
+use pattern::{pattern_grid_rc, PatternGridRc, PatternGridView};
+use preset::{preset_browser_rc, PresetBrowserRc, PresetBrowserView};
+use settings::{song_settings_rc, SongSettingsRc, SongSettingsView};
+use song::pattern::Note;
+use song::player::{Player, Playhead};
+use std::cell::RefCell;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
 use voice::list::{voicelist_rc, VoiceListRc, VoiceListView};
 
-#[derive(Copy, Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum AppTask {
-    PlayVoice(synth::Voice, synth::Frequency),
+    PlayVoice(synth::Voice, synth::Frequency, synth::Velocity),
     StopVoice,
+    PlaySong(usize),
+    StopSong,
 }
 
 #[derive(Copy, Clone, Debug, PartialEq)]
@@ -67,15 +86,39 @@ pub enum Message {
     Quit,
     Play,
     Stop,
-    PlayVoice(synth::Frequency),
+    /// Claviature key, in semitones above C-0
+    PlayNote(u8),
     StopVoice,
+    SetKbdVelocity(u8),
+    SetOctave(u8),
+    SetEditStep(usize),
+    ToggleRecord,
+    RecordNoteOff,
+    RecordClear,
+    Undo,
+    Redo,
+    Save,
+    /// Read the song file again, dropping unsaved changes
+    Load,
     Rewind,
     NextFocus,
     PrevFocus,
     NextKbdMode,
     VoiceList(voice::list::Message),
+    Preset(preset::Message),
+    Export(export::Message),
+    Settings(settings::Message),
+    Order(order::Message),
+    Pattern(pattern::Message),
+    SynthError(synth::Error),
+    /// Position in the song that is currently heard
+    Playhead(Playhead),
+    SetQuantize(usize),
+    ToggleOverdub,
 }
 
+pub type SongRc = Rc<RefCell<song::Song>>;
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum KbdMode {
     Text,
@@ -83,76 +126,406 @@ pub enum KbdMode {
 }
 pub struct App {
     voices: VoiceListRc,
+    presets: PresetBrowserRc,
+    export: ExportOptionsRc,
+    settings: SongSettingsRc,
+    song: SongRc,
+    order: OrderEditorRc,
+    grid: PatternGridRc,
     play_btn: ButtonRc<Message>,
     stop_btn: ButtonRc<Message>,
     rewind_btn: ButtonRc<Message>,
     focus_chain: FocusChain,
     kbd_mode: KbdMode,
+    kbd_velocity: u8,
+    /// Claviature notes are written into the pattern
+    recording: bool,
+    octave: u8,
+    /// Rows the grid cursor moves after recording a note
+    edit_step: usize,
+    /// Notes recorded during playback are moved to a multiple of this many rows
+    quantize: usize,
+    /// Notes recorded during playback go to a free track instead of replacing the cell
+    overdub: bool,
+    status: Option<String>,
+    playing: bool,
+    playhead: Option<Playhead>,
+    history: History,
+    /// Where the song is saved
+    path: PathBuf,
 }
 
+const MAX_OCTAVE: u8 = 8;
+const DEFAULT_PATH: &str = "song.rtrk";
+const MAX_EDIT_STEP: usize = 0x10;
+const MAX_QUANTIZE: usize = 0x10;
+
+/// The synth channel used to audition voices, the channels before it are used by the tracks
+const PREVIEW_CHANNEL: usize = song::pattern::MAX_TRACKS;
+
+/// How far ahead of the audio clock the song is scheduled. Must cover the time between frames.
+const LOOK_AHEAD: u64 = synth::SAMPLE_RATE as u64 / 10;
+
 pub struct AppTaskProcessor {
     synth: AsyncSynth,
+    song: SongRc,
+    player: Option<Player>,
 }
+/// The default audio device
+#[cfg(feature = "audio")]
+fn audio_sink() -> Result<synth::rodio::RodioAudioSink, synth::Error> {
+    synth::rodio::RodioAudioSink::new()
+}
+
+/// Without the audio feature there is no device, the synth plays on a silent sink
+#[cfg(not(feature = "audio"))]
+fn audio_sink() -> Result<synth::null::NullAudioSink, synth::Error> {
+    Err(synth::Error::NoAudioDevice)
+}
+
 impl AppTaskProcessor {
-    pub fn new() -> Self {
+    pub fn new(song: SongRc) -> Self {
         Self {
-            synth: AsyncSynth::new(|| RodioAudioSink::new(4), 4),
+            synth: AsyncSynth::new(audio_sink(), PREVIEW_CHANNEL + 1),
+            song,
+            player: None,
+        }
+    }
+
+    fn stop_song(&mut self) -> Result<(), synth::Error> {
+        match self.player.take() {
+            Some(player) => player.stop(&mut self.synth),
+            None => Ok(()),
         }
     }
 }
-impl TaskProcessor<AppTask> for AppTaskProcessor {
-    fn process(&mut self, task: &AppTask) {
-        let channel = 0;
-        match task {
-            AppTask::PlayVoice(v, freq) => self
-                .synth
-                .send(synth::Message::Play(*v, channel, *freq))
-                .expect(""),
-            AppTask::StopVoice => self.synth.send(synth::Message::Stop(channel)).expect(""),
+impl TaskProcessor<AppTask, Message> for AppTaskProcessor {
+    fn process(&mut self, task: &AppTask) -> Vec<Message> {
+        let channel = PREVIEW_CHANNEL;
+        let result = match task {
+            AppTask::PlayVoice(v, freq, vel) => {
+                self.synth
+                    .send(synth::Message::Play(v.clone(), channel, *freq, *vel))
+            }
+            AppTask::StopVoice => self.synth.send(synth::Message::Stop(channel)),
+            AppTask::PlaySong(pos) => {
+                // Start after the notes of a playing song that have already been sent
+                let start = self.player.as_ref().map_or(self.synth.time(), |p| {
+                    p.scheduled_until().max(self.synth.time())
+                });
+                let result = self.stop_song();
+                self.player = Some(Player::new(&self.song.borrow(), *pos, start));
+                result
+            }
+            AppTask::StopSong => self.stop_song(),
+        };
+        match result {
+            Ok(()) => vec![],
+            Err(e) => vec![Message::SynthError(e)],
+        }
+    }
+
+    fn poll(&mut self) -> Vec<Message> {
+        let mut msgs = vec![];
+        if let Some(player) = &mut self.player {
+            let now = self.synth.time();
+            let song = self.song.borrow();
+            if let Err(e) = player.advance(&song, &mut self.synth, now + LOOK_AHEAD) {
+                msgs.push(Message::SynthError(e));
+            }
+            if let Some(playhead) = player.playhead(now) {
+                msgs.push(Message::Playhead(playhead));
+            }
         }
+        msgs.extend(
+            self.synth
+                .poll_errors()
+                .into_iter()
+                .map(Message::SynthError),
+        );
+        msgs
     }
 }
 
 impl App {
     pub fn new() -> Self {
         let voices = voicelist_rc();
+        let presets = preset_browser_rc();
+        let export = export_options_rc();
         let play_btn = button_rc(">", Message::Play);
         let stop_btn = button_rc(".", Message::Stop);
         let rewind_btn = button_rc("<<", Message::Rewind);
+        let song = Rc::new(RefCell::new(song::Song::new()));
+        let settings = song_settings_rc(song.clone());
+        let order = order_editor_rc(song.clone());
+        let grid = pattern_grid_rc(song.clone());
 
         let mut focus_chain = FocusChain::new();
         focus_chain.push(voices.clone() as FocusableRc);
+        focus_chain.push(presets.clone() as FocusableRc);
+        focus_chain.push(export.clone() as FocusableRc);
+        focus_chain.push(settings.clone() as FocusableRc);
+        focus_chain.push(order.clone() as FocusableRc);
         focus_chain.push(rewind_btn.clone() as FocusableRc);
         focus_chain.push(stop_btn.clone() as FocusableRc);
         focus_chain.push(play_btn.clone() as FocusableRc);
+        focus_chain.push(grid.clone() as FocusableRc);
 
         Self {
             voices,
+            presets,
+            export,
+            settings,
+            song,
+            order,
+            grid,
             rewind_btn,
             stop_btn,
             play_btn,
             focus_chain,
             kbd_mode: KbdMode::Text,
+            kbd_velocity: 0xFF,
+            recording: false,
+            octave: 4,
+            edit_step: 1,
+            quantize: 1,
+            overdub: false,
+            status: None,
+            playing: false,
+            playhead: None,
+            history: History::new(history::DEFAULT_DEPTH),
+            path: PathBuf::from(DEFAULT_PATH),
         }
     }
-}
 
-impl Widget<Message, AppTask, AppView> for App {
-    fn update(&mut self, msg: Message) -> Vec<Task<AppTask>> {
+    /// The song is shared with the task processor that plays it
+    pub fn song(&self) -> SongRc {
+        self.song.clone()
+    }
+
+    /// Number of edits that can be undone
+    pub fn set_undo_depth(&mut self, depth: usize) {
+        self.history.set_depth(depth);
+    }
+
+    /// Load the song from a file, or start a new song there if it doesn't exist
+    pub fn open(&mut self, path: &Path) -> Result<(), String> {
+        self.path = path.to_path_buf();
+        match path.try_exists() {
+            Ok(false) => Ok(()),
+            _ => self.reload(),
+        }
+    }
+
+    /// Read the song file again. Unlike opening it, a missing file is an error.
+    fn reload(&mut self) -> Result<(), String> {
+        fs::read_to_string(&self.path)
+            .map_err(|e| e.to_string())
+            .and_then(|text| self.load(&text))
+            .map_err(|e| format!("{}: {}", self.path.display(), e))
+    }
+
+    fn load(&mut self, text: &str) -> Result<(), String> {
+        let (song, texts) = song::file::read(text).map_err(|e| e.to_string())?;
+        *self.song.borrow_mut() = song;
+        let mut voices = self.voices.borrow_mut();
+        for (slot, text) in texts.iter().enumerate() {
+            voices.set_voice_text(slot, text);
+        }
+        drop(voices);
+        self.history.clear();
+        self.order.borrow_mut().set_position(0);
+        self.sync_grid_to_order();
+        self.grid.borrow_mut().set_row(0);
+        Ok(())
+    }
+
+    fn save(&self) -> Result<(), String> {
+        let voices = self.voices.borrow();
+        let texts: Vec<String> = (0..song::MAX_VOICES)
+            .map(|i| voices.voice_text(i))
+            .collect();
+        let text = song::file::write(&self.song.borrow(), &texts);
+        fs::write(&self.path, text).map_err(|e| format!("{}: {}", self.path.display(), e))
+    }
+
+    /// Render the song to a WAV file next to the song file, or a file per stem named after it
+    fn export_wav(&self) -> Result<String, String> {
+        let export = self.export.borrow();
+        let song = self.song.borrow();
+        let options = export.options();
+        let Some(split) = export.stems() else {
+            let path = self.path.with_extension("wav");
+            let data = song::wav::export(&song, &options).map_err(|e| e.to_string())?;
+            fs::write(&path, data).map_err(|e| format!("{}: {}", path.display(), e))?;
+            return Ok(path.display().to_string());
+        };
+
+        let prefix = self.path.with_extension("").display().to_string();
+        let stems = song::wav::export_stems(&song, split, &options).map_err(|e| e.to_string())?;
+        for (stem, data) in stems.iter() {
+            let path = stem.file_name(&prefix);
+            fs::write(&path, data).map_err(|e| format!("{}: {}", path, e))?;
+        }
+        Ok(format!("{} stems to {}-*.wav", stems.len(), prefix))
+    }
+
+    /// Write the note into the grid with the selected voice
+    fn record_note(&mut self, note: Note) {
+        let voice = match note {
+            Note::Key(_) => Some(self.voices.borrow().selected_index() as u8),
+            Note::Off => None,
+        };
+        match self.overdub && self.playing {
+            true => self.record(pattern::Message::Overdub(note, voice)),
+            false => self.record(pattern::Message::Record(note, voice)),
+        }
+    }
+
+    /// Apply the edit at the grid cursor and move on by the edit step. During playback the edit
+    /// is instead applied at the quantised row nearest the playhead.
+    fn record(&mut self, edit: pattern::Message) {
+        if !self.recording {
+            return;
+        }
+        let mut grid = self.grid.borrow_mut();
+        match self.playhead {
+            Some(playhead) if self.playing => {
+                let len = self.song.borrow().pattern_at(playhead.pos).len();
+                grid.set_row(quantize(playhead, self.quantize, len));
+                grid.update(edit);
+            }
+            _ => {
+                grid.update(edit);
+                grid.update(pattern::Message::Step(self.edit_step));
+            }
+        }
+    }
+
+    fn sync_grid_to_order(&mut self) {
+        let pattern = self.order.borrow().selected_pattern();
+        self.grid.borrow_mut().set_pattern(pattern);
+    }
+
+    /// Keep the order list and grid inside the song after it was changed behind their backs
+    fn sync_to_song(&mut self) {
+        let pos = self.order.borrow().position();
+        self.order.borrow_mut().set_position(pos);
+        self.sync_grid_to_order();
+    }
+
+    fn handle(&mut self, msg: Message) -> Vec<Task<AppTask>> {
         match msg {
             Message::Quit => return vec![Task::Quit],
             Message::VoiceList(m) => {
-                return self.voices.borrow_mut().update(m);
+                let tasks = self.voices.borrow_mut().update(m);
+                if let voice::list::Message::Voice(idx, _) = m {
+                    let voice = self.voices.borrow().get_voice(idx);
+                    self.song.borrow_mut().set_voice(idx, voice);
+                }
+                let selected = self.voices.borrow().selected_index() as u8;
+                self.grid.borrow_mut().set_voice(selected);
+                return tasks;
+            }
+            Message::Preset(preset::Message::Load) => {
+                let slot = self.voices.borrow().selected_index();
+                let preset = self.presets.borrow().preset();
+                self.voices.borrow_mut().set_voice_text(slot, preset.text);
+                self.song.borrow_mut().set_voice(slot, preset.voice());
+            }
+            Message::Preset(m) => return self.presets.borrow_mut().update(m),
+            Message::Export(export::Message::Export) => {
+                self.status = Some(match self.export_wav() {
+                    Ok(what) => format!("Exported {}", what),
+                    Err(e) => e,
+                });
+            }
+            Message::Export(m) => return self.export.borrow_mut().update(m),
+            Message::Settings(m) => return self.settings.borrow_mut().update(m),
+            Message::Order(m) => {
+                let tasks = self.order.borrow_mut().update(m);
+                self.sync_grid_to_order();
+                return tasks;
+            }
+            Message::Pattern(m) => {
+                return self.grid.borrow_mut().update(m);
+            }
+            Message::Rewind => {
+                self.order.borrow_mut().set_position(0);
+                self.sync_grid_to_order();
+                self.grid.borrow_mut().set_row(0);
+                if self.playing {
+                    return vec![Task::App(AppTask::PlaySong(0))];
+                }
+            }
+            Message::Stop => {
+                self.playing = false;
+                self.playhead = None;
+                return vec![Task::App(AppTask::StopSong)];
+            }
+            Message::Play => {
+                self.playing = true;
+                let pos = self.order.borrow().position();
+                return vec![Task::App(AppTask::PlaySong(pos))];
+            }
+            Message::Playhead(playhead) => {
+                // The edit position follows the playback
+                if self.playing {
+                    self.playhead = Some(playhead);
+                    self.order.borrow_mut().set_position(playhead.pos);
+                    self.sync_grid_to_order();
+                    self.grid.borrow_mut().set_row(playhead.row);
+                }
             }
-            Message::Rewind => {}
-            Message::Stop => {}
-            Message::Play => {}
             Message::StopVoice => return vec![Task::App(AppTask::StopVoice)],
-            Message::PlayVoice(freq) => {
-                if let Some(voice) = self.voices.borrow().get_selected_voice() {
-                    return vec![Task::App(AppTask::PlayVoice(voice, freq))];
+            Message::PlayNote(key) => {
+                let note = Note::Key(key);
+                self.record_note(note);
+                // Audition the preset while browsing them
+                let voice = match self.presets.borrow().has_focus() {
+                    true => self.presets.borrow().preset().voice(),
+                    false => {
+                        self.song.borrow().voices[self.voices.borrow().selected_index()].clone()
+                    }
+                };
+                if let (Some(voice), Some(freq)) = (voice, note.frequency()) {
+                    let vel = Velocity(self.kbd_velocity as f32 / 0xFF as f32);
+                    return vec![Task::App(AppTask::PlayVoice(voice, freq, vel))];
                 }
             }
+            Message::SetKbdVelocity(vel) => self.kbd_velocity = vel,
+            Message::SetOctave(octave) => self.octave = octave,
+            Message::SetEditStep(step) => self.edit_step = step,
+            Message::ToggleRecord => self.recording = !self.recording,
+            Message::SetQuantize(rows) => self.quantize = rows,
+            Message::ToggleOverdub => self.overdub = !self.overdub,
+            Message::RecordNoteOff => self.record_note(Note::Off),
+            Message::RecordClear => self.record(pattern::Message::ClearCell),
+            Message::Save => {
+                self.status = Some(match self.save() {
+                    Ok(()) => format!("Saved {}", self.path.display()),
+                    Err(e) => e,
+                });
+            }
+            Message::Load => {
+                self.playing = false;
+                self.playhead = None;
+                self.status = Some(match self.reload() {
+                    Ok(()) => format!("Loaded {}", self.path.display()),
+                    Err(e) => e,
+                });
+                return vec![Task::App(AppTask::StopSong)];
+            }
+            Message::Undo | Message::Redo => {
+                let (mut song, mut voices) = (self.song.borrow_mut(), self.voices.borrow_mut());
+                match msg {
+                    Message::Undo => self.history.undo(&mut song, &mut voices),
+                    _ => self.history.redo(&mut song, &mut voices),
+                };
+                drop((song, voices));
+                self.sync_to_song();
+            }
+            Message::SynthError(e) => self.status = Some(e.to_string()),
             Message::NextFocus => self.next_focus(),
             Message::PrevFocus => self.prev_focus(),
             Message::NextKbdMode => {
@@ -164,78 +537,208 @@ impl Widget<Message, AppTask, AppView> for App {
         };
         vec![]
     }
+}
+
+impl Widget<Message, AppTask, AppView> for App {
+    /// Edits made while handling the message are recorded in the undo history
+    fn update(&mut self, msg: Message) -> Vec<Task<AppTask>> {
+        let slot = match msg {
+            Message::VoiceList(voice::list::Message::Voice(idx, _)) => Some(idx),
+            Message::Preset(preset::Message::Load) => Some(self.voices.borrow().selected_index()),
+            _ => None,
+        };
+        let voice = slot.map(|idx| (idx, self.voices.borrow().voice_text(idx)));
+        let settings =
+            matches!(msg, Message::Settings(_)).then(|| history::Settings::of(&self.song.borrow()));
+
+        let tasks = self.handle(msg);
+
+        let mut edits = self.order.borrow_mut().take_edits();
+        edits.extend(self.grid.borrow_mut().take_edits());
+        if let Some((slot, before)) = voice {
+            let after = self.voices.borrow().voice_text(slot);
+            if after != before {
+                edits.push(history::Edit::Voice {
+                    slot,
+                    before,
+                    after,
+                });
+            }
+        }
+        if let Some(before) = settings {
+            let after = history::Settings::of(&self.song.borrow());
+            if after != before {
+                edits.push(history::Edit::Settings { before, after });
+            }
+        }
+        self.history.record(edits);
+        tasks
+    }
 
     fn view(&self, pos: Pos) -> AppView {
         AppView {
             voices: self.voices.borrow().view(pos + Pos { r: 3, c: 3 }),
+            presets: self.presets.borrow().view(pos + Pos { r: 2, c: 9 }),
+            export: self.export.borrow().view(pos + Pos { r: 1, c: 3 }),
+            settings: self.settings.borrow().view(pos + Pos { r: 9, c: 3 }),
             skin: label(Pos { r: 0, c: 0 }, SKIN),
             rewind_btn: self.rewind_btn.borrow().view(pos + Pos { r: 11, c: 58 }),
             stop_btn: self.stop_btn.borrow().view(pos + Pos { r: 11, c: 63 }),
             play_btn: self.play_btn.borrow().view(pos + Pos { r: 11, c: 67 }),
+            order: self.order.borrow().view(pos + Pos { r: 11, c: 2 }),
+            grid: self.grid.borrow().view(pos + Pos { r: 15, c: 1 }),
             kbd_mode: self.kbd_mode,
+            kbd_velocity: self.kbd_velocity,
+            recording: self.recording,
+            octave: self.octave,
+            edit_step: self.edit_step,
+            quantize: self.quantize,
+            overdub: self.overdub,
+            status: self
+                .status
+                .as_ref()
+                .map(|s| label(pos + Pos { r: 24, c: 2 }, &format!("[ {} ]", s))),
         }
     }
 }
 impl_focusable_with_focuschain!(App, focus_chain);
 
+/// The row nearest the playhead that is a multiple of the quantisation. Rows past the end of
+/// the pattern wrap around to the start of it.
+fn quantize(playhead: Playhead, rows: usize, len: usize) -> usize {
+    let row = playhead.row as f32 + playhead.fraction;
+    (row / rows as f32).round() as usize * rows % len
+}
+
 pub struct AppView {
     voices: VoiceListView,
+    presets: PresetBrowserView,
+    export: ExportOptionsView,
+    settings: SongSettingsView,
     rewind_btn: ButtonView<Message>,
     stop_btn: ButtonView<Message>,
     play_btn: ButtonView<Message>,
+    order: OrderEditorView,
+    grid: PatternGridView,
     skin: Label,
     kbd_mode: KbdMode,
+    kbd_velocity: u8,
+    recording: bool,
+    octave: u8,
+    edit_step: usize,
+    quantize: usize,
+    overdub: bool,
+    status: Option<Label>,
 }
 impl View<Message> for AppView {
     fn draw(&self, renderer: &mut dyn crate::uifw::interaction::Renderer) {
         self.skin.draw(renderer); // Must draw first since it will overwrite everything
         match self.kbd_mode {
             KbdMode::Text => renderer.render_str(Pos { r: 9, c: 67 }, "#"),
-            KbdMode::Claviature => renderer.render_str(Pos { r: 9, c: 67 }, "♫"),
+            KbdMode::Claviature => {
+                let mode = if self.recording { '●' } else { '♫' };
+                let status = format!(
+                    "{}{:02X} O{} S{:X}",
+                    mode, self.kbd_velocity, self.octave, self.edit_step
+                );
+                renderer.render_str(Pos { r: 9, c: 67 }, &status);
+                let mode = if self.overdub { "OVR" } else { "RPL" };
+                let live = format!("Q{:X} {}", self.quantize, mode);
+                renderer.render_str(Pos { r: 11, c: 71 }, &live);
+            }
         }
         self.voices.draw(renderer);
+        self.presets.draw(renderer);
+        self.export.draw(renderer);
+        self.settings.draw(renderer);
+        self.order.draw(renderer);
         self.rewind_btn.draw(renderer);
         self.stop_btn.draw(renderer);
         self.play_btn.draw(renderer);
+        self.grid.draw(renderer);
+        if let Some(status) = &self.status {
+            status.draw(renderer);
+        }
     }
 
     fn on_event(&self, e: Event) -> Vec<Message> {
         match e {
             Event::Quit => return vec![Message::Quit],
             Event::Char('`', _) => return vec![Message::NextKbdMode],
+            Event::Char('z' | 'Z', m) if m.contains(CharModifiers::Ctrl) => {
+                return vec![Message::Undo]
+            }
+            Event::Char('y' | 'Y', m) if m.contains(CharModifiers::Ctrl) => {
+                return vec![Message::Redo]
+            }
+            Event::Char('s' | 'S', m) if m.contains(CharModifiers::Ctrl) => {
+                return vec![Message::Save]
+            }
+            Event::Char('o' | 'O', m) if m.contains(CharModifiers::Ctrl) => {
+                return vec![Message::Load]
+            }
             _ => {}
         }
 
-        // Uppercase all chars
+        // Uppercase all chars, except in the song title and author
+        let typed = e;
         let mut e = e;
         if let Event::Char(c @ 'a'..='z', m) = e {
             e = Event::Char(c.to_ascii_uppercase(), m);
         }
 
-        fn play_message(Frequency(freq): Frequency, cm: CharModifiers) -> Message {
-            Message::PlayVoice(Frequency(
-                freq * match cm {
-                    CharModifiers::Shift => 2.0,
-                    _ => 1.0,
-                },
-            ))
-        }
+        let play_message = |semitone: u8, cm: CharModifiers| {
+            let octave = match cm {
+                CharModifiers::Shift => self.octave + 1,
+                _ => self.octave,
+            };
+            Message::PlayNote((octave * 12 + semitone).min(Note::MAX_KEY))
+        };
 
         if self.kbd_mode == KbdMode::Claviature {
             return match e {
-                Event::Char('Z', m) => vec![play_message(synth::Note::C, m)],
-                Event::Char('S', m) => vec![play_message(synth::Note::CS, m)],
-                Event::Char('X', m) => vec![play_message(synth::Note::D, m)],
-                Event::Char('D', m) => vec![play_message(synth::Note::DS, m)],
-                Event::Char('C', m) => vec![play_message(synth::Note::E, m)],
-                Event::Char('V', m) => vec![play_message(synth::Note::F, m)],
-                Event::Char('G', m) => vec![play_message(synth::Note::FS, m)],
-                Event::Char('B', m) => vec![play_message(synth::Note::G, m)],
-                Event::Char('H', m) => vec![play_message(synth::Note::GS, m)],
-                Event::Char('N', m) => vec![play_message(synth::Note::A, m)],
-                Event::Char('J', m) => vec![play_message(synth::Note::AS, m)],
-                Event::Char('M', m) => vec![play_message(synth::Note::B, m)],
+                Event::Char('Z', m) => vec![play_message(0, m)],
+                Event::Char('S', m) => vec![play_message(1, m)],
+                Event::Char('X', m) => vec![play_message(2, m)],
+                Event::Char('D', m) => vec![play_message(3, m)],
+                Event::Char('C', m) => vec![play_message(4, m)],
+                Event::Char('V', m) => vec![play_message(5, m)],
+                Event::Char('G', m) => vec![play_message(6, m)],
+                Event::Char('B', m) => vec![play_message(7, m)],
+                Event::Char('H', m) => vec![play_message(8, m)],
+                Event::Char('N', m) => vec![play_message(9, m)],
+                Event::Char('J', m) => vec![play_message(10, m)],
+                Event::Char('M', m) => vec![play_message(11, m)],
                 Event::Char(' ', _) => vec![Message::StopVoice],
+                Event::Activate => vec![Message::ToggleRecord],
+                Event::Char('A', _) => vec![Message::RecordNoteOff],
+                Event::Char('Q', _) => {
+                    let rows = match self.quantize {
+                        MAX_QUANTIZE => 1,
+                        rows => rows * 2,
+                    };
+                    vec![Message::SetQuantize(rows)]
+                }
+                Event::Char('W', _) => vec![Message::ToggleOverdub],
+                Event::Del => vec![Message::RecordClear],
+                Event::Char(',', _) => vec![Message::SetOctave(self.octave.saturating_sub(1))],
+                Event::Char('.', _) => vec![Message::SetOctave((self.octave + 1).min(MAX_OCTAVE))],
+                Event::Char('[', _) => vec![Message::SetEditStep(self.edit_step.saturating_sub(1))],
+                Event::Char(']', _) => {
+                    vec![Message::SetEditStep(
+                        (self.edit_step + 1).min(MAX_EDIT_STEP),
+                    )]
+                }
+                // The grid cursor can be moved while recording
+                Event::Up => vec![Message::Pattern(pattern::Message::Up)],
+                Event::Down => vec![Message::Pattern(pattern::Message::Down)],
+                Event::Left => vec![Message::Pattern(pattern::Message::Left)],
+                Event::Right => vec![Message::Pattern(pattern::Message::Right)],
+                // 1-9 is 10-90% velocity and 0 is full velocity
+                Event::Char(c @ '0'..='9', _) => {
+                    let tenths = c.to_digit(10).map_or(10, |d| if d == 0 { 10 } else { d });
+                    vec![Message::SetKbdVelocity((tenths * 0xFF / 10) as u8)]
+                }
                 _ => vec![], // Short circuit other input
             };
         }
@@ -251,12 +754,289 @@ impl View<Message> for AppView {
             .on_event(e)
             .iter()
             .for_each(|&m| msgs.push(Message::VoiceList(m)));
+        self.presets
+            .on_event(e)
+            .iter()
+            .for_each(|&m| msgs.push(Message::Preset(m)));
+        self.export
+            .on_event(e)
+            .iter()
+            .for_each(|&m| msgs.push(Message::Export(m)));
+        self.settings
+            .on_event(typed)
+            .iter()
+            .for_each(|&m| msgs.push(Message::Settings(m)));
+        self.order
+            .on_event(e)
+            .iter()
+            .for_each(|&m| msgs.push(Message::Order(m)));
         self.rewind_btn
             .on_event(e)
             .iter()
             .for_each(|&m| msgs.push(m));
         self.stop_btn.on_event(e).iter().for_each(|&m| msgs.push(m));
         self.play_btn.on_event(e).iter().for_each(|&m| msgs.push(m));
+        self.grid
+            .on_event(e)
+            .iter()
+            .for_each(|&m| msgs.push(Message::Pattern(m)));
         msgs
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::uifw::widget::textbox;
+
+    fn cell(app: &App, row: usize) -> String {
+        app.song.borrow().patterns[0].cell(row, 0).to_string()
+    }
+
+    #[test]
+    fn record_test() {
+        let mut app = App::new();
+
+        // Notes are only played until recording is turned on
+        app.update(Message::PlayNote(4 * 12));
+        assert_eq!(cell(&app, 0), "--- -- -- ---");
+
+        app.update(Message::ToggleRecord);
+        app.update(Message::SetEditStep(2));
+        app.update(Message::PlayNote(4 * 12 + 9));
+        app.update(Message::RecordNoteOff);
+        app.update(Message::RecordClear);
+        assert_eq!(cell(&app, 0), "A-4 00 -- ---");
+        assert_eq!(cell(&app, 2), "=== -- -- ---");
+
+        // Clearing a cell also moves on by the edit step
+        (0..4).for_each(|_| {
+            app.update(Message::Pattern(pattern::Message::Up));
+        });
+        app.update(Message::RecordClear);
+        assert_eq!(cell(&app, 2), "--- -- -- ---");
+        app.update(Message::PlayNote(4 * 12));
+        assert_eq!(cell(&app, 4), "C-4 00 -- ---");
+    }
+
+    #[test]
+    fn live_record_test() {
+        let mut app = App::new();
+        app.update(Message::ToggleRecord);
+        app.update(Message::Play);
+        app.update(Message::SetQuantize(4));
+        let playhead = |row, fraction| {
+            Message::Playhead(Playhead {
+                pos: 0,
+                row,
+                fraction,
+            })
+        };
+
+        // Notes snap to the nearest multiple of the quantisation without stepping
+        app.update(playhead(5, 0.2));
+        app.update(Message::PlayNote(4 * 12));
+        assert_eq!(cell(&app, 4), "C-4 00 -- ---");
+        app.update(playhead(5, 0.6));
+        app.update(Message::PlayNote(4 * 12 + 2));
+        assert_eq!(cell(&app, 4), "D-4 00 -- ---");
+
+        // Overdub keeps the note and uses the next free track
+        app.update(Message::ToggleOverdub);
+        app.update(Message::PlayNote(4 * 12 + 4));
+        assert_eq!(cell(&app, 4), "D-4 00 -- ---");
+        let song = app.song.borrow();
+        assert_eq!(song.patterns[0].cell(4, 1).to_string(), "E-4 00 -- ---");
+    }
+
+    #[test]
+    fn undo_test() {
+        let mut app = App::new();
+        let enter = |app: &mut App, c| {
+            app.update(Message::Pattern(pattern::Message::EnterChar(c)));
+        };
+        enter(&mut app, 'C');
+        enter(&mut app, 'E');
+        assert_eq!(cell(&app, 0), "E-4 -- -- ---");
+
+        app.update(Message::Undo);
+        assert_eq!(cell(&app, 0), "C-4 -- -- ---");
+        app.update(Message::Undo);
+        app.update(Message::Undo);
+        assert_eq!(cell(&app, 0), "--- -- -- ---");
+        app.update(Message::Redo);
+        assert_eq!(cell(&app, 0), "C-4 -- -- ---");
+
+        // A block operation is undone in one step
+        app.update(Message::Pattern(pattern::Message::Mark));
+        app.update(Message::Pattern(pattern::Message::Down));
+        app.update(Message::Pattern(pattern::Message::Copy));
+        app.update(Message::Pattern(pattern::Message::Paste));
+        app.update(Message::Pattern(pattern::Message::Cut));
+        assert_eq!(cell(&app, 1), "--- -- -- ---");
+        app.update(Message::Undo);
+        assert_eq!(cell(&app, 0), "C-4 -- -- ---");
+        assert_eq!(cell(&app, 1), "C-4 -- -- ---");
+
+        // Order list edits take the patterns they added with them
+        app.update(Message::Order(order::Message::Insert));
+        assert_eq!(app.song.borrow().patterns.len(), 2);
+        app.update(Message::Undo);
+        assert_eq!(app.song.borrow().patterns.len(), 1);
+        assert_eq!(app.song.borrow().order.entries(), &[0]);
+
+        // Voice edits
+        let osc = textbox::Message::EnterChar('3', CharModifiers::None);
+        app.update(Message::VoiceList(voice::list::Message::Voice(
+            2,
+            voice::Message::Osc(osc),
+        )));
+        assert!(app.song.borrow().voices[2].is_some());
+        app.update(Message::Undo);
+        assert!(app.song.borrow().voices[2].is_none());
+        assert_eq!(app.voices.borrow().voice_text(2).trim(), "");
+
+        // Song settings
+        app.update(Message::Settings(settings::Message::Up));
+        app.update(Message::Settings(settings::Message::NextField));
+        app.update(Message::Settings(settings::Message::NextField));
+        app.update(Message::Settings(settings::Message::EnterChar('x')));
+        app.update(Message::Undo);
+        assert_eq!(app.song.borrow().title, "");
+        app.update(Message::Undo);
+        assert_eq!(app.song.borrow().bpm, song::DEFAULT_BPM);
+        app.update(Message::Redo);
+        app.update(Message::Redo);
+        assert_eq!(app.song.borrow().bpm, song::DEFAULT_BPM + 1);
+        assert_eq!(app.song.borrow().title, "x");
+    }
+
+    #[test]
+    fn preset_test() {
+        let mut app = App::new();
+        let preset = |app: &App| app.presets.borrow().preset();
+        app.update(Message::Preset(preset::Message::Next));
+        app.update(Message::VoiceList(voice::list::Message::Down));
+
+        // The browsed preset is auditioned while it has focus
+        app.presets.borrow_mut().focus();
+        let tasks = app.update(Message::PlayNote(4 * 12));
+        let Some(Task::App(AppTask::PlayVoice(voice, _, _))) = tasks.first() else {
+            panic!("No voice played");
+        };
+        assert_eq!(Some(voice.clone()), preset(&app).voice());
+
+        // Loading copies it into the selected slot and can be undone
+        app.update(Message::Preset(preset::Message::Load));
+        assert_eq!(app.voices.borrow().voice_text(1), preset(&app).text);
+        assert_eq!(app.song.borrow().voices[1], preset(&app).voice());
+        app.update(Message::Undo);
+        assert_eq!(app.voices.borrow().voice_text(1).trim(), "");
+        assert!(app.song.borrow().voices[1].is_none());
+    }
+
+    #[test]
+    fn file_test() {
+        let path = std::env::temp_dir().join(format!("rtrk-{}.rtrk", std::process::id()));
+        let mut app = App::new();
+        assert_eq!(app.open(&path), Ok(()));
+        app.update(Message::Pattern(pattern::Message::EnterChar('D')));
+        app.voices.borrow_mut().set_voice_text(3, "2");
+        app.update(Message::Save);
+        assert_eq!(app.status, Some(format!("Saved {}", path.display())));
+
+        // Loading replaces the song and forgets the edits
+        app.update(Message::Pattern(pattern::Message::EnterChar('E')));
+        app.update(Message::Load);
+        assert_eq!(cell(&app, 0), "D-4 -- -- ---");
+        assert!(app.song.borrow().voices[3].is_some());
+        app.update(Message::Undo);
+        assert_eq!(cell(&app, 0), "D-4 -- -- ---");
+
+        fs::write(&path, "rtrk 1\nbpm 0\n").unwrap();
+        let error = app.open(&path).unwrap_err();
+        assert_eq!(error, format!("{}: Line 2: Invalid number", path.display()));
+        fs::remove_file(&path).unwrap();
+
+        // Loading a file that is gone keeps the song
+        app.update(Message::Load);
+        assert!(app
+            .status
+            .as_ref()
+            .is_some_and(|s| !s.starts_with("Loaded")));
+        assert_eq!(cell(&app, 0), "D-4 -- -- ---");
+    }
+
+    #[test]
+    fn export_test() {
+        let path = std::env::temp_dir().join(format!("rtrk-export-{}.rtrk", std::process::id()));
+        let mut app = App::new();
+        assert_eq!(app.open(&path), Ok(()));
+        app.update(Message::Export(export::Message::Export));
+
+        // The song is rendered next to the song file, which doesn't have to be saved
+        let wav = path.with_extension("wav");
+        assert_eq!(app.status, Some(format!("Exported {}", wav.display())));
+        assert_eq!(&fs::read(&wav).unwrap()[..4], b"RIFF");
+        fs::remove_file(&wav).unwrap();
+
+        // A file per track
+        app.update(Message::Export(export::Message::PrevField));
+        app.update(Message::Export(export::Message::Up));
+        app.update(Message::Export(export::Message::Export));
+        let prefix = path.with_extension("").display().to_string();
+        assert_eq!(
+            app.status,
+            Some(format!("Exported 4 stems to {}-*.wav", prefix))
+        );
+        for track in 0..4 {
+            let stem = song::wav::Stem::Track(track).file_name(&prefix);
+            assert_eq!(&fs::read(&stem).unwrap()[..4], b"RIFF");
+            fs::remove_file(&stem).unwrap();
+        }
+    }
+
+    #[test]
+    fn quantize_test() {
+        let playhead = |row, fraction| Playhead {
+            pos: 0,
+            row,
+            fraction,
+        };
+        assert_eq!(quantize(playhead(3, 0.9), 1, 0x40), 4);
+        assert_eq!(quantize(playhead(5, 0.9), 4, 0x40), 4);
+        assert_eq!(quantize(playhead(6, 0.1), 4, 0x40), 8);
+        assert_eq!(quantize(playhead(0x3F, 0.0), 8, 0x40), 0);
+    }
+
+    #[test]
+    fn claviature_test() {
+        let mut app = App::new();
+        app.update(Message::NextKbdMode);
+        app.update(Message::SetOctave(2));
+
+        let view = app.view(Pos { r: 0, c: 0 });
+        let on_event = |e| view.on_event(e);
+        assert_eq!(
+            on_event(Event::Char('n', CharModifiers::None)),
+            vec![Message::PlayNote(2 * 12 + 9)]
+        );
+        assert_eq!(
+            on_event(Event::Char('N', CharModifiers::Shift)),
+            vec![Message::PlayNote(3 * 12 + 9)]
+        );
+        assert_eq!(
+            on_event(Event::Char(',', CharModifiers::None)),
+            vec![Message::SetOctave(1)]
+        );
+        assert_eq!(
+            on_event(Event::Char(']', CharModifiers::None)),
+            vec![Message::SetEditStep(2)]
+        );
+        assert_eq!(on_event(Event::Activate), vec![Message::ToggleRecord]);
+        assert_eq!(
+            on_event(Event::Char('a', CharModifiers::None)),
+            vec![Message::RecordNoteOff]
+        );
+    }
+}
@@ -0,0 +1,235 @@
+// Copyright (C) 2025 Anders Sundman <anders@4zm.org>
+//
+// This file is part of RTRK - The Rust Tracker
+//
+// RTRK is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// RTRK is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with RTRK. If not, see <https://www.gnu.org/licenses/>.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::app::AppTask;
+use crate::cycle::Cycle;
+use crate::song::wav::{self, Format, Stems, SAMPLE_RATES};
+use crate::uifw::interaction::{Event, Renderer, Style};
+use crate::uifw::pos::Pos;
+use crate::uifw::widget::{Focusable, Task, View, Widget};
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Message {
+    NextField,
+    PrevField,
+    Up,
+    Down,
+    /// Render the song to a WAV file
+    Export,
+}
+
+const FIELDS: usize = 5;
+const MAX_LOOPS: usize = 9;
+/// Longest fade out tail, in seconds
+const MAX_FADE: usize = 9;
+
+/// The song is exported as a mix, or split into stems
+const SPLITS: [Option<Stems>; 3] = [None, Some(Stems::Tracks), Some(Stems::Voices)];
+
+/// Options of the WAV export: sample format, sample rate, loops, fade out and stems
+pub struct ExportOptions {
+    field: Cycle,
+    format: Cycle,
+    rate: Cycle,
+    /// One less than the number of loops
+    loops: Cycle,
+    fade: Cycle,
+    split: Cycle,
+    has_focus: bool,
+}
+
+impl ExportOptions {
+    pub fn new() -> Self {
+        let default = wav::Options::default();
+        let index = |found: Option<usize>| found.unwrap_or(0);
+        Self {
+            field: Cycle::new(0, FIELDS),
+            format: Cycle::new(
+                index(Format::ALL.iter().position(|f| *f == default.format)),
+                Format::ALL.len(),
+            ),
+            rate: Cycle::new(
+                index(SAMPLE_RATES.iter().position(|r| *r == default.sample_rate)),
+                SAMPLE_RATES.len(),
+            ),
+            loops: Cycle::new(default.loops - 1, MAX_LOOPS),
+            fade: Cycle::new(0, MAX_FADE + 1),
+            split: Cycle::new(0, SPLITS.len()),
+            has_focus: false,
+        }
+    }
+
+    pub fn options(&self) -> wav::Options {
+        wav::Options {
+            format: Format::ALL[*self.format],
+            sample_rate: SAMPLE_RATES[*self.rate],
+            loops: *self.loops + 1,
+            fade_ms: *self.fade as u32 * 1000,
+        }
+    }
+
+    /// How the song is split into stems, if it is
+    pub fn stems(&self) -> Option<Stems> {
+        SPLITS[*self.split]
+    }
+
+    fn value(&mut self) -> &mut Cycle {
+        match *self.field {
+            0 => &mut self.format,
+            1 => &mut self.rate,
+            2 => &mut self.loops,
+            3 => &mut self.fade,
+            _ => &mut self.split,
+        }
+    }
+}
+
+impl Widget<Message, AppTask, ExportOptionsView> for ExportOptions {
+    fn update(&mut self, msg: Message) -> Vec<Task<AppTask>> {
+        match msg {
+            Message::NextField => self.field += 1,
+            Message::PrevField => self.field -= 1,
+            Message::Up => *self.value() += 1,
+            Message::Down => *self.value() -= 1,
+            Message::Export => {} // Handled by the app, it owns the song file
+        }
+        vec![]
+    }
+
+    fn view(&self, pos: Pos) -> ExportOptionsView {
+        let options = self.options();
+        ExportOptionsView {
+            pos,
+            fields: [
+                options.format.to_string(),
+                format!("{}Hz", options.sample_rate),
+                format!("x{}", options.loops),
+                format!("+{}s", options.fade_ms / 1000),
+                match self.stems() {
+                    None => "mix",
+                    Some(Stems::Tracks) => "tracks",
+                    Some(Stems::Voices) => "voices",
+                }
+                .to_string(),
+            ],
+            selected: self.has_focus.then_some(*self.field),
+        }
+    }
+}
+
+impl Focusable for ExportOptions {
+    fn has_focus(&self) -> bool {
+        self.has_focus
+    }
+    fn focus(&mut self) {
+        self.has_focus = true
+    }
+    fn defocus(&mut self) {
+        self.has_focus = false
+    }
+    fn next_focus(&mut self) {
+        self.has_focus = !self.has_focus;
+    }
+    fn prev_focus(&mut self) {
+        self.has_focus = !self.has_focus;
+    }
+}
+
+pub struct ExportOptionsView {
+    pos: Pos,
+    fields: [String; FIELDS],
+    /// The field that is edited, if the options have focus
+    selected: Option<usize>,
+}
+
+impl View<Message> for ExportOptionsView {
+    fn on_event(&self, e: Event) -> Vec<Message> {
+        if self.selected.is_none() {
+            return vec![];
+        }
+
+        match e {
+            Event::Left => vec![Message::PrevField],
+            Event::Right => vec![Message::NextField],
+            Event::Up => vec![Message::Up],
+            Event::Down => vec![Message::Down],
+            Event::Activate => vec![Message::Export],
+            _ => vec![],
+        }
+    }
+
+    fn draw(&self, renderer: &mut dyn Renderer) {
+        renderer.render_str(self.pos, "WAV");
+        let mut pos = self.pos + Pos { r: 0, c: 4 };
+        for (i, field) in self.fields.iter().enumerate() {
+            match self.selected == Some(i) {
+                true => renderer.render_fmt_str(pos, field, Style::Invert),
+                false => renderer.render_str(pos, field),
+            }
+            pos = pos
+                + Pos {
+                    r: 0,
+                    c: field.len() as u16 + 1,
+                };
+        }
+    }
+}
+
+pub type ExportOptionsRc = Rc<RefCell<ExportOptions>>;
+pub fn export_options_rc() -> ExportOptionsRc {
+    Rc::new(RefCell::new(ExportOptions::new()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::uifw::interaction::tests::TestRenderer;
+
+    #[test]
+    fn options_test() {
+        let mut export = ExportOptions::new();
+        assert_eq!(export.options(), wav::Options::default());
+
+        export.update(Message::Down);
+        export.update(Message::NextField);
+        export.update(Message::Up);
+        // The fields wrap around
+        export.update(Message::PrevField);
+        export.update(Message::PrevField);
+        export.update(Message::Down);
+        export.update(Message::PrevField);
+        export.update(Message::Up);
+        let options = export.options();
+        assert_eq!(options.format, Format::Pcm8);
+        assert_eq!(options.sample_rate, 48000);
+        assert_eq!(options.fade_ms, 1000);
+        assert_eq!(export.stems(), Some(Stems::Voices));
+
+        let view = export.view(Pos { r: 0, c: 0 });
+        assert_eq!(view.on_event(Event::Activate), vec![]);
+        export.focus();
+        let view = export.view(Pos { r: 0, c: 0 });
+        assert_eq!(view.on_event(Event::Activate), vec![Message::Export]);
+
+        let mut renderer = TestRenderer::new();
+        view.draw(&mut renderer);
+        assert_eq!(renderer.out, "WAV8bit48000Hzx1+1svoices");
+    }
+}
@@ -0,0 +1,366 @@
+// Copyright (C) 2025 Anders Sundman <anders@4zm.org>
+//
+// This file is part of RTRK - The Rust Tracker
+//
+// RTRK is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// RTRK is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with RTRK. If not, see <https://www.gnu.org/licenses/>.
+
+use std::collections::VecDeque;
+
+use crate::app::voice::list::VoiceList;
+use crate::song::block::{self, Area, Field};
+use crate::song::pattern::{Pattern, Row};
+use crate::song::Song;
+
+/// Number of commands that can be undone by default
+pub const DEFAULT_DEPTH: usize = 0x100;
+
+/// One field of a pattern before and after an edit
+#[derive(Clone, Debug, PartialEq)]
+pub struct FieldChange {
+    pub row: usize,
+    pub col: usize,
+    pub before: Field,
+    pub after: Field,
+}
+
+/// The settings of a song that are not in its patterns
+#[derive(Clone, Debug, PartialEq)]
+pub struct Settings {
+    pub title: String,
+    pub author: String,
+    pub bpm: u32,
+    pub speed: u32,
+}
+
+impl Settings {
+    pub fn of(song: &Song) -> Self {
+        Self {
+            title: song.title.clone(),
+            author: song.author.clone(),
+            bpm: song.bpm,
+            speed: song.speed,
+        }
+    }
+
+    fn apply(&self, song: &mut Song) {
+        song.title = self.title.clone();
+        song.author = self.author.clone();
+        song.bpm = self.bpm;
+        song.speed = self.speed;
+    }
+}
+
+/// A change to the song, made by an edit operation of a widget. Only what changed is kept.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Edit {
+    Fields {
+        pattern: usize,
+        changes: Vec<FieldChange>,
+    },
+    /// A pattern added at the end of the pattern list
+    AddPattern(Pattern),
+    /// The number of rows of a pattern, with the rows that were removed by making it shorter
+    Length {
+        pattern: usize,
+        before: usize,
+        after: usize,
+        removed: Vec<Row>,
+    },
+    /// An entry of the order list, None where there is no entry
+    Order {
+        pos: usize,
+        before: Option<usize>,
+        after: Option<usize>,
+    },
+    /// The designer text of a voice slot
+    Voice {
+        slot: usize,
+        before: String,
+        after: String,
+    },
+    /// The title, author, tempo or speed of the song
+    Settings { before: Settings, after: Settings },
+}
+
+impl Edit {
+    fn apply(&self, song: &mut Song, voices: &mut VoiceList, redo: bool) {
+        match self {
+            Edit::Fields { pattern, changes } => {
+                for c in changes.iter() {
+                    let field = *pick(redo, &c.before, &c.after);
+                    block::set_field(&mut song.patterns[*pattern], c.row, c.col, field);
+                }
+            }
+            Edit::AddPattern(pattern) => match redo {
+                true => song.patterns.push(pattern.clone()),
+                false => {
+                    song.patterns.pop();
+                }
+            },
+            Edit::Length {
+                pattern,
+                before,
+                after,
+                removed,
+            } => {
+                let pattern = &mut song.patterns[*pattern];
+                pattern.set_len(*pick(redo, before, after));
+                if !redo {
+                    for (i, row) in removed.iter().enumerate() {
+                        *pattern.row_mut(after + i) = row.clone();
+                    }
+                }
+            }
+            Edit::Order { pos, before, after } => {
+                let (from, to) = (pick(!redo, before, after), pick(redo, before, after));
+                match (from, to) {
+                    (None, Some(pattern)) => song.order.insert(*pos, *pattern),
+                    (Some(_), None) => {
+                        song.order.remove(*pos);
+                    }
+                    (Some(_), Some(pattern)) => song.order.set(*pos, *pattern),
+                    (None, None) => {}
+                }
+            }
+            Edit::Voice {
+                slot,
+                before,
+                after,
+            } => {
+                voices.set_voice_text(*slot, pick(redo, before, after));
+                song.set_voice(*slot, voices.get_voice(*slot));
+            }
+            Edit::Settings { before, after } => pick(redo, before, after).apply(song),
+        }
+    }
+}
+
+fn pick<'a, T: ?Sized>(redo: bool, before: &'a T, after: &'a T) -> &'a T {
+    if redo {
+        after
+    } else {
+        before
+    }
+}
+
+/// The fields of the area, to find what an edit of the area changes
+pub fn fields(pattern: &Pattern, area: Area) -> Vec<(usize, usize, Field)> {
+    (area.first_row..=area.last_row.min(pattern.len().saturating_sub(1)))
+        .flat_map(|row| (area.first_col..=area.last_col).map(move |col| (row, col)))
+        .filter_map(|(row, col)| Some((row, col, block::field(pattern, row, col)?)))
+        .collect()
+}
+
+/// The fields that are no longer what they were before an edit
+pub fn changes(pattern: &Pattern, before: Vec<(usize, usize, Field)>) -> Vec<FieldChange> {
+    before
+        .into_iter()
+        .filter_map(|(row, col, before)| {
+            let after = block::field(pattern, row, col)?;
+            (after != before).then_some(FieldChange {
+                row,
+                col,
+                before,
+                after,
+            })
+        })
+        .collect()
+}
+
+/// Edits made by one user action. They are undone together, so a block operation that changes
+/// many fields is a single step.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Command {
+    edits: Vec<Edit>,
+}
+
+impl Command {
+    fn undo(&self, song: &mut Song, voices: &mut VoiceList) {
+        self.edits
+            .iter()
+            .rev()
+            .for_each(|e| e.apply(song, voices, false));
+    }
+
+    fn redo(&self, song: &mut Song, voices: &mut VoiceList) {
+        self.edits.iter().for_each(|e| e.apply(song, voices, true));
+    }
+}
+
+/// Undo and redo stacks. The oldest commands are forgotten beyond the depth.
+pub struct History {
+    undo: VecDeque<Command>,
+    redo: Vec<Command>,
+    depth: usize,
+}
+
+impl History {
+    pub fn new(depth: usize) -> Self {
+        Self {
+            undo: VecDeque::new(),
+            redo: vec![],
+            depth,
+        }
+    }
+
+    pub fn set_depth(&mut self, depth: usize) {
+        self.depth = depth;
+        self.trim();
+    }
+
+    /// Add a command for the edits, unless there are none. Clears the redo stack.
+    pub fn record(&mut self, edits: Vec<Edit>) {
+        if edits.is_empty() {
+            return;
+        }
+        self.redo.clear();
+        self.undo.push_back(Command { edits });
+        self.trim();
+    }
+
+    /// Revert the last command. Returns false if there was nothing to undo.
+    pub fn undo(&mut self, song: &mut Song, voices: &mut VoiceList) -> bool {
+        let Some(command) = self.undo.pop_back() else {
+            return false;
+        };
+        command.undo(song, voices);
+        self.redo.push(command);
+        true
+    }
+
+    /// Reapply the last undone command. Returns false if there was nothing to redo.
+    pub fn redo(&mut self, song: &mut Song, voices: &mut VoiceList) -> bool {
+        let Some(command) = self.redo.pop() else {
+            return false;
+        };
+        command.redo(song, voices);
+        self.undo.push_back(command);
+        true
+    }
+
+    /// Forget all commands, for when the song is replaced
+    pub fn clear(&mut self) {
+        self.undo.clear();
+        self.redo.clear();
+    }
+
+    fn trim(&mut self) {
+        while self.undo.len() > self.depth {
+            self.undo.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::song::block::FIELDS;
+    use crate::song::pattern::Note;
+
+    #[test]
+    fn changes_test() {
+        let mut pattern = Pattern::default();
+        let area = Area::spanning((1, 0), (2, 2 * FIELDS));
+        let before = fields(&pattern, area);
+        assert_eq!(before.len(), 2 * (2 * FIELDS + 1));
+
+        pattern.cell_mut(1, 2).note = Some(Note::Off);
+        pattern.cell_mut(3, 0).note = Some(Note::Off); // Outside of the area
+        assert_eq!(
+            changes(&pattern, before),
+            vec![FieldChange {
+                row: 1,
+                col: 2 * FIELDS,
+                before: Field::Note(None),
+                after: Field::Note(Some(Note::Off)),
+            }]
+        );
+    }
+
+    #[test]
+    fn history_test() {
+        let mut song = Song::new();
+        let mut voices = VoiceList::new();
+        let mut history = History::new(2);
+
+        for pattern in [1, 2, 3] {
+            history.record(vec![Edit::Order {
+                pos: 0,
+                before: Some(song.order.get(0)),
+                after: Some(pattern),
+            }]);
+            song.order.set(0, pattern);
+        }
+        history.record(vec![]);
+
+        // Only the last two commands are kept
+        assert!(history.undo(&mut song, &mut voices));
+        assert_eq!(song.order.entries(), &[2]);
+        assert!(history.undo(&mut song, &mut voices));
+        assert_eq!(song.order.entries(), &[1]);
+        assert!(!history.undo(&mut song, &mut voices));
+
+        assert!(history.redo(&mut song, &mut voices));
+        assert_eq!(song.order.entries(), &[2]);
+
+        // A pattern is added and then played, and undone in the reverse order
+        song.patterns.push(Pattern::new(2, 1));
+        song.order.insert(1, 1);
+        history.record(vec![
+            Edit::AddPattern(Pattern::new(2, 1)),
+            Edit::Order {
+                pos: 1,
+                before: None,
+                after: Some(1),
+            },
+        ]);
+        assert!(history.undo(&mut song, &mut voices));
+        assert_eq!(song.patterns.len(), 1);
+        assert_eq!(song.order.entries(), &[2]);
+        assert!(history.redo(&mut song, &mut voices));
+        assert_eq!(song.patterns[1], Pattern::new(2, 1));
+        assert_eq!(song.order.entries(), &[2, 1]);
+
+        // A new command can't be followed by redo
+        history.record(vec![Edit::Voice {
+            slot: 1,
+            before: voices.voice_text(1),
+            after: "4          FF  A0".to_string(),
+        }]);
+        assert!(!history.redo(&mut song, &mut voices));
+        assert!(history.undo(&mut song, &mut voices));
+        assert!(history.redo(&mut song, &mut voices));
+        assert_eq!(voices.voice_text(1), "4          FF  A0");
+        assert!(song.voices[1].is_some());
+
+        // Rows removed by a shorter pattern come back
+        song.patterns[0].cell_mut(3, 0).note = Some(Note::Off);
+        let removed = vec![song.patterns[0].row(3).clone()];
+        song.patterns[0].set_len(3);
+        history.record(vec![Edit::Length {
+            pattern: 0,
+            before: 4,
+            after: 3,
+            removed,
+        }]);
+        assert!(history.undo(&mut song, &mut voices));
+        assert_eq!(song.patterns[0].len(), 4);
+        assert_eq!(song.patterns[0].cell(3, 0).note, Some(Note::Off));
+        assert!(history.redo(&mut song, &mut voices));
+        assert_eq!(song.patterns[0].len(), 3);
+
+        history.set_depth(0);
+        assert!(!history.undo(&mut song, &mut voices));
+    }
+}
@@ -0,0 +1,415 @@
+// Copyright (C) 2025 Anders Sundman <anders@4zm.org>
+//
+// This file is part of RTRK - The Rust Tracker
+//
+// RTRK is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// RTRK is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with RTRK. If not, see <https://www.gnu.org/licenses/>.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::app::history::Edit;
+use crate::app::{AppTask, SongRc};
+use crate::cycle::Cycle;
+use crate::song::pattern::MAX_ROWS;
+use crate::uifw::interaction::{Event, Renderer, Style};
+use crate::uifw::pos::Pos;
+use crate::uifw::widget::{Focusable, Task, View, Widget};
+
+/// Number of song positions shown at once
+const WINDOW_LEN: usize = 16;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Message {
+    Left,
+    Right,
+    NextPattern,
+    PrevPattern,
+    Insert,
+    Delete,
+    Duplicate,
+    Clone,
+    /// Add a row to the end of the selected pattern
+    Longer,
+    /// Remove the last row of the selected pattern
+    Shorter,
+}
+
+/// Song overview. Shows the pattern played at each song position and selects the pattern that
+/// is edited in the grid.
+pub struct OrderEditor {
+    song: SongRc,
+    selected: Cycle,
+    first: usize,
+    /// Edits made since the app last took them, for the undo history
+    edits: Vec<Edit>,
+    has_focus: bool,
+}
+
+impl OrderEditor {
+    pub fn new(song: SongRc) -> Self {
+        let len = song.borrow().order.len();
+        Self {
+            song,
+            selected: Cycle::new(0, len),
+            first: 0,
+            edits: vec![],
+            has_focus: false,
+        }
+    }
+
+    /// The song position of the selected entry
+    pub fn position(&self) -> usize {
+        *self.selected
+    }
+
+    pub fn set_position(&mut self, pos: usize) {
+        self.select(pos);
+        self.scroll_to_selected();
+    }
+
+    /// The edits made to the song since the last call
+    pub fn take_edits(&mut self) -> Vec<Edit> {
+        std::mem::take(&mut self.edits)
+    }
+
+    /// Record a change of the order list entry at the position
+    fn edited(&mut self, pos: usize, before: Option<usize>, after: Option<usize>) {
+        self.edits.push(Edit::Order { pos, before, after });
+    }
+
+    /// Record a pattern added to the song and played at the position
+    fn added(&mut self, pos: usize, pattern: usize) {
+        let added = self.song.borrow().patterns[pattern].clone();
+        self.edits.push(Edit::AddPattern(added));
+        self.edited(pos, None, Some(pattern));
+    }
+
+    /// Change the number of rows of the pattern at the position by one
+    fn resize(&mut self, pos: usize, longer: bool) {
+        let pattern = self.song.borrow().order.get(pos);
+        let mut song = self.song.borrow_mut();
+        let resized = &mut song.patterns[pattern];
+        let before = resized.len();
+        let after = match longer {
+            true => (before + 1).min(MAX_ROWS),
+            false => before.saturating_sub(1).max(1),
+        };
+        let removed = (after..before)
+            .map(|row| resized.row(row).clone())
+            .collect();
+        resized.set_len(after);
+        drop(song);
+        if after != before {
+            self.edits.push(Edit::Length {
+                pattern,
+                before,
+                after,
+                removed,
+            });
+        }
+    }
+
+    /// The pattern played at the selected position
+    pub fn selected_pattern(&self) -> usize {
+        self.song.borrow().order.get(*self.selected)
+    }
+
+    /// Select a position after the order list has changed length
+    fn select(&mut self, pos: usize) {
+        let len = self.song.borrow().order.len();
+        self.selected = Cycle::new(pos.min(len - 1), len);
+    }
+
+    fn scroll_to_selected(&mut self) {
+        if *self.selected < self.first {
+            self.first = *self.selected;
+        } else if *self.selected >= self.first + WINDOW_LEN {
+            self.first = *self.selected + 1 - WINDOW_LEN;
+        }
+    }
+}
+
+impl Widget<Message, AppTask, OrderEditorView> for OrderEditor {
+    fn update(&mut self, msg: Message) -> Vec<Task<AppTask>> {
+        let pos = *self.selected;
+        match msg {
+            Message::Left => self.selected -= 1,
+            Message::Right => self.selected += 1,
+            Message::NextPattern | Message::PrevPattern => {
+                let mut song = self.song.borrow_mut();
+                let before = song.order.get(pos);
+                let patterns = Cycle::new(before, song.patterns.len());
+                let pattern = match msg {
+                    Message::NextPattern => patterns + 1,
+                    _ => patterns - 1,
+                };
+                song.order.set(pos, *pattern);
+                drop(song);
+                if *pattern != before {
+                    self.edited(pos, Some(before), Some(*pattern));
+                }
+            }
+            Message::Insert => {
+                let inserted = self.song.borrow_mut().insert_pattern(pos);
+                if let Some(pattern) = inserted {
+                    self.added(pos, pattern);
+                    self.select(pos);
+                }
+            }
+            Message::Delete => {
+                let before = self.song.borrow().order.get(pos);
+                if self.song.borrow_mut().order.remove(pos) {
+                    self.edited(pos, Some(before), None);
+                    self.select(pos);
+                }
+            }
+            Message::Duplicate => {
+                self.song.borrow_mut().order.duplicate(pos);
+                let pattern = self.song.borrow().order.get(pos);
+                self.edited(pos + 1, None, Some(pattern));
+                self.select(pos + 1);
+            }
+            Message::Clone => {
+                let cloned = self.song.borrow_mut().clone_pattern(pos);
+                if let Some(pattern) = cloned {
+                    self.added(pos + 1, pattern);
+                    self.select(pos + 1);
+                }
+            }
+            Message::Longer => self.resize(pos, true),
+            Message::Shorter => self.resize(pos, false),
+        }
+        self.scroll_to_selected();
+        vec![]
+    }
+
+    fn view(&self, pos: Pos) -> OrderEditorView {
+        let song = self.song.borrow();
+        let entries = song
+            .order
+            .entries()
+            .iter()
+            .skip(self.first)
+            .take(WINDOW_LEN)
+            .map(|p| format!("{:02X}", p))
+            .collect();
+        OrderEditorView {
+            pos,
+            entries,
+            selected: *self.selected - self.first,
+            has_focus: self.has_focus,
+        }
+    }
+}
+
+impl Focusable for OrderEditor {
+    fn has_focus(&self) -> bool {
+        self.has_focus
+    }
+    fn focus(&mut self) {
+        self.has_focus = true
+    }
+    fn defocus(&mut self) {
+        self.has_focus = false
+    }
+    fn next_focus(&mut self) {
+        self.has_focus = !self.has_focus;
+    }
+    fn prev_focus(&mut self) {
+        self.has_focus = !self.has_focus;
+    }
+}
+
+pub struct OrderEditorView {
+    pos: Pos,
+    entries: Vec<String>,
+    selected: usize,
+    has_focus: bool,
+}
+
+impl View<Message> for OrderEditorView {
+    fn on_event(&self, e: Event) -> Vec<Message> {
+        if !self.has_focus {
+            return vec![];
+        }
+
+        match e {
+            Event::Left => vec![Message::Left],
+            Event::Right => vec![Message::Right],
+            Event::Del => vec![Message::Delete],
+            Event::Char('+', _) => vec![Message::NextPattern],
+            Event::Char('-', _) => vec![Message::PrevPattern],
+            Event::Char('I', _) => vec![Message::Insert],
+            Event::Char('D', _) => vec![Message::Duplicate],
+            Event::Char('C', _) => vec![Message::Clone],
+            Event::Char('>', _) => vec![Message::Longer],
+            Event::Char('<', _) => vec![Message::Shorter],
+            _ => vec![],
+        }
+    }
+
+    fn draw(&self, renderer: &mut dyn Renderer) {
+        for (i, entry) in self.entries.iter().enumerate() {
+            let pos = self.pos
+                + Pos {
+                    r: 0,
+                    c: 3 * i as u16,
+                };
+            if i != self.selected {
+                renderer.render_str(pos, entry);
+            } else if self.has_focus {
+                renderer.render_fmt_str(pos, entry, Style::Invert);
+            } else {
+                renderer.render_fmt_str(pos, entry, Style::Highlight);
+            }
+        }
+    }
+}
+
+pub type OrderEditorRc = Rc<RefCell<OrderEditor>>;
+pub fn order_editor_rc(song: SongRc) -> OrderEditorRc {
+    Rc::new(RefCell::new(OrderEditor::new(song)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::song::pattern::{Note, Pattern};
+    use crate::song::Song;
+    use crate::uifw::interaction::tests::TestRenderer;
+
+    fn editor() -> OrderEditor {
+        let mut editor = OrderEditor::new(Rc::new(RefCell::new(Song::new())));
+        editor.focus();
+        editor
+    }
+
+    fn entries(editor: &OrderEditor) -> Vec<usize> {
+        editor.song.borrow().order.entries().to_vec()
+    }
+
+    #[test]
+    fn edit_test() {
+        let mut editor = editor();
+
+        editor.update(Message::Insert);
+        assert_eq!(entries(&editor), vec![1, 0]);
+        assert_eq!(editor.selected_pattern(), 1);
+
+        editor.update(Message::Duplicate);
+        assert_eq!(entries(&editor), vec![1, 1, 0]);
+        assert_eq!(editor.position(), 1);
+
+        editor.update(Message::Clone);
+        assert_eq!(entries(&editor), vec![1, 1, 2, 0]);
+        assert_eq!(editor.selected_pattern(), 2);
+
+        // Pattern numbers wrap around the existing patterns
+        editor.update(Message::NextPattern);
+        assert_eq!(editor.selected_pattern(), 0);
+        editor.update(Message::PrevPattern);
+        editor.update(Message::PrevPattern);
+        assert_eq!(editor.selected_pattern(), 1);
+
+        editor.update(Message::Delete);
+        assert_eq!(entries(&editor), vec![1, 1, 0]);
+        assert_eq!(editor.position(), 2);
+
+        // Only the changed entries and the added patterns are recorded
+        let order = |pos, before, after| Edit::Order { pos, before, after };
+        let pattern = Pattern::default();
+        assert_eq!(
+            editor.take_edits(),
+            vec![
+                Edit::AddPattern(pattern.clone()),
+                order(0, None, Some(1)),
+                order(1, None, Some(1)),
+                Edit::AddPattern(pattern),
+                order(2, None, Some(2)),
+                order(2, Some(2), Some(0)),
+                order(2, Some(0), Some(2)),
+                order(2, Some(2), Some(1)),
+                order(2, Some(1), None),
+            ]
+        );
+        editor.update(Message::Left);
+        assert_eq!(editor.take_edits(), vec![]);
+    }
+
+    #[test]
+    fn resize_test() {
+        let mut editor = editor();
+        editor.song.borrow_mut().patterns[0].set_len(2);
+        editor.song.borrow_mut().patterns[0].cell_mut(1, 0).note = Some(Note::Off);
+
+        editor.update(Message::Longer);
+        assert_eq!(editor.song.borrow().patterns[0].len(), 3);
+        editor.update(Message::Shorter);
+        editor.update(Message::Shorter);
+        editor.update(Message::Shorter);
+        assert_eq!(editor.song.borrow().patterns[0].len(), 1);
+
+        // The removed rows are kept for undo, nothing changes at the shortest length
+        let row = editor.song.borrow().patterns[0].row(0).clone();
+        let mut removed = row.clone();
+        removed.cells[0].note = Some(Note::Off);
+        let length = |before, after, removed| Edit::Length {
+            pattern: 0,
+            before,
+            after,
+            removed,
+        };
+        assert_eq!(
+            editor.take_edits(),
+            vec![
+                length(2, 3, vec![]),
+                length(3, 2, vec![row]),
+                length(2, 1, vec![removed]),
+            ]
+        );
+
+        editor.song.borrow_mut().patterns[0].set_len(MAX_ROWS);
+        editor.update(Message::Longer);
+        assert_eq!(editor.song.borrow().patterns[0].len(), MAX_ROWS);
+        assert_eq!(editor.take_edits(), vec![]);
+    }
+
+    #[test]
+    fn navigation_test() {
+        let mut editor = editor();
+        (0..20).for_each(|_| {
+            editor.update(Message::Duplicate);
+        });
+        assert_eq!(editor.position(), 20);
+
+        // Wraps around and the window follows the selection
+        editor.update(Message::Right);
+        assert_eq!(editor.position(), 0);
+        editor.update(Message::Left);
+        assert_eq!(editor.position(), 20);
+
+        let view = editor.view(Pos { r: 0, c: 0 });
+        assert_eq!(view.entries.len(), WINDOW_LEN);
+        assert_eq!(view.selected, WINDOW_LEN - 1);
+
+        let mut renderer = TestRenderer::new();
+        view.draw(&mut renderer);
+        assert_eq!(renderer.out, "00".repeat(WINDOW_LEN));
+
+        // The last entry can't be deleted
+        let mut editor = super::tests::editor();
+        editor.update(Message::Delete);
+        assert_eq!(entries(&editor), vec![0]);
+    }
+}
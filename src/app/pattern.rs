@@ -0,0 +1,1040 @@
+// Copyright (C) 2025 Anders Sundman <anders@4zm.org>
+//
+// This file is part of RTRK - The Rust Tracker
+//
+// RTRK is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// RTRK is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with RTRK. If not, see <https://www.gnu.org/licenses/>.
+
+use std::cell::{Ref, RefCell, RefMut};
+use std::ops::Range;
+use std::rc::Rc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::app::history::{self, Edit};
+use crate::app::{AppTask, SongRc};
+use crate::song::block::{self, Area, Block, Curve, Rng, FIELDS};
+use crate::song::pattern::{Cell, Effect, Note, Pattern};
+use crate::uifw::interaction::{CharModifiers, Event, Renderer, Style};
+use crate::uifw::pos::Pos;
+use crate::uifw::widget::{Focusable, Task, View, Widget};
+
+/// Number of rows above and below the cursor row
+const HALF_HEIGHT: usize = 4;
+const TRACK_C: usize = 6;
+const TRACK_STRIDE: usize = Cell::WIDTH + 3;
+/// Number of tracks shown at once, the grid scrolls sideways to the cursor track
+const VISIBLE_TRACKS: usize = 4;
+/// Char offset of each editable column within a cell, the note is 3 chars wide
+const CELL_COLUMNS: [usize; 8] = [0, 4, 5, 7, 8, 10, 11, 12];
+const GFX_COLUMNS: [usize; 3] = [0, 1, 2];
+/// Largest random change of the volume when humanising
+const HUMANISE_RANGE: u8 = 0x10;
+/// Char offset and width of each block field within a cell
+const FIELD_SPANS: [(usize, usize); FIELDS] = [(0, 3), (4, 2), (7, 2), (10, 3)];
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Message {
+    Up,
+    Down,
+    Left,
+    Right,
+    EnterChar(char),
+    Del,
+    DelBack,
+    /// Write a note, and the voice if given, into the cell under the cursor
+    Record(Note, Option<u8>),
+    /// Like record, but into the first track from the cursor that has no note on the row
+    Overdub(Note, Option<u8>),
+    /// Clear all fields of the cell under the cursor
+    ClearCell,
+    /// Move the cursor down a number of rows
+    Step(usize),
+    /// Start a selection at the cursor. The selection spans from here to the cursor.
+    Mark,
+    Unmark,
+    Copy,
+    Cut,
+    Paste,
+    /// Paste only into empty fields
+    MixPaste,
+    /// Move the selected notes by semitones, optionally only those of a voice
+    Transpose(i8, Option<u8>),
+    /// Fill the selection with volumes and effect parameters between the first and last row
+    Interpolate(Curve),
+    /// Randomise the selected volumes within a range
+    Humanise(u8),
+}
+
+/// Position of the edit cursor. The track after the last one is the global effect column.
+#[derive(Copy, Clone, Debug, PartialEq, Default)]
+pub struct Cursor {
+    pub row: usize,
+    pub track: usize,
+    pub col: usize,
+}
+
+/// Edits one of the patterns of the song
+pub struct PatternGrid {
+    song: SongRc,
+    pattern_idx: usize,
+    cursor: Cursor,
+    /// The leftmost track on screen
+    first_track: usize,
+    /// Row and block column where the selection was started
+    mark: Option<(usize, usize)>,
+    clipboard: Option<Block>,
+    /// Voice selected in the voice list, for transposing a single voice
+    voice: u8,
+    rng: Rng,
+    /// Edits made since the app last took them, for the undo history
+    edits: Vec<Edit>,
+    has_focus: bool,
+}
+
+impl PatternGrid {
+    pub fn new(song: SongRc) -> Self {
+        Self {
+            song,
+            pattern_idx: 0,
+            cursor: Cursor::default(),
+            first_track: 0,
+            mark: None,
+            clipboard: None,
+            voice: 0,
+            rng: Rng::new(
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(1, |d| d.subsec_nanos()),
+            ),
+            edits: vec![],
+            has_focus: false,
+        }
+    }
+
+    pub fn set_voice(&mut self, voice: u8) {
+        self.voice = voice;
+    }
+
+    pub fn set_pattern(&mut self, idx: usize) {
+        if idx != self.pattern_idx {
+            self.mark = None;
+        }
+        self.pattern_idx = idx;
+        let len = self.pattern().len();
+        self.cursor.row = self.cursor.row.min(len - 1);
+    }
+
+    pub fn set_row(&mut self, row: usize) {
+        let len = self.pattern().len();
+        self.cursor.row = row.min(len - 1);
+    }
+
+    /// The edits made to the song since the last call
+    pub fn take_edits(&mut self) -> Vec<Edit> {
+        std::mem::take(&mut self.edits)
+    }
+
+    /// The fields a message may change, None if it doesn't edit the pattern
+    fn edited_area(&self, msg: Message) -> Option<Area> {
+        let gfx_col = self.pattern().tracks() * FIELDS;
+        let rows = |first: usize, last: usize| Area::spanning((first, 0), (last, gfx_col));
+        let row = self.cursor.row;
+        match msg {
+            Message::EnterChar(_)
+            | Message::DelBack
+            | Message::Record(..)
+            | Message::Overdub(..)
+            | Message::ClearCell => Some(rows(row, row)),
+            Message::Del => self.selection().or(Some(rows(row, row))),
+            Message::Cut
+            | Message::Transpose(..)
+            | Message::Interpolate(_)
+            | Message::Humanise(_) => self.selection(),
+            Message::Paste | Message::MixPaste => {
+                let clipboard = self.clipboard.as_ref()?;
+                Some(rows(row, row + clipboard.rows().max(1) - 1))
+            }
+            _ => None,
+        }
+    }
+
+    fn pattern(&self) -> Ref<'_, Pattern> {
+        Ref::map(self.song.borrow(), |s| &s.patterns[self.pattern_idx])
+    }
+
+    fn pattern_mut(&self) -> RefMut<'_, Pattern> {
+        RefMut::map(self.song.borrow_mut(), |s| {
+            &mut s.patterns[self.pattern_idx]
+        })
+    }
+
+    /// Scroll the tracks so that the cursor track is on screen. The global effect column is
+    /// always shown.
+    fn scroll_to_cursor(&mut self) {
+        let track = self.cursor.track;
+        if track == self.pattern().tracks() {
+            return;
+        }
+        if track < self.first_track {
+            self.first_track = track;
+        } else if track >= self.first_track + VISIBLE_TRACKS {
+            self.first_track = track + 1 - VISIBLE_TRACKS;
+        }
+    }
+
+    /// The block column of the field under the cursor
+    fn block_col(&self) -> usize {
+        let field = match self.cursor.col {
+            0 => 0,
+            1 | 2 => 1,
+            3 | 4 => 2,
+            _ => 3,
+        };
+        match self.cursor.track == self.pattern().tracks() {
+            true => self.cursor.track * FIELDS,
+            false => self.cursor.track * FIELDS + field,
+        }
+    }
+
+    fn selection(&self) -> Option<Area> {
+        let mark = self.mark?;
+        Some(Area::spanning(mark, (self.cursor.row, self.block_col())))
+    }
+
+    fn copy(&mut self) {
+        if let Some(area) = self.selection() {
+            let block = Block::copy(&self.pattern(), area);
+            self.clipboard = Some(block);
+        }
+    }
+
+    fn cut(&mut self) {
+        self.copy();
+        if let Some(area) = self.selection() {
+            block::clear(&mut self.pattern_mut(), area);
+            self.mark = None;
+        }
+    }
+
+    /// Paste the clipboard with its top left corner in the cursor row and track
+    fn paste(&mut self, mix: bool) {
+        if let Some(clipboard) = &self.clipboard {
+            let (row, track) = (self.cursor.row, self.cursor.track);
+            clipboard.paste(&mut self.pattern_mut(), row, track, mix);
+        }
+    }
+
+    fn columns(&self, track: usize) -> usize {
+        if track == self.pattern().tracks() {
+            GFX_COLUMNS.len()
+        } else {
+            CELL_COLUMNS.len()
+        }
+    }
+
+    fn left(&mut self) {
+        if self.cursor.col > 0 {
+            self.cursor.col -= 1;
+        } else {
+            let tracks = self.pattern().tracks();
+            self.cursor.track = (self.cursor.track + tracks) % (tracks + 1);
+            self.cursor.col = self.columns(self.cursor.track) - 1;
+        }
+    }
+
+    fn right(&mut self) {
+        if self.cursor.col + 1 < self.columns(self.cursor.track) {
+            self.cursor.col += 1;
+        } else {
+            let tracks = self.pattern().tracks();
+            self.cursor.track = (self.cursor.track + 1) % (tracks + 1);
+            self.cursor.col = 0;
+        }
+    }
+
+    fn enter_char(&mut self, c: char) {
+        let Cursor { row, track, col } = self.cursor;
+        let mut pattern = self.pattern_mut();
+
+        if track == pattern.tracks() {
+            let Some(nibble) = c.to_digit(16) else {
+                return;
+            };
+            let gfx = &mut pattern.row_mut(row).gfx;
+            *gfx = Some(set_effect_nibble(*gfx, col, nibble as u8));
+        } else {
+            let cell = pattern.cell_mut(row, track);
+            if col == 0 {
+                cell.note = edit_note(cell.note, c);
+                return; // The note is a single column
+            }
+            let Some(nibble) = c.to_digit(16) else {
+                return;
+            };
+            let nibble = nibble as u8;
+            match col {
+                1 | 2 => cell.voice = Some(set_nibble(cell.voice, col - 1, nibble)),
+                3 | 4 => cell.volume = Some(set_nibble(cell.volume, col - 3, nibble)),
+                _ => cell.effect = Some(set_effect_nibble(cell.effect, col - 5, nibble)),
+            }
+        }
+        drop(pattern);
+
+        // Advance like a text box, but stay in the cell
+        if self.cursor.col + 1 < self.columns(track) {
+            self.cursor.col += 1;
+        }
+    }
+
+    fn record(&mut self, note: Note, voice: Option<u8>) {
+        let Cursor { row, track, .. } = self.cursor;
+        let mut pattern = self.pattern_mut();
+        if track < pattern.tracks() {
+            let cell = pattern.cell_mut(row, track);
+            cell.note = Some(note);
+            if voice.is_some() {
+                cell.voice = voice;
+            }
+        }
+    }
+
+    fn overdub(&mut self, note: Note, voice: Option<u8>) {
+        let tracks = self.pattern().tracks();
+        let Cursor { row, track, .. } = self.cursor;
+        let free = (0..tracks)
+            .map(|t| (track + t) % tracks)
+            .find(|&t| self.pattern().cell(row, t).note.is_none());
+        if let Some(free) = free {
+            let cursor = self.cursor;
+            self.cursor.track = free;
+            self.record(note, voice);
+            self.cursor = cursor;
+        }
+    }
+
+    fn clear_cell(&mut self) {
+        let Cursor { row, track, .. } = self.cursor;
+        let mut pattern = self.pattern_mut();
+        if track < pattern.tracks() {
+            *pattern.cell_mut(row, track) = Cell::default();
+        } else {
+            pattern.row_mut(row).gfx = None;
+        }
+    }
+
+    /// Clear the whole field under the cursor
+    fn clear(&mut self) {
+        let Cursor { row, track, col } = self.cursor;
+        let mut pattern = self.pattern_mut();
+
+        if track == pattern.tracks() {
+            pattern.row_mut(row).gfx = None;
+        } else {
+            let cell = pattern.cell_mut(row, track);
+            match col {
+                0 => cell.note = None,
+                1 | 2 => cell.voice = None,
+                3 | 4 => cell.volume = None,
+                _ => cell.effect = None,
+            }
+        }
+    }
+}
+
+fn set_nibble(value: Option<u8>, idx: usize, nibble: u8) -> u8 {
+    let value = value.unwrap_or(0);
+    match idx {
+        0 => (value & 0x0F) | (nibble << 4),
+        _ => (value & 0xF0) | nibble,
+    }
+}
+
+fn set_effect_nibble(effect: Option<Effect>, idx: usize, nibble: u8) -> Effect {
+    let mut effect = effect.unwrap_or(Effect { code: 0, param: 0 });
+    match idx {
+        0 => effect.code = nibble,
+        _ => effect.param = set_nibble(Some(effect.param), idx - 1, nibble),
+    }
+    effect
+}
+
+/// Note letters set the note name, '#' toggles sharp, digits set the octave and '=' is note off
+fn edit_note(note: Option<Note>, c: char) -> Option<Note> {
+    let key = match note {
+        Some(Note::Key(k)) => k,
+        _ => 4 * 12, // C-4
+    };
+    let (octave, semitone) = (key / 12, key % 12);
+    let key = match c {
+        'C' => octave * 12,
+        'D' => octave * 12 + 2,
+        'E' => octave * 12 + 4,
+        'F' => octave * 12 + 5,
+        'G' => octave * 12 + 7,
+        'A' => octave * 12 + 9,
+        'B' => octave * 12 + 11,
+        '#' if note.is_some() => match semitone {
+            1 | 3 | 6 | 8 | 10 => key - 1,
+            4 | 11 => key, // No E# or B#
+            _ => key + 1,
+        },
+        '0'..='9' if note.is_some() => c.to_digit(10).unwrap() as u8 * 12 + semitone,
+        '=' => return Some(Note::Off),
+        _ => return note,
+    };
+    Some(Note::Key(key))
+}
+
+impl Widget<Message, AppTask, PatternGridView> for PatternGrid {
+    fn update(&mut self, msg: Message) -> Vec<Task<AppTask>> {
+        let len = self.pattern().len();
+        let before = self
+            .edited_area(msg)
+            .map(|area| history::fields(&self.pattern(), area));
+        match msg {
+            Message::Up => self.cursor.row = (self.cursor.row + len - 1) % len,
+            Message::Down => self.cursor.row = (self.cursor.row + 1) % len,
+            Message::Left => self.left(),
+            Message::Right => self.right(),
+            Message::EnterChar(c) => self.enter_char(c),
+            Message::Del => match self.selection() {
+                Some(area) => block::clear(&mut self.pattern_mut(), area),
+                None => self.clear(),
+            },
+            Message::DelBack => {
+                self.left();
+                self.clear();
+            }
+            Message::Record(note, voice) => self.record(note, voice),
+            Message::Overdub(note, voice) => self.overdub(note, voice),
+            Message::ClearCell => self.clear_cell(),
+            Message::Step(rows) => self.cursor.row = (self.cursor.row + rows) % len,
+            Message::Mark => self.mark = Some((self.cursor.row, self.block_col())),
+            Message::Unmark => self.mark = None,
+            Message::Copy => self.copy(),
+            Message::Cut => self.cut(),
+            Message::Paste => self.paste(false),
+            Message::MixPaste => self.paste(true),
+            Message::Transpose(semitones, voice) => {
+                if let Some(area) = self.selection() {
+                    block::transpose(&mut self.pattern_mut(), area, semitones as i32, voice);
+                }
+            }
+            Message::Interpolate(curve) => {
+                if let Some(area) = self.selection() {
+                    block::interpolate(&mut self.pattern_mut(), area, curve);
+                }
+            }
+            Message::Humanise(range) => {
+                if let Some(area) = self.selection() {
+                    // The pattern borrows the grid, the generator is put back after use
+                    let mut rng = self.rng;
+                    block::humanise(&mut self.pattern_mut(), area, range, &mut rng);
+                    self.rng = rng;
+                }
+            }
+        }
+
+        self.scroll_to_cursor();
+
+        if let Some(before) = before {
+            let changes = history::changes(&self.pattern(), before);
+            if !changes.is_empty() {
+                self.edits.push(Edit::Fields {
+                    pattern: self.pattern_idx,
+                    changes,
+                });
+            }
+        }
+        vec![]
+    }
+
+    fn view(&self, pos: Pos) -> PatternGridView {
+        let selection = self.selection();
+        let pattern = self.pattern();
+        let first_track = self
+            .first_track
+            .min(pattern.tracks().saturating_sub(VISIBLE_TRACKS));
+        PatternGridView::new(
+            pos,
+            &pattern,
+            self.cursor,
+            first_track,
+            selection,
+            self.voice,
+            self.has_focus,
+        )
+    }
+}
+
+impl Focusable for PatternGrid {
+    fn has_focus(&self) -> bool {
+        self.has_focus
+    }
+    fn focus(&mut self) {
+        self.has_focus = true
+    }
+    fn defocus(&mut self) {
+        self.has_focus = false
+    }
+    fn next_focus(&mut self) {
+        self.has_focus = !self.has_focus;
+    }
+    fn prev_focus(&mut self) {
+        self.has_focus = !self.has_focus;
+    }
+}
+
+pub struct PatternGridView {
+    pos: Pos,
+    lines: Vec<Option<String>>,
+    cursor: Option<(Pos, String)>,
+    /// Text of the selected fields on screen
+    selection: Vec<(Pos, String)>,
+    voice: u8,
+    has_focus: bool,
+}
+
+impl PatternGridView {
+    fn new(
+        pos: Pos,
+        pattern: &Pattern,
+        cursor: Cursor,
+        first_track: usize,
+        selection: Option<Area>,
+        voice: u8,
+        has_focus: bool,
+    ) -> Self {
+        let shown = first_track..(first_track + VISIBLE_TRACKS).min(pattern.tracks());
+        // Screen column of a track on screen, the global effects follow the last track shown
+        let track_c = |track: usize| match track == pattern.tracks() {
+            true => Some(TRACK_C + shown.len() * TRACK_STRIDE),
+            false => shown
+                .contains(&track)
+                .then(|| TRACK_C + (track - first_track) * TRACK_STRIDE),
+        };
+        let rows: Vec<Option<usize>> = (0..=2 * HALF_HEIGHT)
+            .map(|i| {
+                let row = (cursor.row + i).checked_sub(HALF_HEIGHT)?;
+                (row < pattern.len()).then_some(row)
+            })
+            .collect();
+        let lines = rows
+            .iter()
+            .enumerate()
+            .map(|(i, row)| row.map(|row| format_row(pattern, row, i, shown.clone())))
+            .collect();
+
+        let mut selected = vec![];
+        for (i, row) in rows.iter().enumerate() {
+            let (Some(row), Some(area)) = (row, selection) else {
+                continue;
+            };
+            for col in (0..=pattern.tracks() * FIELDS).filter(|c| area.contains(*row, *c)) {
+                let (track, field) = (col / FIELDS, col % FIELDS);
+                let Some(track_c) = track_c(track) else {
+                    continue;
+                };
+                let (c, text) = if track == pattern.tracks() {
+                    let gfx = pattern.row(*row).gfx;
+                    (
+                        track_c + 1,
+                        gfx.map_or("---".to_string(), |e| e.to_string()),
+                    )
+                } else {
+                    let (start, width) = FIELD_SPANS[field];
+                    let text = pattern.cell(*row, track).to_string();
+                    (track_c + start, text[start..start + width].to_string())
+                };
+                let r = i as u16;
+                selected.push((pos + Pos { r, c: c as u16 }, text));
+            }
+        }
+
+        // Cursor field in the center row
+        let cursor = track_c(cursor.track).map(|track_c| {
+            let (c, text) = if cursor.track == pattern.tracks() {
+                let gfx = pattern.row(cursor.row).gfx;
+                let text = gfx.map_or("---".to_string(), |e| e.to_string());
+                let c = track_c + 1 + GFX_COLUMNS[cursor.col];
+                (c, text[cursor.col..cursor.col + 1].to_string())
+            } else {
+                let text = pattern.cell(cursor.row, cursor.track).to_string();
+                let width = if cursor.col == 0 { 3 } else { 1 };
+                let start = CELL_COLUMNS[cursor.col];
+                (track_c + start, text[start..start + width].to_string())
+            };
+            let r = HALF_HEIGHT as u16;
+            (pos + Pos { r, c: c as u16 }, text)
+        });
+
+        Self {
+            pos,
+            lines,
+            cursor,
+            selection: selected,
+            voice,
+            has_focus,
+        }
+    }
+}
+
+/// A row with row number, the cells of the tracks shown and global effect. `line` is the screen
+/// line within the grid, it decides what separators to use.
+fn format_row(pattern: &Pattern, row: usize, line: usize, tracks: Range<usize>) -> String {
+    let (sep, left, right) = match line {
+        0 => ('.', ' ', ' '),
+        l if l == HALF_HEIGHT => (':', '<', '>'),
+        l if l == 2 * HALF_HEIGHT => ('\'', ' ', ' '),
+        _ => (':', ' ', ' '),
+    };
+    let marker = if line == HALF_HEIGHT { '>' } else { sep };
+
+    let r = pattern.row(row);
+    let mut text = format!(" {:02X} {} ", row, marker);
+    for cell in r.cells[tracks].iter() {
+        text += &format!("{}{}{}{}", cell, left, sep, right);
+    }
+    text += &format!(" {}", r.gfx.map_or("---".to_string(), |e| e.to_string()));
+    text
+}
+
+impl View<Message> for PatternGridView {
+    fn on_event(&self, e: Event) -> Vec<Message> {
+        if !self.has_focus {
+            return vec![];
+        }
+
+        match e {
+            Event::Up => vec![Message::Up],
+            Event::Down => vec![Message::Down],
+            Event::Left => vec![Message::Left],
+            Event::Right => vec![Message::Right],
+            Event::Del => vec![Message::Del],
+            Event::DelBack => vec![Message::DelBack],
+            Event::Char(c, m) if m.contains(CharModifiers::Alt) => {
+                // With shift, transpose only the current voice and interpolate along a curve
+                let shift = m.contains(CharModifiers::Shift);
+                let voice = shift.then_some(self.voice);
+                let curve = if shift {
+                    Curve::Quadratic
+                } else {
+                    Curve::Linear
+                };
+                match c.to_ascii_uppercase() {
+                    'B' => vec![Message::Mark],
+                    'U' => vec![Message::Unmark],
+                    'C' => vec![Message::Copy],
+                    'Z' => vec![Message::Cut],
+                    'P' => vec![Message::Paste],
+                    'M' => vec![Message::MixPaste],
+                    'Q' => vec![Message::Transpose(1, voice)],
+                    'A' => vec![Message::Transpose(-1, voice)],
+                    'W' => vec![Message::Transpose(12, voice)],
+                    'S' => vec![Message::Transpose(-12, voice)],
+                    'I' => vec![Message::Interpolate(curve)],
+                    'H' => vec![Message::Humanise(HUMANISE_RANGE)],
+                    _ => vec![],
+                }
+            }
+            Event::Char(c, _) => vec![Message::EnterChar(c)],
+            _ => vec![],
+        }
+    }
+
+    fn draw(&self, renderer: &mut dyn Renderer) {
+        for (i, line) in self.lines.iter().enumerate() {
+            let Some(line) = line else {
+                continue;
+            };
+            let pos = self.pos + Pos { r: i as u16, c: 0 };
+            if self.has_focus && i == HALF_HEIGHT {
+                renderer.render_fmt_str(pos, line, Style::Highlight);
+            } else {
+                renderer.render_str(pos, line);
+            }
+        }
+
+        for (pos, text) in self.selection.iter() {
+            renderer.render_fmt_str(*pos, text, Style::Invert);
+        }
+
+        if let (true, Some((pos, text))) = (self.has_focus, &self.cursor) {
+            renderer.render_fmt_str(*pos, text, Style::Invert);
+        }
+    }
+}
+
+pub type PatternGridRc = Rc<RefCell<PatternGrid>>;
+pub fn pattern_grid_rc(song: SongRc) -> PatternGridRc {
+    Rc::new(RefCell::new(PatternGrid::new(song)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::history::FieldChange;
+    use crate::song::block::Field;
+    use crate::song::pattern::Row;
+    use crate::song::Song;
+    use crate::uifw::interaction::tests::TestRenderer;
+
+    fn grid() -> PatternGrid {
+        let mut song = Song::new();
+        song.patterns[0] = Pattern::new(0x10, 4);
+        let mut grid = PatternGrid::new(Rc::new(RefCell::new(song)));
+        grid.focus();
+        grid
+    }
+
+    fn enter(grid: &mut PatternGrid, text: &str) {
+        text.chars().for_each(|c| {
+            grid.update(Message::EnterChar(c));
+        });
+    }
+
+    #[test]
+    fn hex_entry_test() {
+        let mut grid = grid();
+        grid.update(Message::Right);
+        enter(&mut grid, "1A0");
+        let cell = *grid.pattern().cell(0, 0);
+        assert_eq!(cell.voice, Some(0x1A));
+        assert_eq!(cell.volume, Some(0x00));
+
+        // Non hex input is ignored, the cursor stays in the cell
+        enter(&mut grid, "X51FF");
+        let cell = *grid.pattern().cell(0, 0);
+        assert_eq!(cell.volume, Some(0x05));
+        assert_eq!(
+            cell.effect,
+            Some(Effect {
+                code: 1,
+                param: 0xFF
+            })
+        );
+        assert_eq!(
+            grid.cursor,
+            Cursor {
+                row: 0,
+                track: 0,
+                col: 7
+            }
+        );
+
+        grid.update(Message::Del);
+        assert_eq!(grid.pattern().cell(0, 0).effect, None);
+        (0..3).for_each(|_| {
+            grid.update(Message::DelBack);
+        });
+        assert_eq!(grid.cursor.col, 4);
+        assert_eq!(grid.pattern().cell(0, 0).volume, None);
+    }
+
+    #[test]
+    fn note_entry_test() {
+        let mut grid = grid();
+        enter(&mut grid, "F#5");
+        assert_eq!(grid.pattern().cell(0, 0).note, Some(Note::Key(5 * 12 + 6)));
+        assert_eq!(grid.cursor.col, 0);
+
+        enter(&mut grid, "E#");
+        assert_eq!(grid.pattern().cell(0, 0).note, Some(Note::Key(5 * 12 + 4)));
+        enter(&mut grid, "=");
+        assert_eq!(grid.pattern().cell(0, 0).note, Some(Note::Off));
+    }
+
+    #[test]
+    fn navigation_test() {
+        let mut grid = grid();
+
+        // Wraps around to the global effect column and the last row
+        grid.update(Message::Left);
+        grid.update(Message::Up);
+        assert_eq!(
+            grid.cursor,
+            Cursor {
+                row: 0xF,
+                track: 4,
+                col: 2
+            }
+        );
+        enter(&mut grid, "2");
+        grid.update(Message::Left);
+        enter(&mut grid, "F");
+        assert_eq!(
+            grid.pattern().row(0xF).gfx,
+            Some(Effect {
+                code: 0,
+                param: 0xF2
+            })
+        );
+
+        grid.update(Message::Right);
+        grid.update(Message::Down);
+        assert_eq!(
+            grid.cursor,
+            Cursor {
+                row: 0,
+                track: 0,
+                col: 0
+            }
+        );
+
+        // Switching to a shorter pattern keeps the cursor inside it
+        grid.update(Message::Up);
+        grid.song.borrow_mut().patterns.push(Pattern::new(4, 4));
+        grid.set_pattern(1);
+        assert_eq!(grid.cursor.row, 3);
+    }
+
+    #[test]
+    fn record_test() {
+        let mut grid = grid();
+        grid.update(Message::Right);
+        grid.update(Message::Record(Note::Key(4 * 12), Some(0x12)));
+        grid.update(Message::Step(2));
+        grid.update(Message::Record(Note::Off, None));
+        grid.update(Message::Step(0x10 - 2));
+
+        assert_eq!(grid.cursor.row, 0);
+        assert_eq!(grid.pattern().cell(0, 0).to_string(), "C-4 12 -- ---");
+        assert_eq!(grid.pattern().cell(2, 0).to_string(), "=== -- -- ---");
+
+        // The voice of the cell is kept when none is given
+        grid.update(Message::Record(Note::Key(5 * 12), None));
+        assert_eq!(grid.pattern().cell(0, 0).to_string(), "C-5 12 -- ---");
+
+        grid.update(Message::ClearCell);
+        assert!(grid.pattern().cell(0, 0).is_empty());
+    }
+
+    #[test]
+    fn overdub_test() {
+        let mut grid = grid();
+        grid.cursor.track = 1;
+        grid.update(Message::Overdub(Note::Key(4 * 12), Some(1)));
+        grid.update(Message::Overdub(Note::Key(4 * 12 + 4), Some(1)));
+        assert_eq!(grid.pattern().cell(0, 1).to_string(), "C-4 01 -- ---");
+        assert_eq!(grid.pattern().cell(0, 2).to_string(), "E-4 01 -- ---");
+        assert_eq!(grid.cursor.track, 1);
+
+        // Wraps around to the first track and drops the note when all tracks are taken
+        grid.update(Message::Overdub(Note::Key(4 * 12 + 7), Some(1)));
+        grid.update(Message::Overdub(Note::Off, None));
+        grid.update(Message::Overdub(Note::Key(5 * 12), Some(1)));
+        assert_eq!(grid.pattern().cell(0, 3).to_string(), "G-4 01 -- ---");
+        assert_eq!(grid.pattern().cell(0, 0).to_string(), "=== -- -- ---");
+        assert_eq!(grid.pattern().cell(0, 1).to_string(), "C-4 01 -- ---");
+    }
+
+    #[test]
+    fn block_test() {
+        let mut grid = grid();
+        *grid.pattern_mut() = Pattern::parse_rows(
+            &"C-4 01 40 101 : --- -- -- --- : --- -- -- --- : --- -- -- --- : 0FF\n".repeat(2),
+        )
+        .unwrap();
+
+        // Note to volume of track 0 on both rows
+        grid.update(Message::Mark);
+        (0..4).for_each(|_| {
+            grid.update(Message::Right);
+        });
+        grid.update(Message::Down);
+        grid.update(Message::Copy);
+        let view = grid.view(Pos { r: 0, c: 0 });
+        assert_eq!(view.selection.len(), 6);
+
+        // Pasted at the cursor track with the fields lined up
+        grid.update(Message::Unmark);
+        grid.cursor = Cursor {
+            row: 1,
+            track: 1,
+            col: 6,
+        };
+        grid.update(Message::Paste);
+        assert_eq!(grid.pattern().cell(1, 1).to_string(), "C-4 01 40 ---");
+
+        grid.pattern_mut().cell_mut(0, 2).voice = Some(0x12);
+        grid.cursor.track = 2;
+        grid.cursor.row = 0;
+        grid.update(Message::MixPaste);
+        assert_eq!(grid.pattern().cell(0, 2).to_string(), "C-4 12 40 ---");
+
+        // Cut clears the selection and the clipboard can be pasted again
+        grid.cursor.col = 0;
+        grid.update(Message::Mark);
+        grid.update(Message::Cut);
+        assert_eq!(grid.pattern().cell(0, 2).to_string(), "--- 12 40 ---");
+        assert_eq!(grid.mark, None);
+        grid.update(Message::Paste);
+        assert_eq!(grid.pattern().cell(0, 2).to_string(), "C-4 12 40 ---");
+
+        // Del clears the whole selection, including the global effect
+        grid.cursor.track = 0;
+        grid.update(Message::Mark);
+        grid.cursor.track = 4;
+        grid.update(Message::Del);
+        assert_eq!(grid.pattern().row(0).to_string(), Row::new(4).to_string());
+    }
+
+    #[test]
+    fn selection_tools_test() {
+        let mut grid = grid();
+        *grid.pattern_mut() = Pattern::parse_rows(
+            "C-4 01 00 --- : C-4 02 -- --- : ---\n\
+             --- -- -- --- : --- -- -- --- : ---\n\
+             C-4 01 40 --- : C-4 02 -- --- : ---\n",
+        )
+        .unwrap();
+        grid.set_voice(2);
+
+        // Nothing happens without a selection
+        let view = grid.view(Pos { r: 0, c: 0 });
+        let alt_shift = CharModifiers::Alt | CharModifiers::Shift;
+        let transpose = view.on_event(Event::Char('Q', alt_shift));
+        assert_eq!(transpose, vec![Message::Transpose(1, Some(2))]);
+        grid.update(transpose[0]);
+        assert_eq!(grid.pattern().cell(0, 1).to_string(), "C-4 02 -- ---");
+
+        grid.update(Message::Mark);
+        grid.cursor = Cursor {
+            row: 2,
+            track: 1,
+            col: 0,
+        };
+        grid.update(transpose[0]);
+        grid.update(Message::Transpose(-12, None));
+        assert_eq!(grid.pattern().cell(0, 0).to_string(), "C-3 01 00 ---");
+        assert_eq!(grid.pattern().cell(2, 1).to_string(), "C#3 02 -- ---");
+
+        let view = grid.view(Pos { r: 0, c: 0 });
+        let interpolate = view.on_event(Event::Char('i', CharModifiers::Alt));
+        assert_eq!(interpolate, vec![Message::Interpolate(Curve::Linear)]);
+        grid.update(interpolate[0]);
+        assert_eq!(grid.pattern().cell(1, 0).volume, Some(0x20));
+
+        // Extend the selection to the volume of track 1
+        grid.cursor.col = 3;
+        grid.update(Message::Humanise(0x10));
+        assert!((0xEF..=0xFF).contains(&grid.pattern().cell(0, 1).volume.unwrap()));
+        assert_eq!(grid.pattern().cell(1, 1).volume, None);
+    }
+
+    #[test]
+    fn edits_test() {
+        let mut grid = grid();
+
+        // Moving around edits nothing
+        grid.update(Message::Down);
+        grid.update(Message::Mark);
+        grid.update(Message::Copy);
+        assert_eq!(grid.take_edits(), vec![]);
+
+        enter(&mut grid, "C");
+        grid.update(Message::Del);
+        let edits = grid.take_edits();
+        let note = |before, after| FieldChange {
+            row: 1,
+            col: 0,
+            before: Field::Note(before),
+            after: Field::Note(after),
+        };
+        let key = Some(Note::Key(4 * 12));
+        assert_eq!(
+            edits,
+            vec![
+                Edit::Fields {
+                    pattern: 0,
+                    changes: vec![note(None, key)],
+                },
+                Edit::Fields {
+                    pattern: 0,
+                    changes: vec![note(key, None)],
+                },
+            ]
+        );
+        assert_eq!(grid.take_edits(), vec![]);
+    }
+
+    #[test]
+    fn view_test() {
+        let mut grid = grid();
+        grid.pattern_mut().cell_mut(1, 1).note = Some(Note::Key(4 * 12 + 1));
+
+        // The cursor row is centered, no rows are drawn above the first
+        let view = grid.view(Pos { r: 0, c: 0 });
+        assert!(view.lines[..HALF_HEIGHT].iter().all(|l| l.is_none()));
+        assert_eq!(
+            view.lines[HALF_HEIGHT + 1].as_deref(),
+            Some(" 01 : --- -- -- --- : C#4 -- -- --- : --- -- -- --- : --- -- -- --- :  ---")
+        );
+
+        grid.update(Message::Down);
+        let view = grid.view(Pos { r: 0, c: 0 });
+        assert_eq!(
+            view.lines[HALF_HEIGHT].as_deref(),
+            Some(" 01 > --- -- -- ---<:>C#4 -- -- ---<:>--- -- -- ---<:>--- -- -- ---<:> ---")
+        );
+
+        let mut renderer = TestRenderer::new();
+        view.draw(&mut renderer);
+        assert!(renderer.out.ends_with("---")); // Cursor on the note
+    }
+
+    #[test]
+    fn scroll_test() {
+        let mut grid = grid();
+        grid.song.borrow_mut().patterns[0] = Pattern::new(0x10, 6);
+        grid.pattern_mut().cell_mut(0, 5).note = Some(Note::Off);
+        let line = |grid: &PatternGrid| grid.view(Pos { r: 0, c: 0 }).lines[HALF_HEIGHT].clone();
+        let cursor = |grid: &PatternGrid| grid.view(Pos { r: 0, c: 0 }).cursor.unwrap().0;
+
+        // The tracks follow the cursor to the right, the global effects stay in the last column
+        (0..5 * 8).for_each(|_| {
+            grid.update(Message::Right);
+        });
+        assert_eq!(
+            line(&grid).as_deref(),
+            Some(" 00 > --- -- -- ---<:>--- -- -- ---<:>--- -- -- ---<:>=== -- -- ---<:> ---")
+        );
+        assert_eq!(cursor(&grid), Pos { r: 4, c: 54 });
+        (0..8).for_each(|_| {
+            grid.update(Message::Right);
+        });
+        assert_eq!(cursor(&grid), Pos { r: 4, c: 71 });
+
+        // And back to the left
+        (0..3).for_each(|_| {
+            grid.update(Message::Right);
+        });
+        assert_eq!(cursor(&grid), Pos { r: 4, c: 6 });
+        assert!(line(&grid)
+            .unwrap()
+            .contains(">--- -- -- ---<:>--- -- -- ---<:>"));
+        assert!(!line(&grid).unwrap().contains("==="));
+
+        // A selection is only drawn where it is on screen
+        grid.update(Message::Mark);
+        (0..5 * 8).for_each(|_| {
+            grid.update(Message::Right);
+        });
+        let view = grid.view(Pos { r: 0, c: 0 });
+        assert_eq!(
+            view.selection.first().map(|s| s.0),
+            Some(Pos { r: 4, c: 6 })
+        );
+        assert_eq!(view.selection.len(), 3 * FIELDS + 1);
+    }
+}
@@ -0,0 +1,150 @@
+// Copyright (C) 2025 Anders Sundman <anders@4zm.org>
+//
+// This file is part of RTRK - The Rust Tracker
+//
+// RTRK is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// RTRK is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with RTRK. If not, see <https://www.gnu.org/licenses/>.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::app::AppTask;
+use crate::cycle::Cycle;
+use crate::song::preset::{Preset, PRESETS};
+use crate::uifw::interaction::{Event, Renderer, Style};
+use crate::uifw::pos::Pos;
+use crate::uifw::widget::{Focusable, Task, View, Widget};
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Message {
+    Next,
+    Prev,
+    /// Copy the preset into the selected voice slot
+    Load,
+}
+
+/// Browses the factory presets
+pub struct PresetBrowser {
+    selected: Cycle,
+    has_focus: bool,
+}
+
+impl PresetBrowser {
+    pub fn new() -> Self {
+        Self {
+            selected: Cycle::new(0, PRESETS.len()),
+            has_focus: false,
+        }
+    }
+
+    pub fn preset(&self) -> Preset {
+        PRESETS[*self.selected]
+    }
+}
+
+impl Widget<Message, AppTask, PresetBrowserView> for PresetBrowser {
+    fn update(&mut self, msg: Message) -> Vec<Task<AppTask>> {
+        match msg {
+            Message::Next => self.selected += 1,
+            Message::Prev => self.selected -= 1,
+            Message::Load => {} // Handled by the app, it owns the voice slots
+        }
+        vec![]
+    }
+
+    fn view(&self, pos: Pos) -> PresetBrowserView {
+        PresetBrowserView {
+            pos,
+            text: format!("{:<8}", self.preset().name),
+            has_focus: self.has_focus,
+        }
+    }
+}
+
+impl Focusable for PresetBrowser {
+    fn has_focus(&self) -> bool {
+        self.has_focus
+    }
+    fn focus(&mut self) {
+        self.has_focus = true
+    }
+    fn defocus(&mut self) {
+        self.has_focus = false
+    }
+    fn next_focus(&mut self) {
+        self.has_focus = !self.has_focus;
+    }
+    fn prev_focus(&mut self) {
+        self.has_focus = !self.has_focus;
+    }
+}
+
+pub struct PresetBrowserView {
+    pos: Pos,
+    text: String,
+    has_focus: bool,
+}
+
+impl View<Message> for PresetBrowserView {
+    fn on_event(&self, e: Event) -> Vec<Message> {
+        if !self.has_focus {
+            return vec![];
+        }
+
+        match e {
+            Event::Left => vec![Message::Prev],
+            Event::Right => vec![Message::Next],
+            Event::Activate => vec![Message::Load],
+            _ => vec![],
+        }
+    }
+
+    fn draw(&self, renderer: &mut dyn Renderer) {
+        if self.has_focus {
+            renderer.render_fmt_str(self.pos, &self.text, Style::Invert);
+        } else {
+            renderer.render_str(self.pos, &self.text);
+        }
+    }
+}
+
+pub type PresetBrowserRc = Rc<RefCell<PresetBrowser>>;
+pub fn preset_browser_rc() -> PresetBrowserRc {
+    Rc::new(RefCell::new(PresetBrowser::new()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::uifw::interaction::tests::TestRenderer;
+
+    #[test]
+    fn browse_test() {
+        let mut browser = PresetBrowser::new();
+        browser.update(Message::Prev);
+        assert_eq!(browser.preset(), PRESETS[PRESETS.len() - 1]);
+        browser.update(Message::Next);
+        browser.update(Message::Next);
+        assert_eq!(browser.preset(), PRESETS[1]);
+
+        let view = browser.view(Pos { r: 0, c: 0 });
+        assert_eq!(view.on_event(Event::Right), vec![]);
+        browser.focus();
+        let view = browser.view(Pos { r: 0, c: 0 });
+        assert_eq!(view.on_event(Event::Activate), vec![Message::Load]);
+
+        let mut renderer = TestRenderer::new();
+        view.draw(&mut renderer);
+        assert_eq!(renderer.out, "Sub bass");
+    }
+}
@@ -0,0 +1,240 @@
+// Copyright (C) 2025 Anders Sundman <anders@4zm.org>
+//
+// This file is part of RTRK - The Rust Tracker
+//
+// RTRK is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// RTRK is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with RTRK. If not, see <https://www.gnu.org/licenses/>.
+
+use std::cell::{RefCell, RefMut};
+use std::rc::Rc;
+
+use crate::app::{AppTask, SongRc};
+use crate::cycle::Cycle;
+use crate::song::{MAX_BPM, MAX_SPEED};
+use crate::uifw::interaction::{Event, Renderer, Style};
+use crate::uifw::pos::Pos;
+use crate::uifw::widget::{Focusable, Task, View, Widget};
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Message {
+    NextField,
+    PrevField,
+    Up,
+    Down,
+    EnterChar(char),
+    DelBack,
+}
+
+const FIELDS: usize = 4;
+const TITLE_WIDTH: usize = 12;
+const AUTHOR_WIDTH: usize = 8;
+/// Longest title or author, like the names of module formats
+const MAX_TEXT: usize = 0x20;
+
+/// Settings of the song that are not in the patterns: tempo, speed, title and author
+pub struct SongSettings {
+    song: SongRc,
+    field: Cycle,
+    has_focus: bool,
+}
+
+impl SongSettings {
+    pub fn new(song: SongRc) -> Self {
+        Self {
+            song,
+            field: Cycle::new(0, FIELDS),
+            has_focus: false,
+        }
+    }
+
+    /// Step the tempo or speed, they stay in the range the song file accepts
+    fn step(&mut self, up: bool) {
+        let mut song = self.song.borrow_mut();
+        let (value, max) = match *self.field {
+            0 => (&mut song.bpm, MAX_BPM),
+            1 => (&mut song.speed, MAX_SPEED),
+            _ => return,
+        };
+        *value = match up {
+            true => (*value + 1).min(max),
+            false => value.saturating_sub(1).max(1),
+        };
+    }
+
+    fn text(&mut self) -> Option<RefMut<'_, String>> {
+        let song = self.song.borrow_mut();
+        match *self.field {
+            2 => Some(RefMut::map(song, |s| &mut s.title)),
+            3 => Some(RefMut::map(song, |s| &mut s.author)),
+            _ => None,
+        }
+    }
+}
+
+impl Widget<Message, AppTask, SongSettingsView> for SongSettings {
+    fn update(&mut self, msg: Message) -> Vec<Task<AppTask>> {
+        match msg {
+            Message::NextField => self.field += 1,
+            Message::PrevField => self.field -= 1,
+            Message::Up => self.step(true),
+            Message::Down => self.step(false),
+            Message::EnterChar(c) => {
+                if let Some(mut text) = self.text() {
+                    if text.chars().count() < MAX_TEXT {
+                        text.push(c);
+                    }
+                }
+            }
+            Message::DelBack => {
+                if let Some(mut text) = self.text() {
+                    text.pop();
+                }
+            }
+        }
+        vec![]
+    }
+
+    fn view(&self, pos: Pos) -> SongSettingsView {
+        let song = self.song.borrow();
+        let selected = self.has_focus.then_some(*self.field);
+        // The end of a text that is edited is shown, that is where it is typed
+        let text = |text: &str, width: usize, field: usize| {
+            let skip = match selected == Some(field) {
+                true => text.chars().count().saturating_sub(width),
+                false => 0,
+            };
+            let shown: String = text.chars().skip(skip).take(width).collect();
+            format!("{:.<1$}", shown, width)
+        };
+        SongSettingsView {
+            pos,
+            fields: [
+                format!("{}bpm", song.bpm),
+                format!("{}tk", song.speed),
+                text(&song.title, TITLE_WIDTH, 2),
+                text(&song.author, AUTHOR_WIDTH, 3),
+            ],
+            selected,
+        }
+    }
+}
+
+impl Focusable for SongSettings {
+    fn has_focus(&self) -> bool {
+        self.has_focus
+    }
+    fn focus(&mut self) {
+        self.has_focus = true
+    }
+    fn defocus(&mut self) {
+        self.has_focus = false
+    }
+    fn next_focus(&mut self) {
+        self.has_focus = !self.has_focus;
+    }
+    fn prev_focus(&mut self) {
+        self.has_focus = !self.has_focus;
+    }
+}
+
+pub struct SongSettingsView {
+    pos: Pos,
+    fields: [String; FIELDS],
+    /// The field that is edited, if the settings have focus
+    selected: Option<usize>,
+}
+
+impl View<Message> for SongSettingsView {
+    fn on_event(&self, e: Event) -> Vec<Message> {
+        if self.selected.is_none() {
+            return vec![];
+        }
+
+        match e {
+            Event::Left => vec![Message::PrevField],
+            Event::Right => vec![Message::NextField],
+            Event::Up => vec![Message::Up],
+            Event::Down => vec![Message::Down],
+            Event::Char(c, _) => vec![Message::EnterChar(c)],
+            Event::DelBack => vec![Message::DelBack],
+            _ => vec![],
+        }
+    }
+
+    fn draw(&self, renderer: &mut dyn Renderer) {
+        let mut pos = self.pos;
+        for (i, field) in self.fields.iter().enumerate() {
+            match self.selected == Some(i) {
+                true => renderer.render_fmt_str(pos, field, Style::Invert),
+                false => renderer.render_str(pos, field),
+            }
+            pos = pos
+                + Pos {
+                    r: 0,
+                    c: field.chars().count() as u16 + 1,
+                };
+        }
+    }
+}
+
+pub type SongSettingsRc = Rc<RefCell<SongSettings>>;
+pub fn song_settings_rc(song: SongRc) -> SongSettingsRc {
+    Rc::new(RefCell::new(SongSettings::new(song)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::song::{Song, DEFAULT_BPM};
+    use crate::uifw::interaction::tests::TestRenderer;
+
+    #[test]
+    fn settings_test() {
+        let song = Rc::new(RefCell::new(Song::new()));
+        let mut settings = SongSettings::new(song.clone());
+
+        settings.update(Message::Up);
+        settings.update(Message::NextField);
+        (0..10).for_each(|_| {
+            settings.update(Message::Down);
+        });
+        assert_eq!(song.borrow().bpm, DEFAULT_BPM + 1);
+        // The speed doesn't go below one tick per row
+        assert_eq!(song.borrow().speed, 1);
+
+        // Text is only typed into the title and author
+        settings.update(Message::EnterChar('x'));
+        settings.update(Message::NextField);
+        "Sweet noise".chars().for_each(|c| {
+            settings.update(Message::EnterChar(c));
+        });
+        settings.update(Message::EnterChar('!'));
+        settings.update(Message::DelBack);
+        settings.update(Message::PrevField);
+        settings.update(Message::PrevField);
+        settings.update(Message::PrevField);
+        settings.update(Message::EnterChar('4'));
+        assert_eq!(song.borrow().title, "Sweet noise");
+        assert_eq!(song.borrow().author, "4");
+
+        let view = settings.view(Pos { r: 0, c: 0 });
+        assert_eq!(view.on_event(Event::Up), vec![]);
+        settings.focus();
+        let view = settings.view(Pos { r: 0, c: 0 });
+        assert_eq!(view.on_event(Event::DelBack), vec![Message::DelBack]);
+
+        let mut renderer = TestRenderer::new();
+        view.draw(&mut renderer);
+        assert_eq!(renderer.out, "126bpm1tkSweet noise.4.......");
+    }
+}
@@ -19,8 +19,8 @@ use std::cell::RefCell;
 use std::rc::Rc;
 
 use crate::impl_focusable_with_focuschain;
-use crate::synth;
 use crate::uifw::interaction::Event;
+use crate::{song, synth};
 
 use crate::app::AppTask;
 use crate::uifw::pos::Pos;
@@ -64,25 +64,28 @@ impl Voice {
         }
     }
 
-    pub fn get_voice(&self) -> Option<synth::Voice> {
-        let osc = self.osc_txt.borrow().text().parse::<usize>();
-        let osc = match osc {
-            Ok(1) => synth::Oscillator::Sine,
-            Ok(2) => synth::Oscillator::Triangle,
-            Ok(3) => synth::Oscillator::Saw,
-            Ok(4) => synth::Oscillator::Square,
-            Ok(5) => synth::Oscillator::Pulse,
-            _ => return None,
-        };
+    /// The voice as written in the designer, `1 AADDSSRR LLHHXX`
+    pub fn text(&self) -> String {
+        format!(
+            "{} {} {}",
+            self.osc_txt.borrow().text(),
+            self.env_txt.borrow().text(),
+            self.flt_txt.borrow().text()
+        )
+    }
 
-        // TODO Populate rest of voice data from text boxes
+    pub fn set_text(&mut self, text: &str) {
+        let field = |range: std::ops::Range<usize>| {
+            text.get(range.start.min(text.len())..range.end.min(text.len()))
+                .unwrap_or("")
+        };
+        self.osc_txt.borrow_mut().set_text(field(0..1));
+        self.env_txt.borrow_mut().set_text(field(2..10));
+        self.flt_txt.borrow_mut().set_text(field(11..17));
+    }
 
-        Some(synth::Voice {
-            osc,
-            env: None,
-            lp: None,
-            hp: None,
-        })
+    pub fn get_voice(&self) -> Option<synth::Voice> {
+        song::voice::parse(&self.text())
     }
 }
 
@@ -163,6 +166,55 @@ pub fn voice_rc() -> VoiceRc {
     Rc::new(RefCell::new(Voice::new()))
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::uifw::interaction::CharModifiers;
+
+    fn enter(voice: &mut Voice, osc: &str, flt: &str) {
+        osc.chars().for_each(|c| {
+            voice.update(Message::Osc(textbox::Message::EnterChar(
+                c,
+                CharModifiers::None,
+            )));
+        });
+        flt.chars().for_each(|c| {
+            voice.update(Message::Flt(textbox::Message::EnterChar(
+                c,
+                CharModifiers::None,
+            )));
+        });
+    }
+
+    #[test]
+    fn get_voice_test() {
+        let mut voice = Voice::new();
+        assert_eq!(voice.get_voice(), None);
+
+        enter(&mut voice, "4", "FF  A0");
+        let v = voice.get_voice().expect("");
+        assert_eq!(v.osc, synth::Oscillator::Square);
+        assert_eq!(v.lp.expect("").cutoff, 20000.0);
+        assert_eq!(v.hp, None);
+        assert_eq!(v.vel_amp, 10.0 / 15.0);
+        assert_eq!(v.vel_cutoff, 0.0);
+    }
+
+    #[test]
+    fn text_test() {
+        let mut voice = Voice::new();
+        enter(&mut voice, "4", "FF  A0");
+        assert_eq!(voice.text(), "4          FF  A0");
+
+        voice.set_text("2 01020304 10");
+        assert_eq!(voice.text(), "2 01020304 10    ");
+        assert_eq!(
+            voice.get_voice().expect("").osc,
+            synth::Oscillator::Triangle
+        );
+    }
+}
+
 pub mod list {
     use crate::app::AppTask;
     use std::cell::RefCell;
@@ -195,8 +247,17 @@ pub mod list {
     }
 
     impl VoiceList {
-        pub fn get_selected_voice(&self) -> Option<synth::Voice> {
-            self.voices[*self.selected_voice_idx].borrow().get_voice()
+        pub fn selected_index(&self) -> usize {
+            *self.selected_voice_idx
+        }
+        pub fn get_voice(&self, idx: usize) -> Option<synth::Voice> {
+            self.voices[idx].borrow().get_voice()
+        }
+        pub fn voice_text(&self, idx: usize) -> String {
+            self.voices[idx].borrow().text()
+        }
+        pub fn set_voice_text(&mut self, idx: usize, text: &str) {
+            self.voices[idx].borrow_mut().set_text(text);
         }
         pub fn new() -> Self {
             let list_window_len = 6;
@@ -273,7 +334,7 @@ pub mod list {
     impl VoiceListView {
         pub fn new(
             pos: Pos,
-            voices: &Vec<VoiceRc>,
+            voices: &[VoiceRc],
             first_voice_idx: Cycle,
             list_len: usize,
             has_focus: bool,
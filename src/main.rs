@@ -17,15 +17,283 @@
 
 mod app;
 mod cycle;
+mod song;
 mod synth;
 mod uifw;
 
-/// App -> Task -> Send [Synth Ctrl Channel] Recv -> Synth
-/// Synt defines the channel and messages
-/// App uses synt and translates task messages to synt messages
+use std::fs;
+use std::ops::RangeInclusive;
+
+use uifw::widget::Focusable;
+
+// App -> Task -> Send [Synth Ctrl Channel] Recv -> Synth
+// Synt defines the channel and messages
+// App uses synt and translates task messages to synt messages
+
+fn usage() -> ! {
+    eprintln!("Usage: rtrk [--undo-depth <edits>] [song.rtrk]");
+    eprintln!("       rtrk song.rtrk --export-voices <bank.rtv> [--slots <first>[-<last>]]");
+    eprintln!("       rtrk song.rtrk --import-voices <bank.rtv> [--offset <slot>]");
+    eprintln!("       rtrk song.rtrk --import-module <module.mod|xm|s3m>");
+    eprintln!("       rtrk song.rtrk --export-module <module.mod>");
+    eprintln!(
+        "       rtrk song.rtrk --export-wav <song.wav> [--bits 8|16|24|32] [--rate <hz>] \
+         [--loops <n>] [--fade <seconds>]"
+    );
+    eprintln!("       rtrk song.rtrk --export-stems <prefix> [--per-voice] [wav options]");
+    eprintln!("       rtrk song.rtrk --export-midi <song.mid>");
+    eprintln!("       rtrk song.rtrk --import-midi <song.mid> [--rows-per-beat <n>]");
+    std::process::exit(1);
+}
+
+/// Headless commands that work on a song file
+enum Command {
+    ExportVoices(String),
+    ImportVoices(String),
+    ImportModule(String),
+    ExportModule(String),
+    ExportWav(String),
+    /// Files named after the prefix, per track or per voice
+    ExportStems(String, song::wav::Stems),
+    ExportMidi(String),
+    /// Notes quantised to rows, with the number of rows per beat
+    ImportMidi(String, usize),
+}
+
+fn read_song(path: &str) -> Result<(song::Song, Vec<String>), String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+    song::file::read(&text).map_err(|e| format!("{}: {}", path, e))
+}
+
+/// Hex voice slot number
+fn parse_slot(text: &str) -> Option<usize> {
+    usize::from_str_radix(text, 16)
+        .ok()
+        .filter(|s| *s < song::MAX_VOICES)
+}
+
+/// Hex voice slot range, a single slot or `first-last`. The last slot can't be before the first.
+fn parse_slots(text: &str) -> Option<RangeInclusive<usize>> {
+    let (first, last) = text.split_once('-').unwrap_or((text, text));
+    let (first, last) = (parse_slot(first)?, parse_slot(last)?);
+    (first <= last).then_some(first..=last)
+}
+
+/// What was imported, followed by the conversion report if there is anything to report
+fn imported(from: &str, to: &str, report: &song::convert::Report) -> String {
+    let summary = format!("Imported {} into {}", from, to);
+    match report.is_empty() {
+        true => summary,
+        false => format!("{}\n{}", summary, report.to_string().trim_end()),
+    }
+}
+
+fn run(
+    command: Command,
+    song_path: &str,
+    slots: RangeInclusive<usize>,
+    offset: Option<usize>,
+    wav: song::wav::Options,
+) -> Result<String, String> {
+    match command {
+        Command::ExportVoices(bank_path) => {
+            let (_, voices) = read_song(song_path)?;
+            let text = song::bank::write(&voices, slots);
+            fs::write(&bank_path, &text).map_err(|e| format!("{}: {}", bank_path, e))?;
+            let count = text.lines().count() - 1;
+            Ok(format!("Exported {} voices to {}", count, bank_path))
+        }
+        Command::ImportVoices(bank_path) => {
+            let (song, mut voices) = read_song(song_path)?;
+            let text =
+                fs::read_to_string(&bank_path).map_err(|e| format!("{}: {}", bank_path, e))?;
+            let bank = song::bank::read(&text).map_err(|e| format!("{}: {}", bank_path, e))?;
+            let dropped = song::bank::merge(&mut voices, &bank, offset);
+            let text = song::file::write(&song, &voices);
+            fs::write(song_path, text).map_err(|e| format!("{}: {}", song_path, e))?;
+            Ok(format!(
+                "Imported {} voices into {}, {} did not fit",
+                bank.len() - dropped,
+                song_path,
+                dropped
+            ))
+        }
+        Command::ImportModule(module_path) => {
+            let data = fs::read(&module_path).map_err(|e| format!("{}: {}", module_path, e))?;
+            let import =
+                song::convert::read(&data).map_err(|e| format!("{}: {}", module_path, e))?;
+            let text = song::file::write(&import.song, &import.voices);
+            fs::write(song_path, text).map_err(|e| format!("{}: {}", song_path, e))?;
+            Ok(imported(&module_path, song_path, &import.report))
+        }
+        Command::ExportModule(module_path) => {
+            let (song, _) = read_song(song_path)?;
+            let data = song::protracker::write(&song).map_err(|errors| {
+                let lines: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+                format!("{}: can't be exported\n{}", song_path, lines.join("\n"))
+            })?;
+            fs::write(&module_path, data).map_err(|e| format!("{}: {}", module_path, e))?;
+            Ok(format!("Exported {} to {}", song_path, module_path))
+        }
+        Command::ExportWav(wav_path) => {
+            let (song, _) = read_song(song_path)?;
+            let data = song::wav::export(&song, &wav).map_err(|e| e.to_string())?;
+            fs::write(&wav_path, data).map_err(|e| format!("{}: {}", wav_path, e))?;
+            Ok(format!("Exported {} to {}", song_path, wav_path))
+        }
+        Command::ExportStems(prefix, split) => {
+            let (song, _) = read_song(song_path)?;
+            let stems = song::wav::export_stems(&song, split, &wav).map_err(|e| e.to_string())?;
+            let mut paths = vec![];
+            for (stem, data) in stems {
+                let path = stem.file_name(&prefix);
+                fs::write(&path, data).map_err(|e| format!("{}: {}", path, e))?;
+                paths.push(path);
+            }
+            Ok(format!("Exported {} to {}", song_path, paths.join(" ")))
+        }
+        Command::ExportMidi(midi_path) => {
+            let (song, _) = read_song(song_path)?;
+            fs::write(&midi_path, song::midi::write(&song))
+                .map_err(|e| format!("{}: {}", midi_path, e))?;
+            Ok(format!("Exported {} to {}", song_path, midi_path))
+        }
+        Command::ImportMidi(midi_path, rows_per_beat) => {
+            let data = fs::read(&midi_path).map_err(|e| format!("{}: {}", midi_path, e))?;
+            let import = song::midi::read(&data, rows_per_beat)
+                .map_err(|e| format!("{}: {}", midi_path, e))?;
+            let text = song::file::write(&import.song, &import.voices);
+            fs::write(song_path, text).map_err(|e| format!("{}: {}", song_path, e))?;
+            Ok(imported(&midi_path, song_path, &import.report))
+        }
+    }
+}
 
 fn main() {
     let mut app = app::App::new();
-    let mut task_processor = app::AppTaskProcessor::new();
+
+    let mut path = None;
+    let mut command = None;
+    let mut slots = 0..=song::MAX_VOICES - 1;
+    let mut offset = None;
+    let mut wav = song::wav::Options::default();
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--undo-depth" => {
+                let depth = args.next().and_then(|d| d.parse().ok());
+                app.set_undo_depth(depth.unwrap_or_else(|| usage()));
+            }
+            "--export-voices" => {
+                command = Some(Command::ExportVoices(
+                    args.next().unwrap_or_else(|| usage()),
+                ))
+            }
+            "--import-voices" => {
+                command = Some(Command::ImportVoices(
+                    args.next().unwrap_or_else(|| usage()),
+                ))
+            }
+            "--import-module" => {
+                command = Some(Command::ImportModule(
+                    args.next().unwrap_or_else(|| usage()),
+                ))
+            }
+            "--export-module" => {
+                command = Some(Command::ExportModule(
+                    args.next().unwrap_or_else(|| usage()),
+                ))
+            }
+            "--export-wav" => {
+                command = Some(Command::ExportWav(args.next().unwrap_or_else(|| usage())))
+            }
+            "--export-stems" => {
+                command = Some(Command::ExportStems(
+                    args.next().unwrap_or_else(|| usage()),
+                    song::wav::Stems::Tracks,
+                ))
+            }
+            "--per-voice" => match &mut command {
+                Some(Command::ExportStems(_, split)) => *split = song::wav::Stems::Voices,
+                _ => usage(),
+            },
+            "--export-midi" => {
+                command = Some(Command::ExportMidi(args.next().unwrap_or_else(|| usage())))
+            }
+            "--import-midi" => {
+                command = Some(Command::ImportMidi(
+                    args.next().unwrap_or_else(|| usage()),
+                    song::midi::DEFAULT_ROWS_PER_BEAT,
+                ))
+            }
+            "--rows-per-beat" => match &mut command {
+                Some(Command::ImportMidi(_, rows_per_beat)) => {
+                    let rows = args.next().and_then(|r| r.parse().ok());
+                    let rows = rows.filter(|r| (1..=song::midi::MAX_ROWS_PER_BEAT).contains(r));
+                    *rows_per_beat = rows.unwrap_or_else(|| usage());
+                }
+                _ => usage(),
+            },
+            "--bits" => {
+                wav.format = match args.next().as_deref() {
+                    Some("8") => song::wav::Format::Pcm8,
+                    Some("16") => song::wav::Format::Pcm16,
+                    Some("24") => song::wav::Format::Pcm24,
+                    Some("32") => song::wav::Format::Float32,
+                    _ => usage(),
+                }
+            }
+            "--rate" => {
+                let rate = args.next().and_then(|r| r.parse().ok()).filter(|r| *r > 0);
+                wav.sample_rate = rate.unwrap_or_else(|| usage());
+            }
+            "--loops" => {
+                let loops = args.next().and_then(|l| l.parse().ok()).filter(|l| *l > 0);
+                wav.loops = loops.unwrap_or_else(|| usage());
+            }
+            "--fade" => {
+                let seconds = args.next().and_then(|f| f.parse::<f32>().ok());
+                let max = song::wav::MAX_SECONDS as f32;
+                let seconds = seconds.filter(|s| (0.0..=max).contains(s));
+                let seconds = seconds.unwrap_or_else(|| usage());
+                wav.fade_ms = (seconds * 1000.0) as u32;
+            }
+            "--slots" => {
+                slots = args
+                    .next()
+                    .and_then(|s| parse_slots(&s))
+                    .unwrap_or_else(|| usage())
+            }
+            "--offset" => {
+                let slot = args.next().and_then(|s| parse_slot(&s));
+                offset = Some(slot.unwrap_or_else(|| usage()));
+            }
+            _ if !arg.starts_with('-') && path.is_none() => path = Some(arg),
+            _ => usage(),
+        }
+    }
+
+    if let Some(command) = command {
+        let path = path.unwrap_or_else(|| usage());
+        match run(command, &path, slots, offset, wav) {
+            Ok(report) => println!("{}", report),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    if let Some(path) = path {
+        if let Err(e) = app.open(std::path::Path::new(&path)) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+
+    // Keys go to the first widget from the start, as after a first Tab
+    app.focus();
+    let mut task_processor = app::AppTaskProcessor::new(app.song());
     uifw::start(&mut app, &mut task_processor);
 }
@@ -0,0 +1,177 @@
+// Copyright (C) 2025 Anders Sundman <anders@4zm.org>
+//
+// This file is part of RTRK - The Rust Tracker
+//
+// RTRK is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// RTRK is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with RTRK. If not, see <https://www.gnu.org/licenses/>.
+pub mod bank;
+pub mod block;
+pub mod convert;
+pub mod fasttracker;
+pub mod file;
+pub mod midi;
+pub mod order;
+pub mod pattern;
+pub mod player;
+pub mod preset;
+pub mod protracker;
+pub mod screamtracker;
+pub mod voice;
+pub mod wav;
+
+use crate::synth::{Oscillator, Sample, Voice};
+use order::OrderList;
+use pattern::Pattern;
+
+pub const MAX_PATTERNS: usize = 0x100;
+pub const MAX_VOICES: usize = 0x100;
+pub const DEFAULT_BPM: u32 = 125;
+pub const DEFAULT_SPEED: u32 = 6;
+pub const MAX_BPM: u32 = 999;
+pub const MAX_SPEED: u32 = 0xFF;
+
+/// The patterns of a song, the order they are played in and the voices they play
+#[derive(Clone, Debug, PartialEq)]
+pub struct Song {
+    pub title: String,
+    pub author: String,
+    pub patterns: Vec<Pattern>,
+    pub order: OrderList,
+    /// Indexed by the voice number of a cell. Slots without a valid voice are silent.
+    pub voices: Vec<Option<Voice>>,
+    /// The samples played by sample voices, indexed like the voices
+    pub samples: Vec<Option<Sample>>,
+    /// Initial tempo in beats (four rows at speed 6) per minute
+    pub bpm: u32,
+    /// Initial number of ticks per row
+    pub speed: u32,
+}
+
+impl Song {
+    pub fn new() -> Self {
+        Self {
+            title: String::new(),
+            author: String::new(),
+            patterns: vec![Pattern::default()],
+            order: OrderList::default(),
+            voices: vec![None; MAX_VOICES],
+            samples: vec![None; MAX_VOICES],
+            bpm: DEFAULT_BPM,
+            speed: DEFAULT_SPEED,
+        }
+    }
+
+    /// Set the voice of a slot. A sample voice plays the sample in the same slot, without one
+    /// the slot is silent.
+    pub fn set_voice(&mut self, slot: usize, voice: Option<Voice>) {
+        self.voices[slot] = match voice {
+            Some(
+                voice @ Voice {
+                    osc: Oscillator::Sample(_),
+                    ..
+                },
+            ) => self.samples[slot].as_ref().map(|sample| Voice {
+                osc: Oscillator::Sample(sample.clone()),
+                ..voice
+            }),
+            _ => voice,
+        };
+    }
+
+    /// The pattern played at a song position
+    pub fn pattern_at(&self, pos: usize) -> &Pattern {
+        &self.patterns[self.order.get(pos)]
+    }
+
+    /// Insert a new, empty, pattern at the song position. Returns the index of the new pattern
+    /// or None if there is no room for more patterns.
+    pub fn insert_pattern(&mut self, pos: usize) -> Option<usize> {
+        if self.patterns.len() == MAX_PATTERNS {
+            return None;
+        }
+        let template = self.pattern_at(pos.min(self.order.len() - 1));
+        let pattern = Pattern::new(template.len(), template.tracks());
+        self.patterns.push(pattern);
+        self.order.insert(pos, self.patterns.len() - 1);
+        Some(self.patterns.len() - 1)
+    }
+
+    /// Insert a copy of the pattern at the song position after it. Unlike duplicating the order
+    /// entry, the copy can be edited independently.
+    pub fn clone_pattern(&mut self, pos: usize) -> Option<usize> {
+        if self.patterns.len() == MAX_PATTERNS {
+            return None;
+        }
+        self.patterns.push(self.pattern_at(pos).clone());
+        self.order.insert(pos + 1, self.patterns.len() - 1);
+        Some(self.patterns.len() - 1)
+    }
+}
+
+impl Default for Song {
+    fn default() -> Self {
+        Song::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pattern::Note;
+    use std::sync::Arc;
+
+    #[test]
+    fn song_test() {
+        let mut song = Song::new();
+        song.patterns[0].cell_mut(0, 0).note = Some(Note::Off);
+
+        assert_eq!(song.insert_pattern(1), Some(1));
+        assert_eq!(song.order.entries(), &[0, 1]);
+        assert!(song.pattern_at(1).cell(0, 0).is_empty());
+
+        assert_eq!(song.clone_pattern(0), Some(2));
+        assert_eq!(song.order.entries(), &[0, 2, 1]);
+        song.patterns[2].cell_mut(0, 0).note = None;
+        assert_eq!(song.pattern_at(0).cell(0, 0).note, Some(Note::Off));
+
+        song.patterns.resize(MAX_PATTERNS, Pattern::default());
+        assert_eq!(song.insert_pattern(0), None);
+        assert_eq!(song.clone_pattern(0), None);
+    }
+
+    #[test]
+    fn set_voice_test() {
+        let mut song = Song::new();
+        let voice = voice::parse("6").unwrap();
+        song.set_voice(1, Some(voice.clone()));
+        assert_eq!(song.voices[1], None);
+
+        // The sample voice plays the sample of its slot
+        let sample = Sample {
+            data: Arc::from([0.5]),
+            ..Sample::default()
+        };
+        song.samples[1] = Some(sample.clone());
+        song.set_voice(1, Some(voice.clone()));
+        assert_eq!(
+            song.voices[1].as_ref().unwrap().osc,
+            Oscillator::Sample(sample)
+        );
+        song.set_voice(2, Some(voice));
+        assert_eq!(song.voices[2], None);
+
+        let voice = voice::parse("4").unwrap();
+        song.set_voice(1, Some(voice.clone()));
+        assert_eq!(song.voices[1], Some(voice));
+    }
+}
@@ -0,0 +1,159 @@
+// Copyright (C) 2025 Anders Sundman <anders@4zm.org>
+//
+// This file is part of RTRK - The Rust Tracker
+//
+// RTRK is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// RTRK is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with RTRK. If not, see <https://www.gnu.org/licenses/>.
+
+/// Voice banks, for sharing voices between songs. A bank is written like the voices of a song
+/// file, after its own header:
+///
+/// ```text
+/// rtrk-voices 1
+/// voice 10 4 -------- FF--A0
+/// voice 11 1 -------- 80----
+/// ```
+use std::ops::RangeInclusive;
+
+use crate::song::file::{self, ErrorKind, FileError};
+use crate::song::MAX_VOICES;
+
+const MAGIC: &str = "rtrk-voices";
+
+/// Write the voices in the slots. Empty slots are left out.
+pub fn write(voices: &[String], slots: RangeInclusive<usize>) -> String {
+    let mut text = format!("{} {}\n", MAGIC, file::VERSION);
+    for slot in slots {
+        let Some(voice) = voices.get(slot) else {
+            break;
+        };
+        if !voice.trim().is_empty() {
+            text += &format!("voice {:02X} {}\n", slot, file::format_voice(voice));
+        }
+    }
+    text
+}
+
+/// The slots and designer texts of the voices in a bank
+pub fn read(text: &str) -> Result<Vec<(usize, String)>, FileError> {
+    let mut lines = text
+        .lines()
+        .enumerate()
+        .map(|(i, l)| (i + 1, l.trim()))
+        .filter(|(_, l)| !l.is_empty() && !l.starts_with('#'));
+
+    let (line, header) = lines.next().unwrap_or((1, ""));
+    let err = |line, kind| FileError { line, kind };
+    match header.split_whitespace().collect::<Vec<_>>()[..] {
+        [MAGIC, v] => match v.parse() {
+            Ok(file::VERSION) => {}
+            Ok(v) => return Err(err(line, ErrorKind::Version(v))),
+            Err(_) => return Err(err(line, ErrorKind::Number)),
+        },
+        _ => return Err(err(line, ErrorKind::Header)),
+    }
+
+    lines
+        .map(|(line, l)| {
+            let (keyword, rest) = l.split_once(' ').unwrap_or((l, ""));
+            if keyword != "voice" {
+                return Err(err(line, ErrorKind::Keyword(keyword.to_string())));
+            }
+            let (slot, voice) = rest.trim().split_once(' ').unwrap_or((rest, ""));
+            let slot = usize::from_str_radix(slot, 16)
+                .ok()
+                .filter(|s| *s < MAX_VOICES)
+                .ok_or(err(line, ErrorKind::Number))?;
+            let voice = file::parse_voice(voice).ok_or(err(line, ErrorKind::Voice))?;
+            Ok((slot, voice))
+        })
+        .collect()
+}
+
+/// Put the voices of a bank into the voice slots. With an offset, the voices are moved so the
+/// first one lands in that slot and the rest keep their distance to it. Returns the number of
+/// voices that ended up past the last slot and were dropped.
+pub fn merge(voices: &mut [String], bank: &[(usize, String)], offset: Option<usize>) -> usize {
+    let first = bank.iter().map(|(slot, _)| *slot).min().unwrap_or(0);
+    let mut dropped = 0;
+    for (slot, voice) in bank.iter() {
+        let slot = match offset {
+            Some(offset) => offset + slot - first,
+            None => *slot,
+        };
+        match voices.get_mut(slot) {
+            Some(v) => *v = voice.clone(),
+            None => dropped += 1,
+        }
+    }
+    dropped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn voices() -> Vec<String> {
+        let mut voices = vec![String::new(); MAX_VOICES];
+        voices[0x10] = "4          FF  A0".to_string();
+        voices[0x12] = "1          80    ".to_string();
+        voices[0x20] = "2                ".to_string();
+        voices
+    }
+
+    #[test]
+    fn write_read_test() {
+        let text = write(&voices(), 0x10..=0x1F);
+        assert_eq!(
+            text,
+            "rtrk-voices 1\nvoice 10 4 -------- FF--A0\nvoice 12 1 -------- 80----\n"
+        );
+        let bank = read(&text).unwrap();
+        assert_eq!(bank.len(), 2);
+        assert_eq!(bank[1], (0x12, voices()[0x12].clone()));
+
+        assert_eq!(write(&voices(), 0x20..=0x100).lines().count(), 2);
+        assert_eq!(read("rtrk-voices 1").unwrap(), vec![]);
+    }
+
+    #[test]
+    fn error_test() {
+        let error = |text: &str| read(text).unwrap_err();
+        assert_eq!(error("rtrk 1").kind, ErrorKind::Header);
+        assert_eq!(
+            error("rtrk-voices 1\n\nvoice 100 4 -------- ------").to_string(),
+            "Line 3: Invalid number"
+        );
+        assert_eq!(error("rtrk-voices 1\nvoice 01 4").kind, ErrorKind::Voice);
+        assert_eq!(error("rtrk-voices 1\npattern 00 01").line, 2);
+    }
+
+    #[test]
+    fn merge_test() {
+        let bank = read(&write(&voices(), 0..=0xFF)).unwrap();
+
+        let mut slots = vec![String::new(); MAX_VOICES];
+        assert_eq!(merge(&mut slots, &bank, None), 0);
+        assert_eq!(slots, voices());
+
+        // The distance between the voices is kept
+        let mut slots = vec![String::new(); MAX_VOICES];
+        assert_eq!(merge(&mut slots, &bank, Some(0)), 0);
+        assert_eq!(slots[0x02], voices()[0x12]);
+        assert_eq!(slots[0x10], voices()[0x20]);
+
+        assert_eq!(merge(&mut slots, &bank, Some(0xF0)), 1);
+        assert_eq!(slots[0xF0], voices()[0x10]);
+        assert_eq!(slots[0xF2], voices()[0x12]);
+    }
+}
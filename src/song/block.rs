@@ -0,0 +1,454 @@
+// Copyright (C) 2025 Anders Sundman <anders@4zm.org>
+//
+// This file is part of RTRK - The Rust Tracker
+//
+// RTRK is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// RTRK is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with RTRK. If not, see <https://www.gnu.org/licenses/>.
+
+/// Rectangular blocks of pattern fields. Fields are addressed by a column that counts the note,
+/// voice, volume and effect of each track in turn, followed by the global effect.
+use crate::song::pattern::{Effect, Note, Pattern};
+
+/// Number of block columns per track
+pub const FIELDS: usize = 4;
+
+/// A single field of a cell, or the global effect of a row
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Field {
+    Note(Option<Note>),
+    Voice(Option<u8>),
+    Volume(Option<u8>),
+    Effect(Option<Effect>),
+    Gfx(Option<Effect>),
+}
+
+impl Field {
+    pub fn is_empty(&self) -> bool {
+        match self {
+            Field::Note(n) => n.is_none(),
+            Field::Voice(v) | Field::Volume(v) => v.is_none(),
+            Field::Effect(e) | Field::Gfx(e) => e.is_none(),
+        }
+    }
+
+    /// The same kind of field without a value
+    fn cleared(&self) -> Field {
+        match self {
+            Field::Note(_) => Field::Note(None),
+            Field::Voice(_) => Field::Voice(None),
+            Field::Volume(_) => Field::Volume(None),
+            Field::Effect(_) => Field::Effect(None),
+            Field::Gfx(_) => Field::Gfx(None),
+        }
+    }
+}
+
+/// Inclusive range of rows and block columns
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Area {
+    pub first_row: usize,
+    pub last_row: usize,
+    pub first_col: usize,
+    pub last_col: usize,
+}
+
+impl Area {
+    /// The area spanned by two corners, in any order
+    pub fn spanning(a: (usize, usize), b: (usize, usize)) -> Self {
+        Self {
+            first_row: a.0.min(b.0),
+            last_row: a.0.max(b.0),
+            first_col: a.1.min(b.1),
+            last_col: a.1.max(b.1),
+        }
+    }
+
+    pub fn contains(&self, row: usize, col: usize) -> bool {
+        (self.first_row..=self.last_row).contains(&row)
+            && (self.first_col..=self.last_col).contains(&col)
+    }
+}
+
+/// Read the field at a block column. Returns None past the global effect.
+pub fn field(pattern: &Pattern, row: usize, col: usize) -> Option<Field> {
+    let (track, kind) = (col / FIELDS, col % FIELDS);
+    if track == pattern.tracks() && kind == 0 {
+        return Some(Field::Gfx(pattern.row(row).gfx));
+    }
+    if track >= pattern.tracks() {
+        return None;
+    }
+    let cell = pattern.cell(row, track);
+    Some(match kind {
+        0 => Field::Note(cell.note),
+        1 => Field::Voice(cell.voice),
+        2 => Field::Volume(cell.volume),
+        _ => Field::Effect(cell.effect),
+    })
+}
+
+/// Write a field at a block column. Fields of the wrong kind for the column are ignored.
+pub fn set_field(pattern: &mut Pattern, row: usize, col: usize, field: Field) {
+    let (track, kind) = (col / FIELDS, col % FIELDS);
+    if track == pattern.tracks() {
+        if let (0, Field::Gfx(e)) = (kind, field) {
+            pattern.row_mut(row).gfx = e;
+        }
+        return;
+    }
+    if track > pattern.tracks() {
+        return;
+    }
+    let cell = pattern.cell_mut(row, track);
+    match (kind, field) {
+        (0, Field::Note(n)) => cell.note = n,
+        (1, Field::Voice(v)) => cell.voice = v,
+        (2, Field::Volume(v)) => cell.volume = v,
+        (3, Field::Effect(e)) => cell.effect = e,
+        _ => {}
+    }
+}
+
+/// Clear all fields in the area
+pub fn clear(pattern: &mut Pattern, area: Area) {
+    for row in area.first_row..=area.last_row.min(pattern.len() - 1) {
+        for col in area.first_col..=area.last_col {
+            if let Some(f) = field(pattern, row, col) {
+                set_field(pattern, row, col, f.cleared());
+            }
+        }
+    }
+}
+
+/// Move the notes in the area by a number of semitones. With a voice, only notes that are
+/// written with that voice are moved.
+pub fn transpose(pattern: &mut Pattern, area: Area, semitones: i32, voice: Option<u8>) {
+    for row in area.first_row..=area.last_row.min(pattern.len() - 1) {
+        for track in area.first_col.div_ceil(FIELDS)..=area.last_col / FIELDS {
+            if track >= pattern.tracks() {
+                break;
+            }
+            let cell = pattern.cell_mut(row, track);
+            if voice.is_some() && cell.voice != voice {
+                continue;
+            }
+            if let Some(Note::Key(k)) = cell.note {
+                let key = (k as i32 + semitones).clamp(0, Note::MAX_KEY as i32);
+                cell.note = Some(Note::Key(key as u8));
+            }
+        }
+    }
+}
+
+/// Shape of an interpolation from the first to the last row
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Curve {
+    Linear,
+    /// Starts slow and speeds up towards the last row
+    Quadratic,
+}
+
+impl Curve {
+    fn at(&self, t: f32) -> f32 {
+        match self {
+            Curve::Linear => t,
+            Curve::Quadratic => t * t,
+        }
+    }
+}
+
+/// Fill the rows between the first and last row of the area with values interpolated between
+/// theirs. Volumes and effect parameters are interpolated, the effects get the code of the
+/// first row. Columns where the first or last row is empty are left alone.
+pub fn interpolate(pattern: &mut Pattern, area: Area, curve: Curve) {
+    let last_row = area.last_row.min(pattern.len() - 1);
+    if last_row <= area.first_row {
+        return;
+    }
+    let value = |a: u8, b: u8, row: usize| {
+        let t = (row - area.first_row) as f32 / (last_row - area.first_row) as f32;
+        (a as f32 + (b as f32 - a as f32) * curve.at(t)).round() as u8
+    };
+
+    for col in area.first_col..=area.last_col {
+        let first = field(pattern, area.first_row, col);
+        let last = field(pattern, last_row, col);
+        for row in area.first_row + 1..last_row {
+            let f = match (first, last) {
+                (Some(Field::Volume(Some(a))), Some(Field::Volume(Some(b)))) => {
+                    Field::Volume(Some(value(a, b, row)))
+                }
+                (Some(Field::Effect(Some(a))), Some(Field::Effect(Some(b)))) => {
+                    Field::Effect(Some(Effect {
+                        code: a.code,
+                        param: value(a.param, b.param, row),
+                    }))
+                }
+                (Some(Field::Gfx(Some(a))), Some(Field::Gfx(Some(b)))) => {
+                    Field::Gfx(Some(Effect {
+                        code: a.code,
+                        param: value(a.param, b.param, row),
+                    }))
+                }
+                _ => break,
+            };
+            set_field(pattern, row, col, f);
+        }
+    }
+}
+
+/// Xorshift random numbers, good enough to make a pattern sound less mechanical
+#[derive(Copy, Clone, Debug)]
+pub struct Rng(u32);
+
+impl Rng {
+    pub fn new(seed: u32) -> Self {
+        Self(seed.max(1))
+    }
+
+    pub fn next(&mut self) -> u32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        self.0
+    }
+}
+
+/// Move the volumes in the area randomly up or down by at most `range`. Notes without a volume
+/// are played at full volume and get one.
+pub fn humanise(pattern: &mut Pattern, area: Area, range: u8, rng: &mut Rng) {
+    for row in area.first_row..=area.last_row.min(pattern.len() - 1) {
+        for col in area.first_col..=area.last_col {
+            if col % FIELDS != 2 || col / FIELDS >= pattern.tracks() {
+                continue;
+            }
+            let cell = pattern.cell_mut(row, col / FIELDS);
+            let volume = match (cell.volume, cell.note) {
+                (Some(v), _) => v,
+                (None, Some(Note::Key(_))) => 0xFF,
+                _ => continue,
+            };
+            let offset = (rng.next() % (2 * range as u32 + 1)) as i32 - range as i32;
+            cell.volume = Some((volume as i32 + offset).clamp(0, 0xFF) as u8);
+        }
+    }
+}
+
+/// A copy of the fields of an area. The fields keep their kind, so a block is always pasted
+/// with notes in note columns and global effects in the global effect column.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Block {
+    /// Kind of the first column, the offset into the first track
+    offset: usize,
+    rows: Vec<Vec<Field>>,
+}
+
+impl Block {
+    pub fn copy(pattern: &Pattern, area: Area) -> Self {
+        let rows = (area.first_row..=area.last_row.min(pattern.len() - 1))
+            .map(|row| {
+                (area.first_col..=area.last_col)
+                    .filter_map(|col| field(pattern, row, col))
+                    .collect()
+            })
+            .collect();
+        Self {
+            offset: area.first_col % FIELDS,
+            rows,
+        }
+    }
+
+    pub fn rows(&self) -> usize {
+        self.rows.len()
+    }
+
+    /// Paste with the first row at `row` and the first track at `track`. Fields that fall
+    /// outside the pattern are dropped. A mix paste only fills fields that are empty.
+    pub fn paste(&self, pattern: &mut Pattern, row: usize, track: usize, mix: bool) {
+        let gfx_col = pattern.tracks() * FIELDS;
+        for (r, fields) in self.rows.iter().enumerate() {
+            let row = row + r;
+            if row >= pattern.len() {
+                break;
+            }
+            for (i, f) in fields.iter().enumerate() {
+                let col = match f {
+                    Field::Gfx(_) => gfx_col,
+                    _ => track * FIELDS + self.offset + i,
+                };
+                if col >= gfx_col && !matches!(f, Field::Gfx(_)) {
+                    continue;
+                }
+                if mix && !field(pattern, row, col).is_some_and(|f| f.is_empty()) {
+                    continue;
+                }
+                set_field(pattern, row, col, *f);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pattern() -> Pattern {
+        Pattern::parse_rows(
+            "C-4 01 40 101 : D-4 02 -- --- : 0FF\n\
+             E-4 01 -- --- : --- -- 20 A01 : ---\n\
+             G-4 01 -- 302 : A-4 03 -- --- : 140\n",
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn copy_paste_test() {
+        let mut pattern = pattern();
+
+        // From the volume of track 0 to the volume of track 1. Pasted into track 1, the fields
+        // of track 1 fall outside the pattern.
+        let area = Area::spanning((1, 6), (0, 2));
+        let block = Block::copy(&pattern, area);
+        assert_eq!(block.rows(), 2);
+        block.paste(&mut pattern, 1, 1, false);
+        assert_eq!(
+            pattern.row(1).to_string(),
+            "E-4 01 -- --- : --- -- 40 101 : ---"
+        );
+        assert_eq!(
+            pattern.row(2).to_string(),
+            "G-4 01 -- 302 : A-4 03 -- --- : 140"
+        );
+
+        // The global effect is always pasted into its own column
+        let block = Block::copy(&pattern, Area::spanning((0, 4), (0, 8)));
+        block.paste(&mut pattern, 1, 1, false);
+        assert_eq!(
+            pattern.row(1).to_string(),
+            "E-4 01 -- --- : D-4 02 -- --- : 0FF"
+        );
+        block.paste(&mut pattern, 2, 2, false);
+        assert_eq!(
+            pattern.row(2).to_string(),
+            "G-4 01 -- 302 : A-4 03 -- --- : 0FF"
+        );
+    }
+
+    #[test]
+    fn mix_paste_test() {
+        let mut pattern = pattern();
+        let block = Block::copy(&pattern, Area::spanning((0, 0), (0, 8)));
+        block.paste(&mut pattern, 1, 0, true);
+        assert_eq!(
+            pattern.row(1).to_string(),
+            "E-4 01 40 101 : D-4 02 20 A01 : 0FF"
+        );
+
+        // Rows past the end are dropped
+        block.paste(&mut pattern, 2, 0, true);
+        assert_eq!(pattern.len(), 3);
+        assert_eq!(
+            pattern.row(2).to_string(),
+            "G-4 01 40 302 : A-4 03 -- --- : 140"
+        );
+    }
+
+    #[test]
+    fn transpose_test() {
+        let mut pattern = pattern();
+
+        // Only note columns in the area are moved
+        transpose(&mut pattern, Area::spanning((0, 0), (1, 3)), 12, None);
+        assert_eq!(pattern.cell(0, 0).note, Some(Note::Key(5 * 12)));
+        assert_eq!(pattern.cell(1, 0).note, Some(Note::Key(5 * 12 + 4)));
+        assert_eq!(pattern.cell(0, 1).note, Some(Note::Key(4 * 12 + 2)));
+
+        transpose(&mut pattern, Area::spanning((0, 0), (2, 7)), -1, Some(3));
+        assert_eq!(pattern.cell(2, 0).note, Some(Note::Key(4 * 12 + 7)));
+        assert_eq!(pattern.cell(2, 1).note, Some(Note::Key(4 * 12 + 8)));
+
+        transpose(&mut pattern, Area::spanning((0, 0), (0, 0)), 0x100, None);
+        assert_eq!(pattern.cell(0, 0).note, Some(Note::Key(Note::MAX_KEY)));
+    }
+
+    #[test]
+    fn interpolate_test() {
+        let mut pattern = Pattern::parse_rows(
+            &[
+                "--- -- 00 410 : 000",
+                &"--- -- -- --- : ---\n".repeat(3),
+                "--- -- 80 3F0 : 480",
+            ]
+            .join("\n"),
+        )
+        .unwrap();
+        let area = Area::spanning((0, 0), (4, 4));
+
+        interpolate(&mut pattern, area, Curve::Linear);
+        assert_eq!(pattern.row(2).to_string(), "--- -- 40 480 : 040");
+        interpolate(&mut pattern, area, Curve::Quadratic);
+        assert_eq!(pattern.row(1).to_string(), "--- -- 08 41E : 008");
+
+        // Nothing to interpolate from
+        let mut pattern = Pattern::new(4, 1);
+        interpolate(&mut pattern, Area::spanning((0, 0), (3, 4)), Curve::Linear);
+        assert_eq!(pattern, Pattern::new(4, 1));
+    }
+
+    #[test]
+    fn humanise_test() {
+        let mut pattern = pattern();
+        let mut rng = Rng::new(1);
+        let area = Area::spanning((0, 0), (2, 8));
+        (0..0x10).for_each(|_| {
+            let mut p = pattern.clone();
+            humanise(&mut p, area, 0x10, &mut rng);
+            let volume = |row, track| p.cell(row, track).volume.unwrap();
+            assert!((0x30..=0x50).contains(&volume(0, 0)));
+            assert!((0xEF..=0xFF).contains(&volume(1, 0)));
+            assert!((0x10..=0x30).contains(&volume(1, 1)));
+        });
+
+        // Cells without a note or volume are left alone
+        humanise(&mut pattern, area, 0x10, &mut rng);
+        assert_eq!(pattern.cell(1, 1).note, None);
+        assert!(pattern.cell(0, 1).volume.is_some());
+        assert_eq!(
+            pattern.row(0).gfx,
+            Some(Effect {
+                code: 0,
+                param: 0xFF
+            })
+        );
+    }
+
+    #[test]
+    fn clear_test() {
+        let mut pattern = pattern();
+        clear(&mut pattern, Area::spanning((0, 3), (2, 5)));
+        assert_eq!(
+            pattern.row(0).to_string(),
+            "C-4 01 40 --- : --- -- -- --- : 0FF"
+        );
+        assert_eq!(
+            pattern.row(1).to_string(),
+            "E-4 01 -- --- : --- -- 20 A01 : ---"
+        );
+        assert_eq!(
+            pattern.row(2).to_string(),
+            "G-4 01 -- --- : --- -- -- --- : 140"
+        );
+        assert!(Area::spanning((0, 3), (2, 5)).contains(2, 4));
+        assert!(!Area::spanning((0, 3), (2, 5)).contains(2, 6));
+    }
+}
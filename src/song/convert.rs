@@ -0,0 +1,252 @@
+// Copyright (C) 2025 Anders Sundman <anders@4zm.org>
+//
+// This file is part of RTRK - The Rust Tracker
+//
+// RTRK is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// RTRK is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with RTRK. If not, see <https://www.gnu.org/licenses/>.
+
+/// Conversion of modules from other trackers. What can't be represented in an rtrk song is
+/// listed in a report instead of failing the import.
+use std::fmt;
+
+use crate::song::pattern::Effect;
+use crate::song::{fasttracker, file, protracker, screamtracker, voice, Song};
+use crate::synth;
+
+/// Synth voice that stands in for an instrument: a square wave where the volume sets the
+/// amplitude
+pub const PLACEHOLDER_VOICE: &str = "4              F0";
+
+/// Voice that plays the sample of its slot, the volume sets the amplitude
+pub const SAMPLE_VOICE: &str = "6              F0";
+
+/// The sample voice with an envelope
+pub fn sample_voice(env: &synth::Envelope) -> String {
+    format!(
+        "{}{}{}",
+        &SAMPLE_VOICE[..2],
+        voice::format_envelope(env),
+        &SAMPLE_VOICE[10..]
+    )
+}
+
+/// The sample of a sample voice. The rate is rounded to whole Hz, as in the song file, and
+/// the repeated part is cut to the data like players do.
+pub fn sample(data: Vec<f32>, rate: f32, repeat: Option<(usize, usize)>) -> synth::Sample {
+    let len = data.len();
+    synth::Sample {
+        data: data.into(),
+        rate: rate.round().clamp(1.0, file::MAX_SAMPLE_RATE as f32),
+        repeat: repeat
+            .filter(|(start, repeat)| *start < len && *repeat > 0)
+            .map(|(start, repeat)| (start, repeat.min(len - start))),
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Error {
+    /// Not a module format that can be imported
+    Format,
+    /// The module ended before all of its patterns or instruments
+    Truncated,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Format => write!(f, "Unknown module format"),
+            Error::Truncated => write!(f, "The module is truncated"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// A cell of the converted song
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Location {
+    pub pattern: usize,
+    pub row: usize,
+    pub track: usize,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Entry {
+    what: String,
+    cells: usize,
+    first: Option<Location>,
+}
+
+/// What the conversion could not represent. Problems in cells are counted per kind, with the
+/// first cell they were found in.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Report {
+    entries: Vec<Entry>,
+}
+
+impl Report {
+    pub fn warn(&mut self, what: String) {
+        self.entries.push(Entry {
+            what,
+            cells: 0,
+            first: None,
+        });
+    }
+
+    pub fn warn_at(&mut self, what: String, at: Location) {
+        match self.entries.iter_mut().find(|e| e.what == what) {
+            Some(entry) => entry.cells += 1,
+            None => self.entries.push(Entry {
+                what,
+                cells: 1,
+                first: Some(at),
+            }),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+/// One line per problem
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for entry in self.entries.iter() {
+            write!(f, "{}", entry.what)?;
+            if let Some(at) = entry.first {
+                let plural = if entry.cells == 1 { "" } else { "s" };
+                write!(
+                    f,
+                    ": {} cell{}, first at pattern {:02X} row {:02X} track {}",
+                    entry.cells, plural, at.pattern, at.row, at.track
+                )?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+/// A converted song with the designer text of all voice slots, like `file::read`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Import {
+    pub song: Song,
+    pub voices: Vec<String>,
+    pub report: Report,
+}
+
+/// Convert a module, the format is found from its contents
+pub fn read(data: &[u8]) -> Result<Import, Error> {
+    if fasttracker::matches(data) {
+        fasttracker::read(data)
+    } else if screamtracker::matches(data) {
+        screamtracker::read(data)
+    } else {
+        protracker::read(data)
+    }
+}
+
+/// Put a global effect in the gFx column of a row. There is only room for one per row.
+pub fn set_global_effect(
+    gfx: &mut Option<Effect>,
+    effect: Effect,
+    at: Location,
+    report: &mut Report,
+) {
+    match gfx {
+        Some(_) => report.warn_at(
+            "Global effects after the first on a row dropped".to_string(),
+            at,
+        ),
+        None => *gfx = Some(effect),
+    }
+}
+
+/// Little endian 16 bit number at the offset
+pub fn le16(data: &[u8], at: usize) -> Result<usize, Error> {
+    let bytes = data.get(at..at + 2).ok_or(Error::Truncated)?;
+    Ok(u16::from_le_bytes([bytes[0], bytes[1]]) as usize)
+}
+
+/// Little endian 32 bit number at the offset
+pub fn le32(data: &[u8], at: usize) -> Result<usize, Error> {
+    let bytes = data.get(at..at + 4).ok_or(Error::Truncated)?;
+    Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize)
+}
+
+/// A fixed length name from a module, without padding and unprintable characters
+pub fn name(bytes: &[u8]) -> String {
+    let text: String = bytes
+        .iter()
+        .take_while(|b| **b != 0)
+        .map(|b| match b.is_ascii_graphic() {
+            true => *b as char,
+            false => ' ',
+        })
+        .collect();
+    text.trim().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn report_test() {
+        let at = |row| Location {
+            pattern: 1,
+            row,
+            track: 2,
+        };
+        let mut report = Report::default();
+        assert!(report.is_empty());
+        report.warn("Sample 01 'kick' replaced".to_string());
+        report.warn_at("Tremolo (7xy) dropped".to_string(), at(4));
+        report.warn_at("Tremolo (7xy) dropped".to_string(), at(8));
+        report.warn_at("Set panning (8xx) dropped".to_string(), at(9));
+        assert_eq!(
+            report.to_string(),
+            "Sample 01 'kick' replaced\n\
+             Tremolo (7xy) dropped: 2 cells, first at pattern 01 row 04 track 2\n\
+             Set panning (8xx) dropped: 1 cell, first at pattern 01 row 09 track 2\n"
+        );
+    }
+
+    #[test]
+    fn name_test() {
+        assert_eq!(name(b"kick\0\0\0"), "kick");
+        assert_eq!(name(b" lead \x01\0x"), "lead");
+        assert_eq!(name(b""), "");
+    }
+
+    #[test]
+    fn format_test() {
+        assert_eq!(read(b"rtrk 1").unwrap_err(), Error::Format);
+        assert_eq!(le16(&[0x34, 0x12], 0), Ok(0x1234));
+        assert_eq!(le32(&[0, 0x34, 0x12, 0, 0], 1), Ok(0x1234));
+        assert_eq!(le32(&[0, 0x34, 0x12, 0], 1), Err(Error::Truncated));
+
+        let env = synth::Envelope {
+            attack_ms: 20.0,
+            decay_ms: 0.0,
+            sustain_lvl: 1.0,
+            release_ms: 100.0,
+        };
+        assert_eq!(sample_voice(&env), "6 0200FF0A     F0");
+
+        let s = sample(vec![0.0; 8], 4181.5, Some((4, 8)));
+        assert_eq!((s.rate, s.repeat), (4182.0, Some((4, 4))));
+        assert_eq!(sample(vec![0.0; 8], 0.0, Some((8, 1))).repeat, None);
+    }
+}
@@ -0,0 +1,692 @@
+// Copyright (C) 2025 Anders Sundman <anders@4zm.org>
+//
+// This file is part of RTRK - The Rust Tracker
+//
+// RTRK is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// RTRK is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with RTRK. If not, see <https://www.gnu.org/licenses/>.
+
+/// FastTracker 2 extended modules. The header with the order list is followed by the patterns
+/// and then the instruments with their samples. Pattern cells are packed: a first byte with
+/// the high bit set tells which of note, instrument, volume, effect and parameter follow.
+/// All numbers are little endian.
+use crate::song::convert::{self, le16, le32, Error, Import, Location, Report};
+use crate::song::order::OrderList;
+use crate::song::pattern::{Cell, Effect, Note, Pattern, MAX_TRACKS};
+use crate::song::player::{GFX_SET_VOLUME, GFX_VOLUME_FADE};
+use crate::song::protracker::{self, Pitch, MAX_VOLUME};
+use crate::song::{voice, Song, MAX_PATTERNS, MAX_VOICES};
+use crate::synth;
+
+const MAGIC: &[u8] = b"Extended Module: ";
+/// The header size is counted from here
+const HEADER_OFFSET: usize = 60;
+const ORDER_LEN: usize = 256;
+const NOTE_OFF: u8 = 97;
+const PACKED: u8 = 0x80;
+const ENVELOPE_POINTS: usize = 12;
+
+// Instrument header offsets
+const INSTRUMENT_SAMPLES: usize = 27;
+const SAMPLE_HEADER_SIZE: usize = 29;
+const VOLUME_ENVELOPE: usize = 129;
+const VOLUME_POINTS: usize = 225;
+const VOLUME_SUSTAIN: usize = 227;
+const VOLUME_TYPE: usize = 233;
+const PANNING_TYPE: usize = 234;
+
+// Envelope type flags
+const ENVELOPE_ON: u8 = 0x1;
+const ENVELOPE_SUSTAIN: u8 = 0x2;
+const ENVELOPE_LOOP: u8 = 0x4;
+
+// Sample type flags
+const SAMPLE_LOOP: u8 = 0x3;
+const PING_PONG_LOOP: u8 = 0x2;
+const SAMPLE_16_BIT: u8 = 0x10;
+
+/// Sample rate of C-4 when the sample is in tune
+const C4_RATE: f32 = 8363.0;
+
+// Effects after the ProTracker ones
+const SET_VOLUME: u8 = 0xC;
+const SET_GLOBAL_VOLUME: u8 = 0x10;
+const GLOBAL_VOLUME_SLIDE: u8 = 0x11;
+const KEY_OFF: u8 = 0x14;
+const MULTI_RETRIGGER: u8 = 0x1B;
+
+// rtrk effects the XM ones are translated to
+const VOLUME_SLIDE: u8 = 0xA;
+const TONE_PORTAMENTO: u8 = 0x3;
+const VIBRATO: u8 = 0x4;
+const EXTENDED: u8 = 0xE;
+const RETRIGGER: u8 = 0x9;
+const NOTE_CUT: u8 = 0xC;
+
+/// True if the data looks like an XM module
+pub fn matches(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+struct Instrument {
+    samples: usize,
+    /// Volume of the first sample
+    volume: u8,
+    /// Envelope points, ticks and level
+    envelope: Vec<(usize, usize)>,
+    sustain: Option<usize>,
+    envelope_loop: bool,
+    panning_envelope: bool,
+    /// The first sample, which the voice plays for all notes. None if it's empty.
+    sample: Option<synth::Sample>,
+    ping_pong: bool,
+}
+
+/// Sample data, which is stored as the difference to the previous value
+fn decode(data: &[u8], sixteen_bit: bool) -> Vec<f32> {
+    match sixteen_bit {
+        true => data
+            .chunks_exact(2)
+            .scan(0i16, |level, d| {
+                *level = level.wrapping_add(i16::from_le_bytes([d[0], d[1]]));
+                Some(*level as f32 / 32768.0)
+            })
+            .collect(),
+        false => data
+            .iter()
+            .scan(0i8, |level, d| {
+                *level = level.wrapping_add(*d as i8);
+                Some(*level as f32 / 128.0)
+            })
+            .collect(),
+    }
+}
+
+/// The sample of a sample header and the data after the headers, and if it has a ping-pong
+/// loop. Lengths are in bytes also for 16 bit samples.
+fn sample(header: &[u8], data: &[u8]) -> Result<(Option<synth::Sample>, bool), Error> {
+    let byte = |i: usize| header.get(i).copied().ok_or(Error::Truncated);
+    let len = le32(header, 0)?;
+    if len == 0 {
+        return Ok((None, false));
+    }
+    let kind = byte(14)?;
+    let width = if kind & SAMPLE_16_BIT != 0 { 2 } else { 1 };
+    let repeat = (le32(header, 4)? / width, le32(header, 8)? / width);
+    let looped = kind & SAMPLE_LOOP != 0;
+
+    let data = decode(data.get(..len).ok_or(Error::Truncated)?, width == 2);
+    let tune = byte(16)? as i8 as f32 + byte(13)? as i8 as f32 / 128.0;
+    let rate = C4_RATE * 2.0_f32.powf(tune / 12.0);
+    let sample = convert::sample(data, rate, looped.then_some(repeat));
+    Ok((Some(sample), looped && kind & SAMPLE_LOOP == PING_PONG_LOOP))
+}
+
+/// Parse the instrument at the offset. Returns the instrument and the offset of the next one.
+fn instrument(data: &[u8], at: usize) -> Result<(Instrument, usize), Error> {
+    let size = le32(data, at)?;
+    let header = data.get(at..at + size).ok_or(Error::Truncated)?;
+    let samples = le16(header, INSTRUMENT_SAMPLES)?;
+    let mut instrument = Instrument {
+        samples,
+        volume: MAX_VOLUME,
+        envelope: vec![],
+        sustain: None,
+        envelope_loop: false,
+        panning_envelope: false,
+        sample: None,
+        ping_pong: false,
+    };
+    if samples == 0 {
+        return Ok((instrument, at + size));
+    }
+
+    let sample_header_size = le32(header, SAMPLE_HEADER_SIZE)?;
+    let byte = |i: usize| header.get(i).copied().ok_or(Error::Truncated);
+    let volume_type = byte(VOLUME_TYPE)?;
+    if volume_type & ENVELOPE_ON != 0 {
+        let points = (byte(VOLUME_POINTS)? as usize).clamp(1, ENVELOPE_POINTS);
+        instrument.envelope = (0..points)
+            .map(|i| {
+                let point = VOLUME_ENVELOPE + i * 4;
+                Ok((le16(header, point)?, le16(header, point + 2)?))
+            })
+            .collect::<Result<_, _>>()?;
+        let sustain = byte(VOLUME_SUSTAIN)? as usize;
+        instrument.sustain =
+            (volume_type & ENVELOPE_SUSTAIN != 0 && sustain < points).then_some(sustain);
+        instrument.envelope_loop = volume_type & ENVELOPE_LOOP != 0;
+    }
+    instrument.panning_envelope = byte(PANNING_TYPE)? & ENVELOPE_ON != 0;
+
+    // The sample headers follow the instrument, then the sample data
+    let mut next = at + size;
+    let mut sample_data = 0;
+    let mut first = &[][..];
+    for i in 0..samples {
+        let header = data
+            .get(next..next + sample_header_size)
+            .ok_or(Error::Truncated)?;
+        let byte = |i: usize| header.get(i).copied().ok_or(Error::Truncated);
+        sample_data += le32(header, 0)?;
+        if i == 0 {
+            first = header;
+            instrument.volume = byte(12)?.min(MAX_VOLUME);
+        }
+        next += sample_header_size;
+    }
+    (instrument.sample, instrument.ping_pong) = sample(first, data.get(next..).unwrap_or(&[]))?;
+    Ok((instrument, next + sample_data))
+}
+
+/// ADSR approximation of an envelope. The attack goes to the highest point before the
+/// sustain point and the release follows the sustain point. Without a sustain point the last
+/// point is sustained.
+fn envelope(points: &[(usize, usize)], sustain: Option<usize>, tick_ms: f32) -> synth::Envelope {
+    let last = points.len() - 1;
+    let hold = sustain.unwrap_or(last);
+    let level = |i: usize| points[i].1;
+    let peak = (0..=hold).fold(0, |peak, i| if level(i) > level(peak) { i } else { peak });
+    let ms = |from: usize, to: usize| points[to].0.saturating_sub(points[from].0) as f32 * tick_ms;
+    synth::Envelope {
+        attack_ms: ms(0, peak),
+        decay_ms: ms(peak, hold),
+        sustain_lvl: level(hold).min(MAX_VOLUME as usize) as f32 / MAX_VOLUME as f32,
+        release_ms: ms(hold, last),
+    }
+}
+
+/// The fields of a pattern cell as stored
+#[derive(Copy, Clone, Default)]
+struct Fields {
+    note: u8,
+    instrument: u8,
+    volume: u8,
+    code: u8,
+    param: u8,
+}
+
+fn unpack(data: &[u8], pos: &mut usize) -> Result<Fields, Error> {
+    let mut next = || {
+        let byte = data.get(*pos).copied().ok_or(Error::Truncated);
+        *pos += 1;
+        byte
+    };
+    let first = next()?;
+    if first & PACKED == 0 {
+        return Ok(Fields {
+            note: first,
+            instrument: next()?,
+            volume: next()?,
+            code: next()?,
+            param: next()?,
+        });
+    }
+    let mut field = |bit: u8| match first & bit {
+        0 => Ok(0),
+        _ => next(),
+    };
+    Ok(Fields {
+        note: field(0x01)?,
+        instrument: field(0x02)?,
+        volume: field(0x04)?,
+        code: field(0x08)?,
+        param: field(0x10)?,
+    })
+}
+
+/// Conversion state of a channel
+#[derive(Copy, Clone)]
+struct Channel {
+    key: u8,
+    volume: u8,
+}
+
+impl Default for Channel {
+    fn default() -> Self {
+        Self {
+            key: 4 * 12,
+            volume: MAX_VOLUME,
+        }
+    }
+}
+
+/// What the conversion of a cell needs from the module
+struct Context<'a> {
+    instruments: &'a [Instrument],
+    linear: bool,
+}
+
+impl Context<'_> {
+    fn pitch(&self, channel: &Channel) -> Pitch {
+        match self.linear {
+            true => Pitch::Linear,
+            // C-4 plays at the rate of ProTracker C-2
+            false => Pitch::Period(protracker::period(channel.key + 12)),
+        }
+    }
+}
+
+/// The effect of the volume column, if it has one
+fn volume_effect(
+    volume: u8,
+    pitch: Pitch,
+    at: Location,
+    report: &mut Report,
+) -> Option<(Effect, &'static str)> {
+    let (x, y) = (volume >> 4, volume & 0xF);
+    let (code, param, name) = match x {
+        0x6 => (VOLUME_SLIDE, y, "Volume slide down (-x)"),
+        0x7 => (VOLUME_SLIDE, y << 4, "Volume slide up (+x)"),
+        0xA => (VIBRATO, y << 4, "Vibrato speed (Sx)"),
+        0xB if y == 0 => (VIBRATO, 0, "Vibrato depth (Vx)"),
+        0xB => {
+            let depth = protracker::effect(VIBRATO, y, pitch, at, report)?.param;
+            (VIBRATO, depth, "Vibrato depth (Vx)")
+        }
+        0xF if y == 0 => (TONE_PORTAMENTO, 0, "Tone portamento (Mx)"),
+        0xF => {
+            let speed = protracker::effect(TONE_PORTAMENTO, y << 4, pitch, at, report)?.param;
+            (TONE_PORTAMENTO, speed, "Tone portamento (Mx)")
+        }
+        _ => {
+            let name = match x {
+                0x8 => "Fine volume slide down (Dx)",
+                0x9 => "Fine volume slide up (Ux)",
+                0xC => "Set panning (Px)",
+                0xD => "Panning slide left (Lx)",
+                0xE => "Panning slide right (Rx)",
+                _ => return None,
+            };
+            report.warn_at(format!("{} in the volume column dropped", name), at);
+            return None;
+        }
+    };
+    Some((Effect { code, param }, name))
+}
+
+/// The effect of an effect column command, track effects first and global effects second
+fn effect(
+    fields: Fields,
+    pitch: Pitch,
+    at: Location,
+    report: &mut Report,
+) -> (Option<Effect>, Option<Effect>) {
+    let Fields { code, param, .. } = fields;
+    let (x, y) = (param >> 4, param & 0xF);
+    let effect = |code, param| Some(Effect { code, param });
+    match code {
+        0x0..=0xF => (protracker::effect(code, param, pitch, at, report), None),
+        SET_GLOBAL_VOLUME => (None, effect(GFX_SET_VOLUME, protracker::volume(param))),
+        GLOBAL_VOLUME_SLIDE => (None, effect(GFX_VOLUME_FADE, param)),
+        // A key off on tick 0 is a note off, see cell()
+        KEY_OFF if param == 0 => (None, None),
+        KEY_OFF => (effect(EXTENDED, NOTE_CUT << 4 | param.min(0xF)), None),
+        MULTI_RETRIGGER => {
+            if x != 0 && x != 8 {
+                report.warn_at(
+                    "Volume change of multi retrigger (Rxy) dropped".to_string(),
+                    at,
+                );
+            }
+            (effect(EXTENDED, RETRIGGER << 4 | y), None)
+        }
+        _ => {
+            let name = match code {
+                0x15 => "Set envelope position (Lxx)".to_string(),
+                0x19 => "Panning slide (Pxy)".to_string(),
+                0x1D => "Tremor (Txy)".to_string(),
+                0x21 => "Extra fine slide (Xxy)".to_string(),
+                _ => format!(
+                    "Effect {}xx",
+                    char::from_digit(code as u32, 36).unwrap_or('?')
+                ),
+            };
+            report.warn_at(format!("{} dropped", name), at);
+            (None, None)
+        }
+    }
+}
+
+fn cell(
+    fields: Fields,
+    context: &Context,
+    channel: &mut Channel,
+    gfx: &mut Option<Effect>,
+    at: Location,
+    report: &mut Report,
+) -> Cell {
+    let mut note = match fields.note {
+        0 => None,
+        1..=96 => {
+            channel.key = fields.note - 1;
+            Some(Note::Key(channel.key))
+        }
+        NOTE_OFF => Some(Note::Off),
+        _ => {
+            report.warn_at("Invalid notes dropped".to_string(), at);
+            None
+        }
+    };
+    let voice = (fields.instrument > 0).then_some(fields.instrument);
+    let instrument = (fields.instrument as usize)
+        .checked_sub(1)
+        .and_then(|i| context.instruments.get(i));
+    if let Some(instrument) = instrument {
+        channel.volume = instrument.volume;
+    }
+
+    let mut set_volume = false;
+    if (0x10..=0x50).contains(&fields.volume) {
+        channel.volume = fields.volume - 0x10;
+        set_volume = true;
+    }
+    if fields.code == SET_VOLUME {
+        channel.volume = fields.param.min(MAX_VOLUME);
+        set_volume = true;
+    }
+    if fields.code == KEY_OFF && fields.param == 0 && note.is_none() {
+        note = Some(Note::Off);
+    }
+
+    let pitch = context.pitch(channel);
+    let (mut effect, global) = match fields.code {
+        SET_VOLUME => (None, None),
+        _ => effect(fields, pitch, at, report),
+    };
+    if let Some(global) = global {
+        convert::set_global_effect(gfx, global, at, report);
+    }
+    if let Some((volume_effect, name)) = volume_effect(fields.volume, pitch, at, report) {
+        match effect {
+            Some(_) => report.warn_at(
+                format!("{} in the volume column dropped, the effect is used", name),
+                at,
+            ),
+            None => effect = Some(volume_effect),
+        }
+    }
+
+    // Instruments set the volume of the track, notes without one keep it
+    let set_volume = set_volume
+        || (voice.is_some() && note.is_none())
+        || (matches!(note, Some(Note::Key(_))) && channel.volume < MAX_VOLUME);
+    Cell {
+        note,
+        voice,
+        volume: set_volume.then_some(protracker::volume(channel.volume)),
+        effect,
+    }
+}
+
+/// Convert a module. Each instrument is played by a sample voice in the slot with the number
+/// of the instrument, with its first sample and the volume envelope of the instrument.
+pub fn read(data: &[u8]) -> Result<Import, Error> {
+    if !matches(data) {
+        return Err(Error::Format);
+    }
+    let header = |offset: usize| le16(data, HEADER_OFFSET + 4 + offset);
+    let song_len = header(0)?.clamp(1, ORDER_LEN);
+    let channels = header(4)?;
+    let patterns = header(6)?;
+    let instruments = header(8)?;
+    let linear = header(10)? & 0x1 != 0;
+    if channels == 0 || patterns > MAX_PATTERNS {
+        return Err(Error::Format);
+    }
+
+    let mut report = Report::default();
+    let mut song = Song::new();
+    song.title = convert::name(&data[17..37.min(data.len())]);
+    song.speed = header(12)?.clamp(1, 0xFF) as u32;
+    song.bpm = header(14)?.clamp(1, 999) as u32;
+    let order = data
+        .get(HEADER_OFFSET + 20..HEADER_OFFSET + 20 + song_len)
+        .ok_or(Error::Truncated)?;
+
+    let tracks = channels.min(MAX_TRACKS);
+    if channels > MAX_TRACKS {
+        report.warn(format!("Channels after the first {} dropped", MAX_TRACKS));
+    }
+
+    // The instruments come after the patterns, find them first
+    let mut pos = HEADER_OFFSET + le32(data, HEADER_OFFSET)?;
+    let mut packed = vec![];
+    for _ in 0..patterns {
+        let header_len = le32(data, pos)?;
+        let rows = le16(data, pos + 5)?.max(1);
+        let len = le16(data, pos + 7)?;
+        let cells = data
+            .get(pos + header_len..pos + header_len + len)
+            .ok_or(Error::Truncated)?;
+        packed.push((rows, cells));
+        pos += header_len + len;
+    }
+
+    let mut voices = vec![String::new(); MAX_VOICES];
+    let mut parsed = vec![];
+    let tick_ms = 2500.0 / song.bpm as f32;
+    for idx in 0..instruments.min(MAX_VOICES - 1) {
+        let (instrument, next) = instrument(data, pos)?;
+        pos = next;
+        let slot = idx + 1;
+        if let Some(sample) = &instrument.sample {
+            voices[slot] = match instrument.envelope.is_empty() {
+                true => convert::SAMPLE_VOICE.to_string(),
+                false => convert::sample_voice(&envelope(
+                    &instrument.envelope,
+                    instrument.sustain,
+                    tick_ms,
+                )),
+            };
+            song.samples[slot] = Some(sample.clone());
+            song.set_voice(slot, voice::parse(&voices[slot]));
+        }
+        if instrument.samples > 1 {
+            report.warn(format!(
+                "Instrument {:02X} has {} samples, the first plays all notes",
+                slot, instrument.samples
+            ));
+        }
+        if instrument.ping_pong {
+            report.warn(format!(
+                "Instrument {:02X} ping-pong loop played forwards",
+                slot
+            ));
+        }
+        if instrument.envelope_loop {
+            report.warn(format!("Instrument {:02X} envelope loop ignored", slot));
+        }
+        if instrument.panning_envelope {
+            report.warn(format!("Instrument {:02X} panning envelope ignored", slot));
+        }
+        parsed.push(instrument);
+    }
+
+    let context = Context {
+        instruments: &parsed,
+        linear,
+    };
+    let mut state = vec![Channel::default(); tracks];
+    song.patterns = packed
+        .iter()
+        .enumerate()
+        .map(|(idx, (rows, cells))| {
+            let mut pattern = Pattern::new(*rows, tracks);
+            let mut pos = 0;
+            for row in 0..*rows {
+                let mut gfx = None;
+                for track in 0..channels {
+                    let fields = match pos < cells.len() {
+                        true => unpack(cells, &mut pos)?,
+                        false => Fields::default(),
+                    };
+                    let Some(channel) = state.get_mut(track) else {
+                        continue;
+                    };
+                    let at = Location {
+                        pattern: idx,
+                        row,
+                        track,
+                    };
+                    *pattern.cell_mut(row, track) =
+                        cell(fields, &context, channel, &mut gfx, at, &mut report);
+                }
+                pattern.row_mut(row).gfx = gfx;
+            }
+            Ok(pattern)
+        })
+        .collect::<Result<_, Error>>()?;
+
+    // Patterns that are played but not stored are empty
+    let entries: Vec<usize> = order.iter().map(|p| *p as usize).collect();
+    let played = entries.iter().max().map_or(0, |p| p + 1);
+    while song.patterns.len() < played.max(1) {
+        song.patterns.push(Pattern::new(protracker::ROWS, tracks));
+    }
+    song.order = OrderList::new(entries);
+
+    Ok(Import {
+        song,
+        voices,
+        report,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FIXTURE: &[u8] = include_bytes!("../../fixtures/fasttracker.xm");
+
+    fn cell(import: &Import, pattern: usize, row: usize, track: usize) -> String {
+        import.song.patterns[pattern].cell(row, track).to_string()
+    }
+
+    #[test]
+    fn envelope_test() {
+        // Attack to the peak, decay to the sustain point and release to the end
+        let points = [(0, 0), (2, 64), (6, 32), (16, 0)];
+        let env = envelope(&points, Some(2), 20.0);
+        assert_eq!(env.attack_ms, 40.0);
+        assert_eq!(env.decay_ms, 80.0);
+        assert_eq!(env.sustain_lvl, 0.5);
+        assert_eq!(env.release_ms, 200.0);
+
+        let env = envelope(&points, None, 20.0);
+        assert_eq!(env.decay_ms, 280.0);
+        assert_eq!(env.sustain_lvl, 0.0);
+        assert_eq!(env.release_ms, 0.0);
+    }
+
+    #[test]
+    fn read_test() {
+        let import = convert::read(FIXTURE).unwrap();
+        let song = &import.song;
+        assert_eq!(song.title, "rtrk xm fixture");
+        assert_eq!((song.speed, song.bpm), (4, 140));
+        assert_eq!(song.order.entries(), &[0, 1, 2]);
+        assert_eq!(song.patterns.len(), 3);
+        assert_eq!(song.patterns[0].tracks(), 6);
+        assert_eq!(song.patterns[0].len(), 0x20);
+        assert_eq!(song.patterns[1].len(), 0x10);
+        assert!(song.patterns[2].cell(0, 0).is_empty());
+
+        // The first instrument has an envelope, the second doesn't
+        assert_eq!(import.voices[1], "6 0E1D8047     F0");
+        assert_eq!(import.voices[2], convert::SAMPLE_VOICE);
+        assert!(song.voices[1].as_ref().expect("").env.is_some());
+
+        // The voices play the first sample of the instrument
+        let bass = song.samples[2].as_ref().expect("");
+        assert_eq!((bass.data.len(), bass.rate, bass.repeat), (8, 8363.0, None));
+        assert_eq!(song.samples[1].as_ref().expect("").data.len(), 16);
+
+        // Notes, instruments and volumes
+        assert_eq!(cell(&import, 0, 0, 0), "C-4 01 -- ---");
+        assert_eq!(cell(&import, 0, 0, 5), "A-5 02 7F ---");
+        assert_eq!(cell(&import, 0, 1, 0), "D-4 -- BF ---");
+        assert_eq!(cell(&import, 0, 2, 0), "=== -- -- ---");
+        assert_eq!(cell(&import, 0, 3, 0), "=== -- -- ---");
+
+        // Effects, the frequencies are linear so the slides keep their parameter
+        assert_eq!(cell(&import, 0, 4, 1), "--- -- -- 120");
+        assert_eq!(cell(&import, 0, 5, 1), "--- -- -- 468");
+        assert_eq!(cell(&import, 0, 6, 1), "--- -- -- EC3");
+        assert_eq!(cell(&import, 0, 7, 1), "--- -- -- E94");
+
+        // Volume column effects go to the effect column when it's free
+        assert_eq!(cell(&import, 0, 8, 2), "--- -- -- A03");
+        assert_eq!(cell(&import, 0, 9, 2), "--- -- -- 320");
+        assert_eq!(cell(&import, 0, 10, 2), "--- -- -- A50");
+
+        // Global volume
+        assert_eq!(song.patterns[0].row(11).gfx.expect("").to_string(), "27F");
+        assert_eq!(song.patterns[0].row(12).gfx.expect("").to_string(), "310");
+        assert_eq!(cell(&import, 1, 0, 3), "E-4 01 -- ---");
+    }
+
+    #[test]
+    fn report_test() {
+        let report = convert::read(FIXTURE).unwrap().report.to_string();
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(
+            lines,
+            vec![
+                "Instrument 01 envelope loop ignored",
+                "Instrument 02 has 2 samples, the first plays all notes",
+                "Instrument 02 panning envelope ignored",
+                "Panning slide (Pxy) dropped: 1 cell, first at pattern 00 row 0D track 1",
+                "Set panning (Px) in the volume column dropped: 1 cell, first at pattern 00 row 0D track 2",
+                "Volume slide up (+x) in the volume column dropped, the effect is used: 1 cell, first at pattern 00 row 0E track 2",
+                "Global effects after the first on a row dropped: 1 cell, first at pattern 00 row 0F track 4",
+            ]
+        );
+    }
+
+    #[test]
+    fn sample_test() {
+        assert_eq!(decode(&[0x10, 0x10, 0xE0], false), vec![0.125, 0.25, 0.0]);
+        assert_eq!(decode(&[0x00, 0x40, 0x00, 0xC0], true), vec![0.5, 0.0]);
+
+        // 16 bit with a ping-pong loop, tuned an octave up and half a semitone down
+        let mut header = [0; 40];
+        header[0] = 8;
+        header[4] = 2;
+        header[8] = 4;
+        header[13] = 0xC0;
+        header[14] = SAMPLE_16_BIT | PING_PONG_LOOP;
+        header[16] = 12;
+        let (tuned, ping_pong) = sample(&header, &[0; 8]).unwrap();
+        let tuned = tuned.expect("");
+        assert_eq!(tuned.data.len(), 4);
+        assert_eq!(tuned.repeat, Some((1, 2)));
+        assert_eq!(
+            tuned.rate,
+            (2.0 * C4_RATE * 2.0_f32.powf(-0.5 / 12.0)).round()
+        );
+        assert!(ping_pong);
+        assert_eq!(sample(&header, &[0; 7]).unwrap_err(), Error::Truncated);
+    }
+
+    #[test]
+    fn error_test() {
+        assert_eq!(read(b"Extended").unwrap_err(), Error::Format);
+        assert_eq!(read(&FIXTURE[..400]).unwrap_err(), Error::Truncated);
+        // Only the data of the first sample of an instrument is used
+        assert!(read(&FIXTURE[..FIXTURE.len() - 10]).is_ok());
+        assert_eq!(
+            read(&FIXTURE[..FIXTURE.len() - 21]).unwrap_err(),
+            Error::Truncated
+        );
+    }
+}
@@ -0,0 +1,471 @@
+// Copyright (C) 2025 Anders Sundman <anders@4zm.org>
+//
+// This file is part of RTRK - The Rust Tracker
+//
+// RTRK is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// RTRK is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with RTRK. If not, see <https://www.gnu.org/licenses/>.
+
+/// The song file format. It is a line based text format, so songs can be diffed and kept in
+/// version control:
+///
+/// ```text
+/// rtrk 1
+/// title Sweet noise
+/// author 4ZM
+/// bpm 125
+/// speed 6
+/// order 00 01 00
+/// voice 00 4 -------- FF--A0
+/// voice 01 6 -------- ----F0
+/// sample 01 4182 100 80 80
+/// 00000C0018002400300024001800...
+/// pattern 00 40
+/// C-4 00 A0 101 : --- -- -- --- : --- -- -- --- : --- -- -- --- : 2FF
+/// ...
+/// ```
+///
+/// A pattern is followed by its rows, as many as given in hex after the pattern number. Voices
+/// are in designer notation with dashes for unset digits. A sample has its rate in Hz, then its
+/// length and optionally the start and length of the repeated part in hex. It is followed by
+/// its data as 16 bit hex numbers, 32 to a line. Blank lines and lines starting with `#` are
+/// ignored.
+use std::fmt;
+use std::ops::RangeInclusive;
+
+use crate::song::order::OrderList;
+use crate::song::pattern::{ParseError, Pattern, Row, MAX_ROWS, MAX_TRACKS};
+use crate::song::{voice, Song, MAX_BPM, MAX_PATTERNS, MAX_SPEED, MAX_VOICES};
+use crate::synth;
+
+pub const VERSION: u32 = 1;
+const MAGIC: &str = "rtrk";
+/// Sample data per line
+const SAMPLE_LINE: usize = 32;
+/// Longest sample, more than six minutes at the output rate
+const MAX_SAMPLE_LEN: usize = 0xFFFFFF;
+/// Highest sample rate in Hz
+pub const MAX_SAMPLE_RATE: usize = 999999;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ErrorKind {
+    Header,
+    Version(u32),
+    Keyword(String),
+    Number,
+    Voice,
+    /// A sample header or data line that can't be read, or a repeat outside of the sample
+    Sample,
+    Row(ParseError),
+    /// The file ended before all rows of a pattern
+    MissingRows,
+    /// Patterns must be numbered from 00 and up
+    PatternNumber,
+    /// All patterns must have the same number of tracks
+    Tracks,
+    /// More tracks than the player has channels for
+    TooManyTracks,
+    /// The order list is empty or refers to a pattern that doesn't exist
+    Order,
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ErrorKind::Header => write!(f, "Not an RTRK song"),
+            ErrorKind::Version(v) => write!(f, "Unsupported version {}", v),
+            ErrorKind::Keyword(k) => write!(f, "Unknown keyword '{}'", k),
+            ErrorKind::Number => write!(f, "Invalid number"),
+            ErrorKind::Voice => write!(f, "Invalid voice"),
+            ErrorKind::Sample => write!(f, "Invalid sample"),
+            ErrorKind::Row(e) => write!(f, "{}", e),
+            ErrorKind::MissingRows => write!(f, "Missing pattern rows"),
+            ErrorKind::PatternNumber => write!(f, "Patterns must be numbered in order"),
+            ErrorKind::Tracks => write!(f, "Patterns have different numbers of tracks"),
+            ErrorKind::TooManyTracks => write!(f, "More than {} tracks", MAX_TRACKS),
+            ErrorKind::Order => write!(f, "Invalid order list"),
+        }
+    }
+}
+
+/// What went wrong and on which line, counted from 1
+#[derive(Clone, Debug, PartialEq)]
+pub struct FileError {
+    pub line: usize,
+    pub kind: ErrorKind,
+}
+
+impl fmt::Display for FileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Line {}: {}", self.line, self.kind)
+    }
+}
+
+impl std::error::Error for FileError {}
+
+/// A voice designer text as written in the file, unset digits are dashes
+pub fn format_voice(text: &str) -> String {
+    let text = format!("{:1$.1$}", text, voice::TEXT_LEN);
+    let dashes = |s: &str| s.replace(' ', "-");
+    format!(
+        "{} {} {}",
+        dashes(&text[0..1]),
+        dashes(&text[2..10]),
+        dashes(&text[11..17])
+    )
+}
+
+/// The voice designer text of a voice in the file. None if it's malformed.
+pub fn parse_voice(text: &str) -> Option<String> {
+    let parts: Vec<&str> = text.split_whitespace().collect();
+    let [osc, env, flt] = parts[..] else {
+        return None;
+    };
+    if osc.len() != 1 || env.len() != 8 || flt.len() != 6 {
+        return None;
+    }
+    if !text
+        .chars()
+        .all(|c| c.is_ascii_hexdigit() || c == '-' || c == ' ')
+    {
+        return None;
+    }
+    Some(format!("{} {} {}", osc, env, flt).replace('-', " "))
+}
+
+/// A sample header followed by the data lines
+fn format_sample(slot: usize, sample: &synth::Sample) -> String {
+    let mut text = format!(
+        "sample {:02X} {} {:X}",
+        slot,
+        sample.rate.round() as u32,
+        sample.data.len()
+    );
+    if let Some((start, repeat)) = sample.repeat {
+        text += &format!(" {:X} {:X}", start, repeat);
+    }
+    text += "\n";
+    for line in sample.data.chunks(SAMPLE_LINE) {
+        for s in line {
+            let word = (s * 32768.0)
+                .round()
+                .clamp(i16::MIN as f32, i16::MAX as f32) as i16;
+            text += &format!("{:04X}", word as u16);
+        }
+        text += "\n";
+    }
+    text
+}
+
+/// Write the song, with the designer text of each voice slot. Empty slots are left out.
+pub fn write(song: &Song, voices: &[String]) -> String {
+    let mut text = format!("{} {}\n", MAGIC, VERSION);
+    if !song.title.is_empty() {
+        text += &format!("title {}\n", song.title);
+    }
+    if !song.author.is_empty() {
+        text += &format!("author {}\n", song.author);
+    }
+    text += &format!("bpm {}\n", song.bpm);
+    text += &format!("speed {}\n", song.speed);
+    let order: Vec<String> = song
+        .order
+        .entries()
+        .iter()
+        .map(|p| format!("{:02X}", p))
+        .collect();
+    text += &format!("order {}\n", order.join(" "));
+
+    text += "\n";
+    for (slot, voice) in voices.iter().enumerate() {
+        if !voice.trim().is_empty() {
+            text += &format!("voice {:02X} {}\n", slot, format_voice(voice));
+        }
+    }
+    for (slot, sample) in song.samples.iter().enumerate() {
+        if let Some(sample) = sample {
+            text += &format_sample(slot, sample);
+        }
+    }
+
+    for (idx, pattern) in song.patterns.iter().enumerate() {
+        text += &format!("\npattern {:02X} {:02X}\n", idx, pattern.len());
+        text += &pattern.to_string();
+    }
+    text
+}
+
+fn number(text: &str, radix: u32, range: RangeInclusive<usize>) -> Result<usize, ErrorKind> {
+    usize::from_str_radix(text, radix)
+        .ok()
+        .filter(|n| range.contains(n))
+        .ok_or(ErrorKind::Number)
+}
+
+/// Read a song written by `write`. Returns the song and the designer text of all voice slots.
+pub fn read(text: &str) -> Result<(Song, Vec<String>), FileError> {
+    let mut lines = text
+        .lines()
+        .enumerate()
+        .map(|(i, l)| (i + 1, l.trim()))
+        .filter(|(_, l)| !l.is_empty() && !l.starts_with('#'));
+
+    let (line, header) = lines.next().unwrap_or((1, ""));
+    let err = |line, kind| FileError { line, kind };
+    match header.split_whitespace().collect::<Vec<_>>()[..] {
+        [MAGIC, v] => match v.parse() {
+            Ok(VERSION) => {}
+            Ok(v) => return Err(err(line, ErrorKind::Version(v))),
+            Err(_) => return Err(err(line, ErrorKind::Number)),
+        },
+        _ => return Err(err(line, ErrorKind::Header)),
+    }
+
+    let mut song = Song::new();
+    song.patterns.clear();
+    let mut voices = vec![String::new(); MAX_VOICES];
+    let mut order = (line, vec![0]);
+
+    while let Some((line, l)) = lines.next() {
+        let (keyword, rest) = l.split_once(' ').unwrap_or((l, ""));
+        let rest = rest.trim();
+        let at = |kind| err(line, kind);
+        match keyword {
+            "title" => song.title = rest.to_string(),
+            "author" => song.author = rest.to_string(),
+            "bpm" => song.bpm = number(rest, 10, 1..=MAX_BPM as usize).map_err(at)? as u32,
+            "speed" => song.speed = number(rest, 10, 1..=MAX_SPEED as usize).map_err(at)? as u32,
+            "order" => {
+                let entries = rest
+                    .split_whitespace()
+                    .map(|p| number(p, 16, 0..=MAX_PATTERNS - 1))
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(at)?;
+                order = (line, entries);
+            }
+            "voice" => {
+                let (slot, voice) = rest.split_once(' ').ok_or(at(ErrorKind::Voice))?;
+                let slot = number(slot, 16, 0..=MAX_VOICES - 1).map_err(at)?;
+                voices[slot] = parse_voice(voice).ok_or(at(ErrorKind::Voice))?;
+            }
+            "sample" => {
+                let (slot, rate, len, repeat) = match rest.split_whitespace().collect::<Vec<_>>()[..]
+                {
+                    [slot, rate, len] => (slot, rate, len, None),
+                    [slot, rate, len, start, repeat] => (slot, rate, len, Some((start, repeat))),
+                    _ => return Err(at(ErrorKind::Sample)),
+                };
+                let slot = number(slot, 16, 0..=MAX_VOICES - 1).map_err(at)?;
+                let rate = number(rate, 10, 1..=MAX_SAMPLE_RATE).map_err(at)?;
+                let len = number(len, 16, 1..=MAX_SAMPLE_LEN).map_err(at)?;
+                let repeat = match repeat {
+                    Some((start, repeat)) => Some((
+                        number(start, 16, 0..=len - 1).map_err(at)?,
+                        number(repeat, 16, 1..=len).map_err(at)?,
+                    )),
+                    None => None,
+                };
+                if repeat.is_some_and(|(start, repeat)| start + repeat > len) {
+                    return Err(at(ErrorKind::Sample));
+                }
+
+                let mut data = Vec::with_capacity(len);
+                while data.len() < len {
+                    let (line, l) = lines.next().ok_or(at(ErrorKind::Sample))?;
+                    let words = (len - data.len()).min(SAMPLE_LINE);
+                    if l.len() != 4 * words || !l.is_ascii() {
+                        return Err(err(line, ErrorKind::Sample));
+                    }
+                    for i in 0..words {
+                        let word = u16::from_str_radix(&l[4 * i..4 * i + 4], 16)
+                            .map_err(|_| err(line, ErrorKind::Sample))?;
+                        data.push(word as i16 as f32 / 32768.0);
+                    }
+                }
+                song.samples[slot] = Some(synth::Sample {
+                    data: data.into(),
+                    rate: rate as f32,
+                    repeat,
+                });
+            }
+            "pattern" => {
+                let [idx, len] = rest.split_whitespace().collect::<Vec<_>>()[..] else {
+                    return Err(at(ErrorKind::Number));
+                };
+                if number(idx, 16, 0..=MAX_PATTERNS - 1).map_err(at)? != song.patterns.len() {
+                    return Err(at(ErrorKind::PatternNumber));
+                }
+                let len = number(len, 16, 1..=MAX_ROWS).map_err(at)?;
+                let mut rows: Vec<Row> = vec![];
+                for _ in 0..len {
+                    let (line, l) = lines.next().ok_or(at(ErrorKind::MissingRows))?;
+                    let row: Row = l.parse().map_err(|e| err(line, ErrorKind::Row(e)))?;
+                    if row.cells.len() > MAX_TRACKS {
+                        return Err(err(line, ErrorKind::TooManyTracks));
+                    }
+                    if rows
+                        .first()
+                        .is_some_and(|r| r.cells.len() != row.cells.len())
+                    {
+                        return Err(err(line, ErrorKind::Row(ParseError::Row)));
+                    }
+                    rows.push(row);
+                }
+                let tracks = rows[0].cells.len();
+                if song.patterns.first().is_some_and(|p| p.tracks() != tracks) {
+                    return Err(at(ErrorKind::Tracks));
+                }
+                let mut pattern = Pattern::new(len, tracks);
+                for (i, row) in rows.into_iter().enumerate() {
+                    *pattern.row_mut(i) = row;
+                }
+                song.patterns.push(pattern);
+            }
+            _ => return Err(at(ErrorKind::Keyword(keyword.to_string()))),
+        }
+    }
+
+    if song.patterns.is_empty() {
+        song.patterns.push(Pattern::default());
+    }
+    let (line, entries) = order;
+    if entries.is_empty() || entries.iter().any(|p| *p >= song.patterns.len()) {
+        return Err(err(line, ErrorKind::Order));
+    }
+    song.order = OrderList::new(entries);
+
+    for (slot, text) in voices.iter().enumerate() {
+        song.set_voice(slot, voice::parse(text));
+    }
+    Ok((song, voices))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::song::pattern::Note;
+
+    fn voices() -> Vec<String> {
+        let mut voices = vec![String::new(); MAX_VOICES];
+        voices[1] = "4          FF  A0".to_string();
+        voices[0xFF] = "1 01020304 ".to_string();
+        voices
+    }
+
+    #[test]
+    fn round_trip_test() {
+        let mut song = Song::new();
+        song.title = "Sweet noise".to_string();
+        song.bpm = 140;
+        song.speed = 3;
+        song.patterns[0].cell_mut(2, 1).note = Some(Note::Off);
+        song.clone_pattern(0);
+        song.patterns[1].set_len(0x10);
+
+        let text = write(&song, &voices());
+        assert!(text.starts_with("rtrk 1\ntitle Sweet noise\nbpm 140\n"));
+        assert!(text.contains("order 00 01\n"));
+        assert!(text.contains("voice 01 4 -------- FF--A0\nvoice FF 1 01020304 ------\n"));
+
+        let (read_song, read_voices) = read(&text).unwrap();
+        song.voices = read_song.voices.clone();
+        assert_eq!(read_song, song);
+        assert_eq!(read_voices[1], voices()[1]);
+        assert_eq!(read_voices[0xFF], "1 01020304       ");
+        assert!(read_song.voices[1].is_some());
+        assert!(read_song.voices[0].is_none());
+    }
+
+    #[test]
+    fn sample_test() {
+        let mut song = Song::new();
+        let data: Vec<f32> = (0..40).map(|i| (i as f32 - 20.0) / 32.0).collect();
+        song.samples[2] = Some(synth::Sample {
+            data: data.into(),
+            rate: 4182.0,
+            repeat: Some((0x10, 0x18)),
+        });
+        let mut voices = vec![String::new(); MAX_VOICES];
+        voices[2] = "6              F0".to_string();
+        song.set_voice(2, voice::parse(&voices[2]));
+
+        let text = write(&song, &voices);
+        assert!(text.contains("voice 02 6 -------- ----F0\nsample 02 4182 28 10 18\nB000"));
+        let (read_song, _) = read(&text).unwrap();
+        assert_eq!(read_song, song);
+        assert!(read_song.voices[2].is_some());
+
+        let error = |text: &str| read(text).unwrap_err();
+        let sample = |header: &str, data: &str| format!("rtrk 1\n{}\n{}", header, data);
+        assert!(read(&sample("sample 01 8363 2", "0000FFFF")).is_ok());
+        assert_eq!(error(&sample("sample 01 8363 2", "0000FFF")).line, 3);
+        assert_eq!(error(&sample("sample 01 8363 2", "0000XXXX")).line, 3);
+        assert_eq!(error(&sample("sample 01 8363 3", "0000FFFF")).line, 3);
+        assert_eq!(
+            error(&sample("sample 01 8363 21", &"0000".repeat(32))).line,
+            2
+        );
+        assert_eq!(
+            error(&sample("sample 01 8363 2 1 2", "0000FFFF")).to_string(),
+            "Line 2: Invalid sample"
+        );
+        assert_eq!(
+            error(&sample("sample 01 0 2", "0000FFFF")).kind,
+            ErrorKind::Number
+        );
+    }
+
+    #[test]
+    fn error_test() {
+        let error = |text: &str| read(text).unwrap_err();
+        assert_eq!(error("").kind, ErrorKind::Header);
+        assert_eq!(
+            error("# Comment\n\nrtrk 2").to_string(),
+            "Line 3: Unsupported version 2"
+        );
+        assert_eq!(
+            error("rtrk 1\ntempo 120"),
+            FileError {
+                line: 2,
+                kind: ErrorKind::Keyword("tempo".to_string())
+            }
+        );
+        assert_eq!(error("rtrk 1\nbpm fast").kind, ErrorKind::Number);
+        assert_eq!(error("rtrk 1\nvoice 01 4 ---- FF").kind, ErrorKind::Voice);
+        assert_eq!(
+            error("rtrk 1\norder 01").to_string(),
+            "Line 2: Invalid order list"
+        );
+        assert_eq!(
+            error("rtrk 1\npattern 01 01").kind,
+            ErrorKind::PatternNumber
+        );
+        assert_eq!(error("rtrk 1\npattern 00 02\n--- -- -- --- : ---").line, 2);
+        assert_eq!(
+            error("rtrk 1\npattern 00 01\n--- -- -- --- : ---\npattern 01 01\n--- : ---")
+                .to_string(),
+            "Line 5: Invalid cell"
+        );
+        assert_eq!(
+            error("rtrk 1\npattern 00 01\n--- -- -- --- : ---\npattern 01 01\n--- -- -- --- : --- -- -- --- : ---").line,
+            4
+        );
+
+        let row = vec!["--- -- -- ---"; MAX_TRACKS + 1].join(" : ") + " : ---";
+        assert_eq!(
+            error(&format!("rtrk 1\npattern 00 01\n{}", row)).to_string(),
+            "Line 3: More than 32 tracks"
+        );
+        let row = vec!["--- -- -- ---"; MAX_TRACKS].join(" : ") + " : ---";
+        assert!(read(&format!("rtrk 1\npattern 00 01\n{}", row)).is_ok());
+    }
+}
@@ -0,0 +1,699 @@
+// Copyright (C) 2025 Anders Sundman <anders@4zm.org>
+//
+// This file is part of RTRK - The Rust Tracker
+//
+// RTRK is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// RTRK is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with RTRK. If not, see <https://www.gnu.org/licenses/>.
+
+/// Standard MIDI Files. Songs are written as type 1 files with a tempo track followed by a
+/// MIDI track per track of the song. A MIDI tick is a tracker tick, and with 24 ticks per
+/// quarter note the MIDI tempo is the BPM of the song. Imported notes are quantised to rows.
+use std::collections::HashSet;
+
+use crate::song::convert::{self, Error, Import, Report, PLACEHOLDER_VOICE};
+use crate::song::order::OrderList;
+use crate::song::pattern::{Cell, Effect, Note, Pattern, DEFAULT_ROWS, MAX_TRACKS};
+use crate::song::player::GFX_SET_TEMPO;
+use crate::song::{voice, Song, MAX_PATTERNS, MAX_VOICES};
+
+/// Ticks per quarter note of exported files, 4 rows of 6 ticks like the default song
+const TICKS_PER_BEAT: u32 = 24;
+/// Rows per quarter note of imported files, a beat is 4 rows in the default song
+pub const DEFAULT_ROWS_PER_BEAT: usize = 4;
+/// One tick per row at most
+pub const MAX_ROWS_PER_BEAT: usize = TICKS_PER_BEAT as usize;
+/// MIDI note of C-0, rtrk C-4 is middle C
+const C0_NOTE: u8 = 12;
+const MAX_VELOCITY: u8 = 0x7F;
+/// Tempo of a file without tempo events, in microseconds per quarter note
+const DEFAULT_TEMPO: u32 = 500_000;
+/// The General MIDI percussion channel is left out of the channels of the tracks
+const PERCUSSION_CHANNEL: u8 = 9;
+
+// Tracker effects that change the timing of the song or of the notes
+const POSITION_JUMP: u8 = 0xB;
+const PATTERN_BREAK: u8 = 0xD;
+const EXTENDED: u8 = 0xE;
+const NOTE_CUT: u8 = 0xC;
+const NOTE_DELAY: u8 = 0xD;
+const SET_SPEED: u8 = 0xF;
+const GFX_SET_SPEED: u8 = 0x0;
+
+// Status bytes
+const NOTE_OFF: u8 = 0x80;
+const NOTE_ON: u8 = 0x90;
+const PROGRAM_CHANGE: u8 = 0xC0;
+const META: u8 = 0xFF;
+const SYSEX: u8 = 0xF0;
+const SYSEX_ESCAPE: u8 = 0xF7;
+
+// Meta events
+const TRACK_NAME: u8 = 0x03;
+const END_OF_TRACK: u8 = 0x2F;
+const SET_TEMPO: u8 = 0x51;
+
+/// Variable length quantity, 7 bits per byte with the high bit set on all but the last
+fn write_vlq(mut n: u32, out: &mut Vec<u8>) {
+    let mut bytes = vec![(n & 0x7F) as u8];
+    n >>= 7;
+    while n > 0 {
+        bytes.push((n & 0x7F) as u8 | 0x80);
+        n >>= 7;
+    }
+    out.extend(bytes.iter().rev());
+}
+
+fn read_vlq(data: &[u8], at: &mut usize) -> Result<u32, Error> {
+    let mut n = 0u32;
+    for _ in 0..4 {
+        let byte = *data.get(*at).ok_or(Error::Truncated)?;
+        *at += 1;
+        n = n << 7 | (byte & 0x7F) as u32;
+        if byte & 0x80 == 0 {
+            return Ok(n);
+        }
+    }
+    Err(Error::Format)
+}
+
+fn chunk(id: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut chunk = id.to_vec();
+    chunk.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    chunk.extend_from_slice(data);
+    chunk
+}
+
+/// MIDI channel of a track
+fn channel(track: usize) -> u8 {
+    match (track % 15) as u8 {
+        ch if ch >= PERCUSSION_CHANNEL => ch + 1,
+        ch => ch,
+    }
+}
+
+fn tempo(bpm: u32) -> u32 {
+    60_000_000 / bpm.max(1)
+}
+
+/// A meta event with its data
+fn meta(kind: u8, data: &[u8]) -> Vec<u8> {
+    let mut event = vec![META, kind];
+    write_vlq(data.len() as u32, &mut event);
+    event.extend_from_slice(data);
+    event
+}
+
+/// The events of a track, in ticks from the start. Events at the same time keep their order.
+fn track_chunk(mut events: Vec<(u32, Vec<u8>)>) -> Vec<u8> {
+    events.sort_by_key(|(time, _)| *time);
+    let mut data = vec![];
+    let mut now = 0;
+    for (time, event) in events.iter() {
+        write_vlq(time - now, &mut data);
+        data.extend_from_slice(event);
+        now = *time;
+    }
+    write_vlq(0, &mut data);
+    data.extend(meta(END_OF_TRACK, &[]));
+    chunk(b"MTrk", &data)
+}
+
+/// Playback state of a track while the song is written
+#[derive(Copy, Clone, Default)]
+struct Track {
+    /// The sounding MIDI note
+    playing: Option<u8>,
+    voice: Option<u8>,
+    program: Option<u8>,
+}
+
+/// Write the song as a type 1 file. The song is played once through, like the player does
+/// it, with jumps, breaks, note delays and note cuts. A loop of the gFx column plays once.
+/// The velocity of a note is its volume and voices become program changes.
+pub fn write(song: &Song) -> Vec<u8> {
+    let tracks = song.pattern_at(0).tracks();
+    let mut conductor = vec![
+        (0, meta(TRACK_NAME, song.title.as_bytes())),
+        (0, meta(SET_TEMPO, &tempo(song.bpm).to_be_bytes()[1..])),
+    ];
+    let mut events: Vec<Vec<(u32, Vec<u8>)>> = (0..tracks)
+        .map(|t| vec![(0, meta(TRACK_NAME, format!("Track {}", t).as_bytes()))])
+        .collect();
+    let mut state = vec![Track::default(); tracks];
+
+    let (mut speed, mut bpm) = (song.speed, song.bpm);
+    let mut last_bpm = bpm;
+    let (mut pos, mut row, mut time) = (0, 0, 0);
+    let mut played = HashSet::new();
+    while played.insert((pos, row)) {
+        let pattern = song.pattern_at(pos);
+        let (mut jump, mut break_row) = (None, None);
+        let effects = pattern.row(row).cells.iter().filter_map(|c| c.effect);
+        for Effect { code, param } in effects {
+            match code {
+                SET_SPEED if param > 0 && param < 0x20 => speed = param as u32,
+                SET_SPEED if param > 0 => bpm = param as u32,
+                POSITION_JUMP => jump = Some(param as usize),
+                PATTERN_BREAK => break_row = Some(param as usize),
+                _ => {}
+            }
+        }
+        match pattern.row(row).gfx {
+            Some(Effect { code, param }) if code == GFX_SET_SPEED && param > 0 => {
+                speed = param as u32
+            }
+            Some(Effect { code, param }) if code == GFX_SET_TEMPO && param > 0 => {
+                bpm = param as u32
+            }
+            _ => {}
+        }
+        if bpm != last_bpm {
+            conductor.push((time, meta(SET_TEMPO, &tempo(bpm).to_be_bytes()[1..])));
+            last_bpm = bpm;
+        }
+
+        for (track, cell) in pattern.row(row).cells.iter().enumerate() {
+            let (state, events) = (&mut state[track], &mut events[track]);
+            let channel = channel(track);
+            let (kind, tick) = match cell.effect {
+                Some(Effect {
+                    code: EXTENDED,
+                    param,
+                }) => (param >> 4, (param & 0xF) as u32),
+                _ => (0, 0),
+            };
+            if cell.voice.is_some() {
+                state.voice = cell.voice;
+            }
+
+            // A delay longer than the row skips the note
+            let at = match kind {
+                NOTE_DELAY if tick >= speed => None,
+                NOTE_DELAY => Some(time + tick),
+                _ => Some(time),
+            };
+            if let (Some(at), Some(note)) = (at, cell.note) {
+                if let Some(playing) = state.playing.take() {
+                    events.push((at, vec![NOTE_OFF | channel, playing, 0]));
+                }
+                let key = match note {
+                    Note::Key(key) => Some(key + C0_NOTE).filter(|k| *k <= 0x7F),
+                    Note::Off => None,
+                };
+                if let (Some(key), Some(voice)) = (key, state.voice) {
+                    if state.program != Some(voice) {
+                        events.push((at, vec![PROGRAM_CHANGE | channel, voice & 0x7F]));
+                        state.program = Some(voice);
+                    }
+                    let velocity = (cell.volume.unwrap_or(0xFF) / 2).max(1);
+                    events.push((at, vec![NOTE_ON | channel, key, velocity]));
+                    state.playing = Some(key);
+                }
+            }
+            if kind == NOTE_CUT && tick < speed {
+                if let Some(playing) = state.playing.take() {
+                    events.push((time + tick, vec![NOTE_OFF | channel, playing, 0]));
+                }
+            }
+        }
+
+        time += speed;
+        if jump.is_some() || break_row.is_some() {
+            pos = jump.unwrap_or(pos + 1) % song.order.len();
+            row = break_row.unwrap_or(0);
+            if row >= song.pattern_at(pos).len() {
+                row = 0;
+            }
+        } else {
+            row += 1;
+            if row >= pattern.len() {
+                row = 0;
+                pos = (pos + 1) % song.order.len();
+            }
+        }
+    }
+
+    for (track, state) in state.iter().enumerate() {
+        if let Some(playing) = state.playing {
+            events[track].push((time, vec![NOTE_OFF | channel(track), playing, 0]));
+        }
+    }
+
+    let mut data = chunk(
+        b"MThd",
+        &[
+            [0, 1],
+            (tracks as u16 + 1).to_be_bytes(),
+            (TICKS_PER_BEAT as u16).to_be_bytes(),
+        ]
+        .concat(),
+    );
+    data.extend(track_chunk(conductor));
+    for events in events {
+        data.extend(track_chunk(events));
+    }
+    data
+}
+
+/// A note of the file, in ticks
+#[derive(Copy, Clone, Debug)]
+struct MidiNote {
+    start: u32,
+    end: u32,
+    key: u8,
+    velocity: u8,
+    program: u8,
+}
+
+/// What is read from the file: the notes per track and channel, the tempo changes and the name
+/// of the first track
+#[derive(Default)]
+struct Contents {
+    sources: Vec<Vec<MidiNote>>,
+    tempos: Vec<(u32, u32)>,
+    title: String,
+}
+
+fn read_track(data: &[u8], contents: &mut Contents, first: bool) -> Result<(), Error> {
+    let mut notes: [Vec<MidiNote>; 16] = Default::default();
+    let mut open: Vec<(u8, MidiNote)> = vec![];
+    let mut programs = [0u8; 16];
+    let (mut at, mut time, mut status) = (0, 0, 0);
+    while at < data.len() {
+        time += read_vlq(data, &mut at)?;
+        let byte = *data.get(at).ok_or(Error::Truncated)?;
+        // Running status repeats the last status byte of a channel message
+        if byte & 0x80 != 0 {
+            status = byte;
+            at += 1;
+        } else if status == 0 {
+            return Err(Error::Format);
+        }
+        let arg = |i: usize| data.get(at + i).copied().ok_or(Error::Truncated);
+        let channel = status & 0xF;
+
+        match status {
+            META => {
+                let kind = arg(0)?;
+                at += 1;
+                let len = read_vlq(data, &mut at)? as usize;
+                let bytes = data.get(at..at + len).ok_or(Error::Truncated)?;
+                match kind {
+                    SET_TEMPO if len == 3 => {
+                        let tempo = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+                        contents.tempos.push((time, tempo));
+                    }
+                    TRACK_NAME if first => contents.title = convert::name(bytes),
+                    END_OF_TRACK => break,
+                    _ => {}
+                }
+                at += len;
+            }
+            SYSEX | SYSEX_ESCAPE => {
+                let len = read_vlq(data, &mut at)? as usize;
+                at += len;
+            }
+            _ => match status & 0xF0 {
+                NOTE_ON if arg(1)? > 0 => {
+                    let note = MidiNote {
+                        start: time,
+                        end: time,
+                        key: arg(0)?,
+                        velocity: arg(1)?,
+                        program: programs[channel as usize],
+                    };
+                    open.push((channel, note));
+                    at += 2;
+                }
+                NOTE_ON | NOTE_OFF => {
+                    let key = arg(0)?;
+                    if let Some(idx) = open.iter().position(|(c, n)| *c == channel && n.key == key)
+                    {
+                        let (_, note) = open.remove(idx);
+                        notes[channel as usize].push(MidiNote { end: time, ..note });
+                    }
+                    at += 2;
+                }
+                PROGRAM_CHANGE => {
+                    programs[channel as usize] = arg(0)?;
+                    at += 1;
+                }
+                // Channel pressure
+                0xD0 => at += 1,
+                0xA0 | 0xB0 | 0xE0 => at += 2,
+                _ => return Err(Error::Format),
+            },
+        }
+    }
+
+    // Notes that are never released end with the track
+    for (channel, note) in open {
+        notes[channel as usize].push(MidiNote { end: time, ..note });
+    }
+    for mut notes in notes.into_iter().filter(|n| !n.is_empty()) {
+        notes.sort_by_key(|n| n.start);
+        contents.sources.push(notes);
+    }
+    Ok(())
+}
+
+fn read_contents(data: &[u8]) -> Result<(Contents, u32), Error> {
+    if data.get(..4) != Some(b"MThd") {
+        return Err(Error::Format);
+    }
+    let be32 = |at: usize| -> Result<usize, Error> {
+        let bytes = data.get(at..at + 4).ok_or(Error::Truncated)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize)
+    };
+    let header = data.get(8..14).ok_or(Error::Truncated)?;
+    let division = u16::from_be_bytes([header[4], header[5]]) as u32;
+    // SMPTE time code divisions are not supported
+    if division == 0 || division & 0x8000 != 0 {
+        return Err(Error::Format);
+    }
+
+    let mut contents = Contents::default();
+    let mut at = 8 + be32(4)?;
+    let mut first = true;
+    while at + 8 <= data.len() {
+        let len = be32(at + 4)?;
+        let chunk = data.get(at + 8..at + 8 + len).ok_or(Error::Truncated)?;
+        if &data[at..at + 4] == b"MTrk" {
+            read_track(chunk, &mut contents, first)?;
+            first = false;
+        }
+        at += 8 + len;
+    }
+    Ok((contents, division))
+}
+
+/// BPM that plays the rows at the tempo, at the speed
+fn bpm(tempo: u32, speed: u32, rows_per_beat: usize) -> u32 {
+    (speed as f64 * 2.5 * rows_per_beat as f64 * 1_000_000.0 / tempo.max(1) as f64).round() as u32
+}
+
+/// Convert a type 0 or 1 file. The notes are quantised to rows, with `rows_per_beat` rows
+/// per quarter note. Every channel of every MIDI track gets tracks of its own, as many as it
+/// plays notes at once. Programs select the voice slot, which gets a placeholder voice.
+pub fn read(data: &[u8], rows_per_beat: usize) -> Result<Import, Error> {
+    let (contents, division) = read_contents(data)?;
+    let mut report = Report::default();
+    let rows_per_beat = rows_per_beat.clamp(1, MAX_ROWS_PER_BEAT);
+    let row_of =
+        |tick: u32| (tick as f64 * rows_per_beat as f64 / division as f64).round() as usize;
+
+    // The speed gives an exported file a tick per MIDI tick
+    let mut song = Song::new();
+    song.title = contents.title.clone();
+    song.speed = (TICKS_PER_BEAT / rows_per_beat as u32).max(1);
+    let bpm_of = |tempo: u32, report: &mut Report| {
+        let bpm = bpm(tempo, song.speed, rows_per_beat);
+        if !(0x20..=0xFF).contains(&bpm) {
+            report.warn(format!("Tempo {} BPM clamped to 20-FF", bpm));
+        }
+        bpm.clamp(0x20, 0xFF)
+    };
+    let mut tempos = contents.tempos.clone();
+    tempos.sort_by_key(|(time, _)| *time);
+    let initial = match tempos.first() {
+        Some((0, tempo)) => *tempo,
+        _ => DEFAULT_TEMPO,
+    };
+    song.bpm = bpm_of(initial, &mut report);
+
+    // Each track plays one note at a time, and knows the end row of its last note
+    let mut lanes: Vec<(usize, Vec<(usize, Cell)>)> = vec![];
+    let mut dropped = 0;
+    let mut out_of_range = 0;
+    let mut voices = [false; MAX_VOICES];
+    for notes in contents.sources.iter() {
+        let first_lane = lanes.len();
+        for note in notes {
+            let Some(key) = note
+                .key
+                .checked_sub(C0_NOTE)
+                .filter(|k| *k <= Note::MAX_KEY)
+            else {
+                out_of_range += 1;
+                continue;
+            };
+            let start = row_of(note.start);
+            let end = row_of(note.end).max(start + 1);
+            let lane = match (first_lane..lanes.len()).find(|l| lanes[*l].0 <= start) {
+                Some(lane) => lane,
+                None if lanes.len() < MAX_TRACKS => {
+                    lanes.push((0, vec![]));
+                    lanes.len() - 1
+                }
+                None => {
+                    dropped += 1;
+                    continue;
+                }
+            };
+
+            let volume =
+                (note.velocity.min(MAX_VELOCITY) as u32 * 0xFF / MAX_VELOCITY as u32) as u8;
+            let (last, cells) = &mut lanes[lane];
+            // The note replaces the note off of the note before it
+            cells.retain(|(row, _)| *row != start);
+            cells.push((
+                start,
+                Cell {
+                    note: Some(Note::Key(key)),
+                    voice: Some(note.program),
+                    volume: (volume < 0xFF).then_some(volume),
+                    effect: None,
+                },
+            ));
+            cells.push((
+                end,
+                Cell {
+                    note: Some(Note::Off),
+                    ..Cell::default()
+                },
+            ));
+            *last = end;
+            voices[note.program as usize] = true;
+        }
+    }
+    if out_of_range > 0 {
+        report.warn(format!(
+            "{} notes outside of C-0 to B-9 dropped",
+            out_of_range
+        ));
+    }
+    if dropped > 0 {
+        report.warn(format!(
+            "{} notes dropped, more than {} notes play at once",
+            dropped, MAX_TRACKS
+        ));
+    }
+
+    let tracks = lanes.len().max(1);
+    let rows = lanes.iter().map(|(end, _)| end + 1).max().unwrap_or(1);
+    let mut patterns = rows.div_ceil(DEFAULT_ROWS);
+    if patterns > MAX_PATTERNS {
+        report.warn(format!(
+            "Rows after pattern {:02X} dropped",
+            MAX_PATTERNS - 1
+        ));
+        patterns = MAX_PATTERNS;
+    }
+    song.patterns = vec![Pattern::new(DEFAULT_ROWS, tracks); patterns];
+    song.order = OrderList::new((0..patterns).collect());
+    for (track, (_, cells)) in lanes.iter().enumerate() {
+        for (row, cell) in cells
+            .iter()
+            .filter(|(row, _)| *row / DEFAULT_ROWS < patterns)
+        {
+            *song.patterns[row / DEFAULT_ROWS].cell_mut(row % DEFAULT_ROWS, track) = *cell;
+        }
+    }
+
+    // Tempo changes go into the gFx column
+    for (time, tempo) in tempos.iter().filter(|(time, _)| *time > 0) {
+        let row = row_of(*time);
+        let Some(pattern) = song.patterns.get_mut(row / DEFAULT_ROWS) else {
+            continue;
+        };
+        let gfx = Effect {
+            code: GFX_SET_TEMPO,
+            param: bpm_of(*tempo, &mut report) as u8,
+        };
+        let at = convert::Location {
+            pattern: row / DEFAULT_ROWS,
+            row: row % DEFAULT_ROWS,
+            track: tracks,
+        };
+        let row = pattern.row_mut(row % DEFAULT_ROWS);
+        convert::set_global_effect(&mut row.gfx, gfx, at, &mut report);
+    }
+
+    let mut texts = vec![String::new(); MAX_VOICES];
+    for slot in (0..MAX_VOICES).filter(|s| voices[*s]) {
+        texts[slot] = PLACEHOLDER_VOICE.to_string();
+        song.voices[slot] = voice::parse(PLACEHOLDER_VOICE);
+        report.warn(format!(
+            "Program {:02X} replaced by a placeholder voice",
+            slot
+        ));
+    }
+
+    Ok(Import {
+        song,
+        voices: texts,
+        report,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::song::player::tests::song;
+
+    fn cell(import: &Import, pattern: usize, row: usize, track: usize) -> String {
+        import.song.patterns[pattern].cell(row, track).to_string()
+    }
+
+    #[test]
+    fn vlq_test() {
+        for (n, bytes) in [
+            (0, vec![0]),
+            (0x7F, vec![0x7F]),
+            (0x80, vec![0x81, 0]),
+            (0x0FFFFFFF, vec![0xFF, 0xFF, 0xFF, 0x7F]),
+        ] {
+            let mut out = vec![];
+            write_vlq(n, &mut out);
+            assert_eq!(out, bytes);
+            assert_eq!(read_vlq(&bytes, &mut 0), Ok(n));
+        }
+        assert_eq!(read_vlq(&[0x81], &mut 0), Err(Error::Truncated));
+        assert_eq!(channel(8), 8);
+        assert_eq!(channel(9), 10);
+    }
+
+    #[test]
+    fn write_test() {
+        let mut song = song(
+            "C-4 01 -- --- : E-4 02 80 --- : ---
+             --- -- -- --- : === -- -- --- : 1A0
+             D-4 -- -- ED3 : G-4 02 -- EC2 : ---",
+        );
+        song.title = "tune".to_string();
+        let data = write(&song);
+        assert_eq!(&data[..4], b"MThd");
+        // Type 1, a tempo track and two tracks, 24 ticks per quarter note
+        assert_eq!(&data[8..14], &[0, 1, 0, 3, 0, 24]);
+
+        let (contents, division) = read_contents(&data).unwrap();
+        assert_eq!(division, TICKS_PER_BEAT);
+        assert_eq!(contents.title, "tune");
+        assert_eq!(contents.tempos, vec![(0, tempo(125)), (6, tempo(0xA0))]);
+
+        let notes: Vec<Vec<_>> = contents
+            .sources
+            .iter()
+            .map(|s| {
+                s.iter()
+                    .map(|n| (n.start, n.end, n.key, n.velocity, n.program))
+                    .collect()
+            })
+            .collect();
+        assert_eq!(
+            notes,
+            vec![
+                // The delayed note plays until the end of the song
+                vec![(0, 15, 60, 0x7F, 1), (15, 18, 62, 0x7F, 1)],
+                vec![(0, 6, 64, 0x40, 2), (12, 14, 67, 0x7F, 2)],
+            ]
+        );
+    }
+
+    #[test]
+    fn read_test() {
+        let song = song(
+            "C-4 01 -- --- : E-4 02 80 --- : ---
+             --- -- -- --- : --- -- -- --- : ---
+             D-4 -- -- --- : G-4 -- -- --- : 1A0
+             === -- -- --- : === -- -- --- : ---",
+        );
+        let import = read(&write(&song), 4).unwrap();
+        assert_eq!(import.song.speed, 6);
+        assert_eq!(import.song.bpm, 125);
+        assert_eq!(import.song.order.entries(), &[0]);
+        assert_eq!(import.voices[1], PLACEHOLDER_VOICE);
+        assert!(import.song.voices[2].is_some());
+        assert_eq!(cell(&import, 0, 0, 0), "C-4 01 -- ---");
+        assert_eq!(cell(&import, 0, 0, 1), "E-4 02 80 ---");
+        assert_eq!(cell(&import, 0, 2, 0), "D-4 01 -- ---");
+        assert_eq!(cell(&import, 0, 3, 0), "=== -- -- ---");
+        assert_eq!(cell(&import, 0, 2, 1), "G-4 02 -- ---");
+        assert_eq!(
+            import.song.patterns[0].row(2).gfx,
+            Some("1A0".parse().unwrap())
+        );
+
+        // Half the resolution, twice the ticks per row
+        let import = read(&write(&song), 2).unwrap();
+        assert_eq!(import.song.speed, 12);
+        assert_eq!(import.song.bpm, 125);
+        assert_eq!(cell(&import, 0, 1, 0), "D-4 01 -- ---");
+        assert_eq!(cell(&import, 0, 2, 0), "=== -- -- ---");
+    }
+
+    #[test]
+    fn chord_test() {
+        // A type 0 file with a three note chord and a note on another channel
+        let mut events = vec![];
+        for (key, delta) in [(60, 0), (64, 0), (67, 0)] {
+            events.extend([delta, NOTE_ON, key, 0x7F]);
+        }
+        events.extend([0, NOTE_ON | 1, 72, 0x7F]);
+        // Running status, note on with velocity 0 is a note off
+        events.extend([96, NOTE_ON, 60, 0, 0, 64, 0, 0, 67, 0]);
+        events.extend([0, 0xFF, END_OF_TRACK, 0]);
+        let data = [
+            chunk(b"MThd", &[0, 0, 0, 1, 0, 96]),
+            chunk(b"MTrk", &events),
+        ]
+        .concat();
+
+        let import = read(&data, 4).unwrap();
+        assert_eq!(import.song.patterns[0].tracks(), 4);
+        assert_eq!(import.song.bpm, 120);
+        for (track, note) in ["C-4", "E-4", "G-4", "C-5"].iter().enumerate() {
+            assert_eq!(cell(&import, 0, 0, track), format!("{} 00 -- ---", note));
+        }
+        assert_eq!(cell(&import, 0, 4, 0), "=== -- -- ---");
+        // The note that is never released ends with the track
+        assert_eq!(cell(&import, 0, 4, 3), "=== -- -- ---");
+        assert_eq!(
+            import.report.to_string(),
+            "Program 00 replaced by a placeholder voice\n"
+        );
+    }
+
+    #[test]
+    fn error_test() {
+        assert_eq!(read(b"RIFF", 4).unwrap_err(), Error::Format);
+        let smpte = chunk(b"MThd", &[0, 1, 0, 1, 0xE7, 0x28]);
+        assert_eq!(read(&smpte, 4).unwrap_err(), Error::Format);
+        let data = [
+            chunk(b"MThd", &[0, 1, 0, 1, 0, 96]),
+            b"MTrk\0\0\0\x08\0\x90\x3C".to_vec(),
+        ]
+        .concat();
+        assert_eq!(read(&data, 4).unwrap_err(), Error::Truncated);
+    }
+}
@@ -0,0 +1,99 @@
+// Copyright (C) 2025 Anders Sundman <anders@4zm.org>
+//
+// This file is part of RTRK - The Rust Tracker
+//
+// RTRK is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// RTRK is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with RTRK. If not, see <https://www.gnu.org/licenses/>.
+/// The order list is the sequence of patterns that make up a song. The same pattern can be
+/// played at several positions.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OrderList {
+    entries: Vec<usize>,
+}
+
+impl OrderList {
+    /// There is always at least one entry
+    pub fn new(entries: Vec<usize>) -> Self {
+        assert!(!entries.is_empty(), "Order list can't be empty");
+        Self { entries }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Pattern index at a song position
+    pub fn get(&self, pos: usize) -> usize {
+        self.entries[pos]
+    }
+
+    pub fn set(&mut self, pos: usize, pattern: usize) {
+        self.entries[pos] = pattern;
+    }
+
+    pub fn entries(&self) -> &[usize] {
+        &self.entries
+    }
+
+    pub fn insert(&mut self, pos: usize, pattern: usize) {
+        self.entries.insert(pos, pattern);
+    }
+
+    /// Returns false if it's the last entry, it can't be removed
+    pub fn remove(&mut self, pos: usize) -> bool {
+        if self.entries.len() == 1 {
+            return false;
+        }
+        self.entries.remove(pos);
+        true
+    }
+
+    /// Play the same pattern again after the position
+    pub fn duplicate(&mut self, pos: usize) {
+        self.entries.insert(pos + 1, self.entries[pos]);
+    }
+}
+
+impl Default for OrderList {
+    fn default() -> Self {
+        OrderList::new(vec![0])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn order_list_test() {
+        let mut order = OrderList::default();
+        assert_eq!(order.entries(), &[0]);
+
+        order.insert(1, 3);
+        order.insert(0, 2);
+        assert_eq!(order.entries(), &[2, 0, 3]);
+
+        order.duplicate(1);
+        assert_eq!(order.entries(), &[2, 0, 0, 3]);
+
+        order.set(3, 1);
+        assert_eq!(order.get(3), 1);
+
+        assert!(order.remove(0));
+        assert!(order.remove(0));
+        assert!(order.remove(0));
+        assert_eq!(order.entries(), &[1]);
+        assert!(!order.remove(0));
+        assert_eq!(order.len(), 1);
+    }
+}
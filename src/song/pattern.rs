@@ -0,0 +1,436 @@
+// Copyright (C) 2025 Anders Sundman <anders@4zm.org>
+//
+// This file is part of RTRK - The Rust Tracker
+//
+// RTRK is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// RTRK is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with RTRK. If not, see <https://www.gnu.org/licenses/>.
+
+/// The tracker pattern data model and its text notation. A cell is written as
+/// `C#4 01 A0 101`: note, voice, volume and effect (code + parameter). Empty fields are dashes.
+use crate::synth::Frequency;
+use std::fmt;
+use std::str::FromStr;
+
+pub const DEFAULT_ROWS: usize = 0x40;
+pub const MAX_ROWS: usize = 0x100;
+pub const DEFAULT_TRACKS: usize = 4;
+/// Most tracks a pattern can have, imported modules may use more than the default
+pub const MAX_TRACKS: usize = 0x20;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ParseError {
+    Note,
+    Voice,
+    Volume,
+    Effect,
+    Cell,
+    Row,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::Note => write!(f, "Invalid note"),
+            ParseError::Voice => write!(f, "Invalid voice"),
+            ParseError::Volume => write!(f, "Invalid volume"),
+            ParseError::Effect => write!(f, "Invalid effect"),
+            ParseError::Cell => write!(f, "Invalid cell"),
+            ParseError::Row => write!(f, "Invalid row"),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+const NOTE_NAMES: [&str; 12] = [
+    "C-", "C#", "D-", "D#", "E-", "F-", "F#", "G-", "G#", "A-", "A#", "B-",
+];
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Note {
+    /// Semitones above C-0
+    Key(u8),
+    Off,
+}
+
+impl Note {
+    pub const MAX_KEY: u8 = 10 * 12 - 1;
+    const A4: u8 = 4 * 12 + 9;
+
+    pub fn frequency(&self) -> Option<Frequency> {
+        match self {
+            Note::Key(k) => Some(frequency(*k as f32)),
+            Note::Off => None,
+        }
+    }
+}
+
+/// Frequency of a pitch in semitones above C-0. Fractions of a semitone are allowed.
+pub fn frequency(pitch: f32) -> Frequency {
+    Frequency(440.0 * 2.0_f32.powf((pitch - Note::A4 as f32) / 12.0))
+}
+
+impl fmt::Display for Note {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Note::Key(k) => write!(f, "{}{}", NOTE_NAMES[*k as usize % 12], k / 12),
+            Note::Off => write!(f, "==="),
+        }
+    }
+}
+
+impl FromStr for Note {
+    type Err = ParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "===" {
+            return Ok(Note::Off);
+        }
+        if s.len() != 3 || !s.is_char_boundary(2) {
+            return Err(ParseError::Note);
+        }
+        let semitone = NOTE_NAMES
+            .iter()
+            .position(|n| *n == &s[..2])
+            .ok_or(ParseError::Note)?;
+        let octave = s[2..].parse::<u8>().map_err(|_| ParseError::Note)?;
+        Ok(Note::Key(octave * 12 + semitone as u8))
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Effect {
+    /// Single hex digit
+    pub code: u8,
+    pub param: u8,
+}
+
+impl fmt::Display for Effect {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:X}{:02X}", self.code, self.param)
+    }
+}
+
+impl FromStr for Effect {
+    type Err = ParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.len() != 3 || !s.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(ParseError::Effect);
+        }
+        Ok(Effect {
+            code: u8::from_str_radix(&s[..1], 16).map_err(|_| ParseError::Effect)?,
+            param: u8::from_str_radix(&s[1..], 16).map_err(|_| ParseError::Effect)?,
+        })
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Default)]
+pub struct Cell {
+    pub note: Option<Note>,
+    /// Index into the voice list
+    pub voice: Option<u8>,
+    pub volume: Option<u8>,
+    pub effect: Option<Effect>,
+}
+
+impl Cell {
+    /// Width of a cell in text notation
+    pub const WIDTH: usize = 13;
+
+    #[allow(dead_code)] // Not needed by the editor so far
+    pub fn is_empty(&self) -> bool {
+        *self == Cell::default()
+    }
+}
+
+fn parse_hex_byte(s: &str, err: ParseError) -> Result<Option<u8>, ParseError> {
+    match s {
+        "--" => Ok(None),
+        _ if s.len() == 2 && s.chars().all(|c| c.is_ascii_hexdigit()) => {
+            Ok(Some(u8::from_str_radix(s, 16).map_err(|_| err)?))
+        }
+        _ => Err(err),
+    }
+}
+
+impl fmt::Display for Cell {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.note {
+            Some(n) => write!(f, "{}", n)?,
+            None => write!(f, "---")?,
+        }
+        match self.voice {
+            Some(v) => write!(f, " {:02X}", v)?,
+            None => write!(f, " --")?,
+        }
+        match self.volume {
+            Some(v) => write!(f, " {:02X}", v)?,
+            None => write!(f, " --")?,
+        }
+        match self.effect {
+            Some(e) => write!(f, " {}", e),
+            None => write!(f, " ---"),
+        }
+    }
+}
+
+impl FromStr for Cell {
+    type Err = ParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = s.split_whitespace().collect();
+        let [note, voice, volume, effect] = fields[..] else {
+            return Err(ParseError::Cell);
+        };
+        Ok(Cell {
+            note: match note {
+                "---" => None,
+                _ => Some(note.parse()?),
+            },
+            voice: parse_hex_byte(voice, ParseError::Voice)?,
+            volume: parse_hex_byte(volume, ParseError::Volume)?,
+            effect: match effect {
+                "---" => None,
+                _ => Some(effect.parse()?),
+            },
+        })
+    }
+}
+
+/// One line of the pattern, a cell per track and the global effect
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Row {
+    pub cells: Vec<Cell>,
+    pub gfx: Option<Effect>,
+}
+
+impl Row {
+    pub fn new(tracks: usize) -> Self {
+        Self {
+            cells: vec![Cell::default(); tracks],
+            gfx: None,
+        }
+    }
+}
+
+/// Written as the cells followed by the global effect, separated by ` : `
+impl fmt::Display for Row {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for cell in self.cells.iter() {
+            write!(f, "{} : ", cell)?;
+        }
+        match self.gfx {
+            Some(e) => write!(f, "{}", e),
+            None => write!(f, "---"),
+        }
+    }
+}
+
+impl FromStr for Row {
+    type Err = ParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut fields: Vec<&str> = s.split(':').map(|f| f.trim()).collect();
+        let gfx = fields.pop().ok_or(ParseError::Row)?;
+        if fields.is_empty() {
+            return Err(ParseError::Row);
+        }
+        Ok(Row {
+            cells: fields.iter().map(|c| c.parse()).collect::<Result<_, _>>()?,
+            gfx: match gfx {
+                "---" => None,
+                _ => Some(gfx.parse()?),
+            },
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Pattern {
+    rows: Vec<Row>,
+    tracks: usize,
+}
+
+impl Pattern {
+    pub fn new(rows: usize, tracks: usize) -> Self {
+        Self {
+            rows: vec![Row::new(tracks); rows],
+            tracks,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.rows.len()
+    }
+
+    pub fn tracks(&self) -> usize {
+        self.tracks
+    }
+
+    pub fn row(&self, idx: usize) -> &Row {
+        &self.rows[idx]
+    }
+
+    pub fn row_mut(&mut self, idx: usize) -> &mut Row {
+        &mut self.rows[idx]
+    }
+
+    pub fn cell(&self, row: usize, track: usize) -> &Cell {
+        &self.rows[row].cells[track]
+    }
+
+    pub fn cell_mut(&mut self, row: usize, track: usize) -> &mut Cell {
+        &mut self.rows[row].cells[track]
+    }
+
+    /// Change the number of rows. Rows are added or removed at the end.
+    pub fn set_len(&mut self, rows: usize) {
+        self.rows.resize(rows, Row::new(self.tracks));
+    }
+
+    /// Parse one row per line. All rows must have the same number of tracks.
+    pub fn parse_rows(text: &str) -> Result<Self, ParseError> {
+        let rows: Vec<Row> = text
+            .lines()
+            .filter(|l| !l.trim().is_empty())
+            .map(|l| l.parse())
+            .collect::<Result<_, _>>()?;
+        let tracks = rows.first().map_or(DEFAULT_TRACKS, |r| r.cells.len());
+        if rows.iter().any(|r| r.cells.len() != tracks) {
+            return Err(ParseError::Row);
+        }
+        Ok(Self { rows, tracks })
+    }
+}
+
+impl Default for Pattern {
+    fn default() -> Self {
+        Pattern::new(DEFAULT_ROWS, DEFAULT_TRACKS)
+    }
+}
+
+impl FromStr for Pattern {
+    type Err = ParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse_rows(s)
+    }
+}
+
+/// One row per line
+impl fmt::Display for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for row in self.rows.iter() {
+            writeln!(f, "{}", row)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn note_test() {
+        assert_eq!("C#4".parse(), Ok(Note::Key(4 * 12 + 1)));
+        assert_eq!("A-5".parse(), Ok(Note::Key(5 * 12 + 9)));
+        assert_eq!("===".parse(), Ok(Note::Off));
+        assert_eq!("H-4".parse::<Note>(), Err(ParseError::Note));
+        assert_eq!("C#".parse::<Note>(), Err(ParseError::Note));
+
+        assert_eq!(Note::Key(0).to_string(), "C-0");
+        assert_eq!(Note::Key(Note::MAX_KEY).to_string(), "B-9");
+
+        assert_eq!(Note::Key(4 * 12 + 9).frequency(), Some(Frequency(440.0)));
+        assert_eq!(Note::Key(5 * 12 + 9).frequency(), Some(Frequency(880.0)));
+        assert_eq!(Note::Off.frequency(), None);
+    }
+
+    #[test]
+    fn cell_test() {
+        let cell: Cell = "C#4 01 A0 101".parse().expect("");
+        assert_eq!(
+            cell,
+            Cell {
+                note: Some(Note::Key(4 * 12 + 1)),
+                voice: Some(1),
+                volume: Some(0xA0),
+                effect: Some(Effect { code: 1, param: 1 }),
+            }
+        );
+        assert_eq!(cell.to_string(), "C#4 01 A0 101");
+
+        let empty: Cell = "--- -- -- ---".parse().expect("");
+        assert!(empty.is_empty());
+        assert_eq!(empty.to_string(), "--- -- -- ---");
+        assert_eq!(empty.to_string().len(), Cell::WIDTH);
+
+        let cell: Cell = "--- -- FF 105".parse().expect("");
+        assert_eq!(cell.volume, Some(0xFF));
+        assert_eq!(cell.effect, Some(Effect { code: 1, param: 5 }));
+
+        assert_eq!("C#4 01 A0".parse::<Cell>(), Err(ParseError::Cell));
+        assert_eq!("C#4 1 A0 101".parse::<Cell>(), Err(ParseError::Voice));
+        assert_eq!("C#4 01 G0 101".parse::<Cell>(), Err(ParseError::Volume));
+        assert_eq!("C#4 01 A0 1X1".parse::<Cell>(), Err(ParseError::Effect));
+    }
+
+    #[test]
+    fn row_test() {
+        let text = "C#4 01 A0 101 : --- -- -- --- : --- -- -- --- : A-5 04 20 --- : 2FF";
+        let row: Row = text.parse().expect("");
+        assert_eq!(row.cells.len(), 4);
+        assert_eq!(row.cells[3].note, Some(Note::Key(5 * 12 + 9)));
+        assert_eq!(
+            row.gfx,
+            Some(Effect {
+                code: 2,
+                param: 0xFF
+            })
+        );
+        assert_eq!(row.to_string(), text);
+
+        assert_eq!(
+            Row::new(2).to_string(),
+            "--- -- -- --- : --- -- -- --- : ---"
+        );
+        assert_eq!("2FF".parse::<Row>(), Err(ParseError::Row));
+    }
+
+    #[test]
+    fn pattern_test() {
+        let mut pattern = Pattern::new(0x10, 4);
+        assert_eq!(pattern.len(), 0x10);
+        assert_eq!(pattern.tracks(), 4);
+
+        pattern.cell_mut(3, 1).note = Some(Note::Off);
+        pattern.row_mut(5).gfx = Some(Effect {
+            code: 0,
+            param: 0x20,
+        });
+
+        let parsed = Pattern::parse_rows(&pattern.to_string()).expect("");
+        assert_eq!(parsed, pattern);
+        assert_eq!(pattern.to_string().parse(), Ok(pattern.clone()));
+
+        // Resizing keeps the existing rows
+        pattern.set_len(4);
+        assert_eq!(pattern.len(), 4);
+        assert_eq!(pattern.cell(3, 1).note, Some(Note::Off));
+        pattern.set_len(0x40);
+        assert_eq!(pattern.len(), 0x40);
+        assert!(pattern.row(5).gfx.is_none());
+
+        assert_eq!(
+            Pattern::parse_rows("--- -- -- --- : ---\n--- -- -- --- : --- -- -- --- : ---"),
+            Err(ParseError::Row)
+        );
+    }
+}
@@ -0,0 +1,994 @@
+// Copyright (C) 2025 Anders Sundman <anders@4zm.org>
+//
+// This file is part of RTRK - The Rust Tracker
+//
+// RTRK is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// RTRK is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with RTRK. If not, see <https://www.gnu.org/licenses/>.
+
+use std::collections::VecDeque;
+use std::f32::consts::TAU;
+
+use crate::song::pattern::{frequency, Cell, Effect, Note};
+use crate::song::Song;
+use crate::synth::{
+    cutoff_hz, Error, Message, MessageSink, Time, Timed, Velocity, Voice, MAX_CUTOFF_HZ,
+    SAMPLE_RATE,
+};
+
+/// Number of row start times kept for looking up the playhead
+const HISTORY_LEN: usize = 0x100;
+
+/// Highest pitch a slide can reach, in semitones above C-0
+const MAX_PITCH: f32 = Note::MAX_KEY as f32;
+
+/// Vibrato positions per cycle
+const VIBRATO_STEPS: u8 = 64;
+
+// Effect codes, see the README for the parameters
+const ARPEGGIO: u8 = 0x0;
+const SLIDE_UP: u8 = 0x1;
+const SLIDE_DOWN: u8 = 0x2;
+const TONE_PORTAMENTO: u8 = 0x3;
+const VIBRATO: u8 = 0x4;
+const VOLUME_SLIDE: u8 = 0xA;
+const POSITION_JUMP: u8 = 0xB;
+const PATTERN_BREAK: u8 = 0xD;
+const EXTENDED: u8 = 0xE;
+const SET_SPEED: u8 = 0xF;
+
+// Extended effects, the command is the high nibble of the parameter
+const RETRIGGER: u8 = 0x9;
+const NOTE_CUT: u8 = 0xC;
+const NOTE_DELAY: u8 = 0xD;
+
+// Global effect codes of the gFx column
+pub(super) const GFX_SET_SPEED: u8 = 0x0;
+pub(super) const GFX_SET_TEMPO: u8 = 0x1;
+pub(super) const GFX_SET_VOLUME: u8 = 0x2;
+pub(super) const GFX_VOLUME_FADE: u8 = 0x3;
+const GFX_SET_CUTOFF: u8 = 0x4;
+const GFX_CUTOFF_SWEEP: u8 = 0x5;
+const GFX_LOOP: u8 = 0x6;
+
+/// Gain of the track volume
+fn gain(volume: u8) -> f32 {
+    volume as f32 / 0xFF as f32
+}
+
+fn master_gain(volume: u8) -> f32 {
+    volume as f32 / 0xFF as f32
+}
+
+/// Slide a value up by `step` times the high nibble of the parameter or, if it is zero, down
+/// by the low nibble
+fn slide(value: u8, param: u8, step: u8) -> u8 {
+    match param >> 4 {
+        0 => value.saturating_sub((param & 0xF) * step),
+        x => value.saturating_add(x * step),
+    }
+}
+
+/// Playback state of a track. Track n plays on synth channel n.
+#[derive(Clone, Debug, Default)]
+struct Track {
+    /// Voice of the last cell that set one, used by notes without a voice
+    voice: Option<u8>,
+    /// Voice of the sounding note, None when the track is silent
+    playing: Option<Voice>,
+    /// In semitones above C-0
+    pitch: f32,
+    volume: u8,
+    /// Effect of the current row
+    effect: Option<Effect>,
+    porta_target: f32,
+    porta_speed: u8,
+    vibrato: u8,
+    vibrato_phase: u8,
+    /// The synth plays another pitch than `pitch` because of arpeggio or vibrato
+    modulated: bool,
+    delayed: Option<Cell>,
+}
+
+impl Track {
+    fn start_row(&mut self, song: &Song, channel: usize, cell: &Cell, msgs: &mut Vec<Message>) {
+        self.effect = cell.effect;
+        self.delayed = None;
+        if self.modulated {
+            self.modulated = false;
+            self.set_pitch(self.pitch, channel, msgs);
+        }
+
+        match cell.effect {
+            Some(Effect {
+                code: EXTENDED,
+                param,
+            }) if param >> 4 == NOTE_DELAY && param & 0xF > 0 => {
+                self.delayed = Some(*cell);
+                return;
+            }
+            Some(Effect {
+                code: TONE_PORTAMENTO,
+                param,
+            }) => {
+                if param > 0 {
+                    self.porta_speed = param;
+                }
+                // Slide the playing note towards the new one instead of playing it
+                if let (Some(Note::Key(key)), Some(_)) = (cell.note, &self.playing) {
+                    self.porta_target = key as f32;
+                    let cell = Cell {
+                        note: None,
+                        ..*cell
+                    };
+                    self.trigger(song, channel, &cell, msgs);
+                    return;
+                }
+            }
+            _ => {}
+        }
+        self.trigger(song, channel, cell, msgs);
+    }
+
+    fn trigger(&mut self, song: &Song, channel: usize, cell: &Cell, msgs: &mut Vec<Message>) {
+        if cell.voice.is_some() {
+            self.voice = cell.voice;
+        }
+        if let Some(volume) = cell.volume {
+            self.volume = volume;
+        }
+
+        match cell.note {
+            Some(Note::Off) => self.cut(channel, msgs),
+            Some(Note::Key(key)) => {
+                self.pitch = key as f32;
+                self.porta_target = self.pitch;
+                self.vibrato_phase = 0;
+                self.volume = cell.volume.unwrap_or(0xFF);
+                self.playing = self.voice.and_then(|v| song.voices[v as usize].clone());
+                match self.playing {
+                    Some(_) => {
+                        msgs.push(Message::SetVolume(channel, gain(self.volume)));
+                        self.retrigger(channel, msgs);
+                    }
+                    None => msgs.push(Message::Stop(channel)),
+                }
+            }
+            None if cell.volume.is_some() && self.playing.is_some() => {
+                msgs.push(Message::SetVolume(channel, gain(self.volume)));
+            }
+            None => {}
+        }
+    }
+
+    fn retrigger(&self, channel: usize, msgs: &mut Vec<Message>) {
+        if let Some(voice) = &self.playing {
+            let freq = frequency(self.pitch + self.vibrato_offset());
+            msgs.push(Message::NoteOn(voice.clone(), channel, freq, Velocity::MAX));
+        }
+    }
+
+    fn cut(&mut self, channel: usize, msgs: &mut Vec<Message>) {
+        self.playing = None;
+        msgs.push(Message::Stop(channel));
+    }
+
+    fn set_pitch(&self, pitch: f32, channel: usize, msgs: &mut Vec<Message>) {
+        if self.playing.is_some() {
+            msgs.push(Message::SetFrequency(channel, frequency(pitch)));
+        }
+    }
+
+    fn vibrato_offset(&self) -> f32 {
+        if !self.modulated {
+            return 0.0;
+        }
+        let phase = self.vibrato_phase as f32 / VIBRATO_STEPS as f32;
+        (phase * TAU).sin() * (self.vibrato & 0xF) as f32 / 16.0
+    }
+
+    /// Carry out the effect of the row for the tick. Continuous effects start after tick 0.
+    fn process_tick(&mut self, song: &Song, tick: u32, channel: usize, msgs: &mut Vec<Message>) {
+        let Some(Effect { code, param }) = self.effect else {
+            return;
+        };
+        let (x, y) = (param >> 4, param & 0xF);
+
+        match code {
+            ARPEGGIO if tick > 0 && param != 0 => {
+                let offset = [0, x, y][tick as usize % 3];
+                self.modulated = true;
+                self.set_pitch(self.pitch + offset as f32, channel, msgs);
+            }
+            SLIDE_UP if tick > 0 => {
+                self.pitch = (self.pitch + param as f32 / 16.0).min(MAX_PITCH);
+                self.set_pitch(self.pitch, channel, msgs);
+            }
+            SLIDE_DOWN if tick > 0 => {
+                self.pitch = (self.pitch - param as f32 / 16.0).max(0.0);
+                self.set_pitch(self.pitch, channel, msgs);
+            }
+            TONE_PORTAMENTO if tick > 0 => {
+                let step = self.porta_speed as f32 / 16.0;
+                self.pitch = match self.pitch < self.porta_target {
+                    true => (self.pitch + step).min(self.porta_target),
+                    false => (self.pitch - step).max(self.porta_target),
+                };
+                self.set_pitch(self.pitch, channel, msgs);
+            }
+            VIBRATO if tick > 0 => {
+                if x > 0 {
+                    self.vibrato = (self.vibrato & 0x0F) | x << 4;
+                }
+                if y > 0 {
+                    self.vibrato = (self.vibrato & 0xF0) | y;
+                }
+                self.vibrato_phase = (self.vibrato_phase + (self.vibrato >> 4)) % VIBRATO_STEPS;
+                self.modulated = true;
+                self.set_pitch(self.pitch + self.vibrato_offset(), channel, msgs);
+            }
+            VOLUME_SLIDE if tick > 0 && self.playing.is_some() => {
+                self.volume = slide(self.volume, param, 4);
+                msgs.push(Message::SetVolume(channel, gain(self.volume)));
+            }
+            EXTENDED => match x {
+                RETRIGGER if tick > 0 && y > 0 && tick.is_multiple_of(y as u32) => {
+                    self.retrigger(channel, msgs)
+                }
+                NOTE_CUT if tick == y as u32 && self.playing.is_some() => self.cut(channel, msgs),
+                NOTE_DELAY if tick == y as u32 => {
+                    if let Some(cell) = self.delayed.take() {
+                        self.trigger(song, channel, &cell, msgs);
+                    }
+                }
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+}
+
+/// Where in the song the playback is
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Playhead {
+    pub pos: usize,
+    pub row: usize,
+    /// How far into the row, [0,1]
+    pub fraction: f32,
+}
+
+/// Steps through the order list and patterns of a song and schedules the notes on the synth.
+/// All timing is in audio clock samples, so the caller only needs to call `advance` often
+/// enough to stay ahead of the audio thread.
+pub struct Player {
+    pos: usize,
+    row: usize,
+    tick: u32,
+    speed: u32,
+    bpm: u32,
+    next_tick: f64,
+    tracks: Vec<Track>,
+    jump: Option<usize>,
+    break_row: Option<usize>,
+    /// Global effect of the current row
+    gfx: Option<Effect>,
+    master_volume: u8,
+    master_cutoff: u8,
+    /// Song position and row the loop effect jumps back to
+    loop_start: (usize, usize),
+    /// Remaining repeats of the active loop
+    loop_count: u8,
+    /// The next row is a repeat of the active loop
+    looping: bool,
+    /// Times the song has played to the end and started over
+    passes: usize,
+    history: VecDeque<(u64, usize, usize)>,
+    msgs: Vec<Message>,
+}
+
+impl Player {
+    /// Start playing the song from the song position at the given sample time
+    pub fn new(song: &Song, pos: usize, start: u64) -> Self {
+        Self {
+            pos: pos.min(song.order.len() - 1),
+            row: 0,
+            tick: 0,
+            speed: song.speed,
+            bpm: song.bpm,
+            next_tick: start as f64,
+            tracks: vec![],
+            jump: None,
+            break_row: None,
+            gfx: None,
+            master_volume: 0xFF,
+            master_cutoff: 0xFF,
+            loop_start: (pos, 0),
+            loop_count: 0,
+            looping: false,
+            passes: 0,
+            history: VecDeque::with_capacity(HISTORY_LEN),
+            // A previous song may have left the master section changed
+            msgs: vec![
+                Message::SetMasterGain(1.0),
+                Message::SetMasterCutoff(MAX_CUTOFF_HZ),
+            ],
+        }
+    }
+
+    /// Like classic trackers, 125 BPM is 50 ticks per second
+    fn samples_per_tick(&self) -> f64 {
+        SAMPLE_RATE as f64 * 2.5 / self.bpm as f64
+    }
+
+    /// Schedule all ticks that start before the sample time. The song may be edited between
+    /// calls, changes are picked up from the next row.
+    pub fn advance(
+        &mut self,
+        song: &Song,
+        sink: &mut dyn MessageSink,
+        until: u64,
+    ) -> Result<(), Error> {
+        let mut result = Ok(());
+        while (self.next_tick.round() as u64) < until {
+            self.process_tick(song);
+
+            let time = self.next_tick.round() as u64;
+            for msg in self.msgs.drain(..) {
+                if let Err(e) = sink.schedule(Timed {
+                    time: Time::Sample(time),
+                    msg,
+                }) {
+                    result = Err(e);
+                }
+            }
+
+            self.next_tick += self.samples_per_tick();
+            self.tick += 1;
+            if self.tick >= self.speed {
+                self.tick = 0;
+                self.next_row(song);
+            }
+        }
+        result
+    }
+
+    fn process_tick(&mut self, song: &Song) {
+        if self.tick == 0 {
+            self.start_row(song);
+        }
+        for (channel, track) in self.tracks.iter_mut().enumerate() {
+            track.process_tick(song, self.tick, channel, &mut self.msgs);
+        }
+        self.process_gfx_tick();
+    }
+
+    fn start_row(&mut self, song: &Song) {
+        // The song may have been edited since the last row
+        if self.pos >= song.order.len() {
+            self.pos = 0;
+        }
+        // Skip to the next pattern with rows, the row is silent if no pattern has any
+        for _ in 0..song.order.len() {
+            if self.row < song.pattern_at(self.pos).len() {
+                break;
+            }
+            self.pos = (self.pos + 1) % song.order.len();
+            self.row = 0;
+        }
+        let pattern = song.pattern_at(self.pos);
+        if self.row >= pattern.len() {
+            return;
+        }
+
+        let time = self.next_tick.round() as u64;
+        if self.history.len() == HISTORY_LEN {
+            self.history.pop_front();
+        }
+        self.history.push_back((time, self.pos, self.row));
+
+        let cells = &pattern.row(self.row).cells;
+        if self.tracks.len() < cells.len() {
+            self.tracks.resize(cells.len(), Track::default());
+        }
+
+        for (channel, cell) in cells.iter().enumerate() {
+            self.tracks[channel].start_row(song, channel, cell, &mut self.msgs);
+
+            // Effects on the song rather than on the track
+            match cell.effect {
+                Some(Effect {
+                    code: SET_SPEED,
+                    param,
+                }) if param > 0 => match param < 0x20 {
+                    true => self.speed = param as u32,
+                    false => self.bpm = param as u32,
+                },
+                Some(Effect {
+                    code: POSITION_JUMP,
+                    param,
+                }) => self.jump = Some(param as usize),
+                Some(Effect {
+                    code: PATTERN_BREAK,
+                    param,
+                }) => self.break_row = Some(param as usize),
+                _ => {}
+            }
+        }
+
+        self.start_gfx(pattern.row(self.row).gfx);
+    }
+
+    fn start_gfx(&mut self, gfx: Option<Effect>) {
+        self.gfx = gfx;
+        let Some(Effect { code, param }) = gfx else {
+            return;
+        };
+
+        match code {
+            GFX_SET_SPEED if param > 0 => self.speed = param as u32,
+            GFX_SET_TEMPO if param > 0 => self.bpm = param as u32,
+            GFX_SET_VOLUME => {
+                self.master_volume = param;
+                self.msgs.push(Message::SetMasterGain(master_gain(param)));
+            }
+            GFX_SET_CUTOFF => {
+                self.master_cutoff = param;
+                self.msgs.push(Message::SetMasterCutoff(cutoff_hz(param)));
+            }
+            GFX_LOOP if param == 0 => self.loop_start = (self.pos, self.row),
+            GFX_LOOP => {
+                // Start counting on the first pass, the loop ends when the count runs out
+                match self.loop_count {
+                    0 => self.loop_count = param,
+                    _ => self.loop_count -= 1,
+                }
+                if self.loop_count > 0 {
+                    self.jump = Some(self.loop_start.0);
+                    self.break_row = Some(self.loop_start.1);
+                    self.looping = true;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Fades and sweeps start after tick 0 like the track effects
+    fn process_gfx_tick(&mut self) {
+        let Some(Effect { code, param }) = self.gfx else {
+            return;
+        };
+        if self.tick == 0 {
+            return;
+        }
+
+        match code {
+            GFX_VOLUME_FADE => {
+                self.master_volume = slide(self.master_volume, param, 4);
+                let gain = master_gain(self.master_volume);
+                self.msgs.push(Message::SetMasterGain(gain));
+            }
+            GFX_CUTOFF_SWEEP => {
+                self.master_cutoff = slide(self.master_cutoff, param, 1);
+                let cutoff = cutoff_hz(self.master_cutoff);
+                self.msgs.push(Message::SetMasterCutoff(cutoff));
+            }
+            _ => {}
+        }
+    }
+
+    fn next_row(&mut self, song: &Song) {
+        let from = (self.pos, self.row);
+        let looping = std::mem::take(&mut self.looping);
+        if self.jump.is_some() || self.break_row.is_some() {
+            self.pos = self.jump.take().unwrap_or(self.pos + 1) % song.order.len();
+            self.row = self.break_row.take().unwrap_or(0);
+            if self.row >= song.pattern_at(self.pos).len() {
+                self.row = 0;
+            }
+        } else {
+            self.row += 1;
+            if self.pos < song.order.len() && self.row >= song.pattern_at(self.pos).len() {
+                self.row = 0;
+                self.pos = (self.pos + 1) % song.order.len();
+            }
+        }
+
+        // Going back to a row that has been played starts the song over, unless it is a loop
+        if (self.pos, self.row) <= from && !looping {
+            self.passes += 1;
+        }
+    }
+
+    /// Times the song has played to the end, including jumps back to an earlier position
+    pub fn passes(&self) -> usize {
+        self.passes
+    }
+
+    /// The song position and row that is heard at the sample time
+    pub fn playhead(&self, now: u64) -> Option<Playhead> {
+        let idx = self.history.iter().rposition(|(time, _, _)| *time <= now)?;
+        let (start, pos, row) = self.history[idx];
+        let end = match self.history.get(idx + 1) {
+            Some((next, _, _)) => *next,
+            None => self.scheduled_until(),
+        };
+        Some(Playhead {
+            pos,
+            row,
+            fraction: match end > start {
+                true => ((now - start) as f32 / (end - start) as f32).min(1.0),
+                false => 0.0,
+            },
+        })
+    }
+
+    /// Sample time up to which the song has been scheduled
+    pub fn scheduled_until(&self) -> u64 {
+        self.next_tick.round() as u64
+    }
+
+    /// Silence all tracks after the notes that have already been scheduled. The master section
+    /// is restored for auditioning voices.
+    pub fn stop(&self, sink: &mut dyn MessageSink) -> Result<(), Error> {
+        let time = self.scheduled_until();
+        let mut result = Ok(());
+        let stops = (0..self.tracks.len()).map(Message::Stop);
+        let master = [
+            Message::SetMasterGain(1.0),
+            Message::SetMasterCutoff(MAX_CUTOFF_HZ),
+        ];
+        for msg in stops.chain(master) {
+            if let Err(e) = sink.schedule(Timed {
+                time: Time::Sample(time),
+                msg,
+            }) {
+                result = Err(e);
+            }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::song::pattern::Pattern;
+    use crate::synth::offline::OfflineSynth;
+    use crate::synth::{Oscillator, Voice, BLOCK_LEN};
+
+    pub const SQUARE: Voice = Voice {
+        osc: Oscillator::Square,
+        env: None,
+        lp: None,
+        hp: None,
+        vel_amp: 1.0,
+        vel_cutoff: 0.0,
+    };
+
+    /// A song with a square wave voice in slot 1 and the given rows as the only pattern
+    pub fn song(rows: &str) -> Song {
+        let mut song = Song::new();
+        song.patterns[0] = Pattern::parse_rows(rows).unwrap();
+        song.voices[1] = Some(SQUARE);
+        song
+    }
+
+    /// Headless playback of the song
+    pub fn render(song: &Song, samples: usize) -> Vec<f32> {
+        let mut synth = OfflineSynth::new(song.pattern_at(0).tracks());
+        let mut player = Player::new(song, 0, 0);
+        let mut out = vec![0.0; samples];
+        for block in out.chunks_mut(BLOCK_LEN) {
+            let until = synth.time() + block.len() as u64;
+            player.advance(song, &mut synth, until).unwrap();
+            synth.render(block);
+        }
+        assert_eq!(synth.poll_errors(), vec![]);
+        out
+    }
+
+    /// Samples per row at the default tempo and speed
+    pub const ROW: usize = 44100 * 6 / 50;
+
+    /// Samples per tick at the default tempo
+    pub const TICK: usize = 44100 / 50;
+
+    /// Samples of a tick in the default speed
+    pub fn tick(out: &[f32], row: usize, tick: usize) -> &[f32] {
+        let start = row * ROW + tick * TICK;
+        &out[start..start + TICK]
+    }
+
+    /// Frequency of a square wave, from the number of zero crossings
+    pub fn freq(out: &[f32]) -> f32 {
+        let crossings = out
+            .windows(2)
+            .filter(|w| (w[0] < 0.0) != (w[1] < 0.0))
+            .count();
+        crossings as f32 / 2.0 * 44100.0 / out.len() as f32
+    }
+
+    pub fn peak(out: &[f32]) -> f32 {
+        out.iter().fold(0.0, |m: f32, s| m.max(s.abs()))
+    }
+
+    fn assert_near(actual: f32, expected: f32) {
+        let tolerance = expected * 0.03;
+        assert!(
+            (actual - expected).abs() <= tolerance,
+            "{} is not close to {}",
+            actual,
+            expected
+        );
+    }
+
+    /// Frequency of A-6 shifted by semitones
+    fn a6(semitones: f32) -> f32 {
+        1760.0 * 2.0_f32.powf(semitones / 12.0)
+    }
+
+    /// Records the scheduled messages instead of playing them
+    impl MessageSink for Vec<Timed> {
+        fn schedule(&mut self, timed: Timed) -> Result<(), Error> {
+            self.push(timed);
+            Ok(())
+        }
+    }
+
+    /// Peak amplitude of each row
+    pub fn row_peaks(out: &[f32]) -> Vec<f32> {
+        out.chunks(ROW)
+            .map(|r| r.iter().fold(0.0, |m: f32, s| m.max(s.abs())))
+            .collect()
+    }
+
+    #[test]
+    fn play_test() {
+        let song = song(
+            "A-4 01 -- --- : ---
+             --- -- 80 --- : ---
+             === -- -- --- : ---
+             --- -- -- --- : ---
+             C-4 -- 80 --- : ---
+             --- -- -- --- : ---",
+        );
+        let peaks = row_peaks(&render(&song, 6 * ROW));
+
+        // Notes sustain until the note off and the voice of the track is remembered. A volume
+        // without a note changes the volume of the playing note.
+        assert!(peaks[0] > 0.9);
+        assert!(peaks[1] > 0.4 && peaks[1] < 0.6);
+        assert_eq!(peaks[2], 0.0);
+        assert_eq!(peaks[3], 0.0);
+        assert!(peaks[4] > 0.4 && peaks[4] < 0.6);
+    }
+
+    #[test]
+    fn order_test() {
+        let mut song = song("A-4 01 -- --- : ---\n=== -- -- --- : ---");
+        song.patterns
+            .push(Pattern::parse_rows("--- -- -- --- : ---").unwrap());
+        song.order.insert(1, 1);
+        song.order.insert(0, 1);
+        assert_eq!(song.order.entries(), &[1, 0, 1]);
+
+        // Silent, note, note off, silent and then the song loops
+        let peaks = row_peaks(&render(&song, 5 * ROW));
+        assert_eq!(peaks, vec![0.0, peaks[1], 0.0, 0.0, 0.0]);
+        assert!(peaks[1] > 0.9);
+    }
+
+    #[test]
+    fn tempo_test() {
+        let mut song = song("A-4 01 -- --- : ---\n=== -- -- --- : ---");
+        song.speed = 3;
+        song.bpm = 250;
+
+        // A quarter of the default row length
+        let out = render(&song, ROW);
+        let row = ROW / 4;
+        assert!(out[..row].iter().any(|s| s.abs() > 0.9));
+        assert!(out[row..2 * row].iter().all(|&s| s == 0.0));
+    }
+
+    #[test]
+    fn stop_test() {
+        let song = song("A-4 01 -- --- : ---\n--- -- -- --- : ---");
+        let mut synth = OfflineSynth::new(1);
+        let mut player = Player::new(&song, 0, 0);
+        player.advance(&song, &mut synth, 100).unwrap();
+        player.stop(&mut synth).unwrap();
+
+        // Stops after the scheduled tick
+        let mut out = vec![0.0; ROW];
+        synth.render(&mut out);
+        let until = player.scheduled_until() as usize;
+        assert!(out[..until].iter().any(|s| s.abs() > 0.9));
+        assert!(out[until..].iter().all(|&s| s == 0.0));
+    }
+
+    #[test]
+    fn playhead_test() {
+        let song = song("--- -- -- --- : ---\n".repeat(4).as_str());
+        let mut synth = OfflineSynth::new(1);
+        let mut player = Player::new(&song, 0, 1000);
+        assert_eq!(player.playhead(1000), None);
+
+        player
+            .advance(&song, &mut synth, 1000 + 5 * ROW as u64)
+            .unwrap();
+        let playhead = |time: usize| player.playhead(1000 + time as u64).unwrap();
+        assert_eq!(
+            playhead(0),
+            Playhead {
+                pos: 0,
+                row: 0,
+                fraction: 0.0
+            }
+        );
+        assert_eq!(playhead(2 * ROW + ROW / 4).row, 2);
+        assert_eq!(playhead(2 * ROW + ROW / 4).fraction, 0.25);
+        assert_eq!(playhead(4 * ROW).row, 0);
+    }
+
+    #[test]
+    fn arpeggio_test() {
+        let out = render(&song("A-6 01 -- 047 : ---\n--- -- -- --- : ---"), 2 * ROW);
+        for (t, semitones) in [0.0, 4.0, 7.0, 0.0, 4.0, 7.0].iter().enumerate() {
+            assert_near(freq(tick(&out, 0, t)), a6(*semitones));
+        }
+        // Back to the base note when the effect ends
+        assert_near(freq(tick(&out, 1, 0)), a6(0.0));
+    }
+
+    #[test]
+    fn slide_test() {
+        // One semitone per tick after the first tick of the row
+        let out = render(
+            &song("A-6 01 -- 110 : ---\n--- -- -- --- : ---\n--- -- -- 220 : ---"),
+            3 * ROW,
+        );
+        assert_near(freq(tick(&out, 0, 0)), a6(0.0));
+        assert_near(freq(tick(&out, 0, 2)), a6(2.0));
+        assert_near(freq(&out[ROW..2 * ROW]), a6(5.0));
+        assert_near(freq(tick(&out, 2, 5)), a6(-5.0));
+    }
+
+    #[test]
+    fn tone_portamento_test() {
+        let out = render(
+            &song(
+                "A-5 01 -- --- : ---
+                 A-6 -- -- 320 : ---
+                 --- -- -- 300 : ---
+                 --- -- -- --- : ---",
+            ),
+            4 * ROW,
+        );
+        // Two semitones per tick towards the new note, which is not played
+        assert_near(freq(tick(&out, 1, 0)), a6(-12.0));
+        assert_near(freq(tick(&out, 1, 5)), a6(-2.0));
+        // Continues with the same speed and stops at the target note
+        assert_near(freq(&out[3 * ROW..]), a6(0.0));
+    }
+
+    #[test]
+    fn vibrato_test() {
+        let out = render(&song("A-6 01 -- 48F : ---\n--- -- -- --- : ---"), 2 * ROW);
+        let freqs: Vec<f32> = (1..6).map(|t| freq(tick(&out, 0, t))).collect();
+        assert!(freqs.iter().any(|&f| f > a6(0.5)));
+        assert!(freqs.iter().any(|&f| f < a6(-0.5)));
+        assert_near(freq(&out[ROW..]), a6(0.0));
+    }
+
+    #[test]
+    fn volume_slide_test() {
+        let out = render(&song("A-4 01 40 A40 : ---\nA-4 -- 80 A08 : ---"), 2 * ROW);
+        // 0x40 + 5 * 0x10
+        assert!((peak(tick(&out, 0, 5)) - 144.0 / 255.0).abs() < 0.01);
+        // 0x80 - 4 * 0x20
+        assert_eq!(peak(tick(&out, 1, 4)), 0.0);
+
+        // The volume column and slides set the level of voices without velocity sensitivity
+        let mut song = song("A-4 01 40 A40 : ---\n--- -- 80 --- : ---");
+        song.voices[1] = Some(Voice {
+            vel_amp: 0.0,
+            ..SQUARE
+        });
+        let out = render(&song, 2 * ROW);
+        assert!((peak(tick(&out, 0, 0)) - 64.0 / 255.0).abs() < 0.01);
+        assert!((peak(tick(&out, 0, 5)) - 144.0 / 255.0).abs() < 0.01);
+        assert!((peak(tick(&out, 1, 0)) - 128.0 / 255.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn note_cut_and_delay_test() {
+        let out = render(&song("A-4 01 -- EC3 : ---\nA-4 01 -- ED2 : ---"), 2 * ROW);
+        let peaks: Vec<f32> = (0..12)
+            .map(|t| peak(&out[t * TICK..(t + 1) * TICK]))
+            .collect();
+        assert!(peaks[..3].iter().all(|&p| p > 0.9));
+        assert!(peaks[3..8].iter().all(|&p| p == 0.0));
+        assert!(peaks[8..].iter().all(|&p| p > 0.9));
+    }
+
+    #[test]
+    fn retrigger_test() {
+        let song = song("A-4 01 -- E92 : ---\n--- -- -- --- : ---");
+        let out = render(&song, 2 * ROW);
+
+        // The note starts over every second tick of the row, and then plays on
+        let plain = render(
+            &self::song("A-4 01 -- --- : ---\n--- -- -- --- : ---"),
+            2 * ROW,
+        );
+        let onset = out.iter().zip(plain.iter()).position(|(a, b)| a != b);
+        assert_eq!(onset, Some(2 * TICK));
+        for t in [2, 4] {
+            assert_eq!(out[t * TICK..(t + 2) * TICK], plain[..2 * TICK]);
+        }
+        assert_eq!(out[4 * TICK..2 * ROW], plain[..2 * ROW - 4 * TICK]);
+
+        let mut msgs: Vec<Timed> = vec![];
+        Player::new(&song, 0, 0)
+            .advance(&song, &mut msgs, 2 * ROW as u64)
+            .unwrap();
+        let note_ons: Vec<Time> = msgs
+            .iter()
+            .filter(|t| matches!(t.msg, Message::NoteOn(..)))
+            .map(|t| t.time)
+            .collect();
+        let ticks = [0, 2, 4].map(|t| Time::Sample((t * TICK) as u64));
+        assert_eq!(note_ons, ticks);
+    }
+
+    #[test]
+    fn set_speed_test() {
+        // Speed 3 at 250 BPM is a quarter of the default row length
+        let song = song("A-4 01 -- F03 : --- -- -- FFA : ---\n=== -- -- --- : --- -- -- --- : ---");
+        let out = render(&song, ROW);
+        assert!(peak(&out[..ROW / 4]) > 0.9);
+        assert_eq!(peak(&out[ROW / 4..ROW / 2]), 0.0);
+    }
+
+    #[test]
+    fn jump_and_break_test() {
+        // Every row of a pattern plays a note of its own, a semitone apart
+        let rows = |first: u8, fx: &str| {
+            let rows: Vec<String> = (0..4)
+                .map(|r| format!("{} 01 -- {} : ---", Note::Key(first + r), fx))
+                .collect();
+            Pattern::parse_rows(&rows.join("\n")).unwrap()
+        };
+        let mut song = Song::new();
+        song.voices[1] = Some(SQUARE);
+        song.patterns = vec![rows(6 * 12, "B02"), rows(6 * 12 + 4, "---")];
+        let mut brk = rows(6 * 12 + 8, "---");
+        brk.cell_mut(0, 0).effect = Some("D03".parse().unwrap());
+        song.patterns.push(brk);
+        song.order.insert(1, 1);
+        song.order.insert(2, 2);
+
+        // Jump to position 2, which breaks to row 3 of the next position, wrapping around
+        let out = render(&song, 4 * ROW);
+        for (r, key) in [0, 8, 3, 8].iter().enumerate() {
+            let semitones = *key as f32 - 9.0;
+            assert_near(freq(&out[r * ROW..(r + 1) * ROW]), a6(semitones));
+        }
+
+        let mut msgs: Vec<Timed> = vec![];
+        let mut player = Player::new(&song, 0, 0);
+        player.advance(&song, &mut msgs, 4 * ROW as u64).unwrap();
+        let playhead: Vec<_> = (0..4)
+            .map(|r| player.playhead((r * ROW) as u64).unwrap())
+            .map(|p| (p.pos, p.row))
+            .collect();
+        assert_eq!(playhead, vec![(0, 0), (2, 0), (0, 3), (2, 0)]);
+        // Each break that wraps around to the start of the order list starts another pass
+        assert_eq!(player.passes(), 2);
+    }
+
+    #[test]
+    fn empty_patterns_test() {
+        // Nothing to play, but playback goes on without getting stuck
+        let mut song = song("A-4 01 -- --- : ---");
+        song.patterns[0].set_len(0);
+        song.patterns.push(Pattern::new(0, 1));
+        song.order.insert(1, 1);
+        assert!(render(&song, 2 * ROW).iter().all(|&s| s == 0.0));
+    }
+
+    #[test]
+    fn gfx_speed_and_tempo_test() {
+        // 250 BPM halves the row and then speed 3 halves it again
+        let song = song(
+            "A-4 01 -- --- : 1FA
+             === -- -- --- : 003
+             A-4 -- -- --- : ---",
+        );
+        let out = render(&song, ROW);
+        assert!(peak(&out[..ROW / 2]) > 0.9);
+        assert_eq!(peak(&out[ROW / 2..3 * ROW / 4]), 0.0);
+        assert!(peak(&out[3 * ROW / 4..]) > 0.9);
+    }
+
+    #[test]
+    fn gfx_master_volume_test() {
+        let song = song(
+            "A-4 01 -- --- : 280
+             --- -- -- --- : 30F
+             --- -- -- --- : ---",
+        );
+        let out = render(&song, 3 * ROW);
+        assert!((peak(&out[..ROW]) - 0x80 as f32 / 255.0).abs() < 0.01);
+        // Fades out at 0x3C per tick
+        assert!(peak(tick(&out, 1, 1)) > peak(tick(&out, 1, 2)));
+        assert_eq!(peak(tick(&out, 1, 3)), 0.0);
+        assert_eq!(peak(&out[2 * ROW..]), 0.0);
+    }
+
+    #[test]
+    fn gfx_filter_test() {
+        let song = song(
+            "A-4 01 -- --- : 420
+             --- -- -- --- : 5F0
+             --- -- -- --- : ---",
+        );
+        let out = render(&song, 3 * ROW);
+        assert!(peak(tick(&out, 0, 5)) < 0.25);
+        assert!(peak(tick(&out, 1, 5)) > 2.0 * peak(tick(&out, 1, 1)));
+        // The sweep stops with the effect
+        assert!((peak(tick(&out, 2, 5)) - peak(tick(&out, 1, 5))).abs() < 0.05);
+    }
+
+    #[test]
+    fn gfx_loop_test() {
+        let song = song(
+            "--- -- -- --- : ---
+             --- -- -- --- : 600
+             --- -- -- --- : ---
+             --- -- -- --- : 602
+             --- -- -- --- : ---",
+        );
+        let mut msgs: Vec<Timed> = vec![];
+        let mut player = Player::new(&song, 0, 0);
+        player.advance(&song, &mut msgs, 12 * ROW as u64).unwrap();
+
+        // Rows 1-3 are repeated twice and then the song continues and loops
+        let rows: Vec<_> = (0..12)
+            .map(|r| player.playhead((r * ROW) as u64).unwrap().row)
+            .collect();
+        assert_eq!(rows, vec![0, 1, 2, 3, 1, 2, 3, 1, 2, 3, 4, 0]);
+        // Only the end of the song counts as a pass, not the repeats of the loop
+        assert_eq!(player.passes(), 1);
+    }
+
+    #[test]
+    fn stop_restores_master_test() {
+        let song = song("A-4 01 -- --- : 200");
+        let mut msgs: Vec<Timed> = vec![];
+        let mut player = Player::new(&song, 0, 0);
+        player.advance(&song, &mut msgs, 1).unwrap();
+        assert!(msgs.contains(&Timed {
+            time: Time::Sample(0),
+            msg: Message::SetMasterGain(0.0)
+        }));
+
+        msgs.clear();
+        player.stop(&mut msgs).unwrap();
+        let time = player.scheduled_until();
+        assert!(msgs.contains(&Timed {
+            time: Time::Sample(time),
+            msg: Message::SetMasterGain(1.0)
+        }));
+    }
+}
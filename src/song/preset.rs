@@ -0,0 +1,92 @@
+// Copyright (C) 2025 Anders Sundman <anders@4zm.org>
+//
+// This file is part of RTRK - The Rust Tracker
+//
+// RTRK is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// RTRK is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with RTRK. If not, see <https://www.gnu.org/licenses/>.
+
+/// Factory voices to start a song from
+use crate::song::voice;
+use crate::synth;
+
+/// A named voice in designer notation
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Preset {
+    pub name: &'static str,
+    pub text: &'static str,
+}
+
+impl Preset {
+    pub fn voice(&self) -> Option<synth::Voice> {
+        voice::parse(self.text)
+    }
+}
+
+pub const PRESETS: [Preset; 10] = [
+    Preset {
+        name: "Bass",
+        text: "3 01206010 70  88",
+    },
+    Preset {
+        name: "Sub bass",
+        text: "1 01008010 50  80",
+    },
+    Preset {
+        name: "Lead",
+        text: "4 02104020 C0  A4",
+    },
+    Preset {
+        name: "Chip",
+        text: "5 00009000     A0",
+    },
+    Preset {
+        name: "Pad",
+        text: "2 80608080 90  60",
+    },
+    Preset {
+        name: "Pluck",
+        text: "3 00300010 A0  8F",
+    },
+    Preset {
+        name: "Organ",
+        text: "4 00008008 B020A0",
+    },
+    Preset {
+        name: "Kick",
+        text: "1 00100008 50  F0",
+    },
+    Preset {
+        name: "Snare",
+        text: "5 00080006 E070F0",
+    },
+    Preset {
+        name: "Hat",
+        text: "5 00040002   C0F0",
+    },
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn presets_test() {
+        for preset in PRESETS.iter() {
+            assert_eq!(preset.text.len(), voice::TEXT_LEN, "{}", preset.name);
+            assert!(preset.voice().is_some(), "{}", preset.name);
+        }
+        let hat = PRESETS.iter().find(|p| p.name == "Hat").expect("");
+        assert_eq!(hat.voice().expect("").lp, None);
+        assert!(hat.voice().expect("").hp.is_some());
+    }
+}
@@ -0,0 +1,1176 @@
+// Copyright (C) 2025 Anders Sundman <anders@4zm.org>
+//
+// This file is part of RTRK - The Rust Tracker
+//
+// RTRK is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// RTRK is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with RTRK. If not, see <https://www.gnu.org/licenses/>.
+
+/// ProTracker modules. The 1084 byte header holds the title, 31 sample headers and the order
+/// list, followed by the patterns and the sample data. A pattern is 64 rows of 4 byte cells:
+///
+/// ```text
+/// ssss pppp pppp pppp  ssss eeee xxxx xxxx
+/// ```
+///
+/// Sample number (high and low nibble), period, effect and effect parameter. Songs with four
+/// tracks can be written as modules, with the synth voices rendered to samples.
+use std::f32::consts::LN_2;
+use std::fmt;
+
+use crate::song::convert::{self, Error, Import, Location, Report, SAMPLE_VOICE};
+use crate::song::order::OrderList;
+use crate::song::pattern::{Cell, Effect, Note, Pattern, DEFAULT_TRACKS, MAX_TRACKS};
+use crate::song::player::{GFX_SET_SPEED, GFX_SET_TEMPO};
+use crate::song::{voice, Song, DEFAULT_BPM, DEFAULT_SPEED, MAX_VOICES};
+use crate::synth::offline::OfflineSynth;
+use crate::synth::{self, Frequency, Message, MessageSink, Time, Timed, Velocity, SAMPLE_RATE};
+
+pub const ROWS: usize = 64;
+pub const SAMPLES: usize = 31;
+const SAMPLE_HEADER_LEN: usize = 30;
+const SAMPLE_NAME_LEN: usize = 22;
+const SONG_LEN_OFFSET: usize = 950;
+const ORDER_OFFSET: usize = 952;
+const ORDER_LEN: usize = 128;
+const SIGNATURE_OFFSET: usize = 1080;
+const PATTERN_OFFSET: usize = 1084;
+const CELL_LEN: usize = 4;
+/// Most patterns a module can hold, more than 64 needs the `M!K!` signature
+const MAX_PATTERNS: usize = 100;
+const RESTART: u8 = 0x7F;
+
+/// Highest sample volume
+pub(super) const MAX_VOLUME: u8 = 64;
+
+/// Period of C-1, the lowest note of ProTracker. It is imported as C-4.
+const C1_PERIOD: f32 = 856.0;
+const C1_KEY: f32 = 4.0 * 12.0;
+
+/// Periods of C-1 to B-3 as players expect them, the formula is off by one for some notes
+const PERIODS: [u16; 36] = [
+    856, 808, 762, 720, 678, 640, 604, 570, 538, 508, 480, 453, //
+    428, 404, 381, 360, 339, 320, 302, 285, 269, 254, 240, 226, //
+    214, 202, 190, 180, 170, 160, 151, 143, 135, 127, 120, 113,
+];
+
+/// Sample rate that plays a sample at its own pitch at C-2
+const C2_RATE: usize = 8363;
+/// Length of a rendered voice in bytes
+const RENDERED_LEN: usize = C2_RATE / 4;
+/// Voices are rendered with a period of this many samples, so that the loop is seamless
+const RENDERED_CYCLE: usize = 16;
+/// The end of a rendered voice is looped to let notes sustain
+const RENDERED_LOOP: usize = 4 * RENDERED_CYCLE;
+/// Longest sample in bytes, the length is a word count
+const MAX_SAMPLE_LEN: usize = 2 * u16::MAX as usize;
+/// Fastest tempo, it is the parameter of `Fxx`
+const MAX_TEMPO: u32 = 0xFF;
+
+/// Period used to convert pitch effects before a track has played a note, C-2
+const DEFAULT_PERIOD: u16 = 428;
+
+// Effect codes that don't translate one to one
+const TONE_PORTAMENTO: u8 = 0x3;
+const VIBRATO: u8 = 0x4;
+const PORTAMENTO_VOLUME_SLIDE: u8 = 0x5;
+const VIBRATO_VOLUME_SLIDE: u8 = 0x6;
+const SET_VOLUME: u8 = 0xC;
+const POSITION_JUMP: u8 = 0xB;
+const PATTERN_BREAK: u8 = 0xD;
+const EXTENDED: u8 = 0xE;
+const SET_SPEED: u8 = 0xF;
+
+const EFFECT_NAMES: [&str; 16] = [
+    "Arpeggio (0xy)",
+    "Slide up (1xx)",
+    "Slide down (2xx)",
+    "Tone portamento (3xx)",
+    "Vibrato (4xy)",
+    "Volume slide of tone portamento (5xy)",
+    "Volume slide of vibrato (6xy)",
+    "Tremolo (7xy)",
+    "Set panning (8xx)",
+    "Sample offset (9xx)",
+    "Volume slide (Axy)",
+    "Position jump (Bxx)",
+    "Set volume (Cxx)",
+    "Pattern break (Dxx)",
+    "Extended (Exy)",
+    "Set speed (Fxx)",
+];
+
+const EXTENDED_NAMES: [&str; 16] = [
+    "Filter (E0x)",
+    "Fine slide up (E1x)",
+    "Fine slide down (E2x)",
+    "Glissando control (E3x)",
+    "Vibrato waveform (E4x)",
+    "Set finetune (E5x)",
+    "Pattern loop (E6x)",
+    "Tremolo waveform (E7x)",
+    "Set panning (E8x)",
+    "Retrigger (E9x)",
+    "Fine volume slide up (EAx)",
+    "Fine volume slide down (EBx)",
+    "Note cut (ECx)",
+    "Note delay (EDx)",
+    "Pattern delay (EEx)",
+    "Invert loop (EFx)",
+];
+
+/// Number of channels of a module signature, None if it's not a known signature
+fn channels(signature: &[u8]) -> Option<usize> {
+    let digit = |d: &u8| d.is_ascii_digit().then(|| (d - b'0') as usize);
+    match signature {
+        b"M.K." | b"M!K!" | b"FLT4" => Some(4),
+        [n, b'C', b'H', b'N'] => digit(n),
+        [n, m, b'C', b'H'] => Some(digit(n)? * 10 + digit(m)?),
+        _ => None,
+    }
+    .filter(|n| (1..=MAX_TRACKS).contains(n))
+}
+
+struct Sample {
+    name: String,
+    /// In bytes
+    len: usize,
+    /// In 1/8 semitones
+    finetune: i8,
+    volume: u8,
+    /// Start and length of the loop in bytes
+    repeat: Option<(usize, usize)>,
+}
+
+impl Sample {
+    fn empty() -> Self {
+        Self {
+            name: String::new(),
+            len: 0,
+            finetune: 0,
+            volume: 0,
+            repeat: None,
+        }
+    }
+
+    fn parse(header: &[u8]) -> Self {
+        let word = |i: usize| u16::from_be_bytes([header[i], header[i + 1]]) as usize;
+        let finetune = (header[24] & 0xF) as i8;
+        Self {
+            name: convert::name(&header[..SAMPLE_NAME_LEN]),
+            len: word(22) * 2,
+            finetune: if finetune > 7 {
+                finetune - 16
+            } else {
+                finetune
+            },
+            volume: header[25].min(MAX_VOLUME),
+            // A loop of one word is no loop
+            repeat: (word(28) > 1).then_some((word(26) * 2, word(28) * 2)),
+        }
+    }
+
+    /// The sample as played by a sample voice, C-2 is imported as C-5
+    fn sample(&self, data: &[u8]) -> synth::Sample {
+        let rate = C2_RATE as f32 / 2.0 * 2.0_f32.powf(self.finetune as f32 / 96.0);
+        let data = data.iter().map(|b| *b as i8 as f32 / 128.0).collect();
+        convert::sample(data, rate, self.repeat)
+    }
+}
+
+/// The note of a period, finetune is ignored
+fn note(period: u16) -> Note {
+    let key = C1_KEY + 12.0 * (C1_PERIOD / period as f32).log2();
+    Note::Key(key.round().clamp(0.0, Note::MAX_KEY as f32) as u8)
+}
+
+/// The period of a key, for modules that use periods but store notes
+pub(super) fn period(key: u8) -> u16 {
+    (C1_PERIOD * 2.0_f32.powf((C1_KEY - key as f32) / 12.0)).round() as u16
+}
+
+/// A sample volume as a cell volume
+pub(super) fn volume(volume: u8) -> u8 {
+    (volume.min(MAX_VOLUME) as u32 * 0xFF / MAX_VOLUME as u32) as u8
+}
+
+/// What the parameters of the pitch effects are in
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub(super) enum Pitch {
+    /// Amiga period units at the period of the note. The effect depends on the note.
+    Period(u16),
+    /// 1/16 semitones, like rtrk
+    Linear,
+}
+
+/// Pitch effect units in 1/16 semitones
+fn pitch_steps(units: u8, pitch: Pitch) -> u8 {
+    let steps = match pitch {
+        Pitch::Period(period) => units as f32 * 192.0 / (period.max(1) as f32 * LN_2),
+        Pitch::Linear => units as f32,
+    };
+    steps.round().clamp(1.0, 0xFF as f32) as u8
+}
+
+/// Conversion state of a channel
+#[derive(Copy, Clone)]
+struct Channel {
+    period: u16,
+    volume: u8,
+}
+
+impl Default for Channel {
+    fn default() -> Self {
+        Self {
+            period: DEFAULT_PERIOD,
+            volume: MAX_VOLUME,
+        }
+    }
+}
+
+/// Translate a ProTracker effect. Set volume is a volume rather than an effect in rtrk, it's
+/// left to the caller.
+pub(super) fn effect(
+    code: u8,
+    param: u8,
+    pitch: Pitch,
+    at: Location,
+    report: &mut Report,
+) -> Option<Effect> {
+    let (x, y) = (param >> 4, param & 0xF);
+    let param = match code {
+        0x0 if param == 0 => return None,
+        0x1 | 0x2 => pitch_steps(param, pitch),
+        TONE_PORTAMENTO if param == 0 => 0,
+        TONE_PORTAMENTO => pitch_steps(param, pitch),
+        // The vibrato depth is about twice the parameter in pitch units
+        VIBRATO if y == 0 => x << 4,
+        VIBRATO => x << 4 | pitch_steps(2 * y, pitch).min(0xF),
+        PORTAMENTO_VOLUME_SLIDE | VIBRATO_VOLUME_SLIDE => {
+            report.warn_at(format!("{} dropped", EFFECT_NAMES[code as usize]), at);
+            let code = match code {
+                PORTAMENTO_VOLUME_SLIDE => TONE_PORTAMENTO,
+                _ => VIBRATO,
+            };
+            return Some(Effect { code, param: 0 });
+        }
+        0x0 | 0xA | 0xB => param,
+        // Decimal row number
+        PATTERN_BREAK => x * 10 + y,
+        EXTENDED if [0x9, 0xC, 0xD].contains(&x) => param,
+        EXTENDED => {
+            report.warn_at(format!("{} dropped", EXTENDED_NAMES[x as usize]), at);
+            return None;
+        }
+        SET_SPEED if param == 0 => {
+            report.warn_at("Stop (F00) dropped".to_string(), at);
+            return None;
+        }
+        SET_SPEED => param,
+        _ => {
+            report.warn_at(format!("{} dropped", EFFECT_NAMES[code as usize]), at);
+            return None;
+        }
+    };
+    Some(Effect { code, param })
+}
+
+fn cell(
+    data: &[u8],
+    samples: &[Sample],
+    channel: &mut Channel,
+    at: Location,
+    report: &mut Report,
+) -> Cell {
+    let sample = (data[0] & 0xF0) | data[2] >> 4;
+    let period = u16::from_be_bytes([data[0] & 0xF, data[1]]);
+    let (code, param) = (data[2] & 0xF, data[3]);
+
+    let note = (period > 0).then(|| {
+        channel.period = period;
+        note(period)
+    });
+    if let Some(sample) = (sample as usize)
+        .checked_sub(1)
+        .and_then(|s| samples.get(s))
+    {
+        channel.volume = sample.volume;
+    }
+    if code == SET_VOLUME {
+        channel.volume = param.min(MAX_VOLUME);
+    }
+
+    // Samples set the volume of the track, notes without one keep it
+    let voice = (sample > 0).then_some(sample);
+    let set_volume = code == SET_VOLUME
+        || (voice.is_some() && note.is_none())
+        || (note.is_some() && channel.volume < MAX_VOLUME);
+    Cell {
+        note,
+        voice,
+        volume: set_volume.then_some(volume(channel.volume)),
+        effect: match code {
+            SET_VOLUME => None,
+            _ => effect(code, param, Pitch::Period(channel.period), at, report),
+        },
+    }
+}
+
+/// Convert a module. Each sample is played by a sample voice in the slot with the number of the
+/// sample.
+pub fn read(data: &[u8]) -> Result<Import, Error> {
+    let tracks = data
+        .get(SIGNATURE_OFFSET..PATTERN_OFFSET)
+        .and_then(channels)
+        .ok_or(Error::Format)?;
+    let mut report = Report::default();
+
+    let samples: Vec<Sample> = data[20..20 + SAMPLES * SAMPLE_HEADER_LEN]
+        .chunks(SAMPLE_HEADER_LEN)
+        .map(Sample::parse)
+        .collect();
+    let song_len = (data[SONG_LEN_OFFSET] as usize).clamp(1, ORDER_LEN);
+    let order = &data[ORDER_OFFSET..ORDER_OFFSET + ORDER_LEN];
+    // All entries count, also the ones after the end of the song
+    let patterns = *order.iter().max().unwrap_or(&0) as usize + 1;
+
+    let pattern_len = ROWS * tracks * CELL_LEN;
+    let pattern_data = data
+        .get(PATTERN_OFFSET..PATTERN_OFFSET + patterns * pattern_len)
+        .ok_or(Error::Truncated)?;
+
+    let mut song = Song::new();
+    song.title = convert::name(&data[..20]);
+    song.order = OrderList::new(order[..song_len].iter().map(|p| *p as usize).collect());
+
+    // The sample data follows the patterns, the end of the last sample may be missing
+    let mut voices = vec![String::new(); MAX_VOICES];
+    let mut offset = PATTERN_OFFSET + patterns * pattern_len;
+    for (idx, sample) in samples.iter().enumerate().filter(|(_, s)| s.len > 0) {
+        let slot = idx + 1;
+        let sample_data = &data[offset.min(data.len())..(offset + sample.len).min(data.len())];
+        offset += sample.len;
+        if sample_data.len() < sample.len {
+            report.warn(format!(
+                "Sample {:02X} '{}' truncated to {} bytes",
+                slot,
+                sample.name,
+                sample_data.len()
+            ));
+        }
+        if sample_data.is_empty() {
+            continue;
+        }
+        voices[slot] = SAMPLE_VOICE.to_string();
+        song.samples[slot] = Some(sample.sample(sample_data));
+        song.set_voice(slot, voice::parse(SAMPLE_VOICE));
+    }
+
+    let mut channels = vec![Channel::default(); tracks];
+    song.patterns = pattern_data
+        .chunks(pattern_len)
+        .enumerate()
+        .map(|(idx, data)| {
+            let mut pattern = Pattern::new(ROWS, tracks);
+            for (i, data) in data.chunks(CELL_LEN).enumerate() {
+                let (row, track) = (i / tracks, i % tracks);
+                let at = Location {
+                    pattern: idx,
+                    row,
+                    track,
+                };
+                *pattern.cell_mut(row, track) =
+                    cell(data, &samples, &mut channels[track], at, &mut report);
+            }
+            pattern
+        })
+        .collect();
+
+    Ok(Import {
+        song,
+        voices,
+        report,
+    })
+}
+
+/// Why a song can't be written as a module
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ExportError {
+    /// Modules have four channels
+    Tracks(usize),
+    /// More voices are played than a module has samples
+    Voices(usize),
+    /// The order list is longer than the one of a module
+    Order(usize),
+    /// More patterns are played than a module can hold
+    Patterns(usize),
+    /// Modules have 64 rows per pattern
+    Rows { pattern: usize, rows: usize },
+    /// A sample voice with more data than a module sample holds, in bytes
+    SampleLen { voice: usize, len: usize },
+    /// Modules play at most 255 BPM
+    Tempo(u32),
+    /// A cell that can't be represented. The track after the last is the gFx column.
+    Cell(Location, Problem),
+}
+
+/// Why a cell can't be represented
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Problem {
+    /// Outside of the three octaves of ProTracker
+    Note(Note),
+    Effect(Effect),
+    GlobalEffect(Effect),
+    /// A cell has room for one effect, a volume or a note off take one too
+    NoRoom(&'static str),
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::Note(note) => write!(f, "Note {} is outside of C-4 to B-6", note),
+            Problem::Effect(e) => write!(f, "Effect {} can't be represented", e),
+            Problem::GlobalEffect(e) => write!(f, "Global effect {} can't be represented", e),
+            Problem::NoRoom(what) => write!(f, "No free effect column for the {}", what),
+        }
+    }
+}
+
+impl fmt::Display for ExportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExportError::Tracks(n) => {
+                write!(
+                    f,
+                    "The song has {} tracks, modules have {}",
+                    n, DEFAULT_TRACKS
+                )
+            }
+            ExportError::Voices(n) => {
+                write!(
+                    f,
+                    "The song plays {} voices, modules have {} samples",
+                    n, SAMPLES
+                )
+            }
+            ExportError::Order(n) => write!(
+                f,
+                "The order list has {} entries, modules have {}",
+                n, ORDER_LEN
+            ),
+            ExportError::Patterns(n) => write!(
+                f,
+                "The song plays {} patterns, modules have {}",
+                n, MAX_PATTERNS
+            ),
+            ExportError::Rows { pattern, rows } => write!(
+                f,
+                "Pattern {:02X} has {} rows, modules have {}",
+                pattern, rows, ROWS
+            ),
+            ExportError::SampleLen { voice, len } => write!(
+                f,
+                "Voice {:02X} has {} bytes of sample data, modules hold {}",
+                voice, len, MAX_SAMPLE_LEN
+            ),
+            ExportError::Tempo(bpm) => write!(
+                f,
+                "The song plays at {} BPM, modules at most {}",
+                bpm, MAX_TEMPO
+            ),
+            ExportError::Cell(at, problem) => {
+                write!(f, "Pattern {:02X} row {:02X} ", at.pattern, at.row)?;
+                match at.track {
+                    DEFAULT_TRACKS => write!(f, "gFx: {}", problem),
+                    track => write!(f, "track {}: {}", track, problem),
+                }
+            }
+        }
+    }
+}
+
+impl std::error::Error for ExportError {}
+
+/// Pitch effect units in period units at the period, the inverse of `pitch_steps`
+fn period_units(steps: u8, period: u16) -> u8 {
+    if steps == 0 {
+        return 0;
+    }
+    let units = steps as f32 * period as f32 * LN_2 / 192.0;
+    units.round().clamp(1.0, 0xFF as f32) as u8
+}
+
+/// Translate an rtrk effect, None if it does nothing
+fn module_effect(effect: Effect, period: u16) -> Result<Option<Effect>, Problem> {
+    let Effect { code, param } = effect;
+    let (x, y) = (param >> 4, param & 0xF);
+    let param = match code {
+        0x0 if param == 0 => return Ok(None),
+        0x0 | 0xA | 0xB => param,
+        0x1 | 0x2 | TONE_PORTAMENTO => period_units(param, period),
+        VIBRATO => x << 4 | period_units(y, period).div_ceil(2).min(0xF),
+        PATTERN_BREAK if (param as usize) < ROWS => ((param / 10) << 4) | (param % 10),
+        EXTENDED if [0x9, 0xC, 0xD].contains(&x) => param,
+        // F00 stops ProTracker, rtrk ignores it
+        SET_SPEED if param == 0 => return Ok(None),
+        SET_SPEED => param,
+        _ => return Err(Problem::Effect(effect)),
+    };
+    Ok(Some(Effect { code, param }))
+}
+
+/// Translate a global effect, speed and tempo share the set speed effect
+fn module_global_effect(effect: Effect) -> Result<Effect, Problem> {
+    let speed = Effect {
+        code: SET_SPEED,
+        param: effect.param,
+    };
+    match effect.code {
+        GFX_SET_SPEED if (1..0x20).contains(&effect.param) => Ok(speed),
+        GFX_SET_TEMPO if effect.param >= 0x20 => Ok(speed),
+        _ => Err(Problem::GlobalEffect(effect)),
+    }
+}
+
+/// A voice rendered to a sample, with its loop at the end
+fn rendered(voice: &synth::Voice) -> (Sample, Vec<i8>) {
+    let sample = Sample {
+        name: String::new(),
+        len: RENDERED_LEN,
+        finetune: 0,
+        volume: MAX_VOLUME,
+        repeat: Some((RENDERED_LEN - RENDERED_LOOP, RENDERED_LOOP)),
+    };
+    (sample, render(voice))
+}
+
+/// The data of a sample voice as 8-bit bytes. A rate that a finetune plays is kept, others are
+/// resampled to the rate of C-2. Loops are moved to whole words.
+fn recorded(sample: &synth::Sample) -> (Sample, Vec<i8>) {
+    let c2_rate = C2_RATE as f32 / 2.0;
+    let finetune = (96.0 * (sample.rate / c2_rate).log2())
+        .round()
+        .clamp(-8.0, 7.0);
+    // Rates are whole Hz, so the rate of a finetune is within one of it
+    let (finetune, ratio) = match (c2_rate * 2.0_f32.powf(finetune / 96.0) - sample.rate).abs() {
+        diff if diff < 1.0 => (finetune, 1.0),
+        _ => (0.0, c2_rate / sample.rate),
+    };
+
+    let source = &sample.data;
+    let len = (source.len() as f32 * ratio).round() as usize;
+    let mut data: Vec<i8> = (0..len)
+        .map(|i| {
+            let at = i as f32 / ratio;
+            let from = (at as usize).min(source.len() - 1);
+            let next = (from + 1).min(source.len() - 1);
+            let frac = at - from as f32;
+            let level = source[from] * (1.0 - frac) + source[next] * frac;
+            (level * 128.0)
+                .round()
+                .clamp(i8::MIN as f32, i8::MAX as f32) as i8
+        })
+        .collect();
+    // Lengths are in words
+    if data.len() % 2 == 1 {
+        data.push(0);
+    }
+
+    let repeat = sample.repeat.map(|(start, len)| {
+        let start = ((start as f32 * ratio).round() as usize / 2 * 2).min(data.len());
+        let len = ((len as f32 * ratio).round() as usize / 2 * 2).min(data.len() - start);
+        (start, len)
+    });
+    let sample = Sample {
+        name: String::new(),
+        len: data.len(),
+        finetune: finetune as i8,
+        volume: MAX_VOLUME,
+        // A loop of one word is no loop
+        repeat: repeat.filter(|(_, len)| *len > 2),
+    };
+    (sample, data)
+}
+
+/// Render a voice as a sample played at C-2. It is the note held at full velocity, with a
+/// loop at the end.
+fn render(voice: &synth::Voice) -> Vec<i8> {
+    let mut synth = OfflineSynth::new(1);
+    let freq = Frequency(C2_RATE as f32 / RENDERED_CYCLE as f32);
+    let note_on = Message::NoteOn(voice.clone(), 0, freq, Velocity::MAX);
+    if synth
+        .schedule(Timed {
+            time: Time::Sample(0),
+            msg: note_on,
+        })
+        .is_err()
+    {
+        return vec![0; RENDERED_LEN];
+    }
+
+    let ratio = SAMPLE_RATE as f32 / C2_RATE as f32;
+    let mut out = vec![0.0; (RENDERED_LEN as f32 * ratio).ceil() as usize];
+    synth.render(&mut out);
+    // Each byte is the average of the output it covers
+    (0..RENDERED_LEN)
+        .map(|i| {
+            let from = (i as f32 * ratio) as usize;
+            let to = (((i + 1) as f32 * ratio) as usize).min(out.len());
+            let level = out[from..to].iter().sum::<f32>() / (to - from) as f32;
+            (level.clamp(-1.0, 1.0) * i8::MAX as f32).round() as i8
+        })
+        .collect()
+}
+
+/// Put an effect in the first cell of the row without one
+fn place(
+    effects: &mut [Option<Effect>],
+    effect: Effect,
+    what: &'static str,
+    at: Location,
+    errors: &mut Vec<ExportError>,
+) {
+    match effects.iter_mut().find(|e| e.is_none()) {
+        Some(free) => *free = Some(effect),
+        None => errors.push(ExportError::Cell(at, Problem::NoRoom(what))),
+    }
+}
+
+/// Write a song with four tracks as a module. The voices that are played become samples, in
+/// slot order. Volumes, note offs and global effects take the effect column, which has to be
+/// free. All cells that can't be represented are listed.
+pub fn write(song: &Song) -> Result<Vec<u8>, Vec<ExportError>> {
+    let tracks = song.pattern_at(0).tracks();
+    if tracks != DEFAULT_TRACKS {
+        return Err(vec![ExportError::Tracks(tracks)]);
+    }
+    let mut errors = vec![];
+
+    let entries = song.order.entries();
+    if entries.len() > ORDER_LEN {
+        errors.push(ExportError::Order(entries.len()));
+    }
+    let patterns = entries.iter().max().unwrap_or(&0) + 1;
+    if patterns > MAX_PATTERNS {
+        errors.push(ExportError::Patterns(patterns));
+    }
+    let patterns = &song.patterns[..patterns.min(song.patterns.len())];
+
+    let mut used = [false; MAX_VOICES];
+    for pattern in patterns {
+        for row in 0..pattern.len() {
+            for cell in pattern.row(row).cells.iter() {
+                if let Some(v) = cell.voice {
+                    used[v as usize] = true;
+                }
+            }
+        }
+    }
+    let slots: Vec<usize> = (0..MAX_VOICES).filter(|v| used[*v]).collect();
+    if slots.len() > SAMPLES {
+        errors.push(ExportError::Voices(slots.len()));
+    }
+    let sample_of = |voice: u8| slots.iter().position(|s| *s == voice as usize).unwrap_or(0) + 1;
+
+    if song.bpm > MAX_TEMPO {
+        errors.push(ExportError::Tempo(song.bpm));
+    }
+
+    let mut data = vec![0; PATTERN_OFFSET];
+    let title = song.title.as_bytes();
+    data[..title.len().min(20)].copy_from_slice(&title[..title.len().min(20)]);
+
+    let samples: Vec<(Sample, Vec<i8>)> = slots
+        .iter()
+        .take(SAMPLES)
+        .map(|slot| match song.voices[*slot].as_ref() {
+            Some(synth::Voice {
+                osc: synth::Oscillator::Sample(sample),
+                ..
+            }) => recorded(sample),
+            Some(voice) => rendered(voice),
+            None => (Sample::empty(), vec![]),
+        })
+        .collect();
+    for (slot, (sample, _)) in slots.iter().zip(&samples) {
+        if sample.len > MAX_SAMPLE_LEN {
+            errors.push(ExportError::SampleLen {
+                voice: *slot,
+                len: sample.len,
+            });
+        }
+    }
+    for idx in 0..SAMPLES {
+        let header = &mut data[20 + idx * SAMPLE_HEADER_LEN..][..SAMPLE_HEADER_LEN];
+        let mut word = |i: usize, bytes: usize| {
+            header[i..i + 2].copy_from_slice(&((bytes / 2) as u16).to_be_bytes())
+        };
+        let sample = match samples.get(idx) {
+            Some((sample, _)) if sample.len > 0 => sample,
+            // An empty loop is one word long
+            _ => {
+                word(28, 2);
+                continue;
+            }
+        };
+        word(22, sample.len);
+        let (start, len) = sample.repeat.unwrap_or((0, 2));
+        word(26, start);
+        word(28, len);
+        header[24] = sample.finetune as u8 & 0xF;
+        header[25] = sample.volume;
+        let name = format!("Voice {:02X}", slots[idx]);
+        header[..name.len()].copy_from_slice(name.as_bytes());
+    }
+
+    data[SONG_LEN_OFFSET] = entries.len().min(ORDER_LEN) as u8;
+    data[SONG_LEN_OFFSET + 1] = RESTART;
+    for (i, pattern) in entries.iter().take(ORDER_LEN).enumerate() {
+        data[ORDER_OFFSET + i] = *pattern as u8;
+    }
+    let signature = match patterns.len() > 64 {
+        true => b"M!K!",
+        false => b"M.K.",
+    };
+    data[SIGNATURE_OFFSET..PATTERN_OFFSET].copy_from_slice(signature);
+
+    let mut periods = [DEFAULT_PERIOD; DEFAULT_TRACKS];
+    for (idx, pattern) in patterns.iter().enumerate() {
+        if pattern.len() > ROWS {
+            errors.push(ExportError::Rows {
+                pattern: idx,
+                rows: pattern.len(),
+            });
+        }
+        for row in 0..ROWS {
+            let at = |track| Location {
+                pattern: idx,
+                row,
+                track,
+            };
+            let mut cells = [[0u8; CELL_LEN]; DEFAULT_TRACKS];
+            let mut effects = [None; DEFAULT_TRACKS];
+            if row < pattern.len() {
+                for (track, cell) in pattern.row(row).cells.iter().enumerate() {
+                    let (sample, period) = match cell.note {
+                        Some(Note::Key(key)) => {
+                            let period = (key as usize)
+                                .checked_sub(C1_KEY as usize)
+                                .and_then(|i| PERIODS.get(i));
+                            match period {
+                                Some(period) => periods[track] = *period,
+                                None => errors.push(ExportError::Cell(
+                                    at(track),
+                                    Problem::Note(Note::Key(key)),
+                                )),
+                            }
+                            (cell.voice.map_or(0, sample_of), *period.unwrap_or(&0))
+                        }
+                        _ => (0, 0),
+                    };
+                    cells[track] = [
+                        (sample as u8 & 0xF0) | (period >> 8) as u8,
+                        period as u8,
+                        (sample as u8) << 4,
+                        0,
+                    ];
+
+                    // A sample sets the channel to full volume, notes without one keep it
+                    let volume = match cell.note {
+                        Some(Note::Key(_)) => Some(cell.volume.unwrap_or(0xFF))
+                            .filter(|v| *v < 0xFF || cell.voice.is_none()),
+                        _ => cell.volume,
+                    };
+                    let volume = volume.map(|v| Effect {
+                        code: SET_VOLUME,
+                        param: ((v as u32 * MAX_VOLUME as u32 + 0x7F) / 0xFF) as u8,
+                    });
+                    let note_off = (cell.note == Some(Note::Off)).then_some(Effect {
+                        code: EXTENDED,
+                        param: 0xC0,
+                    });
+                    let effect = match cell.effect.map(|e| module_effect(e, periods[track])) {
+                        Some(Err(problem)) => {
+                            errors.push(ExportError::Cell(at(track), problem));
+                            None
+                        }
+                        Some(Ok(effect)) => effect,
+                        None => None,
+                    };
+
+                    let mut wanted = [
+                        (effect, "effect"),
+                        (volume, "volume"),
+                        (note_off, "note off"),
+                    ]
+                    .into_iter()
+                    .filter_map(|(e, what)| e.map(|e| (e, what)));
+                    effects[track] = wanted.next().map(|(e, _)| e);
+                    for (_, what) in wanted {
+                        errors.push(ExportError::Cell(at(track), Problem::NoRoom(what)));
+                    }
+                }
+
+                // The speed and tempo of the song are set where it starts
+                if idx == entries[0] && row == 0 {
+                    let initial = [
+                        (
+                            GFX_SET_SPEED,
+                            song.speed,
+                            DEFAULT_SPEED,
+                            "speed of the song",
+                        ),
+                        (GFX_SET_TEMPO, song.bpm, DEFAULT_BPM, "tempo of the song"),
+                    ];
+                    for (code, value, default, what) in initial {
+                        if value == default {
+                            continue;
+                        }
+                        let Ok(param) = u8::try_from(value) else {
+                            continue;
+                        };
+                        let gfx = Effect { code, param };
+                        match module_global_effect(gfx) {
+                            Ok(effect) => {
+                                place(&mut effects, effect, what, at(tracks), &mut errors)
+                            }
+                            Err(problem) => errors.push(ExportError::Cell(at(tracks), problem)),
+                        }
+                    }
+                }
+                if let Some(gfx) = pattern.row(row).gfx {
+                    match module_global_effect(gfx) {
+                        Ok(effect) => place(
+                            &mut effects,
+                            effect,
+                            "global effect",
+                            at(tracks),
+                            &mut errors,
+                        ),
+                        Err(problem) => errors.push(ExportError::Cell(at(tracks), problem)),
+                    }
+                }
+            }
+
+            // Shorter patterns end with a break, unless the last row already leaves
+            let jumps = effects
+                .iter()
+                .flatten()
+                .any(|e| [POSITION_JUMP, PATTERN_BREAK].contains(&e.code));
+            if row + 1 == pattern.len() && row + 1 < ROWS && !jumps {
+                let effect = Effect {
+                    code: PATTERN_BREAK,
+                    param: 0,
+                };
+                place(
+                    &mut effects,
+                    effect,
+                    "pattern break",
+                    at(tracks),
+                    &mut errors,
+                );
+            }
+
+            for (cell, effect) in cells.iter_mut().zip(effects) {
+                if let Some(Effect { code, param }) = effect {
+                    cell[2] |= code;
+                    cell[3] = param;
+                }
+                data.extend_from_slice(cell);
+            }
+        }
+    }
+
+    for (_, sample) in samples {
+        data.extend(sample.iter().map(|s| *s as u8));
+    }
+
+    match errors.is_empty() {
+        true => Ok(data),
+        false => Err(errors),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::song::player::tests::{render as play, song, ROW, SQUARE};
+
+    const FIXTURE: &[u8] = include_bytes!("../../fixtures/protracker.mod");
+    const FIXTURE_8CHN: &[u8] = include_bytes!("../../fixtures/8chn.mod");
+
+    fn cell(import: &Import, pattern: usize, row: usize, track: usize) -> String {
+        import.song.patterns[pattern].cell(row, track).to_string()
+    }
+
+    #[test]
+    fn note_test() {
+        assert_eq!(note(856), Note::Key(4 * 12));
+        assert_eq!(note(808), Note::Key(4 * 12 + 1));
+        assert_eq!(note(428), Note::Key(5 * 12));
+        assert_eq!(note(113), Note::Key(6 * 12 + 11));
+        assert_eq!(note(1), Note::Key(Note::MAX_KEY));
+        assert_eq!(period(4 * 12), 856);
+        assert_eq!(period(5 * 12 + 1), 404);
+        assert_eq!(volume(MAX_VOLUME), 0xFF);
+        assert_eq!(volume(32), 0x7F);
+    }
+
+    #[test]
+    fn signature_test() {
+        assert_eq!(channels(b"M.K."), Some(4));
+        assert_eq!(channels(b"6CHN"), Some(6));
+        assert_eq!(channels(b"16CH"), Some(16));
+        assert_eq!(channels(b"0CHN"), None);
+        assert_eq!(channels(b"\x01CHN"), None);
+        assert_eq!(channels(b"99CH"), None);
+        assert_eq!(channels(b"RIFF"), None);
+    }
+
+    #[test]
+    fn read_test() {
+        let import = convert::read(FIXTURE).unwrap();
+        assert_eq!(import.song.title, "rtrk fixture");
+        assert_eq!(import.song.order.entries(), &[0, 1, 0]);
+        assert_eq!(import.song.patterns.len(), 2);
+        assert_eq!(import.song.patterns[0].tracks(), 4);
+        assert_eq!(import.voices[1], SAMPLE_VOICE);
+        assert_eq!(SAMPLE_VOICE.len(), voice::TEXT_LEN);
+        assert!(import.song.voices[2].is_some());
+        assert!(import.song.voices[3].is_none());
+
+        // Samples with their data, finetune and loop
+        let kick = import.song.samples[1].as_ref().unwrap();
+        assert_eq!(kick.data.len(), 32);
+        assert_eq!(kick.data[4], 4.0 / 128.0);
+        assert_eq!((kick.rate, kick.repeat), (4182.0, None));
+        let lead = import.song.samples[2].as_ref().unwrap();
+        assert_eq!((lead.rate, lead.repeat), (4151.0, Some((0, 64))));
+        assert_eq!(
+            import.song.voices[2].as_ref().unwrap().osc,
+            synth::Oscillator::Sample(lead.clone())
+        );
+
+        // Notes, samples and volumes
+        assert_eq!(cell(&import, 0, 0, 0), "C-5 01 -- ---");
+        assert_eq!(cell(&import, 0, 0, 1), "C-4 02 7F ---");
+        assert_eq!(cell(&import, 0, 1, 1), "C#4 -- 7F 103");
+        assert_eq!(cell(&import, 0, 2, 2), "--- 02 7F ---");
+
+        // Effects
+        assert_eq!(cell(&import, 0, 3, 0), "--- -- -- 44A");
+        assert_eq!(cell(&import, 0, 4, 0), "--- -- -- A04");
+        assert_eq!(cell(&import, 0, 5, 3), "--- -- -- E93");
+        assert_eq!(cell(&import, 0, 7, 0), "--- -- -- 037");
+        assert_eq!(cell(&import, 0, 8, 0), "--- -- -- 300");
+        assert_eq!(cell(&import, 0, 9, 0), "--- -- -- F03");
+        assert_eq!(cell(&import, 0, 9, 1), "--- -- -- F7D");
+        assert_eq!(cell(&import, 0, 63, 0), "--- -- -- D10");
+        assert_eq!(cell(&import, 1, 0, 3), "C-6 01 -- 315");
+        assert_eq!(cell(&import, 1, 3, 0), "--- -- -- B02");
+    }
+
+    #[test]
+    fn report_test() {
+        let report = convert::read(FIXTURE).unwrap().report.to_string();
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(
+            lines,
+            vec![
+                "Tremolo (7xy) dropped: 2 cells, first at pattern 00 row 02 track 3",
+                "Pattern loop (E6x) dropped: 1 cell, first at pattern 00 row 06 track 3",
+                "Volume slide of tone portamento (5xy) dropped: 1 cell, first at pattern 00 row 08 track 0",
+                "Set panning (8xx) dropped: 1 cell, first at pattern 01 row 01 track 3",
+                "Stop (F00) dropped: 1 cell, first at pattern 01 row 02 track 0",
+                "Sample offset (9xx) dropped: 1 cell, first at pattern 01 row 02 track 3",
+            ]
+        );
+    }
+
+    #[test]
+    fn channels_test() {
+        let import = convert::read(FIXTURE_8CHN).unwrap();
+        assert_eq!(import.song.patterns[0].tracks(), 8);
+        assert_eq!(cell(&import, 0, 0, 7), "C-5 01 -- ---");
+        assert_eq!(import.song.samples[1].as_ref().unwrap().data.len(), 16);
+        assert!(import.report.is_empty());
+    }
+
+    #[test]
+    fn write_test() {
+        let mut song = song(
+            "C-5 01 -- --- : C-4 02 80 --- : --- -- -- --- : --- -- -- --- : 1A0
+             --- -- -- 104 : === -- -- --- : D-6 01 -- 0C3 : --- -- 40 --- : ---
+             --- -- -- --- : --- -- -- --- : --- -- -- --- : --- -- -- --- : 006
+             --- -- -- --- : --- -- -- --- : --- -- -- --- : --- -- -- --- : ---",
+        );
+        song.title = "export".to_string();
+        song.voices[2] = None;
+        song.speed = 3;
+        let data = write(&song).unwrap();
+        assert_eq!(
+            data.len(),
+            PATTERN_OFFSET + ROWS * 4 * CELL_LEN + RENDERED_LEN
+        );
+
+        let import = convert::read(&data).unwrap();
+        assert_eq!(import.song.title, "export");
+        assert_eq!(import.song.order.entries(), &[0]);
+        assert!(import.song.voices[1].is_some());
+        assert!(import.song.voices[2].is_none());
+
+        // The speed of the song, the tempo and a volume that needs set volume
+        assert_eq!(cell(&import, 0, 0, 0), "C-5 01 -- F03");
+        assert_eq!(cell(&import, 0, 0, 1), "C-4 02 7F ---");
+        assert_eq!(cell(&import, 0, 0, 2), "--- -- -- FA0");
+        assert_eq!(cell(&import, 0, 1, 0), "--- -- -- 104");
+        assert_eq!(cell(&import, 0, 1, 1), "--- -- -- EC0");
+        assert_eq!(cell(&import, 0, 1, 2), "D-6 01 -- 0C3");
+        assert_eq!(cell(&import, 0, 1, 3), "--- -- 3F ---");
+        assert_eq!(cell(&import, 0, 2, 0), "--- -- -- F06");
+        assert_eq!(cell(&import, 0, 3, 0), "--- -- -- D00");
+    }
+
+    #[test]
+    fn write_error_test() {
+        let song = song(
+            "C-7 01 -- --- : --- -- 80 104 : === -- -- 302 : --- -- -- 700 : 2FF
+             C-4 01 -- 0C3 : C-4 01 -- 0C3 : C-4 01 -- 0C3 : C-4 01 -- 0C3 : 010",
+        );
+        let errors: Vec<String> = write(&song)
+            .unwrap_err()
+            .iter()
+            .map(|e| e.to_string())
+            .collect();
+        assert_eq!(
+            errors,
+            vec![
+                "Pattern 00 row 00 track 0: Note C-7 is outside of C-4 to B-6",
+                "Pattern 00 row 00 track 1: No free effect column for the volume",
+                "Pattern 00 row 00 track 2: No free effect column for the note off",
+                "Pattern 00 row 00 track 3: Effect 700 can't be represented",
+                "Pattern 00 row 00 gFx: Global effect 2FF can't be represented",
+                "Pattern 00 row 01 gFx: No free effect column for the global effect",
+                "Pattern 00 row 01 gFx: No free effect column for the pattern break",
+            ]
+        );
+
+        let mut song = Song::new();
+        song.patterns[0] = Pattern::new(ROWS + 1, DEFAULT_TRACKS);
+        assert_eq!(
+            write(&song).unwrap_err(),
+            vec![ExportError::Rows {
+                pattern: 0,
+                rows: ROWS + 1
+            }]
+        );
+        song.patterns[0] = Pattern::new(ROWS, 5);
+        assert_eq!(write(&song).unwrap_err(), vec![ExportError::Tracks(5)]);
+
+        // The tempo isn't clamped to what fits
+        let mut song = Song::new();
+        song.bpm = 0x100;
+        assert_eq!(write(&song).unwrap_err(), vec![ExportError::Tempo(0x100)]);
+    }
+
+    #[test]
+    fn write_sample_test() {
+        let import = convert::read(FIXTURE).unwrap();
+        let mut song = song("C-5 01 -- --- : C-5 02 -- --- : --- -- -- --- : --- -- -- --- : ---");
+        song.samples = import.song.samples.clone();
+        song.voices = import.song.voices.clone();
+
+        // Recorded samples come back as they were, with their finetune and loop
+        let export = convert::read(&write(&song).unwrap()).unwrap();
+        for slot in [1, 2] {
+            assert_eq!(export.song.samples[slot], import.song.samples[slot]);
+        }
+
+        // Other rates are resampled, the loop follows
+        let ramp: Vec<f32> = (0..100).map(|i| i as f32 / 128.0).collect();
+        let sample = convert::sample(ramp, C2_RATE as f32, Some((11, 40)));
+        song.set_voice(1, None);
+        song.samples[1] = Some(sample);
+        song.set_voice(1, import.song.voices[1].clone());
+        let export = convert::read(&write(&song).unwrap()).unwrap();
+        let sample = export.song.samples[1].as_ref().unwrap();
+        assert_eq!(sample.rate, 4182.0);
+        assert_eq!(sample.data.len(), 50);
+        assert_eq!(sample.data[10], 20.0 / 128.0);
+        assert_eq!(sample.repeat, Some((6, 20)));
+
+        let mut data = vec![0.0; MAX_SAMPLE_LEN + 1];
+        data[0] = 1.0;
+        song.samples[1] = Some(convert::sample(data, C2_RATE as f32 / 2.0, None));
+        song.set_voice(1, import.song.voices[1].clone());
+        assert_eq!(
+            write(&song).unwrap_err(),
+            vec![ExportError::SampleLen {
+                voice: 1,
+                len: MAX_SAMPLE_LEN + 2
+            }]
+        );
+    }
+
+    #[test]
+    fn render_test() {
+        let sample = render(&SQUARE);
+        assert_eq!(sample.len(), RENDERED_LEN);
+        assert!(sample.iter().any(|s| *s > 100) && sample.iter().any(|s| *s < -100));
+
+        // The loop at the end continues seamlessly
+        let cycles = |n: usize| &sample[RENDERED_LEN - n * RENDERED_LOOP..][..RENDERED_LOOP];
+        let diff: i32 = (cycles(1).iter().zip(cycles(2)))
+            .map(|(a, b)| (*a as i32 - *b as i32).abs())
+            .sum();
+        assert!(diff < 4 * RENDERED_LOOP as i32, "{}", diff);
+    }
+
+    #[test]
+    fn sample_render_test() {
+        let import = convert::read(FIXTURE).unwrap();
+        let mut song = song("C-5 01 -- --- : ---");
+        song.samples = import.song.samples;
+        song.voices = import.song.voices;
+
+        // The kick ramp is played once, at twice its rate at C-4
+        let out = play(&song, ROW);
+        let end = (32.0 * SAMPLE_RATE as f32 / 8364.0) as usize;
+        assert!(out[..end].iter().any(|s| *s > 0.2));
+        assert!(out[end + 1..].iter().all(|s| *s == 0.0));
+    }
+
+    #[test]
+    fn truncated_sample_test() {
+        let import = convert::read(&FIXTURE[..FIXTURE.len() - 60]).unwrap();
+        assert_eq!(import.song.samples[2].as_ref().unwrap().data.len(), 4);
+        assert_eq!(
+            import.song.samples[2].as_ref().unwrap().repeat,
+            Some((0, 4))
+        );
+        assert!(import
+            .report
+            .to_string()
+            .starts_with("Sample 02 'lead' truncated to 4 bytes\n"));
+
+        let import = convert::read(&FIXTURE[..FIXTURE.len() - 64]).unwrap();
+        assert!(import.song.samples[2].is_none());
+        assert!(import.song.voices[2].is_none());
+    }
+
+    #[test]
+    fn error_test() {
+        assert_eq!(convert::read(&FIXTURE[..1000]).unwrap_err(), Error::Format);
+        let truncated = &FIXTURE[..PATTERN_OFFSET + 2 * ROWS * 4 * CELL_LEN - 1];
+        assert_eq!(convert::read(truncated).unwrap_err(), Error::Truncated);
+    }
+}
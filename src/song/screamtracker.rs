@@ -0,0 +1,566 @@
+// Copyright (C) 2025 Anders Sundman <anders@4zm.org>
+//
+// This file is part of RTRK - The Rust Tracker
+//
+// RTRK is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// RTRK is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with RTRK. If not, see <https://www.gnu.org/licenses/>.
+
+/// ScreamTracker 3 modules. The header with the channel settings and the order list is
+/// followed by pointers, in 16 byte units, to the instruments and the patterns. A pattern is
+/// 64 packed rows: a byte with the channel and which of note and instrument, volume and
+/// command follow, or zero at the end of the row. All numbers are little endian.
+use crate::song::convert::{self, le16, le32, Error, Import, Location, Report};
+use crate::song::order::OrderList;
+use crate::song::pattern::{Cell, Effect, Note, Pattern};
+use crate::song::player::GFX_SET_VOLUME;
+use crate::song::protracker::{self, Pitch, MAX_VOLUME, ROWS};
+use crate::song::{voice, Song, DEFAULT_BPM, DEFAULT_SPEED, MAX_VOICES};
+use crate::synth;
+
+const MAGIC: &[u8] = b"SCRM";
+const MAGIC_OFFSET: usize = 0x2C;
+const CHANNELS: usize = 32;
+const CHANNEL_SETTINGS: usize = 0x40;
+const ORDER_OFFSET: usize = 0x60;
+const PARAGRAPH: usize = 16;
+
+/// Channel settings from here are disabled or unused
+const DISABLED: u8 = 0x80;
+/// Channel settings from here are AdLib channels
+const ADLIB: u8 = 16;
+
+// Order list markers
+const ORDER_SKIP: u8 = 0xFE;
+const ORDER_END: u8 = 0xFF;
+
+const NOTE_CUT: u8 = 0xFE;
+const NO_NOTE: u8 = 0xFF;
+
+// Instrument types
+const SAMPLE: u8 = 1;
+
+// Sample flags
+const SAMPLE_LOOP: u8 = 0x1;
+const SAMPLE_STEREO: u8 = 0x2;
+const SAMPLE_16_BIT: u8 = 0x4;
+
+/// Sample format in the header, otherwise the samples are signed
+const UNSIGNED_SAMPLES: usize = 2;
+
+// ProTracker effects the commands are translated to
+const SLIDE_UP: u8 = 0x1;
+const SLIDE_DOWN: u8 = 0x2;
+const TONE_PORTAMENTO: u8 = 0x3;
+const VIBRATO: u8 = 0x4;
+const PORTAMENTO_VOLUME_SLIDE: u8 = 0x5;
+const VIBRATO_VOLUME_SLIDE: u8 = 0x6;
+const TREMOLO: u8 = 0x7;
+const SAMPLE_OFFSET: u8 = 0x9;
+const VOLUME_SLIDE: u8 = 0xA;
+const POSITION_JUMP: u8 = 0xB;
+const PATTERN_BREAK: u8 = 0xD;
+const EXTENDED: u8 = 0xE;
+const SET_SPEED: u8 = 0xF;
+const ARPEGGIO: u8 = 0x0;
+
+/// True if the data looks like an S3M module
+pub fn matches(data: &[u8]) -> bool {
+    data.get(MAGIC_OFFSET..MAGIC_OFFSET + MAGIC.len()) == Some(MAGIC)
+}
+
+struct Instrument {
+    kind: u8,
+    name: String,
+    volume: u8,
+    sample: Option<synth::Sample>,
+    stereo: bool,
+}
+
+/// Parse the instrument header at the offset, and the sample data it points to for a sample
+/// instrument. Lengths are in samples, also for 16 bit samples.
+fn instrument(data: &[u8], at: usize, unsigned: bool) -> Result<Instrument, Error> {
+    let header = data.get(at..at + 0x50).ok_or(Error::Truncated)?;
+    let kind = header[0];
+    let len = le32(header, 0x10)?;
+    let flags = header[0x1F];
+    let sample = if kind == SAMPLE && len > 0 {
+        let at = ((header[0x0D] as usize) << 16 | le16(header, 0x0E)?) * PARAGRAPH;
+        let width = if flags & SAMPLE_16_BIT != 0 { 2 } else { 1 };
+        let bytes = data.get(at..at + len * width).ok_or(Error::Truncated)?;
+        let (start, end) = (le32(header, 0x14)?, le32(header, 0x18)?);
+        let repeat = (flags & SAMPLE_LOOP != 0 && end > start).then(|| (start, end - start));
+        let rate = le32(header, 0x20)? as f32;
+        Some(convert::sample(
+            decode(bytes, width == 2, unsigned),
+            rate,
+            repeat,
+        ))
+    } else {
+        None
+    };
+    Ok(Instrument {
+        kind,
+        name: convert::name(&header[0x30..0x4C]),
+        volume: header[0x1C].min(MAX_VOLUME),
+        sample,
+        stereo: flags & SAMPLE_STEREO != 0,
+    })
+}
+
+/// Sample data as levels. The left channel comes first in stereo samples.
+fn decode(data: &[u8], sixteen_bit: bool, unsigned: bool) -> Vec<f32> {
+    match sixteen_bit {
+        true => data
+            .chunks_exact(2)
+            .map(|d| {
+                let level = u16::from_le_bytes([d[0], d[1]]);
+                (if unsigned { level ^ 0x8000 } else { level }) as i16 as f32 / 32768.0
+            })
+            .collect(),
+        false => data
+            .iter()
+            .map(|d| (if unsigned { d ^ 0x80 } else { *d }) as i8 as f32 / 128.0)
+            .collect(),
+    }
+}
+
+/// The fields of a pattern cell as stored, 0xFF for the note and volume if they are not set
+#[derive(Copy, Clone)]
+struct Fields {
+    note: u8,
+    instrument: u8,
+    volume: u8,
+    command: u8,
+    info: u8,
+}
+
+impl Default for Fields {
+    fn default() -> Self {
+        Self {
+            note: NO_NOTE,
+            instrument: 0,
+            volume: 0xFF,
+            command: 0,
+            info: 0,
+        }
+    }
+}
+
+/// The rows of a pattern, with the fields of each channel that has any
+fn unpack(data: &[u8], at: usize) -> Result<Vec<Vec<(usize, Fields)>>, Error> {
+    let len = le16(data, at)?;
+    let packed = data.get(at + 2..at + len.max(2)).ok_or(Error::Truncated)?;
+    let mut bytes = packed.iter().copied();
+    let mut next = || bytes.next().ok_or(Error::Truncated);
+
+    let mut rows = vec![];
+    for _ in 0..ROWS {
+        let mut row = vec![];
+        loop {
+            let what = next()?;
+            if what == 0 {
+                break;
+            }
+            let mut fields = Fields::default();
+            if what & 0x20 != 0 {
+                fields.note = next()?;
+                fields.instrument = next()?;
+            }
+            if what & 0x40 != 0 {
+                fields.volume = next()?;
+            }
+            if what & 0x80 != 0 {
+                fields.command = next()?;
+                fields.info = next()?;
+            }
+            row.push(((what & 0x1F) as usize, fields));
+        }
+        rows.push(row);
+    }
+    Ok(rows)
+}
+
+/// Conversion state of a channel
+#[derive(Copy, Clone)]
+struct Channel {
+    key: u8,
+    volume: u8,
+}
+
+impl Default for Channel {
+    fn default() -> Self {
+        Self {
+            key: 4 * 12,
+            volume: MAX_VOLUME,
+        }
+    }
+}
+
+/// The effect of a command, track effects first and global effects second. The commands are
+/// letters, A is 1. Commands past the letters are dropped by number.
+fn effect(
+    command: u8,
+    info: u8,
+    pitch: Pitch,
+    at: Location,
+    report: &mut Report,
+) -> (Option<Effect>, Option<Effect>) {
+    let (x, y) = (info >> 4, info & 0xF);
+    let letter = command.checked_add(b'@').map_or('\0', char::from);
+    let dropped = |name: &str, report: &mut Report| {
+        report.warn_at(format!("{} dropped", name), at);
+        (None, None)
+    };
+    let protracker = |code, param, report: &mut Report| {
+        (protracker::effect(code, param, pitch, at, report), None)
+    };
+    match letter {
+        'A' if info == 0 => (None, None),
+        'A' => protracker(SET_SPEED, info.min(0x1F), report),
+        'B' => protracker(POSITION_JUMP, info, report),
+        'C' => protracker(PATTERN_BREAK, info, report),
+        'D' if (x == 0xF && y > 0) || (y == 0xF && x > 0) => {
+            dropped("Fine volume slide (DxF/DFy)", report)
+        }
+        'D' if x > 0 && y > 0 => dropped("Volume slide up and down (Dxy)", report),
+        'D' => protracker(VOLUME_SLIDE, info, report),
+        'E' | 'F' if info >= 0xE0 => dropped("Fine slide (EEx/EFx/FEx/FFx)", report),
+        'E' => protracker(SLIDE_DOWN, info, report),
+        'F' => protracker(SLIDE_UP, info, report),
+        'G' => protracker(TONE_PORTAMENTO, info, report),
+        'H' => protracker(VIBRATO, info, report),
+        'I' => dropped("Tremor (Ixy)", report),
+        'J' => protracker(ARPEGGIO, info, report),
+        'K' => protracker(VIBRATO_VOLUME_SLIDE, info, report),
+        'L' => protracker(PORTAMENTO_VOLUME_SLIDE, info, report),
+        'O' => protracker(SAMPLE_OFFSET, info, report),
+        'Q' => {
+            if x != 0 {
+                report.warn_at("Volume change of retrigger (Qxy) dropped".to_string(), at);
+            }
+            protracker(EXTENDED, 0x90 | y, report)
+        }
+        'R' => protracker(TREMOLO, info, report),
+        // Same as the ProTracker extended effects for the ones rtrk has
+        'S' => match x {
+            0xB..=0xE => protracker(EXTENDED, info, report),
+            _ => dropped(&format!("Special (S{:X}x)", x), report),
+        },
+        'T' if info < 0x20 => dropped("Tempo below 20 (Txx)", report),
+        'T' => protracker(SET_SPEED, info, report),
+        'U' => dropped("Fine vibrato (Uxy)", report),
+        'V' => (
+            None,
+            Some(Effect {
+                code: GFX_SET_VOLUME,
+                param: protracker::volume(info),
+            }),
+        ),
+        '@' => (None, None),
+        _ if letter.is_ascii_uppercase() => dropped(&format!("Effect {}xx", letter), report),
+        _ => dropped(&format!("Command {:02X}", command), report),
+    }
+}
+
+fn cell(
+    fields: Fields,
+    instruments: &[Instrument],
+    channel: &mut Channel,
+    gfx: &mut Option<Effect>,
+    at: Location,
+    report: &mut Report,
+) -> Cell {
+    let note = match fields.note {
+        NO_NOTE => None,
+        NOTE_CUT => Some(Note::Off),
+        n if n & 0xF < 12 => {
+            channel.key = ((n >> 4) * 12 + (n & 0xF)).min(Note::MAX_KEY);
+            Some(Note::Key(channel.key))
+        }
+        _ => {
+            report.warn_at("Invalid notes dropped".to_string(), at);
+            None
+        }
+    };
+    let voice = (fields.instrument > 0).then_some(fields.instrument);
+    let instrument = (fields.instrument as usize)
+        .checked_sub(1)
+        .and_then(|i| instruments.get(i));
+    if let Some(instrument) = instrument {
+        channel.volume = instrument.volume;
+    }
+    let volume_column = fields.volume <= MAX_VOLUME;
+    if volume_column {
+        channel.volume = fields.volume;
+    }
+
+    // C-4 plays at the rate of ProTracker C-2
+    let pitch = Pitch::Period(protracker::period(channel.key + 12));
+    let (effect, global) = effect(fields.command, fields.info, pitch, at, report);
+    if let Some(global) = global {
+        convert::set_global_effect(gfx, global, at, report);
+    }
+
+    // Instruments set the volume of the track, notes without one keep it
+    let set_volume = volume_column
+        || (voice.is_some() && note.is_none())
+        || (matches!(note, Some(Note::Key(_))) && channel.volume < MAX_VOLUME);
+    Cell {
+        note,
+        voice,
+        volume: set_volume.then_some(protracker::volume(channel.volume)),
+        effect,
+    }
+}
+
+/// Convert a module. Each sample instrument is played by a sample voice in the slot with the
+/// number of the instrument, AdLib instruments by a placeholder voice. The enabled channels
+/// become the tracks, in order.
+pub fn read(data: &[u8]) -> Result<Import, Error> {
+    if !matches(data) || data.len() < ORDER_OFFSET {
+        return Err(Error::Format);
+    }
+    let orders = le16(data, 0x20)?;
+    let instruments = le16(data, 0x22)?;
+    let patterns = le16(data, 0x24)?;
+    let unsigned = le16(data, 0x2A)? == UNSIGNED_SAMPLES;
+
+    let mut report = Report::default();
+    let mut song = Song::new();
+    song.title = convert::name(&data[..28]);
+    song.speed = match data[0x31] {
+        0 | 0xFF => DEFAULT_SPEED,
+        speed => speed as u32,
+    };
+    song.bpm = match data[0x32] {
+        0..0x20 => DEFAULT_BPM,
+        bpm => bpm as u32,
+    };
+
+    let settings = &data[CHANNEL_SETTINGS..CHANNEL_SETTINGS + CHANNELS];
+    let tracks: Vec<Option<usize>> = settings
+        .iter()
+        .scan(0, |track, s| {
+            Some((*s < DISABLED).then(|| {
+                *track += 1;
+                *track - 1
+            }))
+        })
+        .collect();
+    let track_count = tracks.iter().flatten().count().max(1);
+    if settings.iter().any(|s| (ADLIB..DISABLED).contains(s)) {
+        report.warn("AdLib channels are played like sample channels".to_string());
+    }
+
+    let order = data
+        .get(ORDER_OFFSET..ORDER_OFFSET + orders)
+        .ok_or(Error::Truncated)?;
+    let pointer = |i: usize| Ok::<_, Error>(le16(data, ORDER_OFFSET + orders + 2 * i)? * PARAGRAPH);
+
+    let mut voices = vec![String::new(); MAX_VOICES];
+    let mut parsed = vec![];
+    for idx in 0..instruments.min(MAX_VOICES - 1) {
+        let instrument = instrument(data, pointer(idx)?, unsigned)?;
+        let slot = idx + 1;
+        if instrument.sample.is_some() {
+            voices[slot] = convert::SAMPLE_VOICE.to_string();
+            song.samples[slot] = instrument.sample.clone();
+        } else if instrument.kind > SAMPLE {
+            voices[slot] = convert::PLACEHOLDER_VOICE.to_string();
+            report.warn(format!(
+                "Instrument {:02X} '{}' AdLib replaced by a placeholder voice",
+                slot, instrument.name
+            ));
+        }
+        if instrument.stereo {
+            report.warn(format!(
+                "Instrument {:02X} stereo sample played in mono",
+                slot
+            ));
+        }
+        if !voices[slot].is_empty() {
+            song.set_voice(slot, voice::parse(&voices[slot]));
+        }
+        parsed.push(instrument);
+    }
+
+    let mut state = vec![Channel::default(); track_count];
+    let mut disabled = false;
+    song.patterns = (0..patterns)
+        .map(|idx| {
+            let mut pattern = Pattern::new(ROWS, track_count);
+            let at = pointer(instruments + idx)?;
+            // A pattern without data is empty
+            if at == 0 {
+                return Ok(pattern);
+            }
+            for (row, cells) in unpack(data, at)?.into_iter().enumerate() {
+                let mut gfx = None;
+                for (channel, fields) in cells {
+                    let Some(track) = tracks[channel] else {
+                        disabled = true;
+                        continue;
+                    };
+                    let at = Location {
+                        pattern: idx,
+                        row,
+                        track,
+                    };
+                    let state = &mut state[track];
+                    *pattern.cell_mut(row, track) =
+                        cell(fields, &parsed, state, &mut gfx, at, &mut report);
+                }
+                pattern.row_mut(row).gfx = gfx;
+            }
+            Ok(pattern)
+        })
+        .collect::<Result<_, Error>>()?;
+    if disabled {
+        report.warn("Cells in disabled channels dropped".to_string());
+    }
+
+    // Markers are left out, patterns that are played but not stored are empty
+    let entries: Vec<usize> = order
+        .iter()
+        .take_while(|p| **p != ORDER_END)
+        .filter(|p| **p != ORDER_SKIP)
+        .map(|p| *p as usize)
+        .collect();
+    let played = entries.iter().max().map_or(0, |p| p + 1);
+    while song.patterns.len() < played.max(1) {
+        song.patterns.push(Pattern::new(ROWS, track_count));
+    }
+    song.order = OrderList::new(if entries.is_empty() { vec![0] } else { entries });
+
+    Ok(Import {
+        song,
+        voices,
+        report,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FIXTURE: &[u8] = include_bytes!("../../fixtures/screamtracker.s3m");
+
+    fn cell(import: &Import, pattern: usize, row: usize, track: usize) -> String {
+        import.song.patterns[pattern].cell(row, track).to_string()
+    }
+
+    #[test]
+    fn read_test() {
+        let import = convert::read(FIXTURE).unwrap();
+        let song = &import.song;
+        assert_eq!(song.title, "rtrk s3m fixture");
+        assert_eq!((song.speed, song.bpm), (3, 150));
+        assert_eq!(song.order.entries(), &[0, 1, 0]);
+        assert_eq!(song.patterns.len(), 2);
+        // Channel 2 is disabled, so channel 3 is track 2
+        assert_eq!(song.patterns[0].tracks(), 3);
+        assert_eq!(import.voices[1], convert::SAMPLE_VOICE);
+        assert_eq!(import.voices[2], convert::PLACEHOLDER_VOICE);
+        assert!(song.voices[3].is_none());
+
+        // The unsigned sample loops its second half and is in tune at C-4
+        let square = song.samples[1].as_ref().expect("");
+        assert_eq!(&square.data[..2], &[0.5, 0.5]);
+        assert_eq!(square.data[31], -0.5);
+        assert_eq!((square.rate, square.repeat), (8363.0, Some((16, 16))));
+
+        // Notes, instruments and volumes
+        assert_eq!(cell(&import, 0, 0, 0), "C-4 01 -- ---");
+        assert_eq!(cell(&import, 0, 0, 2), "G-5 02 BF ---");
+        assert_eq!(cell(&import, 0, 1, 0), "C#4 -- 7F ---");
+        assert_eq!(cell(&import, 0, 2, 0), "=== -- -- ---");
+
+        // Commands
+        assert_eq!(cell(&import, 0, 3, 1), "--- -- -- F02");
+        assert_eq!(cell(&import, 0, 4, 1), "--- -- -- F96");
+        assert_eq!(cell(&import, 0, 5, 1), "--- -- -- A04");
+        assert_eq!(cell(&import, 0, 6, 1), "--- -- -- 037");
+        assert_eq!(cell(&import, 0, 7, 1), "--- -- -- EC2");
+        assert_eq!(cell(&import, 0, 8, 1), "--- -- -- D10");
+        assert_eq!(cell(&import, 0, 9, 1), "--- -- -- E93");
+        assert_eq!(song.patterns[0].row(10).gfx.expect("").to_string(), "27F");
+        assert_eq!(cell(&import, 1, 0, 1), "D-3 01 -- ---");
+    }
+
+    #[test]
+    fn report_test() {
+        let report = convert::read(FIXTURE).unwrap().report.to_string();
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(
+            lines,
+            vec![
+                "AdLib channels are played like sample channels",
+                "Instrument 02 'organ' AdLib replaced by a placeholder voice",
+                "Tremor (Ixy) dropped: 1 cell, first at pattern 00 row 0B track 1",
+                "Fine volume slide (DxF/DFy) dropped: 2 cells, first at pattern 00 row 0C track 1",
+                "Special (S8x) dropped: 1 cell, first at pattern 00 row 0D track 1",
+                "Cells in disabled channels dropped",
+            ]
+        );
+    }
+
+    #[test]
+    fn command_test() {
+        let mut report = Report::default();
+        let at = Location {
+            pattern: 0,
+            row: 0,
+            track: 0,
+        };
+        let pitch = Pitch::Period(protracker::period(48));
+        for command in [0x1A, 0x1B, 0xC0, 0xFF] {
+            assert_eq!(effect(command, 0, pitch, at, &mut report), (None, None));
+        }
+        let lines: Vec<String> = report.to_string().lines().map(String::from).collect();
+        assert_eq!(
+            lines,
+            vec![
+                "Effect Zxx dropped: 1 cell, first at pattern 00 row 00 track 0",
+                "Command 1B dropped: 1 cell, first at pattern 00 row 00 track 0",
+                "Command C0 dropped: 1 cell, first at pattern 00 row 00 track 0",
+                "Command FF dropped: 1 cell, first at pattern 00 row 00 track 0",
+            ]
+        );
+    }
+
+    #[test]
+    fn decode_test() {
+        assert_eq!(decode(&[0x40, 0xC0], false, false), vec![0.5, -0.5]);
+        assert_eq!(decode(&[0x40, 0xC0], false, true), vec![-0.5, 0.5]);
+        assert_eq!(
+            decode(&[0x00, 0x40, 0x00, 0xC0], true, true),
+            vec![-0.5, 0.5]
+        );
+    }
+
+    #[test]
+    fn error_test() {
+        assert_eq!(read(b"SCRM").unwrap_err(), Error::Format);
+        assert_eq!(read(&FIXTURE[..0x70]).unwrap_err(), Error::Truncated);
+        assert_eq!(
+            read(&FIXTURE[..FIXTURE.len() - 1]).unwrap_err(),
+            Error::Truncated
+        );
+
+        // A loop that ends before it starts doesn't loop
+        let mut data = FIXTURE.to_vec();
+        data[0x88] = 8;
+        let import = read(&data).unwrap();
+        assert_eq!(import.song.samples[1].as_ref().expect("").repeat, None);
+    }
+}
@@ -0,0 +1,111 @@
+// Copyright (C) 2025 Anders Sundman <anders@4zm.org>
+//
+// This file is part of RTRK - The Rust Tracker
+//
+// RTRK is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// RTRK is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with RTRK. If not, see <https://www.gnu.org/licenses/>.
+
+/// The voice designer notation, `1 AADDSSRR LLHHXX`: oscillator, envelope and filters. Unset
+/// digits are spaces. Oscillator `6` plays the sample of the voice slot, see `Song::set_voice`.
+use crate::synth;
+
+/// Length of a voice in designer notation
+pub const TEXT_LEN: usize = 17;
+
+/// The attack, decay and release times are in steps of 10 ms
+pub const ENVELOPE_STEP_MS: f32 = 10.0;
+
+/// The envelope of the `AADDSSRR` digits, None unless all of them are set
+fn envelope(text: &str) -> Option<synth::Envelope> {
+    let byte = |i: usize| u8::from_str_radix(text.get(i..i + 2)?, 16).ok();
+    Some(synth::Envelope {
+        attack_ms: byte(0)? as f32 * ENVELOPE_STEP_MS,
+        decay_ms: byte(2)? as f32 * ENVELOPE_STEP_MS,
+        sustain_lvl: byte(4)? as f32 / 0xFF as f32,
+        release_ms: byte(6)? as f32 * ENVELOPE_STEP_MS,
+    })
+}
+
+/// The `AADDSSRR` digits of an envelope. Times beyond the longest step are clamped.
+pub fn format_envelope(env: &synth::Envelope) -> String {
+    let time = |ms: f32| (ms / ENVELOPE_STEP_MS).round().clamp(0.0, 255.0) as u8;
+    let level = (env.sustain_lvl * 0xFF as f32).round().clamp(0.0, 255.0) as u8;
+    format!(
+        "{:02X}{:02X}{:02X}{:02X}",
+        time(env.attack_ms),
+        time(env.decay_ms),
+        level,
+        time(env.release_ms)
+    )
+}
+
+/// The voice of a designer text, or None if the oscillator is not set
+pub fn parse(text: &str) -> Option<synth::Voice> {
+    let field = |start: usize, end: usize| text.get(start..end).unwrap_or("");
+    let osc = match field(0, 1).parse::<usize>() {
+        Ok(1) => synth::Oscillator::Sine,
+        Ok(2) => synth::Oscillator::Triangle,
+        Ok(3) => synth::Oscillator::Saw,
+        Ok(4) => synth::Oscillator::Square,
+        Ok(5) => synth::Oscillator::Pulse,
+        Ok(6) => synth::Oscillator::Sample(synth::Sample::default()),
+        _ => return None,
+    };
+
+    // Filter format: LLHHXX (low pass, high pass, velocity sensitivity amp + cutoff)
+    let filter = |hex: &str| {
+        u8::from_str_radix(hex, 16).ok().map(|v| synth::Filter {
+            cutoff: synth::cutoff_hz(v),
+            gain: 1.0,
+        })
+    };
+    let sensitivity = |hex: &str| u8::from_str_radix(hex, 16).map_or(0.0, |v| v as f32 / 15.0);
+
+    Some(synth::Voice {
+        osc,
+        env: envelope(field(2, 10)),
+        lp: filter(field(11, 13)),
+        hp: filter(field(13, 15)),
+        vel_amp: sensitivity(field(15, 16)),
+        vel_cutoff: sensitivity(field(16, 17)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_test() {
+        assert_eq!(parse(""), None);
+        assert_eq!(parse("7 -------- ------"), None);
+
+        let v = parse("3          80 0F ").expect("");
+        assert_eq!(v.osc, synth::Oscillator::Saw);
+        assert_eq!(v.lp.expect("").cutoff, synth::cutoff_hz(0x80));
+        assert_eq!(v.hp, None);
+        assert_eq!(v.vel_amp, 1.0);
+        assert_eq!(v.vel_cutoff, 0.0);
+        assert_eq!(v.env, None);
+
+        let env = parse("1 0A1480FF").expect("").env.expect("");
+        assert_eq!(env.attack_ms, 100.0);
+        assert_eq!(env.decay_ms, 200.0);
+        assert_eq!(env.release_ms, 2550.0);
+        assert_eq!(format_envelope(&env), "0A1480FF");
+        assert_eq!(parse("1 0A14 0FF").expect("").env, None);
+
+        let v = parse("6          80 F0").expect("");
+        assert_eq!(v.osc, synth::Oscillator::Sample(synth::Sample::default()));
+    }
+}
@@ -0,0 +1,481 @@
+// Copyright (C) 2025 Anders Sundman <anders@4zm.org>
+//
+// This file is part of RTRK - The Rust Tracker
+//
+// RTRK is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// RTRK is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with RTRK. If not, see <https://www.gnu.org/licenses/>.
+
+/// Offline rendering of songs to mono WAV files. The song is played by the same player and
+/// synth engine as in the UI, as fast as the samples can be computed. It can also be split into
+/// stems, which all start at the start of the song and have the same length.
+use std::fmt;
+
+use crate::song::player::Player;
+use crate::song::{Song, MAX_VOICES};
+use crate::synth::offline::OfflineSynth;
+use crate::synth::{self, Message, MessageSink, Time, Timed, SAMPLE_RATE};
+
+/// Sample format of the file
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Format {
+    Pcm8,
+    Pcm16,
+    Pcm24,
+    Float32,
+}
+
+impl Format {
+    pub const ALL: [Format; 4] = [Format::Pcm8, Format::Pcm16, Format::Pcm24, Format::Float32];
+
+    fn bytes(&self) -> usize {
+        match self {
+            Format::Pcm8 => 1,
+            Format::Pcm16 => 2,
+            Format::Pcm24 => 3,
+            Format::Float32 => 4,
+        }
+    }
+
+    /// The format tag of the fmt chunk
+    fn tag(&self) -> u16 {
+        match self {
+            Format::Float32 => 3,
+            _ => 1,
+        }
+    }
+
+    fn encode(&self, sample: f32, data: &mut Vec<u8>) {
+        let sample = sample.clamp(-1.0, 1.0);
+        let pcm = |max: f32| (sample * max).round() as i32;
+        match self {
+            // 8 bit samples are unsigned
+            Format::Pcm8 => data.push((pcm(i8::MAX as f32) + 0x80) as u8),
+            Format::Pcm16 => data.extend_from_slice(&(pcm(i16::MAX as f32) as i16).to_le_bytes()),
+            Format::Pcm24 => data.extend_from_slice(&pcm(0x7FFFFF as f32).to_le_bytes()[..3]),
+            Format::Float32 => data.extend_from_slice(&sample.to_le_bytes()),
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Format::Pcm8 => write!(f, "8bit"),
+            Format::Pcm16 => write!(f, "16bit"),
+            Format::Pcm24 => write!(f, "24bit"),
+            Format::Float32 => write!(f, "32flt"),
+        }
+    }
+}
+
+/// Sample rates to choose from. The synth runs at `SAMPLE_RATE` and is resampled to the others.
+pub const SAMPLE_RATES: [u32; 4] = [22050, 44100, 48000, 96000];
+
+/// Longest song that is rendered, for songs that never play to the end, and longest fade
+pub const MAX_SECONDS: usize = 60 * 60;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Error {
+    /// The synth could not take a message of the player or failed to play it
+    Synth(synth::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Synth(e) => write!(f, "Rendering failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<synth::Error> for Error {
+    fn from(e: synth::Error) -> Self {
+        Error::Synth(e)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Options {
+    pub format: Format,
+    pub sample_rate: u32,
+    /// Times the song is played
+    pub loops: usize,
+    /// The song keeps playing while it fades out after the last loop
+    pub fade_ms: u32,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            format: Format::Pcm16,
+            sample_rate: SAMPLE_RATE,
+            loops: 1,
+            fade_ms: 0,
+        }
+    }
+}
+
+/// How a song is split into stems
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Stems {
+    Tracks,
+    /// A stem per voice slot that the song plays
+    Voices,
+}
+
+/// A part of the song
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Stem {
+    Track(usize),
+    Voice(usize),
+}
+
+impl Stem {
+    pub fn file_name(&self, prefix: &str) -> String {
+        format!("{}-{}.wav", prefix, self)
+    }
+}
+
+impl fmt::Display for Stem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Stem::Track(track) => write!(f, "track{}", track),
+            Stem::Voice(slot) => write!(f, "voice{:02X}", slot),
+        }
+    }
+}
+
+/// Tracks of the widest pattern, each plays on its own synth channel
+fn tracks(song: &Song) -> usize {
+    song.patterns.iter().map(|p| p.tracks()).max().unwrap_or(1)
+}
+
+/// Play a tick of the song and render it, up to the sample time at most. Errors of the engine
+/// while rendering fail the tick like the ones of scheduling.
+fn play_tick(
+    song: &Song,
+    player: &mut Player,
+    synth: &mut OfflineSynth,
+    out: &mut Vec<f32>,
+    until: usize,
+) -> Result<(), Error> {
+    player.advance(song, synth, player.scheduled_until() + 1)?;
+    let from = out.len();
+    out.resize((player.scheduled_until() as usize).min(until), 0.0);
+    synth.render(&mut out[from..]);
+    match synth.poll_errors().first() {
+        Some(e) => Err(Error::Synth(*e)),
+        None => Ok(()),
+    }
+}
+
+/// Play the song from the start until it has played to the end `loops` times, plus the fade.
+/// The output is at `SAMPLE_RATE`.
+pub fn render(song: &Song, loops: usize, fade_ms: u32) -> Result<Vec<f32>, Error> {
+    render_solo(song, None, loops, fade_ms)
+}
+
+/// Render with only the track heard. The other tracks still play, so the timing is the same.
+fn render_solo(
+    song: &Song,
+    solo: Option<usize>,
+    loops: usize,
+    fade_ms: u32,
+) -> Result<Vec<f32>, Error> {
+    let mut synth = OfflineSynth::new(tracks(song));
+    let mut player = Player::new(song, 0, 0);
+    let mut out = vec![];
+    if solo.is_some() {
+        let msg = Message::SetSolo(solo);
+        synth.schedule(Timed {
+            time: Time::Sample(0),
+            msg,
+        })?;
+    }
+
+    // A tick at a time, to stop right where the last pass ends
+    let limit = MAX_SECONDS * SAMPLE_RATE as usize;
+    while player.passes() < loops && out.len() < limit {
+        play_tick(song, &mut player, &mut synth, &mut out, limit)?;
+    }
+    let end = out.len();
+    let fade = fade_ms as usize * SAMPLE_RATE as usize / 1000;
+    while out.len() < end + fade {
+        play_tick(song, &mut player, &mut synth, &mut out, end + fade)?;
+    }
+    for (i, sample) in out[end..].iter_mut().enumerate() {
+        *sample *= 1.0 - i as f32 / fade as f32;
+    }
+    Ok(out)
+}
+
+/// The stems of the song, the tracks or the voices it plays
+pub fn stems(song: &Song, split: Stems) -> Vec<Stem> {
+    match split {
+        Stems::Tracks => (0..tracks(song)).map(Stem::Track).collect(),
+        Stems::Voices => {
+            let mut played = [false; MAX_VOICES];
+            for pattern in song.order.entries().iter().map(|p| &song.patterns[*p]) {
+                for row in 0..pattern.len() {
+                    for voice in pattern.row(row).cells.iter().filter_map(|c| c.voice) {
+                        played[voice as usize] = true;
+                    }
+                }
+            }
+            (0..MAX_VOICES)
+                .filter(|slot| played[*slot] && song.voices[*slot].is_some())
+                .map(Stem::Voice)
+                .collect()
+        }
+    }
+}
+
+/// Render a part of the song, like `render`. A voice stem is the song with all other voice
+/// slots empty, notes of other voices still cut the notes of the voice.
+pub fn render_stem(song: &Song, stem: Stem, loops: usize, fade_ms: u32) -> Result<Vec<f32>, Error> {
+    match stem {
+        Stem::Track(track) => render_solo(song, Some(track), loops, fade_ms),
+        Stem::Voice(slot) => {
+            let mut song = song.clone();
+            for (_, voice) in song
+                .voices
+                .iter_mut()
+                .enumerate()
+                .filter(|(s, _)| *s != slot)
+            {
+                *voice = None;
+            }
+            render_solo(&song, None, loops, fade_ms)
+        }
+    }
+}
+
+/// Linear interpolation from `SAMPLE_RATE` to the rate
+fn resample(samples: &[f32], rate: u32) -> Vec<f32> {
+    if rate == SAMPLE_RATE || samples.is_empty() {
+        return samples.to_vec();
+    }
+    let step = SAMPLE_RATE as f64 / rate as f64;
+    let len = (samples.len() as f64 / step) as usize;
+    (0..len)
+        .map(|i| {
+            let at = i as f64 * step;
+            let (idx, frac) = (at as usize, at.fract() as f32);
+            let next = samples.get(idx + 1).unwrap_or(&samples[idx]);
+            samples[idx] + (next - samples[idx]) * frac
+        })
+        .collect()
+}
+
+/// A WAV file of the samples, which are at `SAMPLE_RATE`
+pub fn write(samples: &[f32], options: &Options) -> Vec<u8> {
+    let samples = resample(samples, options.sample_rate);
+    let format = options.format;
+    let data_len = samples.len() * format.bytes();
+    // Chunks are padded to an even length
+    let pad = data_len % 2;
+
+    let mut data = Vec::with_capacity(44 + data_len + pad);
+    let le16 = |data: &mut Vec<u8>, v: usize| data.extend_from_slice(&(v as u16).to_le_bytes());
+    let le32 = |data: &mut Vec<u8>, v: usize| data.extend_from_slice(&(v as u32).to_le_bytes());
+    data.extend_from_slice(b"RIFF");
+    le32(&mut data, 36 + data_len + pad);
+    data.extend_from_slice(b"WAVE");
+    data.extend_from_slice(b"fmt ");
+    le32(&mut data, 16);
+    le16(&mut data, format.tag() as usize);
+    le16(&mut data, 1);
+    le32(&mut data, options.sample_rate as usize);
+    le32(&mut data, options.sample_rate as usize * format.bytes());
+    le16(&mut data, format.bytes());
+    le16(&mut data, format.bytes() * 8);
+    data.extend_from_slice(b"data");
+    le32(&mut data, data_len);
+    for sample in samples {
+        format.encode(sample, &mut data);
+    }
+    data.resize(data.len() + pad, 0);
+    data
+}
+
+/// Render the song to a WAV file
+pub fn export(song: &Song, options: &Options) -> Result<Vec<u8>, Error> {
+    let samples = render(song, options.loops, options.fade_ms)?;
+    Ok(write(&samples, options))
+}
+
+/// Render a WAV file per stem
+pub fn export_stems(
+    song: &Song,
+    split: Stems,
+    options: &Options,
+) -> Result<Vec<(Stem, Vec<u8>)>, Error> {
+    stems(song, split)
+        .into_iter()
+        .map(|stem| {
+            let samples = render_stem(song, stem, options.loops, options.fade_ms)?;
+            Ok((stem, write(&samples, options)))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::song::order::OrderList;
+    use crate::song::pattern::Pattern;
+    use crate::song::player::tests::{peak, song};
+
+    /// Length of a row at the default speed and tempo
+    const ROW: usize = 6 * 882;
+
+    fn song_of_rows() -> Song {
+        song(
+            "A-4 01 -- --- : ---
+             --- -- -- --- : ---
+             --- -- -- --- : ---
+             === -- -- --- : ---",
+        )
+    }
+
+    #[test]
+    fn render_test() {
+        let song = song_of_rows();
+        let out = render(&song, 1, 0).unwrap();
+        assert_eq!(out.len(), 4 * ROW);
+        assert!(peak(&out[..ROW]) > 0.9);
+        assert_eq!(peak(&out[3 * ROW..]), 0.0);
+
+        // The second loop plays the song again
+        let out = render(&song, 2, 0).unwrap();
+        assert_eq!(out.len(), 8 * ROW);
+        assert!(peak(&out[4 * ROW..5 * ROW]) > 0.9);
+
+        // The fade continues into the next loop, getting quieter
+        let out = render(&song, 1, 100).unwrap();
+        assert_eq!(out.len(), 4 * ROW + 4410);
+        let fade = &out[4 * ROW..];
+        assert!(peak(&fade[..1000]) > 0.8);
+        assert!(peak(&fade[3410..]) < 0.25);
+    }
+
+    #[test]
+    fn tracks_test() {
+        // The second pattern is wider than the first
+        let mut song = song_of_rows();
+        song.patterns.push(
+            Pattern::parse_rows(
+                "--- -- -- --- : A-4 01 -- --- : ---
+                 --- -- -- --- : === -- -- --- : ---",
+            )
+            .unwrap(),
+        );
+        song.order = OrderList::new(vec![0, 1]);
+        assert_eq!(
+            stems(&song, Stems::Tracks),
+            vec![Stem::Track(0), Stem::Track(1)]
+        );
+        let out = render(&song, 1, 0).unwrap();
+        assert_eq!(out.len(), 6 * ROW);
+        assert!(peak(&out[4 * ROW..5 * ROW]) > 0.9);
+
+        // Errors of the engine fail the render
+        let mut synth = OfflineSynth::new(1);
+        let mut player = Player::new(&song, 1, 0);
+        let mut out = vec![];
+        assert_eq!(
+            play_tick(&song, &mut player, &mut synth, &mut out, ROW),
+            Err(Error::Synth(synth::Error::InvalidChannel(1)))
+        );
+    }
+
+    #[test]
+    fn stems_test() {
+        let mut song = song(
+            "A-4 01 -- --- : C-4 02 -- --- : ---
+             --- -- -- --- : --- -- 80 --- : ---
+             E-4 02 -- --- : --- -- -- --- : 280
+             === -- -- --- : --- -- -- --- : ---",
+        );
+        song.voices[2] = song.voices[1].clone();
+        assert_eq!(
+            stems(&song, Stems::Tracks),
+            vec![Stem::Track(0), Stem::Track(1)]
+        );
+        assert_eq!(
+            stems(&song, Stems::Voices),
+            vec![Stem::Voice(1), Stem::Voice(2)]
+        );
+        assert_eq!(Stem::Voice(2).file_name("song"), "song-voice02.wav");
+
+        // The stems have the same length and add up to the song
+        let mix = render(&song, 1, 50).unwrap();
+        for split in [Stems::Tracks, Stems::Voices] {
+            let stems: Vec<Vec<f32>> = stems(&song, split)
+                .into_iter()
+                .map(|stem| render_stem(&song, stem, 1, 50).unwrap())
+                .collect();
+            assert!(stems.iter().all(|s| s.len() == mix.len()));
+            let sum = |i: usize| stems.iter().map(|s| s[i]).sum::<f32>();
+            assert!((0..mix.len()).all(|i| (sum(i) - mix[i]).abs() < 1e-4));
+            assert!(stems.iter().all(|s| peak(s) > 0.4));
+        }
+
+        // The note of voice 2 cuts the one of voice 1
+        let voice1 = render_stem(&song, Stem::Voice(1), 1, 0).unwrap();
+        assert_eq!(peak(&voice1[2 * ROW..]), 0.0);
+    }
+
+    #[test]
+    fn write_test() {
+        let samples = [0.0, 1.0, -1.0];
+        let header =
+            |data: &[u8], at: usize| u32::from_le_bytes(data[at..at + 4].try_into().unwrap());
+        let options = |format| Options {
+            format,
+            ..Options::default()
+        };
+
+        let data = write(&samples, &options(Format::Pcm16));
+        assert_eq!(&data[..4], b"RIFF");
+        assert_eq!(&data[8..16], b"WAVEfmt ");
+        assert_eq!(header(&data, 24), SAMPLE_RATE);
+        assert_eq!(header(&data, 40), 6);
+        assert_eq!(&data[44..], &[0, 0, 0xFF, 0x7F, 0x01, 0x80]);
+
+        // 8 bit samples are unsigned and chunks have an even length
+        let data = write(&samples, &options(Format::Pcm8));
+        assert_eq!(header(&data, 4) as usize, data.len() - 8);
+        assert_eq!(&data[44..], &[0x80, 0xFF, 0x01, 0]);
+
+        let data = write(&samples, &options(Format::Pcm24));
+        assert_eq!(&data[47..50], &[0xFF, 0xFF, 0x7F]);
+        let data = write(&samples, &options(Format::Float32));
+        assert_eq!(data[20], 3);
+        assert_eq!(&data[48..52], &1.0_f32.to_le_bytes());
+
+        // Resampled to the rate of the file
+        let samples = vec![0.5; SAMPLE_RATE as usize];
+        let data = write(
+            &samples,
+            &Options {
+                sample_rate: 22050,
+                ..Options::default()
+            },
+        );
+        assert_eq!(header(&data, 24), 22050);
+        assert_eq!(header(&data, 40), 2 * 22050);
+    }
+}
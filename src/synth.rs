@@ -15,18 +15,84 @@
 // You should have received a copy of the GNU General Public License
 // along with RTRK. If not, see <https://www.gnu.org/licenses/>.
 
-use std::sync::mpsc::{self, SendError};
-use std::thread;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
 
+pub mod null;
+pub mod offline;
+pub mod queue;
+#[cfg(feature = "audio")]
 pub mod rodio;
 
 #[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Error {
+    /// Channel index outside of the channels the synth was created with
+    InvalidChannel(usize),
+    /// No default audio output device could be opened
+    NoAudioDevice,
+    /// The audio device was found, but no output sink could be created on it
+    #[cfg_attr(not(feature = "audio"), allow(dead_code))]
+    SinkUnavailable,
+    /// Too many messages are waiting for the audio thread
+    QueueFull,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::InvalidChannel(channel) => write!(f, "Invalid synth channel: {}", channel),
+            Error::NoAudioDevice => write!(f, "No audio device, output is silent"),
+            Error::SinkUnavailable => write!(f, "Could not create audio sink"),
+            Error::QueueFull => write!(f, "Synth message queue is full"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+#[derive(Clone, Debug, PartialEq)]
 pub enum Oscillator {
     Sine,
     Triangle,
     Saw,
     Square,
     Pulse,
+    Sample(Sample),
+}
+
+/// Recorded sound played at the pitch of the note. The data is shared by the song and the notes
+/// that play it, and freed with the last of them.
+#[derive(Clone, PartialEq)]
+pub struct Sample {
+    pub data: Arc<[f32]>,
+    /// Playback rate in Hz that plays the sample at its own pitch at middle C
+    pub rate: f32,
+    /// Start and length of the part that repeats while the note sounds, in samples. Without
+    /// it the sample is played once.
+    pub repeat: Option<(usize, usize)>,
+}
+
+/// Without the data, which can be long
+impl fmt::Debug for Sample {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Sample")
+            .field("len", &self.data.len())
+            .field("rate", &self.rate)
+            .field("repeat", &self.repeat)
+            .finish()
+    }
+}
+
+/// A silent sample, to be replaced by the recorded data
+impl Default for Sample {
+    fn default() -> Self {
+        Self {
+            data: Arc::new([]),
+            rate: SAMPLE_RATE as f32,
+            repeat: None,
+        }
+    }
 }
 
 #[derive(Copy, Clone, Debug, PartialEq)]
@@ -54,21 +120,58 @@ pub struct Filter {
     pub gain: f32,
 }
 
-#[derive(Copy, Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct Voice {
     pub osc: Oscillator,
     pub env: Option<Envelope>,
     pub lp: Option<Filter>,
     pub hp: Option<Filter>,
+    /// How much the note velocity affects the amplitude [0,1]
+    pub vel_amp: f32,
+    /// How much the note velocity affects the low pass cutoff [0,1]
+    pub vel_cutoff: f32,
 }
 
-const SAMPLE_RATE: u32 = 44100;
+pub const SAMPLE_RATE: u32 = 44100;
+
+/// Number of samples the engine renders between checking for new messages
+pub const BLOCK_LEN: usize = 64;
 
+const QUEUE_LEN: usize = 1024;
+const NOTE_DURATION_S: f32 = 0.6;
+/// Tempo of the messages scheduled in ticks until one is set, the classic 20 ms tick
+const DEFAULT_BPM: f32 = 125.0;
+const TICKS_PER_BEAT: u32 = 24;
+
+mod envelope;
+mod filter;
 mod math;
 mod wave_tables;
 
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub struct Frequency(pub f32);
+
+/// Highest filter cutoff, a low pass filter at this cutoff is bypassed
+pub const MAX_CUTOFF_HZ: f32 = 20000.0;
+
+/// Exponential mapping of 00-FF to 20 Hz - 20 kHz
+pub fn cutoff_hz(v: u8) -> f32 {
+    20.0 * 1000.0_f32.powf(v as f32 / 255.0)
+}
+
+/// Note on strength [0,1]
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Velocity(pub f32);
+impl Velocity {
+    pub const MAX: Velocity = Velocity(1.0);
+
+    /// Scale factor for a parameter with the given velocity sensitivity. A sensitivity of 0
+    /// ignores the velocity and 1 scales the parameter by the velocity.
+    fn scale(&self, sensitivity: f32) -> f32 {
+        1.0 - sensitivity + sensitivity * self.0
+    }
+}
+
 pub struct Note;
 
 #[allow(dead_code)]
@@ -87,39 +190,45 @@ impl Note {
     pub const B: Frequency = Frequency(493.88);
 }
 
+/// Plays a wave table, or a sample that is played once or repeats a part of it
+#[derive(Clone)]
 pub struct WaveTableOscillator {
-    wave_table: Vec<f32>,
+    wave_table: Arc<[f32]>,
     index: f32,
     index_increment: f32,
-    remaining_samples: u32,
+    /// Index increment per Hz
+    scale: f32,
+    /// Start and end of the part that repeats, None ends after the last sample
+    repeat: Option<(f32, f32)>,
     interpolator: math::Interpolator,
 }
 
 impl WaveTableOscillator {
-    fn new(
-        wave_table: Vec<f32>,
-        interpolator: math::Interpolator,
-        duration_sec: f32, // TODO should be Remaining duration (or None if infinite)
-    ) -> WaveTableOscillator {
-        let wave_table_len = wave_table.len();
-        return WaveTableOscillator {
+    fn new(osc: &Oscillator) -> WaveTableOscillator {
+        let (wave_table, interpolator) = wave_table(osc);
+        let len = wave_table.len();
+        let (scale, repeat) = match osc {
+            Oscillator::Sample(sample) => (
+                sample.rate / (Note::C.0 * SAMPLE_RATE as f32),
+                sample
+                    .repeat
+                    .filter(|(start, repeat)| *repeat > 0 && start + repeat <= len)
+                    .map(|(start, repeat)| (start as f32, (start + repeat) as f32)),
+            ),
+            _ => (len as f32 / SAMPLE_RATE as f32, Some((0.0, len as f32))),
+        };
+        WaveTableOscillator {
             wave_table,
             index: 0.0,
-            index_increment: Note::A.0 * wave_table_len as f32 / SAMPLE_RATE as f32,
-            remaining_samples: (SAMPLE_RATE as f32 * duration_sec) as u32,
+            index_increment: Note::A.0 * scale,
+            scale,
+            repeat,
             interpolator,
-        };
-    }
-
-    fn get_sample(&mut self) -> f32 {
-        let sample = (self.interpolator)(&self.wave_table, self.index);
-        self.index += self.index_increment;
-        self.index %= self.wave_table.len() as f32;
-        sample
+        }
     }
 
     fn set_frequency(&mut self, Frequency(freq_hz): Frequency) {
-        self.index_increment = freq_hz * self.wave_table.len() as f32 / SAMPLE_RATE as f32;
+        self.index_increment = freq_hz * self.scale;
     }
 }
 
@@ -127,134 +236,485 @@ impl Iterator for WaveTableOscillator {
     type Item = f32;
 
     fn next(&mut self) -> Option<f32> {
-        if self.remaining_samples > 0 {
-            self.remaining_samples -= 1;
-            Some(self.get_sample())
-        } else {
-            None
+        if self.index >= self.wave_table.len() as f32 {
+            return None;
+        }
+        let sample = (self.interpolator)(&self.wave_table, self.index);
+        self.index += self.index_increment;
+        if let Some((start, end)) = self.repeat {
+            if self.index >= end {
+                self.index = start + (self.index - end) % (end - start);
+            }
         }
+        Some(sample)
+    }
+}
+
+/// The wave tables are generated once and shared, so starting a note never allocates. A
+/// sample is its own table.
+fn wave_table(osc: &Oscillator) -> (Arc<[f32]>, math::Interpolator) {
+    static TABLES: OnceLock<[Arc<[f32]>; 5]> = OnceLock::new();
+    let tables = TABLES.get_or_init(|| {
+        [
+            wave_tables::sine(32).into(),
+            wave_tables::triangle().into(),
+            wave_tables::saw(32).into(),
+            wave_tables::square().into(),
+            wave_tables::pulse(64, 0.1).into(),
+        ]
+    });
+
+    match osc {
+        Oscillator::Sine => (tables[0].clone(), math::lerp),
+        Oscillator::Triangle => (tables[1].clone(), math::lerp),
+        Oscillator::Saw => (tables[2].clone(), math::lerp),
+        Oscillator::Square => (tables[3].clone(), math::step),
+        Oscillator::Pulse => (tables[4].clone(), math::step),
+        Oscillator::Sample(sample) => (sample.data.clone(), math::lerp),
     }
 }
 
 // TODO use Duration instead of usize
-#[derive(Debug, Copy, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Message {
-    Play(Voice, usize, Frequency),
+    /// Play a note of fixed length, e.g. to audition a voice
+    Play(Voice, usize, Frequency, Velocity),
+    /// Play a note that sounds until the channel is stopped or another note is played on it
+    NoteOn(Voice, usize, Frequency, Velocity),
+    /// Release the note on the channel. It ends when the release of its envelope is over.
     Stop(usize),
-    Terminate,
+    /// Change the pitch of the note playing on the channel
+    SetFrequency(usize, Frequency),
+    /// Gain of the channel, for the playing note and the ones after it. It applies whatever
+    /// the velocity sensitivity of the voice.
+    SetVolume(usize, f32),
+    /// Gain applied to the mix of all channels
+    SetMasterGain(f32),
+    /// Cutoff of the low pass filter applied to the mix of all channels
+    SetMasterCutoff(f32),
+    /// Mix only the channel, or all channels again
+    SetSolo(Option<usize>),
+    /// Map musical ticks to the audio clock, for the messages scheduled in ticks after it
+    #[allow(dead_code)] // The player schedules in samples so far
+    SetTempo(Tempo),
 }
 
+/// Target time of a scheduled message
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Time {
+    /// Absolute audio clock time
+    Sample(u64),
+    /// Musical time, converted to samples with the tempo of the engine when the message is
+    /// picked up at a block boundary
+    #[allow(dead_code)] // The player schedules in samples so far
+    Tick(u64),
+}
+
+/// Maps musical ticks to the audio clock. Tick 0 is at the origin sample.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Tempo {
+    pub origin: u64,
+    pub samples_per_tick: f64,
+}
+impl Tempo {
+    pub fn from_bpm(origin: u64, bpm: f32, ticks_per_beat: u32) -> Self {
+        Self {
+            origin,
+            samples_per_tick: SAMPLE_RATE as f64 * 60.0 / (bpm as f64 * ticks_per_beat as f64),
+        }
+    }
+
+    pub fn sample(&self, tick: u64) -> u64 {
+        self.origin + (tick as f64 * self.samples_per_tick).round() as u64
+    }
+}
+
+/// A message stamped with the time it should take effect at
+#[derive(Debug, Clone, PartialEq)]
+pub struct Timed {
+    pub time: Time,
+    pub msg: Message,
+}
+
+/// The UI side handle of the synth. Messages are passed to the audio thread through a lock free
+/// queue and the audio clock is shared back through an atomic.
 pub struct AsyncSynth {
-    thread: Option<thread::JoinHandle<()>>,
-    tx: mpsc::Sender<Message>,
+    tx: queue::Producer<Timed>,
+    err_rx: queue::Consumer<Error>,
+    clock: Arc<AtomicU64>,
+    errors: Vec<Error>,
+    _sink: Box<dyn AudioSink>,
 }
 impl AsyncSynth {
-    // Use a factory instead of passing the RodioSource directly since it doesn't impl Send
-    // TODO: Revisit this design to consider if there is a better way.
-    pub fn new<S, F>(sink_factory: F, channels: usize) -> Self
-    where
-        F: Fn() -> S + Send + 'static,
-        S: AudioSink<Iter = WaveTableOscillator>,
-    {
-        let (tx, rx) = mpsc::channel();
-
-        let thread = Some(thread::spawn(move || {
-            let sink = sink_factory();
-
-            let mut synth = Synth::new(sink, channels);
-
-            loop {
-                let message = rx.recv();
-
-                match message {
-                    Ok(Message::Play(voice, channel, freq)) => {
-                        synth.play(channel, &voice, freq, 0.6)
-                    }
-                    Ok(Message::Stop(channel)) => synth.stop(channel),
-                    Ok(Message::Terminate) => break,
-                    Err(_) => break,
-                }
+    /// If the sink could not be created, the error is reported and the synth falls back to a
+    /// silent sink.
+    pub fn new<S: AudioSink + 'static>(sink: Result<S, Error>, channels: usize) -> Self {
+        let (tx, rx) = queue::spsc(QUEUE_LEN);
+        let (err_tx, err_rx) = queue::spsc(QUEUE_LEN);
+        let clock = Arc::new(AtomicU64::new(0));
+        let mut errors = vec![];
+
+        let mut sink: Box<dyn AudioSink> = match sink {
+            Ok(sink) => Box::new(sink),
+            Err(e) => {
+                errors.push(e);
+                Box::new(null::NullAudioSink::new())
             }
-        }));
+        };
 
-        Self { thread, tx }
+        let engine = Engine::new(Synth::new(channels), rx, err_tx, clock.clone());
+        if let Err(e) = sink.start(engine) {
+            errors.push(e);
+        }
+
+        Self {
+            tx,
+            err_rx,
+            clock,
+            errors,
+            _sink: sink,
+        }
+    }
+
+    /// Apply the message as soon as possible
+    pub fn send(&mut self, msg: Message) -> Result<(), Error> {
+        let time = Time::Sample(self.time());
+        self.schedule(Timed { time, msg })
     }
-    pub fn send(&mut self, msg: Message) -> Result<(), SendError<Message>> {
-        self.tx.send(msg)
+
+    /// Number of samples rendered by the audio thread
+    pub fn time(&self) -> u64 {
+        self.clock.load(Ordering::Acquire)
     }
+
+    /// Errors reported by the synth since the last call
+    pub fn poll_errors(&mut self) -> Vec<Error> {
+        let mut errors = std::mem::take(&mut self.errors);
+        while let Some(e) = self.err_rx.pop() {
+            errors.push(e);
+        }
+        errors
+    }
+}
+
+/// Takes messages stamped with the time they should be applied at. Implemented by
+/// the live synth and by the offline renderer.
+pub trait MessageSink {
+    fn schedule(&mut self, timed: Timed) -> Result<(), Error>;
 }
-impl Drop for AsyncSynth {
-    fn drop(&mut self) {
-        let _ = self.send(Message::Terminate); // OK if this fails if thread already finished
-        self.thread
-            .take()
-            .unwrap()
-            .join()
-            .expect("Synth thread panic!");
+
+/// Messages that are late are applied as soon as possible, so they should be sent well ahead
+/// of time to be sample accurate.
+impl MessageSink for AsyncSynth {
+    fn schedule(&mut self, timed: Timed) -> Result<(), Error> {
+        self.tx.push(timed).map_err(|_| Error::QueueFull)
     }
 }
 
+/// An audio backend. It takes ownership of the engine and pulls samples from it, typically from
+/// the audio thread of the platform.
 pub trait AudioSink {
-    type Iter: Iterator<Item = f32>;
-    fn play(&mut self, channel: usize, data: Self::Iter);
-    fn stop(&mut self, channel: usize);
-    fn wait(&mut self, channel: usize);
+    fn start(&mut self, engine: Engine) -> Result<(), Error>;
 }
 
-pub struct Synth<S: AudioSink<Iter = WaveTableOscillator>> {
-    sink: S,
-    channels: usize,
+/// The audio render callback. New messages are picked up at block boundaries and then applied
+/// at the exact sample they are scheduled for. Nothing in here allocates or blocks.
+pub struct Engine {
+    synth: Synth,
+    rx: queue::Consumer<Timed>,
+    err_tx: queue::Producer<Error>,
+    clock: Arc<AtomicU64>,
+    /// Converts the messages scheduled in ticks
+    tempo: Tempo,
+    /// Messages and their sample time, sorted by time. Never grows beyond its initial capacity.
+    pending: Vec<(u64, Message)>,
 }
+impl Engine {
+    pub fn new(
+        synth: Synth,
+        rx: queue::Consumer<Timed>,
+        err_tx: queue::Producer<Error>,
+        clock: Arc<AtomicU64>,
+    ) -> Self {
+        Self {
+            synth,
+            rx,
+            err_tx,
+            clock,
+            tempo: Tempo::from_bpm(0, DEFAULT_BPM, TICKS_PER_BEAT),
+            pending: Vec::with_capacity(QUEUE_LEN),
+        }
+    }
+
+    pub fn render(&mut self, out: &mut [f32]) {
+        for block in out.chunks_mut(BLOCK_LEN) {
+            while let Some(timed) = self.rx.pop() {
+                self.schedule(timed);
+            }
+
+            let mut rendered = 0;
+            while rendered < block.len() {
+                let now = self.synth.time();
+                while self.pending.first().is_some_and(|(time, _)| *time <= now) {
+                    let (_, msg) = self.pending.remove(0);
+                    self.apply(msg);
+                }
+
+                let until = match self.pending.first() {
+                    Some((next, _)) => (next - now).min((block.len() - rendered) as u64) as usize,
+                    None => block.len() - rendered,
+                };
+                self.synth.render(&mut block[rendered..rendered + until]);
+                rendered += until;
+            }
 
-impl<S: AudioSink<Iter = WaveTableOscillator>> Synth<S> {
-    pub fn new(sink: S, channels: usize) -> Self {
-        Self { sink, channels }
+            self.clock.store(self.synth.time(), Ordering::Release);
+        }
     }
-    pub fn play(&mut self, channel: usize, voice: &Voice, freq_hz: Frequency, duration_s: f32) {
-        if channel >= self.channels {
-            return; // TODO : Should return propper error
+
+    fn schedule(&mut self, timed: Timed) {
+        if self.pending.len() == self.pending.capacity() {
+            let _ = self.err_tx.push(Error::QueueFull);
+            return;
         }
+        let time = match timed.time {
+            Time::Sample(sample) => sample,
+            Time::Tick(tick) => self.tempo.sample(tick),
+        };
+        // Keep the send order for messages scheduled at the same time
+        let idx = self.pending.partition_point(|(t, _)| *t <= time);
+        self.pending.insert(idx, (time, timed.msg));
+    }
 
-        let mut osc = match voice.osc {
-            Oscillator::Sine => {
-                WaveTableOscillator::new(wave_tables::sine(32), math::lerp, duration_s)
+    fn apply(&mut self, msg: Message) {
+        let result = match msg {
+            Message::Play(voice, channel, freq, vel) => {
+                self.synth
+                    .play(channel, &voice, freq, vel, Some(NOTE_DURATION_S))
             }
-            Oscillator::Triangle => {
-                WaveTableOscillator::new(wave_tables::triangle(), math::lerp, duration_s)
+            Message::NoteOn(voice, channel, freq, vel) => {
+                self.synth.play(channel, &voice, freq, vel, None)
             }
-            Oscillator::Saw => {
-                WaveTableOscillator::new(wave_tables::saw(32), math::lerp, duration_s)
+            Message::Stop(channel) => self.synth.stop(channel),
+            Message::SetFrequency(channel, freq) => self.synth.set_frequency(channel, freq),
+            Message::SetVolume(channel, gain) => self.synth.set_volume(channel, gain),
+            Message::SetMasterGain(gain) => {
+                self.synth.set_master_gain(gain);
+                Ok(())
             }
-            Oscillator::Square => {
-                WaveTableOscillator::new(wave_tables::square(), math::step, duration_s)
+            Message::SetMasterCutoff(cutoff) => {
+                self.synth.set_master_cutoff(cutoff);
+                Ok(())
             }
-            Oscillator::Pulse => {
-                WaveTableOscillator::new(wave_tables::pulse(64, 0.1), math::step, duration_s)
+            Message::SetSolo(channel) => self.synth.set_solo(channel),
+            Message::SetTempo(tempo) => {
+                self.tempo = tempo;
+                Ok(())
             }
         };
-        osc.set_frequency(freq_hz);
+        if let Err(e) = result {
+            let _ = self.err_tx.push(e); // Drop errors if nobody is reading them
+        }
+    }
+}
 
-        self.sink.play(channel, osc);
+/// The sound generation chain of a playing note
+#[derive(Clone)]
+struct Channel {
+    osc: WaveTableOscillator,
+    env: Option<envelope::Adsr>,
+    gain: f32,
+    lp: Option<filter::OnePole>,
+    hp: Option<filter::OnePole>,
+    remaining_samples: Option<u32>, // None plays until stopped
+}
+
+impl Channel {
+    fn new(
+        voice: &Voice,
+        osc: WaveTableOscillator,
+        vel: Velocity,
+        duration_sec: Option<f32>,
+    ) -> Self {
+        Self {
+            osc,
+            env: voice.env.as_ref().map(envelope::Adsr::new),
+            gain: vel.scale(voice.vel_amp),
+            lp: voice
+                .lp
+                .map(|f| filter::OnePole::low_pass(f.cutoff * vel.scale(voice.vel_cutoff))),
+            hp: voice.hp.map(|f| filter::OnePole::high_pass(f.cutoff)),
+            remaining_samples: duration_sec.map(|d| (SAMPLE_RATE as f32 * d) as u32),
+        }
     }
 
-    pub fn stop(&mut self, channel: usize) {
-        if channel >= self.channels {
-            return; // TODO : Should return propper error
+    /// Start the release of the envelope. Returns false if the note has no envelope and ends
+    /// right away.
+    fn release(&mut self) -> bool {
+        self.remaining_samples = None;
+        match &mut self.env {
+            Some(env) => {
+                env.release();
+                true
+            }
+            None => false,
         }
-        self.sink.stop(channel);
     }
-    pub fn wait_all(&mut self) {
-        for channel in 0..self.channels {
-            self.sink.wait(channel);
+
+    fn next(&mut self) -> Option<f32> {
+        if self.remaining_samples == Some(0) && !self.release() {
+            return None;
         }
+        if let Some(remaining) = &mut self.remaining_samples {
+            *remaining -= 1;
+        }
+        let level = match &mut self.env {
+            Some(env) => env.next()?,
+            None => 1.0,
+        };
+        let mut s = self.osc.next()? * self.gain * level;
+        if let Some(lp) = &mut self.lp {
+            s = lp.process(s);
+        }
+        if let Some(hp) = &mut self.hp {
+            s = hp.process(s);
+        }
+        Some(s)
     }
 }
-impl<S: AudioSink<Iter = WaveTableOscillator>> Drop for Synth<S> {
-    fn drop(&mut self) {
-        for channel in 0..self.channels {
-            self.sink.stop(channel);
+
+/// Polyphonic synth with one oscillator per channel. The channels are mixed into a mono output.
+pub struct Synth {
+    channels: Vec<Option<Channel>>,
+    /// Gain of each channel
+    volumes: Vec<f32>,
+    master_gain: f32,
+    master_lp: Option<filter::OnePole>,
+    /// The only channel that is mixed
+    solo: Option<usize>,
+    time: u64,
+}
+
+impl Synth {
+    pub fn new(channels: usize) -> Self {
+        wave_table(&Oscillator::Sine); // Generate the tables before rendering starts
+        Self {
+            channels: vec![None; channels],
+            volumes: vec![1.0; channels],
+            master_gain: 1.0,
+            master_lp: None,
+            solo: None,
+            time: 0,
         }
-        self.wait_all();
+    }
+    pub fn play(
+        &mut self,
+        channel: usize,
+        voice: &Voice,
+        freq_hz: Frequency,
+        vel: Velocity,
+        duration_s: Option<f32>,
+    ) -> Result<(), Error> {
+        if channel >= self.channels.len() {
+            return Err(Error::InvalidChannel(channel));
+        }
+
+        let mut osc = WaveTableOscillator::new(&voice.osc);
+        osc.set_frequency(freq_hz);
+
+        self.channels[channel] = Some(Channel::new(voice, osc, vel, duration_s));
+        Ok(())
+    }
+
+    /// A note with an envelope keeps sounding until its release is over
+    pub fn stop(&mut self, channel: usize) -> Result<(), Error> {
+        if channel >= self.channels.len() {
+            return Err(Error::InvalidChannel(channel));
+        }
+        let released = self.channels[channel]
+            .as_mut()
+            .is_some_and(|ch| ch.release());
+        if !released {
+            self.channels[channel] = None;
+        }
+        Ok(())
+    }
+
+    /// Changing a silent channel has no effect
+    pub fn set_frequency(&mut self, channel: usize, freq: Frequency) -> Result<(), Error> {
+        let ch = self
+            .channels
+            .get_mut(channel)
+            .ok_or(Error::InvalidChannel(channel))?;
+        if let Some(ch) = ch {
+            ch.osc.set_frequency(freq);
+        }
+        Ok(())
+    }
+
+    /// The volume of a silent channel applies to the next note played on it
+    pub fn set_volume(&mut self, channel: usize, gain: f32) -> Result<(), Error> {
+        let volume = self
+            .volumes
+            .get_mut(channel)
+            .ok_or(Error::InvalidChannel(channel))?;
+        *volume = gain;
+        Ok(())
+    }
+
+    pub fn render(&mut self, out: &mut [f32]) {
+        out.fill(0.0);
+        for (idx, channel) in self.channels.iter_mut().enumerate() {
+            if self.solo.is_some_and(|solo| solo != idx) {
+                continue;
+            }
+            let volume = self.volumes[idx];
+            if let Some(ch) = channel {
+                for sample in out.iter_mut() {
+                    match ch.next() {
+                        Some(s) => *sample += s * volume,
+                        None => {
+                            *channel = None;
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+        if let Some(lp) = &mut self.master_lp {
+            out.iter_mut().for_each(|s| *s = lp.process(*s));
+        }
+        if self.master_gain != 1.0 {
+            out.iter_mut().for_each(|s| *s *= self.master_gain);
+        }
+        self.time += out.len() as u64;
+    }
+
+    pub fn set_master_gain(&mut self, gain: f32) {
+        self.master_gain = gain;
+    }
+
+    /// The filter is bypassed at the highest cutoff
+    pub fn set_master_cutoff(&mut self, cutoff_hz: f32) {
+        match &mut self.master_lp {
+            _ if cutoff_hz >= MAX_CUTOFF_HZ => self.master_lp = None,
+            Some(lp) => lp.set_cutoff(cutoff_hz),
+            None => self.master_lp = Some(filter::OnePole::low_pass(cutoff_hz)),
+        }
+    }
+
+    /// The other channels are not rendered at all, notes of fixed length pause on them
+    pub fn set_solo(&mut self, channel: Option<usize>) -> Result<(), Error> {
+        if channel.is_some_and(|ch| ch >= self.channels.len()) {
+            return Err(Error::InvalidChannel(channel.unwrap_or_default()));
+        }
+        self.solo = channel;
+        Ok(())
+    }
+
+    /// Number of samples rendered so far
+    pub fn time(&self) -> u64 {
+        self.time
     }
 }
 
@@ -263,14 +723,13 @@ mod tests {
 
     struct AudioSinkDummy {}
     impl AudioSink for AudioSinkDummy {
-        type Iter = WaveTableOscillator;
-        fn play(&mut self, _channel: usize, _data: Self::Iter) {}
-        fn stop(&mut self, _channel: usize) {}
-        fn wait(&mut self, _channel: usize) {}
+        fn start(&mut self, _engine: Engine) -> Result<(), Error> {
+            Ok(())
+        }
     }
 
     use super::*;
-    //use crate::synth::rodio::RodioAudioSink;
+    use crate::synth::null::NullAudioSink;
     use std::thread;
     use std::time::Duration;
 
@@ -281,17 +740,23 @@ mod tests {
             env: None,
             lp: None,
             hp: None,
+            vel_amp: 0.0,
+            vel_cutoff: 0.0,
         };
 
-        //let mut synth = AsyncSynth::new(|| RodioAudioSink::new(4), 4);
-        let mut synth = AsyncSynth::new(|| AudioSinkDummy {}, 4);
+        let mut synth = AsyncSynth::new(Ok(NullAudioSink::new()), 4);
 
-        synth.send(Message::Play(voice, 0, Note::A)).expect("");
-        synth.send(Message::Play(voice, 2, Note::C)).expect("");
+        synth
+            .send(Message::Play(voice.clone(), 0, Note::A, Velocity::MAX))
+            .expect("");
+        synth
+            .send(Message::Play(voice, 2, Note::C, Velocity::MAX))
+            .expect("");
 
         thread::sleep(Duration::from_millis(100));
 
-        synth.send(Message::Terminate).expect("");
+        assert!(synth.poll_errors().is_empty());
+        assert!(synth.time() > 0);
         drop(synth);
     }
 
@@ -302,15 +767,493 @@ mod tests {
             env: None,
             lp: None,
             hp: None,
+            vel_amp: 0.0,
+            vel_cutoff: 0.0,
         };
 
-        //let sink = RodioAudioSink::new(4);
-        let sink = AudioSinkDummy {};
-        let mut synth = Synth::new(sink, 4);
+        let mut synth = Synth::new(4);
 
         let duration_sec = 1.0;
-        synth.play(0, &voice, Note::A, duration_sec);
-        synth.play(1, &voice, Note::C, duration_sec);
-        synth.wait_all();
+        synth
+            .play(0, &voice, Note::A, Velocity::MAX, Some(duration_sec))
+            .expect("");
+        synth
+            .play(1, &voice, Note::C, Velocity::MAX, Some(duration_sec))
+            .expect("");
+
+        let mut out = [0.0; BLOCK_LEN];
+        synth.render(&mut out);
+        assert!(out.iter().any(|&s| s != 0.0));
+        assert!(out.iter().any(|&s| s > 1.0)); // Both channels are mixed
+
+        synth.stop(0).expect("");
+        synth.stop(1).expect("");
+        synth.render(&mut out);
+        assert!(out.iter().all(|&s| s == 0.0));
+        assert_eq!(synth.time(), 2 * BLOCK_LEN as u64);
+    }
+
+    #[test]
+    fn note_duration_test() {
+        let voice = Voice {
+            osc: Oscillator::Square,
+            env: None,
+            lp: None,
+            hp: None,
+            vel_amp: 0.0,
+            vel_cutoff: 0.0,
+        };
+
+        let mut synth = Synth::new(1);
+        synth
+            .play(0, &voice, Note::A, Velocity::MAX, Some(0.001))
+            .expect("");
+
+        let mut out = [0.0; 100];
+        synth.render(&mut out);
+        let played = (SAMPLE_RATE as f32 * 0.001) as usize;
+        assert!(out[..played].iter().all(|&s| s != 0.0));
+        assert!(out[played..].iter().all(|&s| s == 0.0));
+    }
+
+    #[test]
+    fn invalid_channel_test() {
+        let voice = Voice {
+            osc: Oscillator::Sine,
+            env: None,
+            lp: None,
+            hp: None,
+            vel_amp: 0.0,
+            vel_cutoff: 0.0,
+        };
+
+        let mut synth = Synth::new(2);
+        assert_eq!(
+            synth.play(2, &voice, Note::A, Velocity::MAX, Some(1.0)),
+            Err(Error::InvalidChannel(2))
+        );
+        assert_eq!(synth.stop(5), Err(Error::InvalidChannel(5)));
+        assert_eq!(synth.stop(1), Ok(()));
+    }
+
+    #[test]
+    fn async_synth_error_test() {
+        let voice = Voice {
+            osc: Oscillator::Sine,
+            env: None,
+            lp: None,
+            hp: None,
+            vel_amp: 0.0,
+            vel_cutoff: 0.0,
+        };
+
+        // Errors from the audio thread are reported back instead of panicking
+        let mut synth = AsyncSynth::new(Ok(NullAudioSink::new()), 1);
+        synth
+            .send(Message::Play(voice.clone(), 3, Note::A, Velocity::MAX))
+            .expect("");
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(synth.poll_errors(), vec![Error::InvalidChannel(3)]);
+
+        // A failing sink is reported and the synth keeps running on a silent sink
+        let mut synth = AsyncSynth::new(Err::<AudioSinkDummy, _>(Error::NoAudioDevice), 1);
+        synth
+            .send(Message::Play(voice, 0, Note::A, Velocity::MAX))
+            .expect("");
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(synth.poll_errors(), vec![Error::NoAudioDevice]);
+        assert!(synth.time() > 0);
+    }
+
+    #[test]
+    fn engine_test() {
+        let voice = Voice {
+            osc: Oscillator::Square,
+            env: None,
+            lp: None,
+            hp: None,
+            vel_amp: 0.0,
+            vel_cutoff: 0.0,
+        };
+
+        let (mut tx, rx) = queue::spsc(4);
+        let (err_tx, mut err_rx) = queue::spsc(4);
+        let clock = Arc::new(AtomicU64::new(0));
+        let mut engine = Engine::new(Synth::new(1), rx, err_tx, clock.clone());
+
+        let msg = Message::Play(voice, 0, Note::A, Velocity::MAX);
+        tx.push(Timed {
+            time: Time::Sample(0),
+            msg,
+        })
+        .expect("");
+        tx.push(Timed {
+            time: Time::Sample(0),
+            msg: Message::Stop(7),
+        })
+        .expect("");
+
+        let mut out = [0.0; 2 * BLOCK_LEN];
+        engine.render(&mut out);
+        assert!(out.iter().all(|&s| s != 0.0));
+        assert_eq!(clock.load(Ordering::Acquire), 2 * BLOCK_LEN as u64);
+        assert_eq!(err_rx.pop(), Some(Error::InvalidChannel(7)));
+    }
+
+    #[test]
+    fn scheduled_message_test() {
+        let voice = Voice {
+            osc: Oscillator::Square,
+            env: None,
+            lp: None,
+            hp: None,
+            vel_amp: 0.0,
+            vel_cutoff: 0.0,
+        };
+
+        let (mut tx, rx) = queue::spsc(4);
+        let (err_tx, _err_rx) = queue::spsc(4);
+        let clock = Arc::new(AtomicU64::new(0));
+        let mut engine = Engine::new(Synth::new(1), rx, err_tx, clock);
+
+        // Sent out of order, with the stop in a later block than the start
+        let stop = BLOCK_LEN as u64 + 7;
+        tx.push(Timed {
+            time: Time::Sample(stop),
+            msg: Message::Stop(0),
+        })
+        .expect("");
+        let msg = Message::Play(voice, 0, Note::A, Velocity::MAX);
+        tx.push(Timed {
+            time: Time::Sample(10),
+            msg: msg.clone(),
+        })
+        .expect("");
+
+        let mut out = [0.0; 3 * BLOCK_LEN];
+        engine.render(&mut out);
+        assert!(out[..10].iter().all(|&s| s == 0.0));
+        assert!(out[10..stop as usize].iter().all(|&s| s != 0.0));
+        assert!(out[stop as usize..].iter().all(|&s| s == 0.0));
+
+        // Late messages are applied right away
+        tx.push(Timed {
+            time: Time::Sample(0),
+            msg,
+        })
+        .expect("");
+        engine.render(&mut out[..1]);
+        assert_ne!(out[0], 0.0);
+    }
+
+    #[test]
+    fn tempo_test() {
+        // 125 BPM at 24 ticks per beat is the classic 20 ms tick
+        let tempo = Tempo::from_bpm(100, 125.0, 24);
+        assert_eq!(tempo.sample(0), 100);
+        assert_eq!(tempo.sample(1), 100 + 882);
+        assert_eq!(tempo.sample(50), 100 + SAMPLE_RATE as u64);
+
+        let voice = Voice {
+            osc: Oscillator::Square,
+            env: None,
+            lp: None,
+            hp: None,
+            vel_amp: 0.0,
+            vel_cutoff: 0.0,
+        };
+        let (mut tx, rx) = queue::spsc(4);
+        let (err_tx, _err_rx) = queue::spsc(4);
+        let mut engine = Engine::new(Synth::new(1), rx, err_tx, Arc::new(AtomicU64::new(0)));
+
+        // Ticks are converted with the tempo set before the block they are picked up in
+        let tempo = Tempo {
+            origin: 10,
+            samples_per_tick: 5.0,
+        };
+        let timed = |time, msg| Timed { time, msg };
+        tx.push(timed(Time::Sample(0), Message::SetTempo(tempo)))
+            .expect("");
+        let mut out = [0.0; 2 * BLOCK_LEN];
+        engine.render(&mut out[..BLOCK_LEN]);
+        let note_on = Message::NoteOn(voice, 0, Note::A, Velocity::MAX);
+        tx.push(timed(Time::Tick(20), note_on)).expect("");
+        tx.push(timed(Time::Tick(22), Message::Stop(0))).expect("");
+        engine.render(&mut out);
+        let (start, stop) = (110 - BLOCK_LEN, 120 - BLOCK_LEN);
+        assert!(out[..start].iter().all(|&s| s == 0.0));
+        assert!(out[start..stop].iter().all(|&s| s != 0.0));
+        assert!(out[stop..].iter().all(|&s| s == 0.0));
+    }
+
+    #[test]
+    fn velocity_test() {
+        let mut voice = Voice {
+            osc: Oscillator::Square,
+            env: None,
+            lp: None,
+            hp: None,
+            vel_amp: 1.0,
+            vel_cutoff: 0.0,
+        };
+        let peak = |voice: &Voice, vel: Velocity| {
+            let mut synth = Synth::new(1);
+            synth.play(0, voice, Note::A, vel, Some(1.0)).expect("");
+            let mut out = [0.0; 1000];
+            synth.render(&mut out);
+            out.iter().fold(0.0, |a: f32, b| a.max(b.abs()))
+        };
+
+        // Fully sensitive amplitude follows the velocity
+        assert_eq!(peak(&voice, Velocity::MAX), 1.0);
+        assert_eq!(peak(&voice, Velocity(0.5)), 0.5);
+
+        // Half sensitivity only takes away half as much
+        voice.vel_amp = 0.5;
+        assert_eq!(peak(&voice, Velocity(0.5)), 0.75);
+
+        // Insensitive voices ignore velocity
+        voice.vel_amp = 0.0;
+        assert_eq!(peak(&voice, Velocity(0.1)), 1.0);
+
+        // A softer note closes the low pass filter
+        voice.lp = Some(Filter {
+            cutoff: 2000.0,
+            gain: 1.0,
+        });
+        voice.vel_cutoff = 1.0;
+        assert!(peak(&voice, Velocity(0.1)) < peak(&voice, Velocity::MAX));
+    }
+
+    #[test]
+    fn master_test() {
+        let voice = Voice {
+            osc: Oscillator::Square,
+            env: None,
+            lp: None,
+            hp: None,
+            vel_amp: 0.0,
+            vel_cutoff: 0.0,
+        };
+        let peak = |synth: &mut Synth| {
+            let mut out = [0.0; 1000];
+            synth.render(&mut out);
+            out.iter().fold(0.0, |a: f32, b| a.max(b.abs()))
+        };
+        let mut synth = Synth::new(2);
+        synth
+            .play(0, &voice, Note::A, Velocity::MAX, None)
+            .expect("");
+        synth
+            .play(1, &voice, Note::A, Velocity::MAX, None)
+            .expect("");
+
+        synth.set_master_gain(0.25);
+        assert_eq!(peak(&mut synth), 0.5);
+
+        synth.set_master_cutoff(cutoff_hz(0x20));
+        peak(&mut synth); // Let the filter settle
+        assert!(peak(&mut synth) < 0.1);
+
+        synth.set_master_cutoff(cutoff_hz(0xFF));
+        assert_eq!(peak(&mut synth), 0.5);
+    }
+
+    #[test]
+    fn solo_test() {
+        let voice = Voice {
+            osc: Oscillator::Square,
+            env: None,
+            lp: None,
+            hp: None,
+            vel_amp: 0.0,
+            vel_cutoff: 0.0,
+        };
+        let peak = |synth: &mut Synth| {
+            let mut out = [0.0; 1000];
+            synth.render(&mut out);
+            out.iter().fold(0.0, |a: f32, b| a.max(b.abs()))
+        };
+        let mut synth = Synth::new(2);
+        synth
+            .play(0, &voice, Note::A, Velocity::MAX, None)
+            .expect("");
+        synth
+            .play(1, &voice, Note::A, Velocity::MAX, None)
+            .expect("");
+        assert_eq!(peak(&mut synth), 2.0);
+
+        // Only the solo channel is heard, the others keep their notes
+        assert_eq!(synth.set_solo(Some(1)), Ok(()));
+        assert_eq!(peak(&mut synth), 1.0);
+        synth.stop(1).expect("");
+        assert_eq!(peak(&mut synth), 0.0);
+        assert_eq!(synth.set_solo(None), Ok(()));
+        assert_eq!(peak(&mut synth), 1.0);
+        assert_eq!(synth.set_solo(Some(2)), Err(Error::InvalidChannel(2)));
+    }
+
+    #[test]
+    fn note_change_test() {
+        let voice = Voice {
+            osc: Oscillator::Square,
+            env: None,
+            lp: None,
+            hp: None,
+            vel_amp: 0.0,
+            vel_cutoff: 0.0,
+        };
+        let mut synth = Synth::new(2);
+        synth
+            .play(0, &voice, Note::A, Velocity::MAX, None)
+            .expect("");
+        // The volume applies to a voice without velocity sensitivity
+        synth.set_volume(0, 0.5).expect("");
+        synth.set_frequency(0, Frequency(100.0)).expect("");
+
+        // Silent channels are left alone
+        synth.set_frequency(1, Note::A).expect("");
+        assert_eq!(synth.set_volume(2, 1.0), Err(Error::InvalidChannel(2)));
+
+        let mut out = vec![0.0; SAMPLE_RATE as usize];
+        synth.render(&mut out);
+        let peak = out.iter().fold(0.0, |a: f32, b| a.max(b.abs()));
+        let crossings = out
+            .windows(2)
+            .filter(|w| (w[0] < 0.0) != (w[1] < 0.0))
+            .count();
+        assert_eq!(peak, 0.5);
+        assert!((199..=201).contains(&crossings));
+
+        // The volume stays for the next note on the channel
+        synth
+            .play(0, &voice, Note::A, Velocity::MAX, None)
+            .expect("");
+        synth.render(&mut out);
+        assert_eq!(out.iter().fold(0.0, |a: f32, b| a.max(b.abs())), 0.5);
+    }
+
+    #[test]
+    fn envelope_test() {
+        let mut voice = Voice {
+            osc: Oscillator::Square,
+            env: Some(Envelope {
+                attack_ms: 10.0,
+                decay_ms: 10.0,
+                sustain_lvl: 0.5,
+                release_ms: 10.0,
+            }),
+            lp: None,
+            hp: None,
+            vel_amp: 0.0,
+            vel_cutoff: 0.0,
+        };
+        let peak = |out: &[f32]| out.iter().fold(0.0, |a: f32, b| a.max(b.abs()));
+        let ms = |ms: usize| ms * SAMPLE_RATE as usize / 1000;
+
+        // The note fades in, decays to the sustain level and fades out after it is stopped
+        let mut synth = Synth::new(1);
+        synth
+            .play(0, &voice, Note::A, Velocity::MAX, None)
+            .expect("");
+        let mut out = vec![0.0; ms(40)];
+        synth.render(&mut out);
+        assert!(peak(&out[..ms(1)]) < 0.15);
+        assert!(peak(&out[ms(9)..ms(11)]) > 0.95);
+        assert_eq!(peak(&out[ms(30)..]), 0.5);
+        synth.stop(0).expect("");
+        synth.render(&mut out);
+        assert!(peak(&out[..ms(1)]) > 0.45);
+        assert!(peak(&out[ms(5)..ms(6)]) < 0.3);
+        assert!(out[ms(11)..].iter().all(|&s| s == 0.0));
+
+        // A note of fixed length is released at its end
+        synth
+            .play(0, &voice, Note::A, Velocity::MAX, Some(0.03))
+            .expect("");
+        synth.render(&mut out);
+        assert_eq!(peak(&out[ms(25)..ms(30)]), 0.5);
+        assert!(peak(&out[ms(30)..ms(31)]) > 0.45);
+        assert!(peak(&out[ms(35)..ms(36)]) < 0.3);
+
+        // Without the envelope the note sounds at full level until it is stopped
+        voice.env = None;
+        synth
+            .play(0, &voice, Note::A, Velocity::MAX, None)
+            .expect("");
+        synth.render(&mut out);
+        assert_eq!(peak(&out[..ms(1)]), 1.0);
+        assert_eq!(peak(&out[ms(30)..]), 1.0);
+        synth.stop(0).expect("");
+        synth.render(&mut out);
+        assert!(out.iter().all(|&s| s == 0.0));
+    }
+
+    #[test]
+    fn sample_test() {
+        let sample = Sample {
+            data: Arc::from([0.0, 0.25, 0.5, 0.75]),
+            rate: SAMPLE_RATE as f32,
+            repeat: None,
+        };
+        let mut voice = Voice {
+            osc: Oscillator::Sample(sample.clone()),
+            env: None,
+            lp: None,
+            hp: None,
+            vel_amp: 0.0,
+            vel_cutoff: 0.0,
+        };
+        let render = |voice: &Voice, freq: Frequency| {
+            let mut synth = Synth::new(1);
+            synth.play(0, voice, freq, Velocity::MAX, None).expect("");
+            let mut out = [1.0; 10];
+            synth.render(&mut out);
+            out
+        };
+
+        // The sample is played once at its rate at middle C, an octave up skips every other
+        assert_eq!(
+            render(&voice, Note::C),
+            [0.0, 0.25, 0.5, 0.75, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0]
+        );
+        assert_eq!(
+            render(&voice, Frequency(2.0 * Note::C.0))[..3],
+            [0.0, 0.5, 0.0]
+        );
+
+        // The repeated part sounds until the note is stopped
+        voice.osc = Oscillator::Sample(Sample {
+            repeat: Some((2, 2)),
+            ..sample.clone()
+        });
+        assert_eq!(
+            render(&voice, Note::C),
+            [0.0, 0.25, 0.5, 0.75, 0.5, 0.75, 0.5, 0.75, 0.5, 0.75]
+        );
+
+        // The data is shared with the notes that play it and freed with the last of them
+        let data = Arc::downgrade(&sample.data);
+        drop(sample);
+        let mut synth = Synth::new(1);
+        synth
+            .play(0, &voice, Note::C, Velocity::MAX, None)
+            .expect("");
+        voice.osc = Oscillator::Sample(Sample::default());
+        assert!(data.upgrade().is_some());
+        drop(synth);
+        assert!(data.upgrade().is_none());
+
+        // A sample without data is silent
+        assert_eq!(render(&voice, Note::C), [0.0; 10]);
+    }
+
+    #[test]
+    fn queue_full_test() {
+        let mut synth = AsyncSynth::new(Ok(AudioSinkDummy {}), 1);
+        for _ in 0..QUEUE_LEN {
+            synth.send(Message::Stop(0)).expect("");
+        }
+        assert_eq!(synth.send(Message::Stop(0)), Err(Error::QueueFull));
     }
 }
@@ -0,0 +1,133 @@
+// Copyright (C) 2025 Anders Sundman <anders@4zm.org>
+//
+// This file is part of RTRK - The Rust Tracker
+//
+// RTRK is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// RTRK is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with RTRK. If not, see <https://www.gnu.org/licenses/>.
+use crate::synth::{Envelope, SAMPLE_RATE};
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Stage {
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+}
+
+/// The level of a note over time, from the ADSR envelope of its voice. The segments are
+/// linear and the level is held at the sustain level until the note is released.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Adsr {
+    env: Envelope,
+    stage: Stage,
+    level: f32,
+    /// Level change per sample of the release, which starts from wherever the level is
+    release_step: f32,
+}
+
+/// Level change per sample to cover the distance in the time, all at once if there is no time
+fn step(distance: f32, ms: f32) -> f32 {
+    let samples = ms * SAMPLE_RATE as f32 / 1000.0;
+    match samples > 1.0 {
+        true => distance / samples,
+        false => distance,
+    }
+}
+
+impl Adsr {
+    pub fn new(env: &Envelope) -> Self {
+        Self {
+            env: *env,
+            stage: Stage::Attack,
+            level: 0.0,
+            release_step: 0.0,
+        }
+    }
+
+    pub fn release(&mut self) {
+        self.stage = Stage::Release;
+        self.release_step = step(self.level, self.env.release_ms);
+    }
+}
+
+/// Ends when the release is over
+impl Iterator for Adsr {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        let sustain = self.env.sustain_lvl.clamp(0.0, 1.0);
+        match self.stage {
+            Stage::Attack => {
+                self.level += step(1.0, self.env.attack_ms);
+                if self.level >= 1.0 {
+                    self.level = 1.0;
+                    self.stage = Stage::Decay;
+                }
+            }
+            Stage::Decay => {
+                self.level -= step(1.0 - sustain, self.env.decay_ms);
+                if self.level <= sustain {
+                    self.level = sustain;
+                    self.stage = Stage::Sustain;
+                }
+            }
+            Stage::Sustain => {}
+            Stage::Release if self.level <= 0.0 => return None,
+            Stage::Release => self.level = (self.level - self.release_step).max(0.0),
+        }
+        Some(self.level)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn adsr_test() {
+        let ms = |ms: f32| (ms * SAMPLE_RATE as f32 / 1000.0) as usize;
+        let mut adsr = Adsr::new(&Envelope {
+            attack_ms: 10.0,
+            decay_ms: 10.0,
+            sustain_lvl: 0.5,
+            release_ms: 20.0,
+        });
+
+        let levels: Vec<f32> = adsr.by_ref().take(ms(30.0)).collect();
+        assert!(levels[0] > 0.0 && levels[0] < 0.01);
+        assert!((levels[ms(5.0)] - 0.5).abs() < 0.01);
+        assert_eq!(levels.iter().fold(0.0, |a: f32, b| a.max(*b)), 1.0);
+        assert!((levels[ms(15.0)] - 0.75).abs() < 0.01);
+        assert_eq!(levels[ms(20.0) + 1], 0.5);
+        assert_eq!(levels.last(), Some(&0.5));
+
+        // The release starts from the sustain level and ends the note
+        adsr.release();
+        let levels: Vec<f32> = adsr.collect();
+        assert!(levels.len().abs_diff(ms(20.0)) <= 1);
+        assert!((levels[ms(10.0)] - 0.25).abs() < 0.01);
+        assert_eq!(levels.last(), Some(&0.0));
+
+        // Without times the levels are reached at once
+        let mut adsr = Adsr::new(&Envelope {
+            attack_ms: 0.0,
+            decay_ms: 0.0,
+            sustain_lvl: 0.25,
+            release_ms: 0.0,
+        });
+        assert_eq!(adsr.next(), Some(1.0));
+        assert_eq!(adsr.next(), Some(0.25));
+        adsr.release();
+        assert_eq!(adsr.collect::<Vec<_>>(), vec![0.0]);
+    }
+}
@@ -0,0 +1,89 @@
+// Copyright (C) 2025 Anders Sundman <anders@4zm.org>
+//
+// This file is part of RTRK - The Rust Tracker
+//
+// RTRK is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// RTRK is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with RTRK. If not, see <https://www.gnu.org/licenses/>.
+use crate::synth::SAMPLE_RATE;
+
+/// One pole filter, 6 dB per octave. Cheap enough to run per channel in the render loop.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct OnePole {
+    a: f32,
+    z: f32,
+    high_pass: bool,
+}
+
+impl OnePole {
+    pub fn low_pass(cutoff_hz: f32) -> Self {
+        Self {
+            a: coefficient(cutoff_hz),
+            z: 0.0,
+            high_pass: false,
+        }
+    }
+
+    pub fn high_pass(cutoff_hz: f32) -> Self {
+        Self {
+            a: coefficient(cutoff_hz),
+            z: 0.0,
+            high_pass: true,
+        }
+    }
+
+    /// Change the cutoff without resetting the filter state, for sweeps
+    pub fn set_cutoff(&mut self, cutoff_hz: f32) {
+        self.a = coefficient(cutoff_hz);
+    }
+
+    pub fn process(&mut self, x: f32) -> f32 {
+        self.z += self.a * (x - self.z);
+        if self.high_pass {
+            x - self.z
+        } else {
+            self.z
+        }
+    }
+}
+
+fn coefficient(cutoff_hz: f32) -> f32 {
+    let nyquist = SAMPLE_RATE as f32 / 2.0;
+    let cutoff_hz = cutoff_hz.clamp(1.0, nyquist);
+    1.0 - (-std::f32::consts::TAU * cutoff_hz / SAMPLE_RATE as f32).exp()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn amplitude(filter: &mut OnePole, freq_hz: f32) -> f32 {
+        let n = SAMPLE_RATE as usize / 10;
+        (0..n)
+            .map(|i| (std::f32::consts::TAU * freq_hz * i as f32 / SAMPLE_RATE as f32).sin())
+            .map(|x| filter.process(x))
+            .skip(n / 2) // Let it settle
+            .fold(0.0, |a: f32, b| a.max(b.abs()))
+    }
+
+    #[test]
+    fn low_pass_test() {
+        assert!(amplitude(&mut OnePole::low_pass(200.0), 50.0) > 0.9);
+        assert!(amplitude(&mut OnePole::low_pass(200.0), 8000.0) < 0.1);
+    }
+
+    #[test]
+    fn high_pass_test() {
+        assert!(amplitude(&mut OnePole::high_pass(2000.0), 50.0) < 0.1);
+        assert!(amplitude(&mut OnePole::high_pass(2000.0), 10000.0) > 0.8);
+    }
+}
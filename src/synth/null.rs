@@ -0,0 +1,91 @@
+// Copyright (C) 2025 Anders Sundman <anders@4zm.org>
+//
+// This file is part of RTRK - The Rust Tracker
+//
+// RTRK is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// RTRK is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with RTRK. If not, see <https://www.gnu.org/licenses/>.
+
+use crate::synth::{AudioSink, Engine, Error, BLOCK_LEN, SAMPLE_RATE};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Silent sink used when there is no audio device. The rendered audio is discarded, but the
+/// engine is driven at the real time rate so the audio clock advances like on a real device.
+pub struct NullAudioSink {
+    running: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+impl NullAudioSink {
+    pub fn new() -> Self {
+        Self {
+            running: Arc::new(AtomicBool::new(true)),
+            thread: None,
+        }
+    }
+}
+impl AudioSink for NullAudioSink {
+    fn start(&mut self, mut engine: Engine) -> Result<(), Error> {
+        let running = self.running.clone();
+        self.thread = Some(thread::spawn(move || {
+            let start = Instant::now();
+            let mut rendered = 0u64;
+            let mut block = [0.0; BLOCK_LEN];
+
+            while running.load(Ordering::Relaxed) {
+                engine.render(&mut block);
+                rendered += BLOCK_LEN as u64;
+
+                let due = start + Duration::from_secs_f64(rendered as f64 / SAMPLE_RATE as f64);
+                thread::sleep(due.saturating_duration_since(Instant::now()));
+            }
+        }));
+        Ok(())
+    }
+}
+impl Drop for NullAudioSink {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            thread.join().expect("Null audio thread panic!");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::synth::{queue, Synth};
+    use std::sync::atomic::AtomicU64;
+
+    #[test]
+    fn null_sink_timing_test() {
+        let (_tx, rx) = queue::spsc(1);
+        let (err_tx, _err_rx) = queue::spsc(1);
+        let clock = Arc::new(AtomicU64::new(0));
+        let engine = Engine::new(Synth::new(1), rx, err_tx, clock.clone());
+
+        let mut sink = NullAudioSink::new();
+        let start = Instant::now();
+        sink.start(engine).expect("");
+        thread::sleep(Duration::from_millis(100));
+        drop(sink);
+
+        // The clock follows wall time, it doesn't run ahead
+        let elapsed = start.elapsed().as_secs_f64();
+        let rendered = clock.load(Ordering::Acquire) as f64 / SAMPLE_RATE as f64;
+        assert!(rendered > 0.05);
+        assert!(rendered <= elapsed + BLOCK_LEN as f64 / SAMPLE_RATE as f64);
+    }
+}
@@ -0,0 +1,111 @@
+// Copyright (C) 2025 Anders Sundman <anders@4zm.org>
+//
+// This file is part of RTRK - The Rust Tracker
+//
+// RTRK is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// RTRK is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with RTRK. If not, see <https://www.gnu.org/licenses/>.
+
+use crate::synth::{queue, Engine, Error, MessageSink, Synth, Timed, QUEUE_LEN};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// Renders without an audio device, as fast as the caller pulls samples. Messages go through
+/// the same engine as live playback, so the output is identical.
+pub struct OfflineSynth {
+    tx: queue::Producer<Timed>,
+    err_rx: queue::Consumer<Error>,
+    #[allow(dead_code)] // Only the tests read the clock so far
+    clock: Arc<AtomicU64>,
+    engine: Engine,
+}
+impl OfflineSynth {
+    pub fn new(channels: usize) -> Self {
+        let (tx, rx) = queue::spsc(QUEUE_LEN);
+        let (err_tx, err_rx) = queue::spsc(QUEUE_LEN);
+        let clock = Arc::new(AtomicU64::new(0));
+        let engine = Engine::new(Synth::new(channels), rx, err_tx, clock.clone());
+        Self {
+            tx,
+            err_rx,
+            clock,
+            engine,
+        }
+    }
+
+    pub fn render(&mut self, out: &mut [f32]) {
+        self.engine.render(out);
+    }
+
+    /// Number of samples rendered so far
+    #[allow(dead_code)]
+    pub fn time(&self) -> u64 {
+        self.clock.load(Ordering::Acquire)
+    }
+
+    /// Errors reported by the engine since the last call
+    pub fn poll_errors(&mut self) -> Vec<Error> {
+        let mut errors = vec![];
+        while let Some(e) = self.err_rx.pop() {
+            errors.push(e);
+        }
+        errors
+    }
+}
+
+impl MessageSink for OfflineSynth {
+    fn schedule(&mut self, timed: Timed) -> Result<(), Error> {
+        self.tx.push(timed).map_err(|_| Error::QueueFull)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::synth::{Message, Note, Oscillator, Time, Velocity, Voice};
+
+    #[test]
+    fn offline_render_test() {
+        let voice = Voice {
+            osc: Oscillator::Square,
+            env: None,
+            lp: None,
+            hp: None,
+            vel_amp: 0.0,
+            vel_cutoff: 0.0,
+        };
+        let mut synth = OfflineSynth::new(1);
+        let note_on = Message::NoteOn(voice, 0, Note::A, Velocity::MAX);
+        synth
+            .schedule(Timed {
+                time: Time::Sample(100),
+                msg: note_on,
+            })
+            .unwrap();
+        synth
+            .schedule(Timed {
+                time: Time::Sample(44100),
+                msg: Message::Stop(0),
+            })
+            .unwrap();
+
+        let mut out = vec![1.0; 2 * 44100];
+        synth.render(&mut out);
+        assert_eq!(synth.time(), 2 * 44100);
+
+        // Silent until the note starts and after it is stopped, the note sustains in between
+        assert!(out[..100].iter().all(|&s| s == 0.0));
+        assert!(out[100..44100].iter().all(|&s| s.abs() > 0.5));
+        assert!(out[44100..].iter().all(|&s| s == 0.0));
+        assert!(synth.poll_errors().is_empty());
+    }
+}
@@ -0,0 +1,164 @@
+// Copyright (C) 2025 Anders Sundman <anders@4zm.org>
+//
+// This file is part of RTRK - The Rust Tracker
+//
+// RTRK is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// RTRK is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with RTRK. If not, see <https://www.gnu.org/licenses/>.
+
+/// Wait-free single producer, single consumer ring buffer. It's used to pass messages to and
+/// from the audio thread, where blocking or allocating is not an option.
+use std::cell::UnsafeCell;
+use std::mem::MaybeUninit;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+struct Shared<T> {
+    buf: Box<[UnsafeCell<MaybeUninit<T>>]>,
+    // Monotonic counters, the slot index is the counter modulo the capacity
+    head: AtomicUsize, // Next slot to read
+    tail: AtomicUsize, // Next slot to write
+}
+
+// The producer only writes slots the consumer has released and vice versa
+unsafe impl<T: Send> Sync for Shared<T> {}
+
+/// Items left in the queue are dropped with it
+impl<T> Drop for Shared<T> {
+    fn drop(&mut self) {
+        let (head, tail) = (*self.head.get_mut(), *self.tail.get_mut());
+        let len = self.buf.len();
+        for i in 0..tail.wrapping_sub(head) {
+            unsafe {
+                self.buf[head.wrapping_add(i) % len]
+                    .get_mut()
+                    .assume_init_drop()
+            };
+        }
+    }
+}
+
+pub struct Producer<T> {
+    shared: Arc<Shared<T>>,
+}
+
+pub struct Consumer<T> {
+    shared: Arc<Shared<T>>,
+}
+
+pub fn spsc<T>(capacity: usize) -> (Producer<T>, Consumer<T>) {
+    let shared = Arc::new(Shared {
+        buf: (0..capacity)
+            .map(|_| UnsafeCell::new(MaybeUninit::uninit()))
+            .collect(),
+        head: AtomicUsize::new(0),
+        tail: AtomicUsize::new(0),
+    });
+    (
+        Producer {
+            shared: shared.clone(),
+        },
+        Consumer { shared },
+    )
+}
+
+impl<T> Producer<T> {
+    /// Hands the item back if the queue is full
+    pub fn push(&mut self, item: T) -> Result<(), T> {
+        let s = &self.shared;
+        let tail = s.tail.load(Ordering::Relaxed);
+        let head = s.head.load(Ordering::Acquire);
+        if tail.wrapping_sub(head) == s.buf.len() {
+            return Err(item);
+        }
+
+        unsafe { (*s.buf[tail % s.buf.len()].get()).write(item) };
+        s.tail.store(tail.wrapping_add(1), Ordering::Release);
+        Ok(())
+    }
+}
+
+impl<T> Consumer<T> {
+    pub fn pop(&mut self) -> Option<T> {
+        let s = &self.shared;
+        let head = s.head.load(Ordering::Relaxed);
+        let tail = s.tail.load(Ordering::Acquire);
+        if head == tail {
+            return None;
+        }
+
+        let item = unsafe { (*s.buf[head % s.buf.len()].get()).assume_init_read() };
+        s.head.store(head.wrapping_add(1), Ordering::Release);
+        Some(item)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn fifo_test() {
+        let (mut tx, mut rx) = spsc(3);
+        assert_eq!(rx.pop(), None);
+
+        assert_eq!(tx.push(1), Ok(()));
+        assert_eq!(tx.push(2), Ok(()));
+        assert_eq!(tx.push(3), Ok(()));
+        assert_eq!(tx.push(4), Err(4)); // Full
+
+        assert_eq!(rx.pop(), Some(1));
+        assert_eq!(tx.push(4), Ok(()));
+        assert_eq!(rx.pop(), Some(2));
+        assert_eq!(rx.pop(), Some(3));
+        assert_eq!(rx.pop(), Some(4));
+        assert_eq!(rx.pop(), None);
+    }
+
+    #[test]
+    fn threaded_test() {
+        let (mut tx, mut rx) = spsc(16);
+        let n = 10_000;
+
+        let producer = thread::spawn(move || {
+            for i in 0..n {
+                while tx.push(i).is_err() {
+                    thread::yield_now();
+                }
+            }
+        });
+
+        let mut expected = 0;
+        while expected < n {
+            if let Some(i) = rx.pop() {
+                assert_eq!(i, expected);
+                expected += 1;
+            }
+        }
+        producer.join().expect("");
+    }
+
+    #[test]
+    fn drop_test() {
+        let item = Arc::new(0);
+        let (mut tx, mut rx) = spsc(2);
+        tx.push(item.clone()).expect("");
+        tx.push(item.clone()).expect("");
+        drop(rx.pop());
+        assert_eq!(Arc::strong_count(&item), 2);
+
+        // What was not taken out is dropped with the queue
+        drop((tx, rx));
+        assert_eq!(Arc::strong_count(&item), 1);
+    }
+}
@@ -15,52 +15,59 @@
 // You should have received a copy of the GNU General Public License
 // along with RTRK. If not, see <https://www.gnu.org/licenses/>.
 
-use crate::synth::{AudioSink, WaveTableOscillator, SAMPLE_RATE};
-use rodio::{OutputStream, Sink, Source};
+use crate::synth::{AudioSink, Engine, Error, BLOCK_LEN, SAMPLE_RATE};
+use rodio::{OutputStream, OutputStreamHandle, Source};
 use std::time::Duration;
 
-use std::marker::PhantomData;
-pub struct RodioAudioSink<T> {
-    _marker: PhantomData<T>,
+pub struct RodioAudioSink {
     _stream: OutputStream, // Keep stream alive, can't use just the handle
-    channels: Vec<Sink>,
+    stream_handle: OutputStreamHandle,
 }
-impl<T> RodioAudioSink<T> {
-    pub fn new(n_channels: usize) -> Self {
+impl RodioAudioSink {
+    pub fn new() -> Result<Self, Error> {
         let (_stream, stream_handle) =
-            OutputStream::try_default().expect("Could not use default audio device.");
-        let channels = (0..n_channels)
-            .map(|_| Sink::try_new(&stream_handle).expect("Could not create audio sink"))
-            .collect();
-        Self {
-            _marker: PhantomData,
+            OutputStream::try_default().map_err(|_| Error::NoAudioDevice)?;
+        Ok(Self {
             _stream,
-            channels,
-        }
+            stream_handle,
+        })
     }
 }
-impl<T> AudioSink for RodioAudioSink<T>
-where
-    T: Iterator<Item = f32> + Source + Send + 'static,
-{
-    //type Iter = WaveTableOscillator;
-    type Iter = T; //Iterator<Item = f32> + Source + Send + 'static;
-    fn play(&mut self, channel: usize, data: Self::Iter) {
-        self.channels[channel].clear();
-        self.channels[channel].append(data);
-        self.channels[channel].play();
+impl AudioSink for RodioAudioSink {
+    fn start(&mut self, engine: Engine) -> Result<(), Error> {
+        let source = EngineSource {
+            engine,
+            block: [0.0; BLOCK_LEN],
+            idx: BLOCK_LEN,
+        };
+        self.stream_handle
+            .play_raw(source)
+            .map_err(|_| Error::SinkUnavailable)
     }
+}
 
-    fn stop(&mut self, channel: usize) {
-        self.channels[channel].clear();
-    }
+/// Adapts the block based engine to the sample by sample source rodio pulls from
+struct EngineSource {
+    engine: Engine,
+    block: [f32; BLOCK_LEN],
+    idx: usize,
+}
+
+impl Iterator for EngineSource {
+    type Item = f32;
 
-    fn wait(&mut self, channel: usize) {
-        self.channels[channel].sleep_until_end();
+    fn next(&mut self) -> Option<f32> {
+        if self.idx == BLOCK_LEN {
+            self.engine.render(&mut self.block);
+            self.idx = 0;
+        }
+        let sample = self.block[self.idx];
+        self.idx += 1;
+        Some(sample)
     }
 }
 
-impl Source for WaveTableOscillator {
+impl Source for EngineSource {
     fn channels(&self) -> u16 {
         1
     }
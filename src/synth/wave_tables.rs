@@ -31,11 +31,8 @@ pub fn square() -> Vec<f32> {
 pub fn saw(samples: usize) -> Vec<f32> {
     let mut table = vec![0.0; samples];
     let k = 2.0 / samples as f32;
-    for n in 0..samples / 2 {
-        table[n] = k * n as f32;
-    }
-    for n in samples / 2..samples {
-        table[n] = k * n as f32;
+    for (n, s) in table.iter_mut().enumerate() {
+        *s = k * n as f32;
     }
     table
 }
@@ -44,9 +41,7 @@ pub fn pulse(samples: usize, duty_cycle: f32) -> Vec<f32> {
     let mut table = vec![1.0; samples];
     let flip_index = (duty_cycle * samples as f32) as usize;
 
-    for i in flip_index..samples {
-        table[i] = -1.0;
-    }
+    table[flip_index..].fill(-1.0);
 
     table
 }
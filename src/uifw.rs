@@ -26,16 +26,24 @@ use std::collections::VecDeque;
 use std::time::Duration;
 use widget::{Task, View, Widget};
 
-pub trait TaskProcessor<AppTask> {
-    fn process(&mut self, task: &AppTask);
+/// Carries out the application tasks. Any outcome the application should know about (e.g. an
+/// error) is reported back as messages that are fed to the app on the next frame.
+pub trait TaskProcessor<AppTask, Message> {
+    fn process(&mut self, task: &AppTask) -> Vec<Message>;
+
+    /// Called once per frame to collect messages from asynchronous work
+    fn poll(&mut self) -> Vec<Message> {
+        vec![]
+    }
 }
 
-pub fn start<Message, AppTask, V: View<Message>, TP: TaskProcessor<AppTask>>(
+pub fn start<Message, AppTask, V: View<Message>, TP: TaskProcessor<AppTask, Message>>(
     app: &mut dyn Widget<Message, AppTask, V>,
     task_processor: &mut TP,
 ) {
     let mut renderer = term::CrosstermRenderer::new(std::io::stdout());
     let event_collector = term::CrosstermEventCollector {};
+    let mut task_messages = VecDeque::<Message>::new();
 
     'app: loop {
         // Render state
@@ -47,7 +55,8 @@ pub fn start<Message, AppTask, V: View<Message>, TP: TaskProcessor<AppTask>>(
         std::thread::sleep(Duration::from_millis(30));
 
         // Get UI event interactions
-        let mut unprocessed_messages = VecDeque::<Message>::from([]);
+        let mut unprocessed_messages = std::mem::take(&mut task_messages);
+        unprocessed_messages.extend(task_processor.poll());
         for event in event_collector.poll_events() {
             let event_messages = view.on_event(event);
             unprocessed_messages.extend(event_messages);
@@ -63,7 +72,7 @@ pub fn start<Message, AppTask, V: View<Message>, TP: TaskProcessor<AppTask>>(
         for t in tasks.iter() {
             match t {
                 Task::Quit => break 'app,
-                Task::App(t) => task_processor.process(&t),
+                Task::App(t) => task_messages.extend(task_processor.process(t)),
             }
         }
     }
@@ -25,7 +25,7 @@ pub struct Label {
 
 impl View<()> for Label {
     fn draw(&self, renderer: &mut dyn crate::uifw::interaction::Renderer) {
-        renderer.render_str(self.pos, &format!("{}", &self.text));
+        renderer.render_str(self.pos, &self.text);
     }
 }
 pub fn label(pos: Pos, text: &str) -> Label {
@@ -48,6 +48,10 @@ impl TextBox {
     pub fn text(&self) -> &str {
         &self.text
     }
+    /// Replace the text, padded or cut to the width of the box
+    pub fn set_text(&mut self, text: &str) {
+        self.text = format!("{:1$.1$}", text, self.width);
+    }
 }
 
 impl Widget<Message, (), TextBoxView> for TextBox {
@@ -131,7 +135,7 @@ impl View<Message> for TextBoxView {
             return vec![];
         }
 
-        let msgs = match e {
+        match e {
             Event::Activate => vec![],
             Event::Char(c, m) => vec![Message::EnterChar(c, m)],
             Event::Left => vec![Message::CursorLeft],
@@ -139,25 +143,19 @@ impl View<Message> for TextBoxView {
             Event::Del => vec![Message::Del],
             Event::DelBack => vec![Message::DelBack],
             _ => vec![],
-        };
-
-        msgs
+        }
     }
 
     fn draw(&self, renderer: &mut dyn Renderer) {
         if self.has_focus {
-            renderer.render_fmt_str(
-                self.pos,
-                format!("{}", &self.text[..self.carret_idx]).as_str(),
-                Style::Highlight,
-            );
+            renderer.render_fmt_str(self.pos, &self.text[..self.carret_idx], Style::Highlight);
             renderer.render_fmt_str(
                 self.pos
                     + Pos {
                         r: 0,
                         c: self.carret_idx as u16,
                     },
-                format!("{}", &self.text[self.carret_idx..self.carret_idx + 1]).as_str(),
+                &self.text[self.carret_idx..self.carret_idx + 1],
                 Style::Invert,
             );
             renderer.render_fmt_str(
@@ -166,7 +164,7 @@ impl View<Message> for TextBoxView {
                         r: 0,
                         c: self.carret_idx as u16 + 1,
                     },
-                format!("{}", &self.text[self.carret_idx + 1..]).as_str(),
+                &self.text[self.carret_idx + 1..],
                 Style::Highlight,
             );
         } else {